@@ -6,25 +6,102 @@ use android_logger as logger;
 #[cfg(not(target_os = "android"))]
 use env_logger as logger;
 
-pub struct Logger {}
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::sync::{Arc, Once, OnceLock, RwLock};
+
+/// Receives every log record (panics included) instead of the platform logger, e.g. to route
+/// them into a host app's own logging. Called from whatever thread logged
+pub type LogSink = Box<dyn Fn(Level, &str) + Send + Sync>;
+
+/// Shared so a record is handed to the sink outside the lock, a sink that logs or replaces
+/// itself doesn't deadlock
+static SINK: RwLock<Option<Arc<dyn Fn(Level, &str) + Send + Sync>>> = RwLock::new(None);
+/// Level the platform logger was configured with
+static PLATFORM_LEVEL: OnceLock<LevelFilter> = OnceLock::new();
+static INSTALL: Once = Once::new();
+
+/// Sends log records to `sink` from now on, `None` goes back to the platform logger
+/// The sink gets Debug and above. Installs this crate's logger if `Logger::new` didn't yet,
+/// has no effect when the application installed another `log` implementation
+pub fn set_log_sink(sink: Option<LogSink>) {
+    install();
+    let level = PLATFORM_LEVEL.get().map(|&platform_level| match sink {
+        Some(_) => platform_level.max(LevelFilter::Debug),
+        None => platform_level,
+    });
+    *SINK.write().unwrap() = sink.map(Arc::from);
+    // Only touch the max level of our own logger
+    if let Some(level) = level {
+        log::set_max_level(level);
+    }
+}
+
+/// Forwards records to the registered sink, or to the platform logger when there is none
+struct Dispatch {
+    platform: Box<dyn Log>,
+}
+
+impl Log for Dispatch {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match &*SINK.read().unwrap() {
+            Some(_) => metadata.level() <= LevelFilter::Debug,
+            None => self.platform.enabled(metadata),
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        let sink = SINK.read().unwrap().clone();
+        match sink {
+            Some(sink) if record.level() <= LevelFilter::Debug => {
+                sink(record.level(), &record.args().to_string())
+            }
+            Some(_) => {}
+            None => self.platform.log(record),
+        }
+    }
+
+    fn flush(&self) {
+        self.platform.flush();
+    }
+}
+
+/// Installs `Dispatch` in front of the platform logger and the panic logging, once
+fn install() {
+    INSTALL.call_once(|| {
+        log_panics::init(); // log panics rather than printing them
 
-impl Logger {
-    pub fn new() -> Self {
         #[cfg(target_os = "android")]
-        {
-            logger::init_once(
+        let (platform, level): (Box<dyn Log>, LevelFilter) = (
+            Box::new(logger::AndroidLogger::new(
                 android_logger::Config::default()
-                    .with_max_level(log::LevelFilter::Debug)
+                    .with_max_level(LevelFilter::Debug)
                     .with_tag("tor-ffi"),
-            );
-            info!("Android Logger init!");
-        }
+            )),
+            LevelFilter::Debug,
+        );
         #[cfg(not(target_os = "android"))]
-        {
-            let _ = logger::try_init();
+        let (platform, level): (Box<dyn Log>, LevelFilter) = {
+            let env_logger = logger::Builder::from_default_env().build();
+            let level = env_logger.filter();
+            (Box::new(env_logger), level)
+        };
+
+        let dispatch: &'static Dispatch = Box::leak(Box::new(Dispatch { platform }));
+        if log::set_logger(dispatch).is_ok() {
+            let _ = PLATFORM_LEVEL.set(level);
+            log::set_max_level(level);
         }
+    });
+}
+
+pub struct Logger {}
+
+impl Logger {
+    pub fn new() -> Self {
+        install();
+        #[cfg(target_os = "android")]
+        info!("Android Logger init!");
 
-        log_panics::init(); // log panics rather than printing them
         info!("logging init");
         Logger {}
     }
@@ -1,4 +1,10 @@
 fn main() {
+    #[cfg(feature = "ffi")]
+    generate_header();
+}
+
+#[cfg(feature = "ffi")]
+fn generate_header() {
     let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
 
     // Generate C/C++ headers
@@ -12,6 +18,7 @@ fn main() {
         .write_to_file("tor_ffi.h");
 
     println!("cargo:rerun-if-changed=src/lib.rs");
+    println!("cargo:rerun-if-changed=src/ffi.rs");
     println!("cargo:rerun-if-changed=cbindgen.toml");
     println!("cargo:rustc-link-lib=tor-ffi");
 }
@@ -0,0 +1,1781 @@
+use logger::Logger;
+use logger::log::debug;
+
+use once_cell::sync::OnceCell;
+#[cfg(feature = "leak-tracking")]
+use std::collections::HashSet;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_long, c_uchar, c_uint, c_ulong, c_ushort, c_void};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tor::http_client::{
+    HttpMethod, HttpRequestParams, ProgressCallback, clear_pool, make_http_request,
+    make_raw_http_request, metrics_prometheus, pool_stats,
+};
+
+static INITIALIZED: OnceCell<bool> = OnceCell::new();
+
+use tor::bootstrap::CancelToken;
+use tor::safe_logging::scrub;
+use tor::{
+    OwnedTorService, OwnedTorServiceBootstrapPhase, TorErrors, TorHiddenServiceParam, TorService,
+    TorServiceParam, ensure_runtime,
+};
+
+// Global state management for the Tor service
+static TOR_SERVICE: OnceCell<Mutex<Option<OwnedTorService>>> = OnceCell::new();
+
+fn ensure_tor_service() -> &'static Mutex<Option<OwnedTorService>> {
+    TOR_SERVICE.get_or_init(|| Mutex::new(None))
+}
+
+// Limits applied to every FFI HTTP request, 0 means the Rust default
+static MAX_HEADER_BYTES: AtomicUsize = AtomicUsize::new(0);
+static MAX_RESPONSE_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+// Code (TorErrors::code) and message of the last failed init_tor_service call
+static LAST_ERROR: Mutex<Option<(c_int, String)>> = Mutex::new(None);
+
+fn set_last_error(error: Option<&TorErrors>) {
+    *LAST_ERROR.lock().unwrap() = error.map(|e| (e.code() as c_int, e.to_string()));
+}
+
+// Token of the bootstrap in progress, if any
+static BOOTSTRAP_CANCEL: Mutex<Option<CancelToken>> = Mutex::new(None);
+
+// Starts Tor and waits for bootstrap, cancel_bootstrap aborts the wait
+fn start_tor_service(param: TorServiceParam) -> Result<OwnedTorService, TorErrors> {
+    let cancel = CancelToken::new();
+    *BOOTSTRAP_CANCEL.lock().unwrap() = Some(cancel.clone());
+    let result =
+        TorService::try_from(param).and_then(|service| service.bootstrap_with_cancel(&cancel));
+    *BOOTSTRAP_CANCEL.lock().unwrap() = None;
+    result
+}
+
+// Failure of an FFI call: a TorErrors::code, or -1 when it isn't a TorErrors (library not
+// initialized, no service running, bad arguments), and its message
+struct FfiError {
+    code: c_int,
+    message: String,
+}
+
+impl From<TorErrors> for FfiError {
+    fn from(e: TorErrors) -> Self {
+        FfiError {
+            code: e.code() as c_int,
+            message: e.to_string(),
+        }
+    }
+}
+
+fn ffi_error(message: &str) -> FfiError {
+    FfiError {
+        code: -1,
+        message: message.to_string(),
+    }
+}
+
+// Outcome of a *_with_error call: 0 and a null *out_error on success, otherwise the error
+// code and its message in *out_error, to be freed with free_string. out_error may be null
+fn report(out_error: *mut *mut c_char, result: Result<(), FfiError>) -> c_int {
+    let (code, message) = match result {
+        Ok(()) => (0, None),
+        Err(e) => (e.code, Some(e.message)),
+    };
+    if !out_error.is_null() {
+        unsafe {
+            *out_error = message.map_or(std::ptr::null_mut(), to_c_string);
+        }
+    }
+    code
+}
+
+// Runs f on the running service
+fn with_service<T>(
+    f: impl FnOnce(&mut OwnedTorService) -> Result<T, TorErrors>,
+) -> Result<T, FfiError> {
+    match ensure_tor_service().lock().unwrap().as_mut() {
+        Some(service) => f(service).map_err(FfiError::from),
+        None => Err(ffi_error("Tor service not running")),
+    }
+}
+
+// Starts the service from param and keeps it as the running one
+fn init_with_param(param: Result<TorServiceParam, TorErrors>) -> Result<(), FfiError> {
+    if INITIALIZED.get().is_none() {
+        return Err(ffi_error("Tor library not initialized"));
+    }
+    match param.and_then(start_tor_service) {
+        Ok(service) => {
+            *ensure_tor_service().lock().unwrap() = Some(service);
+            debug!("Rust FFI: Tor service initialized!");
+            set_last_error(None);
+            Ok(())
+        }
+        Err(e) => {
+            debug!("Rust FFI: Error initializing Tor service! {:?}", e);
+            set_last_error(Some(&e));
+            Err(e.into())
+        }
+    }
+}
+
+fn param_from_json(config_json: *const c_char) -> Result<TorServiceParam, TorErrors> {
+    if config_json.is_null() {
+        return Err(TorErrors::InvalidConfig(String::from("No config given")));
+    }
+    TorServiceParam::from_json(&from_c_str(config_json))
+}
+
+fn param_from_args(
+    socks_port: c_ushort,
+    data_dir: *const c_char,
+    timeout_ms: c_ulong,
+) -> TorServiceParam {
+    let data_dir_str = from_c_str(data_dir);
+
+    debug!(
+        "Rust FFI: Initializing Tor service with parameters: socks_port={}, data_dir={}, timeout_ms={}",
+        socks_port, data_dir_str, timeout_ms
+    );
+
+    TorServiceParam {
+        socks_port: Some(socks_port as u16),
+        data_dir: data_dir_str,
+        bootstrap_timeout_ms: Some(timeout_ms as u64),
+        ..Default::default()
+    }
+}
+
+// C-compatible structs with primitive types only
+#[repr(C)]
+pub struct HiddenServiceResponse {
+    pub is_success: bool,
+    pub onion_address: *mut c_char,
+    pub control: *mut c_char,
+    // false when the service was created with its key discarded, secret_key is zeroed then
+    pub has_secret_key: bool,
+    pub secret_key: [c_uchar; 64],
+}
+
+#[repr(C)]
+pub struct StartTorResponse {
+    pub is_success: bool,
+    pub onion_address: *mut c_char,
+    pub control: *mut c_char,
+    pub error_message: *mut c_char,
+}
+
+// Addresses of the C strings handed out and not freed yet, with the leak-tracking feature
+#[cfg(feature = "leak-tracking")]
+static LIVE_STRINGS: OnceCell<Mutex<HashSet<usize>>> = OnceCell::new();
+
+#[cfg(feature = "leak-tracking")]
+fn live_strings() -> &'static Mutex<HashSet<usize>> {
+    LIVE_STRINGS.get_or_init(|| Mutex::new(HashSet::new()))
+}
+
+fn track_string(s: *mut c_char) -> *mut c_char {
+    #[cfg(feature = "leak-tracking")]
+    live_strings().lock().unwrap().insert(s as usize);
+    s
+}
+
+fn untrack_string(_s: *mut c_char) {
+    #[cfg(feature = "leak-tracking")]
+    if !live_strings().lock().unwrap().remove(&(_s as usize)) {
+        debug!("Rust FFI: free_string called on a string not from this library or twice");
+    }
+}
+
+// Helper to create a C string from Rust string
+fn to_c_string(s: String) -> *mut c_char {
+    let c_str = CString::new(s).unwrap_or_else(|_| CString::new("").unwrap());
+    track_string(c_str.into_raw())
+}
+
+// Helper to create an empty C string
+fn empty_c_string() -> *mut c_char {
+    let c_str = CString::new("").unwrap();
+    track_string(c_str.into_raw())
+}
+
+// Helper function to safely convert C string to Rust string
+fn from_c_str(s: *const c_char) -> String {
+    if s.is_null() {
+        return String::new();
+    }
+
+    unsafe { CStr::from_ptr(s).to_string_lossy().into_owned() }
+}
+
+// Export functions with C ABI
+// Called with the level (1 error, 2 warn, 3 info, 4 debug) and text of every log record,
+// panics included. The message is only valid during the call, which may come from any thread
+pub type LogCallback = extern "C" fn(level: c_int, message: *const c_char);
+
+// Routes this library's log output to callback instead of logcat / stderr, null restores
+// the platform logger
+#[unsafe(no_mangle)]
+pub extern "C" fn set_log_callback(callback: Option<LogCallback>) {
+    let sink = callback.map(|callback| -> logger::LogSink {
+        Box::new(move |level, message| {
+            let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+            callback(level as c_int, message.as_ptr());
+        })
+    });
+    logger::set_log_sink(sink);
+}
+
+// Worker threads of the library's runtime (2 by default, or SIFIR_TOR_WORKER_THREADS from
+// the environment). Call before initialize_tor_library, returns false once the runtime runs
+// or for 0 threads
+#[unsafe(no_mangle)]
+pub extern "C" fn set_runtime_worker_threads(threads: c_uint) -> bool {
+    match tor::set_runtime_worker_threads(threads as usize) {
+        Ok(()) => true,
+        Err(e) => {
+            debug!("Rust FFI: Error setting worker threads {:?}", e);
+            false
+        }
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn initialize_tor_library() -> bool {
+    if INITIALIZED.get().is_some() {
+        return true;
+    }
+
+    let _logger = Logger::new();
+
+    // Initialize runtime
+    let _ = ensure_runtime();
+
+    // Initialize TOR_SERVICE
+    let _ = ensure_tor_service();
+
+    match INITIALIZED.set(true) {
+        Ok(_) => true,
+        Err(_) => false,
+    }
+}
+
+/// Same as `init_tor_service` with every option given as a JSON `TorServiceParam`,
+/// e.g. `{"data_dir": "/tmp/tor", "socks_port": 19050, "bootstrap_timeout_ms": 45000}`
+#[unsafe(no_mangle)]
+pub extern "C" fn init_tor_service_json(config_json: *const c_char) -> bool {
+    init_with_param(param_from_json(config_json)).is_ok()
+}
+
+// init_tor_service_json returning 0 or an error code with the message in out_error
+#[unsafe(no_mangle)]
+pub extern "C" fn init_tor_service_json_with_error(
+    config_json: *const c_char,
+    out_error: *mut *mut c_char,
+) -> c_int {
+    report(out_error, init_with_param(param_from_json(config_json)))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn init_tor_service(
+    socks_port: c_ushort,
+    data_dir: *const c_char,
+    timeout_ms: c_ulong,
+) -> bool {
+    init_with_param(Ok(param_from_args(socks_port, data_dir, timeout_ms))).is_ok()
+}
+
+// init_tor_service returning 0 or an error code (see get_last_error_code) with the message in
+// out_error
+#[unsafe(no_mangle)]
+pub extern "C" fn init_tor_service_with_error(
+    socks_port: c_ushort,
+    data_dir: *const c_char,
+    timeout_ms: c_ulong,
+    out_error: *mut *mut c_char,
+) -> c_int {
+    report(
+        out_error,
+        init_with_param(Ok(param_from_args(socks_port, data_dir, timeout_ms))),
+    )
+}
+
+// Why the last init_tor_service call failed: 0 when it succeeded, otherwise a
+// TorErrors::code, e.g. 11 when the SOCKS port is already in use (retry with another port or
+// "auto_socks_port": true through init_tor_service_json)
+#[unsafe(no_mangle)]
+pub extern "C" fn get_last_error_code() -> c_int {
+    match &*LAST_ERROR.lock().unwrap() {
+        Some((code, _)) => *code,
+        None => 0,
+    }
+}
+
+// Message for get_last_error_code, empty when there is none. Free with free_string
+#[unsafe(no_mangle)]
+pub extern "C" fn get_last_error_message() -> *mut c_char {
+    match &*LAST_ERROR.lock().unwrap() {
+        Some((_, message)) => to_c_string(message.clone()),
+        None => empty_c_string(),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn create_hidden_service(
+    port: c_ushort,
+    target_port: c_ushort,
+    key_data: *const c_uchar,
+    has_key: bool,
+) -> HiddenServiceResponse {
+    create_hidden_service_with(port, target_port, key_data, has_key, false)
+}
+
+// Creates a one-shot hidden service whose secret key is discarded by Tor
+#[unsafe(no_mangle)]
+pub extern "C" fn create_hidden_service_discard_key(
+    port: c_ushort,
+    target_port: c_ushort,
+) -> HiddenServiceResponse {
+    create_hidden_service_with(port, target_port, std::ptr::null(), false, true)
+}
+
+fn failed_hidden_service_response() -> HiddenServiceResponse {
+    HiddenServiceResponse {
+        is_success: false,
+        onion_address: empty_c_string(),
+        control: empty_c_string(),
+        has_secret_key: false,
+        secret_key: [0; 64],
+    }
+}
+
+fn create_hidden_service_with(
+    port: c_ushort,
+    target_port: c_ushort,
+    key_data: *const c_uchar,
+    has_key: bool,
+    discard_key: bool,
+) -> HiddenServiceResponse {
+    try_create_hidden_service(port, target_port, key_data, has_key, discard_key)
+        .unwrap_or_else(|_| failed_hidden_service_response())
+}
+
+// create_hidden_service returning 0 or an error code with the message in out_error, the
+// service is written to out_response on success
+#[unsafe(no_mangle)]
+pub extern "C" fn create_hidden_service_with_error(
+    port: c_ushort,
+    target_port: c_ushort,
+    key_data: *const c_uchar,
+    has_key: bool,
+    out_response: *mut HiddenServiceResponse,
+    out_error: *mut *mut c_char,
+) -> c_int {
+    if out_response.is_null() {
+        return report(out_error, Err(ffi_error("No response buffer given")));
+    }
+    let result = try_create_hidden_service(port, target_port, key_data, has_key, false)
+        .map(|response| unsafe { out_response.write(response) });
+    report(out_error, result)
+}
+
+fn try_create_hidden_service(
+    port: c_ushort,
+    target_port: c_ushort,
+    key_data: *const c_uchar,
+    has_key: bool,
+    discard_key: bool,
+) -> Result<HiddenServiceResponse, FfiError> {
+    let mut service_guard = ensure_tor_service().lock().unwrap();
+
+    debug!(
+        "Rust FFI: Creating hidden service with parameters: port={}, target_port={}, has_key={}",
+        port, target_port, has_key
+    );
+
+    if let Some(service) = service_guard.as_mut() {
+        let mut key_bytes = [0u8; 64];
+        if has_key && !key_data.is_null() {
+            unsafe {
+                std::ptr::copy_nonoverlapping(key_data, key_bytes.as_mut_ptr(), 64);
+            }
+        }
+
+        let param = TorHiddenServiceParam {
+            to_port: target_port as u16,
+            hs_port: port as u16,
+            secret_key: if has_key { Some(key_bytes) } else { None },
+            discard_key,
+            ..Default::default()
+        };
+
+        debug!(
+            "Rust FFI: Creating hidden service with parameters: {:?} and control port {} and control host {}",
+            param.to_port,
+            service.control_port.split(":").last().unwrap(),
+            service.control_port.split(":").next().unwrap()
+        );
+
+        match service.create_hidden_service(param) {
+            Ok(result) => {
+                debug!(
+                    "Rust FFI: Hidden service created {} ",
+                    scrub(&result.onion_url.to_string())
+                );
+                Ok(HiddenServiceResponse {
+                    is_success: true,
+                    onion_address: to_c_string(result.onion_url.to_string()),
+                    control: to_c_string(service.control_port.trim().into()),
+                    has_secret_key: result.secret_key.is_some(),
+                    secret_key: result.secret_key.unwrap_or([0; 64]),
+                })
+            }
+            Err(e) => {
+                debug!(
+                    "Rust FFI: Error creating hidden service {}",
+                    scrub(&format!("{:?}", e))
+                );
+                Err(e.into())
+            }
+        }
+    } else {
+        debug!("Rust FFI: No service created");
+        Err(ffi_error("Tor service not running"))
+    }
+}
+
+// Publishes an onion service migrated from a standalone Tor under the same address, from its
+// hs_ed25519_secret_key file. Returns 0 or an error code (8 for a malformed key file) with
+// the message in out_error, the service is written to out_response on success
+#[unsafe(no_mangle)]
+pub extern "C" fn create_hidden_service_from_key_file(
+    key_file: *const c_char,
+    port: c_ushort,
+    target_port: c_ushort,
+    out_response: *mut HiddenServiceResponse,
+    out_error: *mut *mut c_char,
+) -> c_int {
+    if out_response.is_null() {
+        return report(out_error, Err(ffi_error("No response buffer given")));
+    }
+    let key_file_str = from_c_str(key_file);
+    let result = tor::read_hs_secret_key_file(&key_file_str)
+        .map_err(FfiError::from)
+        .and_then(|key| try_create_hidden_service(port, target_port, key.as_ptr(), true, false))
+        .map(|response| unsafe { out_response.write(response) });
+    report(out_error, result)
+}
+
+// Generates a v3 onion identity without a running Tor instance
+// Writes the 64 byte secret key to key_out and returns the onion address
+#[unsafe(no_mangle)]
+pub extern "C" fn generate_onion_keypair(key_out: *mut c_uchar) -> *mut c_char {
+    if key_out.is_null() {
+        debug!("Rust FFI: No key buffer passed to generate_onion_keypair");
+        return empty_c_string();
+    }
+
+    let (onion_address, secret_key) = tor::generate_onion_keypair();
+    unsafe {
+        std::ptr::copy_nonoverlapping(secret_key.as_ptr(), key_out, secret_key.len());
+    }
+    to_c_string(onion_address)
+}
+
+pub type VanityProgressCallback = extern "C" fn(attempts: c_ulong);
+
+// Generates v3 onion identities on every CPU until the address starts with prefix
+// (a-z, 2-7), giving up after max_attempts keys. progress, when set, gets the number of
+// keys tried about every 250ms on the calling thread
+// Writes the 64 byte secret key to key_out and returns the onion address, or an empty
+// string with the error in *out_error (as with the *_with_error calls) on failure
+#[unsafe(no_mangle)]
+pub extern "C" fn generate_vanity_onion(
+    prefix: *const c_char,
+    max_attempts: c_ulong,
+    key_out: *mut c_uchar,
+    progress: Option<VanityProgressCallback>,
+    out_error: *mut *mut c_char,
+) -> *mut c_char {
+    if prefix.is_null() || key_out.is_null() {
+        report(out_error, Err(ffi_error("No prefix or key buffer passed")));
+        return empty_c_string();
+    }
+    let prefix = from_c_str(prefix);
+    let result = tor::vanity::generate_vanity_onion_with_progress(
+        &prefix,
+        max_attempts as u64,
+        |attempts| {
+            if let Some(progress) = progress {
+                progress(attempts as c_ulong);
+            }
+        },
+    );
+    match result {
+        Ok((onion_address, secret_key)) => {
+            report(out_error, Ok(()));
+            unsafe {
+                std::ptr::copy_nonoverlapping(secret_key.as_ptr(), key_out, secret_key.len());
+            }
+            to_c_string(onion_address)
+        }
+        Err(e) => {
+            debug!("Rust FFI: Vanity onion generation failed: {}", e);
+            report(out_error, Err(e.into()));
+            empty_c_string()
+        }
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn start_tor_if_not_running(
+    data_dir: *const c_char,
+    key_data: *const c_uchar,
+    has_key: bool,
+    socks_port: c_ushort,
+    target_port: c_ushort,
+    timeout_ms: c_ulong,
+) -> StartTorResponse {
+    // First initialize library if needed
+    if !initialize_tor_library() {
+        return StartTorResponse {
+            is_success: false,
+            onion_address: to_c_string(String::new()),
+            control: to_c_string(String::new()),
+            error_message: to_c_string("Failed to initialize Tor library".to_string()),
+        };
+    }
+
+    // Check current service status
+    let status = get_service_status();
+
+    // If the service is already ready (status = 1) or in progress (status = 0),
+    // we can attempt to create a hidden service without re-initializing
+    if status == 2 {
+        // Only initialize if status indicates error or not initialized
+        debug!(
+            "Rust FFI: Tor service needs initialization. Status: {}",
+            status
+        );
+
+        // Initialize Tor service
+        if !init_tor_service(socks_port, data_dir, timeout_ms) {
+            return StartTorResponse {
+                is_success: false,
+                onion_address: empty_c_string(),
+                control: empty_c_string(),
+                error_message: to_c_string(match &*LAST_ERROR.lock().unwrap() {
+                    Some((_, message)) => format!("Failed to initialize Tor service: {}", message),
+                    None => "Failed to initialize Tor service".to_string(),
+                }),
+            };
+        }
+    } else {
+        debug!(
+            "Rust FFI: Tor service already initialized. Status: {}",
+            status
+        );
+    }
+
+    // Create hidden service
+    let hs_response = create_hidden_service(socks_port, target_port, key_data, has_key);
+
+    // Create a response with simple types only
+    StartTorResponse {
+        is_success: hs_response.is_success,
+        onion_address: if hs_response.is_success {
+            hs_response.onion_address
+        } else {
+            empty_c_string()
+        },
+        control: if hs_response.is_success {
+            hs_response.control
+        } else {
+            empty_c_string()
+        },
+        error_message: if hs_response.is_success {
+            empty_c_string()
+        } else {
+            to_c_string("Failed to create hidden service".to_string())
+        },
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn get_service_status() -> c_int {
+    let service_guard = ensure_tor_service().lock().unwrap();
+
+    match &*service_guard {
+        Some(service) => match service.get_status() {
+            Ok(OwnedTorServiceBootstrapPhase::Done) => 1,
+            Ok(_) => 0,
+            Err(_) => 2,
+        },
+        None => 2,
+    }
+}
+
+// True only when a service is running and fully bootstrapped, see get_service_status for
+// the detailed state
+#[unsafe(no_mangle)]
+pub extern "C" fn is_bootstrapped() -> bool {
+    match &*ensure_tor_service().lock().unwrap() {
+        Some(service) => service.is_bootstrapped(),
+        None => false,
+    }
+}
+
+// Replaces onion addresses and URLs in this library's log lines with [scrubbed] while
+// enabled, see safe_logging in TorServiceParam for Tor's own log. Off by default
+#[unsafe(no_mangle)]
+pub extern "C" fn set_safe_logging(enabled: bool) {
+    tor::safe_logging::set_safe_logging(enabled);
+}
+
+// Refuses every request and stream that wouldn't go through the running, bootstrapped
+// service (blocked with error code 15) while enabled. Off by default
+#[unsafe(no_mangle)]
+pub extern "C" fn set_killswitch(enabled: bool) {
+    tor::killswitch::set_killswitch(enabled);
+}
+
+// Sends requests that don't pick a circuit pinning over a different circuit per destination
+// host, see tor::http_client::set_isolate_by_destination. Off by default
+#[unsafe(no_mangle)]
+pub extern "C" fn set_isolate_by_destination(enabled: bool) {
+    tor::http_client::set_isolate_by_destination(enabled);
+}
+
+// Caps how many HTTP requests run at once, the rest wait for a free slot. 0 removes the
+// limit, which is the default
+#[unsafe(no_mangle)]
+pub extern "C" fn set_max_concurrent_requests(max: c_uint) {
+    tor::http_client::set_max_concurrent_requests(max as usize);
+}
+
+// Latest bootstrap percentage (0-100), -1 before Tor reported any progress
+// Doesn't lock the service so it can be polled while init_tor_service is still bootstrapping
+#[unsafe(no_mangle)]
+pub extern "C" fn get_bootstrap_progress() -> c_int {
+    match tor::bootstrap::bootstrap_progress() {
+        Some(progress) => progress as c_int,
+        None => -1,
+    }
+}
+
+// Estimated seconds until bootstrap completes, from the recent rate of progress, -1 while
+// there isn't enough progress to tell. Like get_bootstrap_progress it doesn't lock the service
+#[unsafe(no_mangle)]
+pub extern "C" fn get_bootstrap_eta_seconds() -> c_long {
+    match tor::bootstrap::estimated_seconds_remaining() {
+        Some(seconds) => seconds.min(c_long::MAX as u64) as c_long,
+        None => -1,
+    }
+}
+
+// Current bootstrap phase tag as Tor names it (e.g. "conn", "loading_descriptors", "done"),
+// empty before Tor reported one. Free with free_string
+#[unsafe(no_mangle)]
+pub extern "C" fn get_bootstrap_phase() -> *mut c_char {
+    match tor::bootstrap::bootstrap_status() {
+        Some(status) => to_c_string(status.tag),
+        None => empty_c_string(),
+    }
+}
+
+// Current bootstrap phase as a stable code, see tor::bootstrap::BootstrapTag and tor_ffi.h
+#[unsafe(no_mangle)]
+pub extern "C" fn get_bootstrap_phase_code() -> c_int {
+    tor::bootstrap::bootstrap_tag() as c_int
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn get_tor_version() -> *mut c_char {
+    let service_guard = ensure_tor_service().lock().unwrap();
+
+    match &*service_guard {
+        Some(service) => match service.version() {
+            Ok(version) => to_c_string(version),
+            Err(e) => {
+                debug!("Rust FFI: Error getting Tor version {:?}", e);
+                empty_c_string()
+            }
+        },
+        None => empty_c_string(),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn delete_hidden_service(address: *const c_char) -> bool {
+    let address_str = from_c_str(address);
+    with_service(|service| service.delete_hidden_service(address_str)).is_ok()
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn delete_hidden_service_with_error(
+    address: *const c_char,
+    out_error: *mut *mut c_char,
+) -> c_int {
+    let address_str = from_c_str(address);
+    report(
+        out_error,
+        with_service(|service| service.delete_hidden_service(address_str)),
+    )
+}
+
+// Fetches the descriptor of an onion service from the HSDirs, returns its text or an empty
+// string when it couldn't be fetched
+#[unsafe(no_mangle)]
+pub extern "C" fn fetch_onion_descriptor(onion_address: *const c_char) -> *mut c_char {
+    let service_guard = ensure_tor_service().lock().unwrap();
+    let onion_address_str = from_c_str(onion_address);
+
+    match &*service_guard {
+        Some(service) => match service.fetch_descriptor(&onion_address_str) {
+            Ok(descriptor) => to_c_string(descriptor),
+            Err(e) => {
+                debug!(
+                    "Rust FFI: Error fetching descriptor {}",
+                    scrub(&format!("{:?}", e))
+                );
+                empty_c_string()
+            }
+        },
+        None => empty_c_string(),
+    }
+}
+
+// Returns the onion addresses of the hidden services created on this instance as a JSON
+// array of strings, empty string on error
+#[unsafe(no_mangle)]
+pub extern "C" fn get_onion_addresses_json() -> *mut c_char {
+    let service_guard = ensure_tor_service().lock().unwrap();
+
+    match &*service_guard {
+        Some(service) => match service.onion_addresses() {
+            Ok(addresses) => to_c_string(serde_json::to_string(&addresses).unwrap_or_default()),
+            Err(e) => {
+                debug!("Rust FFI: Error listing onion addresses {:?}", e);
+                empty_c_string()
+            }
+        },
+        None => empty_c_string(),
+    }
+}
+
+// Returns the current circuits as a JSON array of CircuitInfo, empty string on error
+#[unsafe(no_mangle)]
+pub extern "C" fn get_circuits_json() -> *mut c_char {
+    let service_guard = ensure_tor_service().lock().unwrap();
+
+    match &*service_guard {
+        Some(service) => match service.circuits() {
+            Ok(circuits) => to_c_string(serde_json::to_string(&circuits).unwrap_or_default()),
+            Err(e) => {
+                debug!("Rust FFI: Error listing circuits {:?}", e);
+                empty_c_string()
+            }
+        },
+        None => empty_c_string(),
+    }
+}
+
+// Returns the entry guards as a JSON array of GuardInfo, empty string on error
+#[unsafe(no_mangle)]
+pub extern "C" fn get_guards_json() -> *mut c_char {
+    let service_guard = ensure_tor_service().lock().unwrap();
+
+    match &*service_guard {
+        Some(service) => match service.get_guards() {
+            Ok(guards) => to_c_string(serde_json::to_string(&guards).unwrap_or_default()),
+            Err(e) => {
+                debug!("Rust FFI: Error listing guards {:?}", e);
+                empty_c_string()
+            }
+        },
+        None => empty_c_string(),
+    }
+}
+
+// Returns a ServiceHealth snapshot as JSON, empty string on error
+#[unsafe(no_mangle)]
+pub extern "C" fn get_service_health_json() -> *mut c_char {
+    let service_guard = ensure_tor_service().lock().unwrap();
+
+    match &*service_guard {
+        Some(service) => match service.service_health() {
+            Ok(health) => to_c_string(serde_json::to_string(&health).unwrap_or_default()),
+            Err(e) => {
+                debug!("Rust FFI: Error reading service health {:?}", e);
+                empty_c_string()
+            }
+        },
+        None => empty_c_string(),
+    }
+}
+
+// Closes a single circuit by id, see OwnedTorService::list_circuits for the ids
+#[unsafe(no_mangle)]
+pub extern "C" fn close_circuit(circuit_id: *const c_char) -> bool {
+    let circuit_id_str = from_c_str(circuit_id);
+    match with_service(|service| service.close_circuit(&circuit_id_str)) {
+        Ok(()) => true,
+        Err(e) => {
+            debug!("Rust FFI: Error closing circuit {}", e.message);
+            false
+        }
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn close_circuit_with_error(
+    circuit_id: *const c_char,
+    out_error: *mut *mut c_char,
+) -> c_int {
+    let circuit_id_str = from_c_str(circuit_id);
+    report(
+        out_error,
+        with_service(|service| service.close_circuit(&circuit_id_str)),
+    )
+}
+
+// Makes the running service re-read its torrc (SIGNAL RELOAD), see
+// OwnedTorService::reload_config. Config errors reported by Tor end up in *out_error
+#[unsafe(no_mangle)]
+pub extern "C" fn reload_config_with_error(out_error: *mut *mut c_char) -> c_int {
+    report(out_error, with_service(|service| service.reload_config()))
+}
+
+// Maps the address `from` to `to`, returns the mapped address or an empty string on error
+#[unsafe(no_mangle)]
+pub extern "C" fn map_address(from: *const c_char, to: *const c_char) -> *mut c_char {
+    let service_guard = ensure_tor_service().lock().unwrap();
+    let from_str = from_c_str(from);
+    let to_str = from_c_str(to);
+
+    match &*service_guard {
+        Some(service) => match service.map_address(&from_str, &to_str) {
+            Ok(mapped) => to_c_string(mapped),
+            Err(e) => {
+                debug!(
+                    "Rust FFI: Error mapping address {}",
+                    scrub(&format!("{:?}", e))
+                );
+                empty_c_string()
+            }
+        },
+        None => empty_c_string(),
+    }
+}
+
+// Times a SOCKS connect to host:port over Tor, returns the latency in milliseconds or -1 on error
+// Gives up after 30 s (DEFAULT_LATENCY_TIMEOUT_MS). The service isn't locked while probing
+#[unsafe(no_mangle)]
+pub extern "C" fn measure_circuit_latency(host: *const c_char, port: c_ushort) -> c_long {
+    let socks_port = match &*ensure_tor_service().lock().unwrap() {
+        Some(service) => service.socks_port,
+        None => return -1,
+    };
+    let host_str = from_c_str(host);
+    match tor::measure_circuit_latency(
+        socks_port,
+        &host_str,
+        port as u16,
+        tor::DEFAULT_LATENCY_TIMEOUT_MS,
+    ) {
+        Ok(latency) => latency.as_millis() as c_long,
+        Err(e) => {
+            debug!("Rust FFI: Error measuring circuit latency {:?}", e);
+            -1
+        }
+    }
+}
+
+// Builds count circuits ahead of the first requests and waits up to timeout_ms for them, see
+// OwnedTorService::warm_up. Returns how many got built or -1 on error, the service stays
+// locked while waiting
+#[unsafe(no_mangle)]
+pub extern "C" fn warm_up_circuits(count: c_uint, timeout_ms: c_ulong) -> c_int {
+    match with_service(|service| service.warm_up(count as usize, timeout_ms as u64)) {
+        Ok(built) => built as c_int,
+        Err(e) => {
+            debug!("Rust FFI: Error warming up circuits {}", e.message);
+            -1
+        }
+    }
+}
+
+// Probes onion_address ("name.onion" or "name.onion:port", port 80 by default) through the
+// running service until a connection succeeds: 1 once reachable, 0 when timeout_ms passed
+// first, -1 on error. The service isn't locked while waiting
+#[unsafe(no_mangle)]
+pub extern "C" fn wait_until_reachable(onion_address: *const c_char, timeout_ms: c_ulong) -> c_int {
+    let socks_port = match &*ensure_tor_service().lock().unwrap() {
+        Some(service) => service.socks_port,
+        None => return -1,
+    };
+    let address = from_c_str(onion_address);
+    match tor::wait_until_onion_reachable(socks_port, &address, timeout_ms as u64) {
+        Ok(reachable) => reachable as c_int,
+        Err(e) => {
+            debug!(
+                "Rust FFI: Error probing onion service {}",
+                scrub(&format!("{:?}", e))
+            );
+            -1
+        }
+    }
+}
+
+// Aborts a bootstrap running in init_tor_service from another thread, the pending init then
+// returns false. Returns false when no bootstrap is in progress
+#[unsafe(no_mangle)]
+pub extern "C" fn cancel_bootstrap() -> bool {
+    match &*BOOTSTRAP_CANCEL.lock().unwrap() {
+        Some(cancel) => {
+            cancel.cancel();
+            true
+        }
+        None => false,
+    }
+}
+
+fn shutdown_running_service() -> Result<(), FfiError> {
+    match ensure_tor_service().lock().unwrap().take() {
+        Some(mut service) => service.shutdown().map_err(FfiError::from),
+        None => Err(ffi_error("Tor service not running")),
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn shutdown_service() -> bool {
+    shutdown_running_service().is_ok()
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn shutdown_service_with_error(out_error: *mut *mut c_char) -> c_int {
+    report(out_error, shutdown_running_service())
+}
+
+// Stops every running service and drops pooled HTTP clients, for application exit
+// Only one service is held at a time today, so the count is 0 or 1
+#[unsafe(no_mangle)]
+pub extern "C" fn shutdown_all() -> c_int {
+    let mut service_guard = ensure_tor_service().lock().unwrap();
+    let mut stopped = 0;
+    if let Some(mut service) = service_guard.take() {
+        match service.shutdown() {
+            Ok(()) => stopped += 1,
+            Err(e) => debug!("Rust FFI: Error shutting down service {:?}", e),
+        }
+    }
+    clear_pool();
+    stopped
+}
+
+// Number of C strings returned by this library that haven't been released with free_string
+// (or the free_* function of the struct holding them) yet, to find leaks in bindings. Only
+// exported with the leak-tracking feature, which records every allocation
+#[cfg(feature = "leak-tracking")]
+#[unsafe(no_mangle)]
+pub extern "C" fn debug_leaked_string_count() -> c_ulong {
+    live_strings().lock().unwrap().len() as c_ulong
+}
+
+// Clean up allocated C strings
+
+#[unsafe(no_mangle)]
+
+pub extern "C" fn free_string(s: *mut c_char) {
+    if !s.is_null() {
+        untrack_string(s);
+        unsafe {
+            let _ = CString::from_raw(s);
+        }
+    }
+}
+
+#[repr(C)]
+pub struct CHttpResponse {
+    pub status_code: c_ushort,
+    pub body: *mut c_char,
+    pub error: *mut c_char,
+}
+
+#[repr(C)]
+pub struct CRawHttpResponse {
+    pub status_code: c_ushort,
+    pub data: *mut c_uchar,
+    pub len: usize,
+    pub error: *mut c_char,
+}
+
+// Helper to hand a byte buffer over to C, paired with free_raw_response
+fn to_c_buffer(data: Vec<u8>) -> (*mut c_uchar, usize) {
+    let boxed = data.into_boxed_slice();
+    let len = boxed.len();
+    (Box::into_raw(boxed) as *mut c_uchar, len)
+}
+
+// Parses a JSON object of header names to string values, preserving key order
+fn parse_headers_json(headers_json: &str) -> Result<Vec<(String, String)>, String> {
+    let map: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_str(headers_json).map_err(|_| "Invalid headers JSON".to_string())?;
+    map.into_iter()
+        .map(|(name, value)| match value {
+            serde_json::Value::String(value) => Ok((name, value)),
+            _ => Err(format!("Header {} must be a string", name)),
+        })
+        .collect()
+}
+
+// Builds the request params and socks proxy address shared by the HTTP FFI functions
+fn prepare_http_request(
+    url: *const c_char,
+    method: HttpMethod,
+    headers_json: *const c_char,
+    body: *const c_char,
+    timeout_ms: c_ulong,
+) -> Result<(HttpRequestParams, String), String> {
+    if INITIALIZED.get().is_none() {
+        return Err("Tor library not initialized".to_string());
+    }
+
+    debug!(
+        "http request params: {:?} {:?} {:?} {}",
+        url, headers_json, body, timeout_ms
+    );
+
+    let url_str = from_c_str(url);
+    let headers_json_str = from_c_str(headers_json);
+    let body_str = from_c_str(body);
+
+    // Parse headers JSON if provided, keeping the order they were given in
+    let headers = if !headers_json_str.is_empty() {
+        Some(parse_headers_json(&headers_json_str)?)
+    } else {
+        None
+    };
+
+    // Create request params
+    let params = HttpRequestParams {
+        url: url_str,
+        method,
+        headers,
+        body: if body_str.is_empty() {
+            None
+        } else {
+            Some(body_str)
+        },
+        timeout_ms: Some(timeout_ms as u64),
+        max_header_bytes: limit(&MAX_HEADER_BYTES),
+        max_response_bytes: limit(&MAX_RESPONSE_BYTES),
+        ..Default::default()
+    };
+
+    // Get socks proxy address from the running Tor service, the lock is dropped right
+    // away so the request itself doesn't block status checks or shutdown
+    let socks_port = match &*ensure_tor_service().lock().unwrap() {
+        Some(service) => service.socks_port,
+        None => return Err("Tor service not running".to_string()),
+    };
+
+    debug!("socks port: {}", socks_port);
+
+    Ok((params, format!("127.0.0.1:{}", socks_port)))
+}
+
+fn limit(setting: &AtomicUsize) -> Option<usize> {
+    match setting.load(Ordering::Relaxed) {
+        0 => None,
+        limit => Some(limit),
+    }
+}
+
+// Caps response header sections and bodies for all following HTTP calls, requests going over
+// return an error response instead of allocating. 0 keeps the default (64 KiB of headers,
+// unlimited body). Wallets and apps talking to untrusted onion services should set both
+#[unsafe(no_mangle)]
+pub extern "C" fn set_http_limits(max_header_bytes: c_ulong, max_response_bytes: c_ulong) {
+    MAX_HEADER_BYTES.store(max_header_bytes as usize, Ordering::Relaxed);
+    MAX_RESPONSE_BYTES.store(max_response_bytes as usize, Ordering::Relaxed);
+}
+
+// Internal helper function (not exposed via FFI)
+fn make_tor_http_request(
+    url: *const c_char,
+    method: HttpMethod,
+    headers_json: *const c_char,
+    body: *const c_char,
+    timeout_ms: c_ulong,
+) -> CHttpResponse {
+    make_tor_http_request_with(url, method, headers_json, body, timeout_ms, |_| {})
+}
+
+// Same as make_tor_http_request but lets the caller adjust the params before sending
+fn make_tor_http_request_with(
+    url: *const c_char,
+    method: HttpMethod,
+    headers_json: *const c_char,
+    body: *const c_char,
+    timeout_ms: c_ulong,
+    customize: impl FnOnce(&mut HttpRequestParams),
+) -> CHttpResponse {
+    let (mut params, socks_proxy) =
+        match prepare_http_request(url, method, headers_json, body, timeout_ms) {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                return CHttpResponse {
+                    status_code: 0,
+                    body: empty_c_string(),
+                    error: to_c_string(e),
+                };
+            }
+        };
+
+    customize(&mut params);
+
+    // Make the HTTP request
+    match make_http_request(params, socks_proxy) {
+        Ok(response) => {
+            debug!("http response: {}", scrub(&format!("{:?}", response)));
+            return CHttpResponse {
+                status_code: response.status_code,
+                body: to_c_string(response.body),
+                error: match response.error {
+                    Some(err) => to_c_string(err),
+                    None => empty_c_string(),
+                },
+            };
+        }
+        Err(e) => {
+            debug!("http error: {}", scrub(&format!("{:?}", e)));
+            return CHttpResponse {
+                status_code: 0,
+                body: empty_c_string(),
+                error: to_c_string(format!("Error making HTTP request: {:?}", e)),
+            };
+        }
+    }
+}
+
+// Internal helper function (not exposed via FFI)
+fn make_tor_raw_http_request(
+    url: *const c_char,
+    method: HttpMethod,
+    headers_json: *const c_char,
+    body: *const c_char,
+    timeout_ms: c_ulong,
+) -> CRawHttpResponse {
+    let (params, socks_proxy) =
+        match prepare_http_request(url, method, headers_json, body, timeout_ms) {
+            Ok(prepared) => prepared,
+            Err(e) => {
+                return CRawHttpResponse {
+                    status_code: 0,
+                    data: std::ptr::null_mut(),
+                    len: 0,
+                    error: to_c_string(e),
+                };
+            }
+        };
+
+    match make_raw_http_request(params, socks_proxy) {
+        Ok(response) => {
+            debug!(
+                "raw http response: {} ({} bytes)",
+                response.status_code,
+                response.data.len()
+            );
+            let (data, len) = to_c_buffer(response.data);
+            CRawHttpResponse {
+                status_code: response.status_code,
+                data,
+                len,
+                error: match response.error {
+                    Some(err) => to_c_string(err),
+                    None => empty_c_string(),
+                },
+            }
+        }
+        Err(e) => {
+            debug!("raw http error: {}", scrub(&format!("{:?}", e)));
+            CRawHttpResponse {
+                status_code: 0,
+                data: std::ptr::null_mut(),
+                len: 0,
+                error: to_c_string(format!("Error making HTTP request: {:?}", e)),
+            }
+        }
+    }
+}
+
+// HTTP method functions exposed via FFI
+
+#[unsafe(no_mangle)]
+pub extern "C" fn http_get(
+    url: *const c_char,
+    headers_json: *const c_char,
+    timeout_ms: c_ulong,
+) -> CHttpResponse {
+    make_tor_http_request(
+        url,
+        HttpMethod::GET,
+        headers_json,
+        std::ptr::null(), // No body for GET
+        timeout_ms,
+    )
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn http_post(
+    url: *const c_char,
+    body: *const c_char,
+    headers_json: *const c_char,
+    timeout_ms: c_ulong,
+) -> CHttpResponse {
+    make_tor_http_request(url, HttpMethod::POST, headers_json, body, timeout_ms)
+}
+
+// Called as an upload or download progresses with the bytes transferred so far and the total
+// size (-1 when unknown), user_data is passed back as is
+pub type TransferProgressCallback =
+    extern "C" fn(bytes_done: u64, total: i64, user_data: *mut c_void);
+
+// Reports to progress, if any. The caller owns user_data and keeps it alive for the call
+fn progress_reporter(
+    progress: Option<TransferProgressCallback>,
+    user_data: *mut c_void,
+) -> impl FnMut(u64, Option<u64>) + Send + 'static {
+    // Raw pointers aren't Send
+    let user_data = user_data as usize;
+    move |done: u64, total: Option<u64>| {
+        if let Some(progress) = progress {
+            let total = total.map(|t| t as i64).unwrap_or(-1);
+            progress(done, total, user_data as *mut c_void);
+        }
+    }
+}
+
+fn progress_callback(
+    progress: Option<TransferProgressCallback>,
+    user_data: *mut c_void,
+) -> Option<ProgressCallback> {
+    progress.map(|_| ProgressCallback::new(progress_reporter(progress, user_data)))
+}
+
+// POST the contents of the file at file_path, streamed from disk instead of held in memory
+#[unsafe(no_mangle)]
+pub extern "C" fn http_post_file(
+    url: *const c_char,
+    file_path: *const c_char,
+    headers_json: *const c_char,
+    timeout_ms: c_ulong,
+) -> CHttpResponse {
+    http_post_file_with_progress(
+        url,
+        file_path,
+        headers_json,
+        timeout_ms,
+        None,
+        std::ptr::null_mut(),
+    )
+}
+
+// http_post_file reporting upload progress to progress
+#[unsafe(no_mangle)]
+pub extern "C" fn http_post_file_with_progress(
+    url: *const c_char,
+    file_path: *const c_char,
+    headers_json: *const c_char,
+    timeout_ms: c_ulong,
+    progress: Option<TransferProgressCallback>,
+    user_data: *mut c_void,
+) -> CHttpResponse {
+    let file_path_str = from_c_str(file_path);
+    if file_path_str.is_empty() {
+        return CHttpResponse {
+            status_code: 0,
+            body: empty_c_string(),
+            error: to_c_string("No file path provided".to_string()),
+        };
+    }
+
+    make_tor_http_request_with(
+        url,
+        HttpMethod::POST,
+        headers_json,
+        std::ptr::null(), // Body comes from the file
+        timeout_ms,
+        |params| {
+            params.body_file = Some(PathBuf::from(file_path_str));
+            params.progress = progress_callback(progress, user_data);
+        },
+    )
+}
+
+// GET url and stream the response body into the file at dest_path instead of memory
+#[unsafe(no_mangle)]
+pub extern "C" fn http_download(
+    url: *const c_char,
+    dest_path: *const c_char,
+    headers_json: *const c_char,
+    timeout_ms: c_ulong,
+) -> CHttpResponse {
+    http_download_with_progress(
+        url,
+        dest_path,
+        headers_json,
+        timeout_ms,
+        None,
+        std::ptr::null_mut(),
+    )
+}
+
+// http_download reporting download progress to progress
+#[unsafe(no_mangle)]
+pub extern "C" fn http_download_with_progress(
+    url: *const c_char,
+    dest_path: *const c_char,
+    headers_json: *const c_char,
+    timeout_ms: c_ulong,
+    progress: Option<TransferProgressCallback>,
+    user_data: *mut c_void,
+) -> CHttpResponse {
+    let dest_path_str = from_c_str(dest_path);
+    if dest_path_str.is_empty() {
+        return CHttpResponse {
+            status_code: 0,
+            body: empty_c_string(),
+            error: to_c_string("No destination path provided".to_string()),
+        };
+    }
+
+    make_tor_http_request_with(
+        url,
+        HttpMethod::GET,
+        headers_json,
+        std::ptr::null(), // No body for GET
+        timeout_ms,
+        |params| {
+            params.download_to = Some(PathBuf::from(dest_path_str));
+            params.progress = progress_callback(progress, user_data);
+        },
+    )
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn http_put(
+    url: *const c_char,
+    body: *const c_char,
+    headers_json: *const c_char,
+    timeout_ms: c_ulong,
+) -> CHttpResponse {
+    make_tor_http_request(url, HttpMethod::PUT, headers_json, body, timeout_ms)
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn http_delete(
+    url: *const c_char,
+    headers_json: *const c_char,
+    timeout_ms: c_ulong,
+) -> CHttpResponse {
+    make_tor_http_request(
+        url,
+        HttpMethod::DELETE,
+        headers_json,
+        std::ptr::null(), // Usually no body for DELETE
+        timeout_ms,
+    )
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn http_head(
+    url: *const c_char,
+    headers_json: *const c_char,
+    timeout_ms: c_ulong,
+) -> CHttpResponse {
+    make_tor_http_request(
+        url,
+        HttpMethod::HEAD,
+        headers_json,
+        std::ptr::null(), // No body for HEAD
+        timeout_ms,
+    )
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn http_options(
+    url: *const c_char,
+    headers_json: *const c_char,
+    timeout_ms: c_ulong,
+) -> CHttpResponse {
+    make_tor_http_request(
+        url,
+        HttpMethod::OPTIONS,
+        headers_json,
+        std::ptr::null(), // No body for OPTIONS
+        timeout_ms,
+    )
+}
+
+// Reads count header names and values from parallel arrays of C strings
+fn headers_from_arrays(
+    names: *const *const c_char,
+    values: *const *const c_char,
+    count: usize,
+) -> Result<Option<Vec<(String, String)>>, String> {
+    if count == 0 {
+        return Ok(None);
+    }
+    if names.is_null() || values.is_null() {
+        return Err("Header arrays are null".to_string());
+    }
+    let (names, values) = unsafe {
+        (
+            std::slice::from_raw_parts(names, count),
+            std::slice::from_raw_parts(values, count),
+        )
+    };
+    names
+        .iter()
+        .zip(values)
+        .map(|(&name, &value)| {
+            if name.is_null() {
+                return Err("Header name is null".to_string());
+            }
+            Ok((from_c_str(name), from_c_str(value)))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(Some)
+}
+
+// Same as make_tor_http_request with headers given as parallel name/value arrays
+fn make_tor_http_request_kv(
+    url: *const c_char,
+    method: HttpMethod,
+    header_names: *const *const c_char,
+    header_values: *const *const c_char,
+    header_count: usize,
+    body: *const c_char,
+    timeout_ms: c_ulong,
+) -> CHttpResponse {
+    let headers = match headers_from_arrays(header_names, header_values, header_count) {
+        Ok(headers) => headers,
+        Err(e) => {
+            return CHttpResponse {
+                status_code: 0,
+                body: empty_c_string(),
+                error: to_c_string(e),
+            };
+        }
+    };
+    make_tor_http_request_with(url, method, std::ptr::null(), body, timeout_ms, |params| {
+        params.headers = headers;
+    })
+}
+
+// Variants of the HTTP functions taking headers as parallel arrays of header_count names and
+// values instead of a JSON object, for bindings without a JSON library
+
+#[unsafe(no_mangle)]
+pub extern "C" fn http_get_kv(
+    url: *const c_char,
+    header_names: *const *const c_char,
+    header_values: *const *const c_char,
+    header_count: usize,
+    timeout_ms: c_ulong,
+) -> CHttpResponse {
+    make_tor_http_request_kv(
+        url,
+        HttpMethod::GET,
+        header_names,
+        header_values,
+        header_count,
+        std::ptr::null(),
+        timeout_ms,
+    )
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn http_post_kv(
+    url: *const c_char,
+    body: *const c_char,
+    header_names: *const *const c_char,
+    header_values: *const *const c_char,
+    header_count: usize,
+    timeout_ms: c_ulong,
+) -> CHttpResponse {
+    make_tor_http_request_kv(
+        url,
+        HttpMethod::POST,
+        header_names,
+        header_values,
+        header_count,
+        body,
+        timeout_ms,
+    )
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn http_put_kv(
+    url: *const c_char,
+    body: *const c_char,
+    header_names: *const *const c_char,
+    header_values: *const *const c_char,
+    header_count: usize,
+    timeout_ms: c_ulong,
+) -> CHttpResponse {
+    make_tor_http_request_kv(
+        url,
+        HttpMethod::PUT,
+        header_names,
+        header_values,
+        header_count,
+        body,
+        timeout_ms,
+    )
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn http_delete_kv(
+    url: *const c_char,
+    header_names: *const *const c_char,
+    header_values: *const *const c_char,
+    header_count: usize,
+    timeout_ms: c_ulong,
+) -> CHttpResponse {
+    make_tor_http_request_kv(
+        url,
+        HttpMethod::DELETE,
+        header_names,
+        header_values,
+        header_count,
+        std::ptr::null(),
+        timeout_ms,
+    )
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn http_head_kv(
+    url: *const c_char,
+    header_names: *const *const c_char,
+    header_values: *const *const c_char,
+    header_count: usize,
+    timeout_ms: c_ulong,
+) -> CHttpResponse {
+    make_tor_http_request_kv(
+        url,
+        HttpMethod::HEAD,
+        header_names,
+        header_values,
+        header_count,
+        std::ptr::null(),
+        timeout_ms,
+    )
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn http_options_kv(
+    url: *const c_char,
+    header_names: *const *const c_char,
+    header_values: *const *const c_char,
+    header_count: usize,
+    timeout_ms: c_ulong,
+) -> CHttpResponse {
+    make_tor_http_request_kv(
+        url,
+        HttpMethod::OPTIONS,
+        header_names,
+        header_values,
+        header_count,
+        std::ptr::null(),
+        timeout_ms,
+    )
+}
+
+// Free the HTTP response to prevent memory leaks
+#[unsafe(no_mangle)]
+pub extern "C" fn free_http_response(response: CHttpResponse) {
+    free_string(response.body);
+    free_string(response.error);
+}
+
+// Connection pool metrics as a JSON object
+#[unsafe(no_mangle)]
+pub extern "C" fn get_pool_stats() -> *mut c_char {
+    match serde_json::to_string(&pool_stats()) {
+        Ok(json) => to_c_string(json),
+        Err(e) => {
+            debug!("Rust FFI: Error serializing pool stats {:?}", e);
+            empty_c_string()
+        }
+    }
+}
+
+// Request, circuit and traffic metrics in the Prometheus text format, only the request
+// metrics when no service is running. Free with free_string
+#[unsafe(no_mangle)]
+pub extern "C" fn get_metrics_prometheus() -> *mut c_char {
+    match &*ensure_tor_service().lock().unwrap() {
+        Some(service) => to_c_string(service.metrics_prometheus()),
+        None => to_c_string(metrics_prometheus()),
+    }
+}
+
+// Downloads url into dest, resuming a partial file and retrying dropped circuits up to
+// max_attempts times. Returns the size of dest or -1 on error
+#[unsafe(no_mangle)]
+pub extern "C" fn download_file_resumable(
+    url: *const c_char,
+    headers_json: *const c_char,
+    dest: *const c_char,
+    max_attempts: c_uint,
+    timeout_ms: c_ulong,
+    progress: Option<TransferProgressCallback>,
+    user_data: *mut c_void,
+) -> c_long {
+    let (params, socks_proxy) = match prepare_http_request(
+        url,
+        HttpMethod::GET,
+        headers_json,
+        std::ptr::null(),
+        timeout_ms,
+    ) {
+        Ok(prepared) => prepared,
+        Err(e) => {
+            debug!("Rust FFI: Error preparing download {}", scrub(&e));
+            return -1;
+        }
+    };
+    let dest = PathBuf::from(from_c_str(dest));
+
+    match tor::http_client::download_file_resumable(
+        params,
+        &dest,
+        max_attempts,
+        socks_proxy,
+        progress_reporter(progress, user_data),
+    ) {
+        Ok(size) => size as c_long,
+        Err(e) => {
+            debug!(
+                "Rust FFI: Error downloading file {}",
+                scrub(&format!("{:?}", e))
+            );
+            -1
+        }
+    }
+}
+
+// Raw variant of http_get: returns the status line, headers and body as one buffer
+// The head is rebuilt from the parsed response (lowercase names, content-length framing)
+#[unsafe(no_mangle)]
+pub extern "C" fn http_get_raw(
+    url: *const c_char,
+    headers_json: *const c_char,
+    timeout_ms: c_ulong,
+) -> CRawHttpResponse {
+    make_tor_raw_http_request(
+        url,
+        HttpMethod::GET,
+        headers_json,
+        std::ptr::null(), // No body for GET
+        timeout_ms,
+    )
+}
+
+// Free the raw HTTP response buffer and error string
+#[unsafe(no_mangle)]
+pub extern "C" fn free_raw_response(response: CRawHttpResponse) {
+    if !response.data.is_null() {
+        unsafe {
+            let _ = Box::from_raw(std::ptr::slice_from_raw_parts_mut(
+                response.data,
+                response.len,
+            ));
+        }
+    }
+    free_string(response.error);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    /// Held by tests that put a service in TOR_SERVICE or expect none there
+    static SERVICE_TEST_LOCK: Mutex<()> = Mutex::new(());
+
+    #[test]
+    fn reports_errors_through_out_parameter() {
+        let mut error: *mut c_char = std::ptr::null_mut();
+        assert_eq!(report(&mut error, Ok(())), 0);
+        assert!(error.is_null());
+
+        let code = report(&mut error, Err(TorErrors::SocksPortInUse(19050).into()));
+        assert_eq!(code, 11);
+        assert_eq!(from_c_str(error), "SOCKS port 19050 is already in use");
+        free_string(error);
+
+        assert_eq!(report(std::ptr::null_mut(), Err(ffi_error("ignored"))), -1);
+    }
+
+    #[test]
+    fn fails_without_running_service() {
+        let _serial = SERVICE_TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut error: *mut c_char = std::ptr::null_mut();
+        let address = CString::new("abc.onion").unwrap();
+        assert_eq!(
+            delete_hidden_service_with_error(address.as_ptr(), &mut error),
+            -1
+        );
+        assert_eq!(from_c_str(error), "Tor service not running");
+        free_string(error);
+        assert_eq!(shutdown_service_with_error(std::ptr::null_mut()), -1);
+    }
+
+    #[test]
+    fn status_answers_while_a_request_is_in_flight() {
+        let _serial = SERVICE_TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        // A SOCKS port that accepts and never answers keeps the request hanging until its
+        // timeout
+        let socks = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = socks.local_addr().unwrap().port();
+        let _ = INITIALIZED.set(true);
+        *ensure_tor_service().lock().unwrap() = Some(OwnedTorService::without_daemon(port));
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        let request = std::thread::spawn(move || {
+            let url = CString::new("http://example.onion/").unwrap();
+            free_http_response(http_get(url.as_ptr(), std::ptr::null(), 2000));
+            done_tx.send(()).unwrap();
+        });
+        let _conn = socks.accept().unwrap();
+
+        let started = Instant::now();
+        // Not connected to a daemon, so not bootstrapped
+        assert_eq!(get_service_status(), 2);
+        assert!(started.elapsed() < Duration::from_millis(500));
+        assert!(done_rx.try_recv().is_err());
+
+        request.join().unwrap();
+        *ensure_tor_service().lock().unwrap() = None;
+    }
+
+    #[test]
+    #[cfg(feature = "leak-tracking")]
+    fn tracks_unfreed_strings() {
+        let s = to_c_string(String::from("leak"));
+        let empty = empty_c_string();
+        assert!(live_strings().lock().unwrap().contains(&(s as usize)));
+        assert!(debug_leaked_string_count() >= 2);
+        free_string(s);
+        free_string(empty);
+        let live = live_strings().lock().unwrap();
+        assert!(!live.contains(&(s as usize)));
+        assert!(!live.contains(&(empty as usize)));
+    }
+}
@@ -13,6 +13,8 @@ struct TOR_HiddenServiceResponse {
   bool is_success;
   char *onion_address;
   char *control;
+  bool has_secret_key;
+  unsigned char secret_key[64];
 };
 
 struct TOR_StartTorResponse {
@@ -28,17 +30,81 @@ struct TOR_CHttpResponse {
   char *error;
 };
 
+struct TOR_CRawHttpResponse {
+  unsigned short status_code;
+  unsigned char *data;
+  uintptr_t len;
+  char *error;
+};
+
+using TOR_LogCallback = void(*)(int level, const char *message);
+
+using TOR_VanityProgressCallback = void(*)(unsigned long attempts);
+
+using TOR_TransferProgressCallback = void(*)(uint64_t bytes_done, int64_t total, void *user_data);
+
 extern "C" {
 
+void set_log_callback(TOR_LogCallback callback);
+
+bool set_runtime_worker_threads(unsigned int threads);
+
 bool initialize_tor_library();
 
 bool init_tor_service(unsigned short socks_port, const char *data_dir, unsigned long timeout_ms);
 
+bool init_tor_service_json(const char *config_json);
+
+// The *_with_error functions return 0 on success and leave *out_error null, otherwise they
+// return the error code (as get_last_error_code, -1 when the service isn't running or the
+// library isn't initialized) and store a message in *out_error, free it with free_string
+int init_tor_service_json_with_error(const char *config_json, char **out_error);
+
+int init_tor_service_with_error(unsigned short socks_port,
+                                const char *data_dir,
+                                unsigned long timeout_ms,
+                                char **out_error);
+
+// 0 or a TorErrors code: 1 control connection, 2 Tor library, 3 bootstrap, 4 I/O,
+// 5 threading, 6 TCP stream, 7 bootstrap cancelled, 8 invalid config, 9 control command,
+// 10 unsupported Tor version, 11 SOCKS port in use, 12 clock skew (message has the offset),
+// 13 DNS error, 14 invalid JSON, 15 blocked by kill switch, 16 HTTP error status,
+// 17 SOCKS general failure, 18 SOCKS host unreachable (onion down), 19 SOCKS connection
+// refused, 20 SOCKS TTL expired (circuit timed out), 21 no vanity onion address found,
+// 22 control connection lost and reconnecting failed, 23 invalid request header or url
+int get_last_error_code();
+
+char *get_last_error_message();
+
 TOR_HiddenServiceResponse create_hidden_service(unsigned short port,
                                                 unsigned short target_port,
                                                 const unsigned char *key_data,
                                                 bool has_key);
 
+int create_hidden_service_with_error(unsigned short port,
+                                     unsigned short target_port,
+                                     const unsigned char *key_data,
+                                     bool has_key,
+                                     TOR_HiddenServiceResponse *out_response,
+                                     char **out_error);
+
+int create_hidden_service_from_key_file(const char *key_file,
+                                        unsigned short port,
+                                        unsigned short target_port,
+                                        TOR_HiddenServiceResponse *out_response,
+                                        char **out_error);
+
+TOR_HiddenServiceResponse create_hidden_service_discard_key(unsigned short port,
+                                                            unsigned short target_port);
+
+char *generate_onion_keypair(unsigned char *key_out);
+
+char *generate_vanity_onion(const char *prefix,
+                            unsigned long max_attempts,
+                            unsigned char *key_out,
+                            TOR_VanityProgressCallback progress,
+                            char **out_error);
+
 TOR_StartTorResponse start_tor_if_not_running(const char *data_dir,
                                               const unsigned char *key_data,
                                               bool has_key,
@@ -48,12 +114,77 @@ TOR_StartTorResponse start_tor_if_not_running(const char *data_dir,
 
 int get_service_status();
 
+bool is_bootstrapped();
+
+void set_killswitch(bool enabled);
+
+void set_isolate_by_destination(bool enabled);
+
+void set_max_concurrent_requests(unsigned int max);
+
+void set_safe_logging(bool enabled);
+
+int get_bootstrap_progress();
+
+long get_bootstrap_eta_seconds();
+
+char *get_bootstrap_phase();
+
+// Bootstrap phase codes: -1 unknown, 0 starting, 1 conn_pt, 2 conn_done_pt, 3 conn_proxy,
+// 4 conn_done_proxy, 5 conn (conn_dir), 6 conn_done, 7 handshake (handshake_dir),
+// 8 handshake_done, 9 onehop_create, 10 requesting_status, 11 loading_status,
+// 12 loading_keys, 13 requesting_descriptors, 14 loading_descriptors, 15 enough_dirinfo,
+// 16 ap_conn_pt, 17 ap_conn_done_pt, 18 ap_conn_proxy, 19 ap_conn_done_proxy,
+// 20 ap_conn (conn_or), 21 ap_conn_done, 22 ap_handshake (handshake_or),
+// 23 ap_handshake_done, 24 circuit_create, 25 done
+int get_bootstrap_phase_code();
+
+char *get_tor_version();
+
 bool delete_hidden_service(const char *address);
 
+int delete_hidden_service_with_error(const char *address, char **out_error);
+
+char *fetch_onion_descriptor(const char *onion_address);
+
+char *get_onion_addresses_json();
+
+char *get_circuits_json();
+
+char *get_guards_json();
+
+char *get_service_health_json();
+
+bool close_circuit(const char *circuit_id);
+
+int close_circuit_with_error(const char *circuit_id, char **out_error);
+
+int reload_config_with_error(char **out_error);
+
+char *map_address(const char *from, const char *to);
+
+long measure_circuit_latency(const char *host, unsigned short port);
+
+int warm_up_circuits(unsigned int count, unsigned long timeout_ms);
+
+int wait_until_reachable(const char *onion_address, unsigned long timeout_ms);
+
+bool cancel_bootstrap();
+
 bool shutdown_service();
 
+int shutdown_service_with_error(char **out_error);
+
+int shutdown_all();
+
+#if defined(TOR_FFI_LEAK_TRACKING)
+unsigned long debug_leaked_string_count();
+#endif
+
 void free_string(char *s);
 
+void set_http_limits(unsigned long max_header_bytes, unsigned long max_response_bytes);
+
 TOR_CHttpResponse http_get(const char *url, const char *headers_json, unsigned long timeout_ms);
 
 TOR_CHttpResponse http_post(const char *url,
@@ -61,6 +192,30 @@ TOR_CHttpResponse http_post(const char *url,
                             const char *headers_json,
                             unsigned long timeout_ms);
 
+TOR_CHttpResponse http_post_file(const char *url,
+                                 const char *file_path,
+                                 const char *headers_json,
+                                 unsigned long timeout_ms);
+
+TOR_CHttpResponse http_post_file_with_progress(const char *url,
+                                               const char *file_path,
+                                               const char *headers_json,
+                                               unsigned long timeout_ms,
+                                               TOR_TransferProgressCallback progress,
+                                               void *user_data);
+
+TOR_CHttpResponse http_download(const char *url,
+                                const char *dest_path,
+                                const char *headers_json,
+                                unsigned long timeout_ms);
+
+TOR_CHttpResponse http_download_with_progress(const char *url,
+                                              const char *dest_path,
+                                              const char *headers_json,
+                                              unsigned long timeout_ms,
+                                              TOR_TransferProgressCallback progress,
+                                              void *user_data);
+
 TOR_CHttpResponse http_put(const char *url,
                            const char *body,
                            const char *headers_json,
@@ -72,8 +227,62 @@ TOR_CHttpResponse http_head(const char *url, const char *headers_json, unsigned
 
 TOR_CHttpResponse http_options(const char *url, const char *headers_json, unsigned long timeout_ms);
 
+TOR_CHttpResponse http_get_kv(const char *url,
+                              const char *const *header_names,
+                              const char *const *header_values,
+                              uintptr_t header_count,
+                              unsigned long timeout_ms);
+
+TOR_CHttpResponse http_post_kv(const char *url,
+                               const char *body,
+                               const char *const *header_names,
+                               const char *const *header_values,
+                               uintptr_t header_count,
+                               unsigned long timeout_ms);
+
+TOR_CHttpResponse http_put_kv(const char *url,
+                              const char *body,
+                              const char *const *header_names,
+                              const char *const *header_values,
+                              uintptr_t header_count,
+                              unsigned long timeout_ms);
+
+TOR_CHttpResponse http_delete_kv(const char *url,
+                                 const char *const *header_names,
+                                 const char *const *header_values,
+                                 uintptr_t header_count,
+                                 unsigned long timeout_ms);
+
+TOR_CHttpResponse http_head_kv(const char *url,
+                               const char *const *header_names,
+                               const char *const *header_values,
+                               uintptr_t header_count,
+                               unsigned long timeout_ms);
+
+TOR_CHttpResponse http_options_kv(const char *url,
+                                  const char *const *header_names,
+                                  const char *const *header_values,
+                                  uintptr_t header_count,
+                                  unsigned long timeout_ms);
+
 void free_http_response(TOR_CHttpResponse response);
 
+char *get_pool_stats();
+
+char *get_metrics_prometheus();
+
+long download_file_resumable(const char *url,
+                             const char *headers_json,
+                             const char *dest,
+                             unsigned int max_attempts,
+                             unsigned long timeout_ms,
+                             TOR_TransferProgressCallback progress,
+                             void *user_data);
+
+TOR_CRawHttpResponse http_get_raw(const char *url, const char *headers_json, unsigned long timeout_ms);
+
+void free_raw_response(TOR_CRawHttpResponse response);
+
 }  // extern "C"
 
 }  // namespace tor
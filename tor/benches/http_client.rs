@@ -0,0 +1,125 @@
+//! Canned responses are served from memory through the loopback SOCKS bridge, so these
+//! measure the client's own cost (request building, SOCKS handshake, parsing, decoding)
+//! without Tor or network latency
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use tor::http_client::{
+    HttpMethod, HttpRequestParams, StubTransport, make_http_request_via, pipeline_via,
+};
+
+fn params() -> HttpRequestParams {
+    HttpRequestParams {
+        url: "http://example.onion/bench".into(),
+        method: HttpMethod::GET,
+        ..Default::default()
+    }
+}
+
+fn content_length_response(body_len: usize) -> Vec<u8> {
+    let mut raw = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body_len).into_bytes();
+    raw.resize(raw.len() + body_len, b'x');
+    raw
+}
+
+fn chunked_response(body_len: usize, chunk_len: usize) -> Vec<u8> {
+    let mut raw = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+    let chunk = vec![b'x'; chunk_len];
+    let mut written = 0;
+    while written < body_len {
+        let len = chunk_len.min(body_len - written);
+        raw.extend_from_slice(format!("{:x}\r\n", len).as_bytes());
+        raw.extend_from_slice(&chunk[..len]);
+        raw.extend_from_slice(b"\r\n");
+        written += len;
+    }
+    raw.extend_from_slice(b"0\r\n\r\n");
+    raw
+}
+
+/// Times one request per iteration, queuing its response is left out of the measurement
+fn bench_response(
+    group: &mut criterion::BenchmarkGroup<'_, criterion::measurement::WallTime>,
+    id: BenchmarkId,
+    transport: &StubTransport,
+    response: &[u8],
+) {
+    group.bench_function(id, |b| {
+        b.iter_batched(
+            || transport.push_response(response.to_vec()),
+            |_| make_http_request_via(params(), transport).unwrap(),
+            BatchSize::PerIteration,
+        )
+    });
+}
+
+fn bench_latency(c: &mut Criterion) {
+    let transport = StubTransport::new();
+    let mut group = c.benchmark_group("request_latency");
+    bench_response(
+        &mut group,
+        BenchmarkId::from_parameter("small_get"),
+        &transport,
+        &content_length_response(64),
+    );
+    group.finish();
+}
+
+fn bench_chunked_response(c: &mut Criterion) {
+    let transport = StubTransport::new();
+    let mut group = c.benchmark_group("chunked_response");
+    group.sample_size(20);
+    let body_len = 1024 * 1024;
+    group.throughput(Throughput::Bytes(body_len as u64));
+    // Tor cell sized chunks are the worst case, 16 KiB is what most servers write
+    for chunk_len in [498, 16 * 1024] {
+        bench_response(
+            &mut group,
+            BenchmarkId::from_parameter(chunk_len),
+            &transport,
+            &chunked_response(body_len, chunk_len),
+        );
+    }
+    group.finish();
+}
+
+fn bench_large_body(c: &mut Criterion) {
+    let transport = StubTransport::new();
+    let mut group = c.benchmark_group("large_body");
+    group.sample_size(10);
+    for mb in [1, 8] {
+        let body_len = mb * 1024 * 1024;
+        group.throughput(Throughput::Bytes(body_len as u64));
+        bench_response(
+            &mut group,
+            BenchmarkId::from_parameter(mb),
+            &transport,
+            &content_length_response(body_len),
+        );
+    }
+    group.finish();
+}
+
+fn bench_pipeline(c: &mut Criterion) {
+    let transport = StubTransport::new();
+    let mut group = c.benchmark_group("pipeline_throughput");
+    for count in [1, 10] {
+        let responses = content_length_response(1024).repeat(count);
+        group.throughput(Throughput::Elements(count as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(count), &count, |b, &count| {
+            b.iter_batched(
+                || transport.push_response(responses.clone()),
+                |_| pipeline_via(vec![params(); count], &transport).unwrap(),
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_latency,
+    bench_chunked_response,
+    bench_large_body,
+    bench_pipeline
+);
+criterion_main!(benches);
@@ -0,0 +1,66 @@
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use tor::hidden_service::{HttpRequestBuffer, MAX_REQUEST_HEADERS};
+
+/// Size of each read, Tor cells carry at most 498 bytes of stream data
+const READ_SIZE: usize = 498;
+
+fn chunked_request(body_len: usize) -> Vec<u8> {
+    let mut raw =
+        b"POST /upload HTTP/1.1\r\nHost: a.onion\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+    let chunk = vec![b'x'; 4096];
+    let mut written = 0;
+    while written < body_len {
+        let len = chunk.len().min(body_len - written);
+        raw.extend_from_slice(format!("{:x}\r\n", len).as_bytes());
+        raw.extend_from_slice(&chunk[..len]);
+        raw.extend_from_slice(b"\r\n");
+        written += len;
+    }
+    raw.extend_from_slice(b"0\r\n\r\n");
+    raw
+}
+
+/// The previous read loop: stringify and reparse the whole buffer after every read
+fn rescan_whole_buffer(raw: &[u8]) -> usize {
+    let mut buffer = Vec::new();
+    for read in raw.chunks(READ_SIZE) {
+        buffer.extend_from_slice(read);
+        let text = String::from_utf8_lossy(&buffer);
+        let mut headers = [httparse::EMPTY_HEADER; MAX_REQUEST_HEADERS];
+        let mut req = httparse::Request::new(&mut headers);
+        let _ = req.parse(&buffer);
+        if text.contains("\r\n\r\n") && text.ends_with("0\r\n\r\n") {
+            break;
+        }
+    }
+    buffer.len()
+}
+
+fn incremental(raw: &[u8]) -> usize {
+    let mut request = HttpRequestBuffer::new();
+    for read in raw.chunks(READ_SIZE) {
+        if request.push(read).unwrap() {
+            break;
+        }
+    }
+    request.as_bytes().len()
+}
+
+fn bench_chunked_request(c: &mut Criterion) {
+    let mut group = c.benchmark_group("chunked_request");
+    group.sample_size(10);
+    for mb in [1, 4] {
+        let raw = chunked_request(mb * 1024 * 1024);
+        group.throughput(Throughput::Bytes(raw.len() as u64));
+        group.bench_with_input(BenchmarkId::new("incremental", mb), &raw, |b, raw| {
+            b.iter(|| incremental(raw))
+        });
+        group.bench_with_input(BenchmarkId::new("rescan", mb), &raw, |b, raw| {
+            b.iter(|| rescan_whole_buffer(raw))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_chunked_request);
+criterion_main!(benches);
@@ -0,0 +1,60 @@
+use std::convert::TryInto;
+use std::sync::Arc;
+
+use tor::http_client::{HttpClientConfig, RequestInterceptor, make_http_request_with_config};
+use tor::prelude::*;
+
+/// Adds a bearer token to every request and logs the outcome
+struct AuthInterceptor {
+    token: String,
+}
+
+impl RequestInterceptor for AuthInterceptor {
+    fn before(&self, params: &mut HttpRequestParams) {
+        params
+            .headers
+            .get_or_insert_with(Vec::new)
+            .push(("Authorization".into(), format!("Bearer {}", self.token)));
+    }
+
+    fn after(&self, response: &HttpResponse) {
+        println!("Request finished with status {}", response.status_code);
+    }
+}
+
+fn main() {
+    println!("Starting Tor service...");
+
+    let service: TorService = TorServiceParam {
+        bootstrap_timeout_ms: Some(60000),
+        ..TorServiceParam::ephemeral().expect("Failed to create a data directory")
+    }
+    .try_into()
+    .expect("Failed to initialize Tor service");
+
+    let mut owned_node = service
+        .into_owned_node()
+        .expect("Failed to bootstrap Tor service");
+
+    let mut config = HttpClientConfig::new();
+    config.add_interceptor(Arc::new(AuthInterceptor {
+        token: "example-token".into(),
+    }));
+
+    let params = HttpRequestParams {
+        url: "https://httpbin.org/headers".to_string(),
+        method: HttpMethod::GET,
+        timeout_ms: Some(30000),
+        ..Default::default()
+    };
+
+    let socks_proxy = format!("127.0.0.1:{}", owned_node.socks_port);
+    match make_http_request_with_config(params, socks_proxy, &config) {
+        Ok(response) => println!("Response Body: {}", response.body),
+        Err(e) => println!("Request failed: {:?}", e),
+    }
+
+    owned_node
+        .shutdown()
+        .expect("Failed to shutdown Tor service");
+}
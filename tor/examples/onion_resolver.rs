@@ -0,0 +1,57 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::sync::Arc;
+
+use tor::http_client::{OnionResolver, set_onion_resolver};
+use tor::prelude::*;
+
+/// Resolves a fixed set of names, e.g. shipped with the app or loaded from a signed list
+struct StaticResolver {
+    names: HashMap<String, String>,
+}
+
+impl OnionResolver for StaticResolver {
+    fn resolve(&self, host: &str) -> Option<String> {
+        self.names.get(host).cloned()
+    }
+}
+
+fn main() {
+    println!("Starting Tor service...");
+
+    let service: TorService = TorServiceParam {
+        bootstrap_timeout_ms: Some(60000),
+        ..TorServiceParam::ephemeral().expect("Failed to create a data directory")
+    }
+    .try_into()
+    .expect("Failed to initialize Tor service");
+
+    let mut owned_node = service
+        .into_owned_node()
+        .expect("Failed to bootstrap Tor service");
+
+    set_onion_resolver(Arc::new(StaticResolver {
+        names: HashMap::from([(
+            "duckduckgo.tor".to_string(),
+            "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion".to_string(),
+        )]),
+    }));
+
+    // Sent to the onion address, Tor never sees "duckduckgo.tor"
+    let params = HttpRequestParams {
+        url: "https://duckduckgo.tor/".to_string(),
+        method: HttpMethod::GET,
+        timeout_ms: Some(60000),
+        ..Default::default()
+    };
+
+    let socks_proxy = format!("127.0.0.1:{}", owned_node.socks_port);
+    match make_http_request(params, socks_proxy) {
+        Ok(response) => println!("Status: {}", response.status_code),
+        Err(e) => println!("Request failed: {:?}", e),
+    }
+
+    owned_node
+        .shutdown()
+        .expect("Failed to shutdown Tor service");
+}
@@ -1,8 +1,6 @@
-use std::collections::HashMap;
 use std::convert::TryInto;
 
-use tor::http_client::{HttpMethod, HttpRequestParams, make_http_request};
-use tor::{TorService, TorServiceParam};
+use tor::prelude::*;
 
 fn main() {
     // Configure logging
@@ -11,9 +9,8 @@ fn main() {
 
     // Start Tor service with a temporary data directory
     let service: TorService = TorServiceParam {
-        socks_port: Some(19054),
-        data_dir: String::from("/tmp/tor_test"),
         bootstrap_timeout_ms: Some(60000), // 60 seconds for bootstrap
+        ..TorServiceParam::ephemeral().expect("Failed to create a data directory")
     }
     .try_into()
     .expect("Failed to initialize Tor service");
@@ -35,6 +32,7 @@ fn main() {
         headers: None,
         body: None,
         timeout_ms: Some(30000), // 30 seconds timeout
+        ..Default::default()
     };
 
     let socks_proxy = format!("127.0.0.1:{}", owned_node.socks_port);
@@ -60,6 +58,7 @@ fn main() {
         headers: None,
         body: None,
         timeout_ms: Some(30000),
+        ..Default::default()
     };
 
     match make_http_request(onion_get_params, socks_proxy.clone()) {
@@ -78,9 +77,10 @@ fn main() {
 
     // Test HTTPS POST request with headers and body
     println!("\nTesting HTTPS POST request...");
-    let mut headers = HashMap::new();
-    headers.insert("Content-Type".to_string(), "application/json".to_string());
-    headers.insert("User-Agent".to_string(), "Tor-Test-Client/1.0".to_string());
+    let headers = vec![
+        ("Content-Type".to_string(), "application/json".to_string()),
+        ("User-Agent".to_string(), "Tor-Test-Client/1.0".to_string()),
+    ];
 
     let post_params = HttpRequestParams {
         url: "https://httpbin.org/post".to_string(),
@@ -88,6 +88,7 @@ fn main() {
         headers: Some(headers),
         body: Some(r#"{"test": "data", "from": "tor"}"#.to_string()),
         timeout_ms: Some(30000), // 30 seconds timeout
+        ..Default::default()
     };
 
     match make_http_request(post_params, socks_proxy) {
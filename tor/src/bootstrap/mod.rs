@@ -0,0 +1,342 @@
+//! Latest bootstrap status seen by this process
+//! Updated whenever `status/bootstrap-phase` is read (while waiting for bootstrap and on
+//! `get_status`), so progress can be polled without touching the control connection
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Parsed `status/bootstrap-phase` line, e.g.
+/// "NOTICE BOOTSTRAP PROGRESS=10 TAG=conn_done SUMMARY="Connected to a relay""
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BootstrapStatus {
+    /// 0 to 100
+    pub progress: u8,
+    pub tag: String,
+    pub summary: String,
+}
+
+impl BootstrapStatus {
+    pub fn parse(line: &str) -> Option<BootstrapStatus> {
+        let progress = field(line, "PROGRESS=")?.parse::<u8>().ok()?.min(100);
+        Some(BootstrapStatus {
+            progress,
+            tag: field(line, "TAG=").unwrap_or_default(),
+            summary: field(line, "SUMMARY=").unwrap_or_default(),
+        })
+    }
+}
+
+/// Clock offset in seconds when `line` is a bootstrap problem caused by clock skew, positive
+/// when the local clock is ahead
+/// "WARN BOOTSTRAP PROGRESS=14 TAG=handshake SUMMARY="..." WARNING="Clock skew 400 in NETINFO
+/// cell from OR" REASON=CLOCK_SKEW COUNT=1 RECOMMENDATION=warn"
+pub fn clock_skew(line: &str) -> Option<i64> {
+    if field(line, "REASON=")? != "CLOCK_SKEW" {
+        return None;
+    }
+    field(line, "WARNING=")?
+        .strip_prefix("Clock skew ")?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Reports of the same skew after which bootstrap gives up with `TorErrors::ClockSkew`
+/// A single relay with a wrong clock also triggers the warning, only repeated reports show
+/// the local clock is the one that is off
+pub const CLOCK_SKEW_REPORTS: u32 = 3;
+
+/// Same as `clock_skew` but only once Tor counted `CLOCK_SKEW_REPORTS` of them (`COUNT=`)
+pub fn sustained_clock_skew(line: &str) -> Option<i64> {
+    let offset = clock_skew(line)?;
+    let count: u32 = field(line, "COUNT=")?.parse().ok()?;
+    (count >= CLOCK_SKEW_REPORTS).then_some(offset)
+}
+
+/// Value of `key` in a status line, quoted values may contain spaces
+fn field(line: &str, key: &str) -> Option<String> {
+    let start = line.find(key)? + key.len();
+    let rest = &line[start..];
+    let value = match rest.strip_prefix('"') {
+        Some(quoted) => quoted.split('"').next()?,
+        None => rest.split_whitespace().next()?,
+    };
+    Some(value.to_string())
+}
+
+/// Progress changes kept for `estimated_seconds_remaining`, enough to smooth over one slow
+/// phase without remembering the fast start of bootstrap
+const HISTORY_LEN: usize = 6;
+
+struct Tracked {
+    status: BootstrapStatus,
+    last_progress_at: Instant,
+    /// When each recent percentage was first seen, oldest first
+    history: VecDeque<(Instant, u8)>,
+}
+
+static LATEST: OnceCell<Mutex<Option<Tracked>>> = OnceCell::new();
+
+fn latest() -> &'static Mutex<Option<Tracked>> {
+    LATEST.get_or_init(|| Mutex::new(None))
+}
+
+/// Records a `status/bootstrap-phase` reply
+pub(crate) fn record(line: &str) {
+    let Some(status) = BootstrapStatus::parse(line) else {
+        return;
+    };
+    let mut latest = latest().lock().unwrap();
+    let now = Instant::now();
+    match latest.as_mut() {
+        Some(tracked) if tracked.status.progress == status.progress => tracked.status = status,
+        Some(tracked) if tracked.status.progress < status.progress => {
+            if tracked.history.len() == HISTORY_LEN {
+                tracked.history.pop_front();
+            }
+            tracked.history.push_back((now, status.progress));
+            tracked.last_progress_at = now;
+            tracked.status = status;
+        }
+        _ => {
+            *latest = Some(Tracked {
+                history: VecDeque::from([(now, status.progress)]),
+                status,
+                last_progress_at: now,
+            })
+        }
+    }
+}
+
+/// Forgets the tracked status, called when a new daemon starts
+pub(crate) fn reset() {
+    *latest().lock().unwrap() = None;
+}
+
+/// Latest bootstrap status, `None` until Tor reported one
+pub fn bootstrap_status() -> Option<BootstrapStatus> {
+    latest()
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|tracked| tracked.status.clone())
+}
+
+/// Latest bootstrap percentage (0 to 100), `None` until Tor reported one
+pub fn bootstrap_progress() -> Option<u8> {
+    bootstrap_status().map(|status| status.progress)
+}
+
+/// How long the percentage has stayed the same, a long time below 100 means bootstrap is stuck
+pub fn time_since_progress() -> Option<Duration> {
+    latest()
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|tracked| tracked.last_progress_at.elapsed())
+}
+
+/// Rough seconds until bootstrap is done, extrapolating linearly from the progress made since
+/// the oldest recent percentage. Time spent stuck counts too, so the estimate grows while
+/// nothing happens. `None` until there is progress to extrapolate from, 0 once done
+pub fn estimated_seconds_remaining() -> Option<u64> {
+    let latest = latest().lock().unwrap();
+    let tracked = latest.as_ref()?;
+    estimate_remaining(&tracked.history, Instant::now())
+}
+
+fn estimate_remaining(history: &VecDeque<(Instant, u8)>, now: Instant) -> Option<u64> {
+    let &(_, progress) = history.back()?;
+    if progress >= 100 {
+        return Some(0);
+    }
+    let &(since, base) = history.front()?;
+    let elapsed = now.saturating_duration_since(since).as_secs_f64();
+    if progress <= base || elapsed <= 0.0 {
+        return None;
+    }
+    let per_second = f64::from(progress - base) / elapsed;
+    Some((f64::from(100 - progress) / per_second).ceil() as u64)
+}
+
+/// Bootstrap phase from the status TAG, with stable numeric codes for the FFI
+/// Tags Tor renamed in 0.4.0 (conn_dir, handshake_dir, conn_or, handshake_or) map to their
+/// current equivalents
+#[repr(i32)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootstrapTag {
+    /// No status yet or a tag this SDK doesn't know
+    Unknown = -1,
+    Starting = 0,
+    ConnPt = 1,
+    ConnDonePt = 2,
+    ConnProxy = 3,
+    ConnDoneProxy = 4,
+    Conn = 5,
+    ConnDone = 6,
+    Handshake = 7,
+    HandshakeDone = 8,
+    OnehopCreate = 9,
+    RequestingStatus = 10,
+    LoadingStatus = 11,
+    LoadingKeys = 12,
+    RequestingDescriptors = 13,
+    LoadingDescriptors = 14,
+    EnoughDirinfo = 15,
+    ApConnPt = 16,
+    ApConnDonePt = 17,
+    ApConnProxy = 18,
+    ApConnDoneProxy = 19,
+    ApConn = 20,
+    ApConnDone = 21,
+    ApHandshake = 22,
+    ApHandshakeDone = 23,
+    CircuitCreate = 24,
+    Done = 25,
+}
+
+impl BootstrapTag {
+    pub fn from_tag(tag: &str) -> BootstrapTag {
+        match tag {
+            "starting" => BootstrapTag::Starting,
+            "conn_pt" => BootstrapTag::ConnPt,
+            "conn_done_pt" => BootstrapTag::ConnDonePt,
+            "conn_proxy" => BootstrapTag::ConnProxy,
+            "conn_done_proxy" => BootstrapTag::ConnDoneProxy,
+            "conn" | "conn_dir" => BootstrapTag::Conn,
+            "conn_done" => BootstrapTag::ConnDone,
+            "handshake" | "handshake_dir" => BootstrapTag::Handshake,
+            "handshake_done" => BootstrapTag::HandshakeDone,
+            "onehop_create" => BootstrapTag::OnehopCreate,
+            "requesting_status" => BootstrapTag::RequestingStatus,
+            "loading_status" => BootstrapTag::LoadingStatus,
+            "loading_keys" => BootstrapTag::LoadingKeys,
+            "requesting_descriptors" => BootstrapTag::RequestingDescriptors,
+            "loading_descriptors" => BootstrapTag::LoadingDescriptors,
+            "enough_dirinfo" => BootstrapTag::EnoughDirinfo,
+            "ap_conn_pt" => BootstrapTag::ApConnPt,
+            "ap_conn_done_pt" => BootstrapTag::ApConnDonePt,
+            "ap_conn_proxy" => BootstrapTag::ApConnProxy,
+            "ap_conn_done_proxy" => BootstrapTag::ApConnDoneProxy,
+            "ap_conn" | "conn_or" => BootstrapTag::ApConn,
+            "ap_conn_done" => BootstrapTag::ApConnDone,
+            "ap_handshake" | "handshake_or" => BootstrapTag::ApHandshake,
+            "ap_handshake_done" => BootstrapTag::ApHandshakeDone,
+            "circuit_create" => BootstrapTag::CircuitCreate,
+            "done" => BootstrapTag::Done,
+            _ => BootstrapTag::Unknown,
+        }
+    }
+}
+
+/// Phase of the latest bootstrap status, `BootstrapTag::Unknown` until Tor reported one
+pub fn bootstrap_tag() -> BootstrapTag {
+    bootstrap_status()
+        .map(|status| BootstrapTag::from_tag(&status.tag))
+        .unwrap_or(BootstrapTag::Unknown)
+}
+
+/// Cancels a bootstrap started with `TorService::bootstrap_with_cancel` from another thread
+/// Clones share the same state
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates_remaining_time() {
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let mut history = VecDeque::from([(at(0), 10)]);
+        assert_eq!(estimate_remaining(&history, at(5)), None);
+
+        // 40% in 20s, the remaining 50% take another 25s
+        history.push_back((at(20), 50));
+        assert_eq!(estimate_remaining(&history, at(20)), Some(25));
+        // Stuck for 20s more: 40% in 40s
+        assert_eq!(estimate_remaining(&history, at(40)), Some(50));
+
+        history.push_back((at(41), 100));
+        assert_eq!(estimate_remaining(&history, at(41)), Some(0));
+    }
+
+    #[test]
+    fn maps_bootstrap_tags() {
+        assert_eq!(BootstrapTag::from_tag("starting") as i32, 0);
+        assert_eq!(
+            BootstrapTag::from_tag("loading_descriptors"),
+            BootstrapTag::LoadingDescriptors
+        );
+        assert_eq!(
+            BootstrapTag::from_tag("handshake_dir"),
+            BootstrapTag::Handshake
+        );
+        assert_eq!(BootstrapTag::from_tag("conn_or"), BootstrapTag::ApConn);
+        assert_eq!(BootstrapTag::from_tag("done") as i32, 25);
+        assert_eq!(
+            BootstrapTag::from_tag("something_new"),
+            BootstrapTag::Unknown
+        );
+    }
+
+    #[test]
+    fn parses_bootstrap_phase() {
+        let status = BootstrapStatus::parse(
+            "NOTICE BOOTSTRAP PROGRESS=10 TAG=conn_done SUMMARY=\"Connected to a relay\"",
+        )
+        .unwrap();
+        assert_eq!(status.progress, 10);
+        assert_eq!(status.tag, "conn_done");
+        assert_eq!(status.summary, "Connected to a relay");
+        assert_eq!(
+            BootstrapStatus::parse("NOTICE BOOTSTRAP PROGRESS=100 TAG=done SUMMARY=\"Done\"")
+                .map(|s| s.progress),
+            Some(100)
+        );
+        assert_eq!(BootstrapStatus::parse("NOTICE BOOTSTRAP"), None);
+    }
+
+    #[test]
+    fn detects_clock_skew() {
+        let skewed = "WARN BOOTSTRAP PROGRESS=14 TAG=handshake SUMMARY=\"Handshaking with a relay\" \
+                      WARNING=\"Clock skew -400 in NETINFO cell from OR\" REASON=CLOCK_SKEW \
+                      COUNT=1 RECOMMENDATION=warn HOSTID=\"ABCD\" HOSTADDR=\"192.0.2.1:443\"";
+        assert_eq!(clock_skew(skewed), Some(-400));
+        assert_eq!(sustained_clock_skew(skewed), None);
+        assert_eq!(
+            sustained_clock_skew(&skewed.replace("COUNT=1", "COUNT=3")),
+            Some(-400)
+        );
+        assert_eq!(
+            clock_skew(
+                "WARN BOOTSTRAP PROGRESS=14 TAG=handshake WARNING=\"Connection refused\" \
+                 REASON=CONNECTREFUSED"
+            ),
+            None
+        );
+        assert_eq!(
+            clock_skew("NOTICE BOOTSTRAP PROGRESS=10 TAG=conn_done SUMMARY=\"Connected\""),
+            None
+        );
+    }
+}
@@ -0,0 +1,138 @@
+//! Tor's on-disk directory cache, kept in the cache dir across restarts
+//! With a consensus that is still valid Tor skips fetching the directory on startup and
+//! bootstrap mostly comes down to building the first circuit (`TorServiceParam::warm_start`)
+use crate::date::days_from_civil;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Consensus files Tor caches, clients use the microdescriptor flavor
+const CONSENSUS_FILES: &[&str] = &["cached-microdesc-consensus", "cached-consensus"];
+
+/// The validity header lines come right after network-status-version, stop looking after these
+const MAX_HEADER_LINES: usize = 32;
+
+/// Validity interval of a cached consensus, as seconds since the Unix epoch
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CachedConsensus {
+    pub valid_after: u64,
+    /// Tor fetches a newer consensus from here on but keeps using this one
+    pub fresh_until: u64,
+    /// Past this the consensus can't be trusted anymore and a full fetch is needed
+    pub valid_until: u64,
+}
+
+impl CachedConsensus {
+    /// Reads the validity header of the consensus cached in `cache_dir`
+    pub fn read(cache_dir: &Path) -> Option<CachedConsensus> {
+        CONSENSUS_FILES.iter().find_map(|name| {
+            let file = fs::File::open(cache_dir.join(name)).ok()?;
+            let header: Vec<String> = BufReader::new(file)
+                .lines()
+                .take(MAX_HEADER_LINES)
+                .map_while(Result::ok)
+                .collect();
+            CachedConsensus::parse(&header.join("\n"))
+        })
+    }
+
+    /// Parses the "valid-after", "fresh-until" and "valid-until" lines of a consensus
+    pub fn parse(header: &str) -> Option<CachedConsensus> {
+        let time = |keyword: &str| {
+            header
+                .lines()
+                .find_map(|line| line.strip_prefix(keyword)?.strip_prefix(' '))
+                .and_then(parse_utc)
+        };
+        Some(CachedConsensus {
+            valid_after: time("valid-after")?,
+            fresh_until: time("fresh-until")?,
+            valid_until: time("valid-until")?,
+        })
+    }
+
+    /// Whether the consensus can be used at `now` (seconds since the Unix epoch)
+    pub fn is_valid_at(&self, now: u64) -> bool {
+        self.valid_after <= now && now < self.valid_until
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.is_valid_at(unix_now())
+    }
+}
+
+/// Deletes the cached consensus files so Tor doesn't start from an expired one
+pub(crate) fn remove_consensus(cache_dir: &Path) {
+    for name in CONSENSUS_FILES {
+        let _ = fs::remove_file(cache_dir.join(name));
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// "YYYY-MM-DD HH:MM:SS" in UTC to seconds since the Unix epoch
+fn parse_utc(value: &str) -> Option<u64> {
+    let (date, time) = value.trim().split_once(' ')?;
+    let mut date = date.splitn(3, '-').map(|p| p.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    let mut time = time.splitn(3, ':').map(|p| p.parse::<i64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+        return None;
+    }
+    let secs = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second;
+    u64::try_from(secs).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_consensus_validity() {
+        assert_eq!(parse_utc("1970-01-01 00:00:00"), Some(0));
+        assert_eq!(parse_utc("2024-02-29 12:30:15"), Some(1709209815));
+        assert_eq!(parse_utc("2024-13-01 00:00:00"), None);
+
+        let consensus = CachedConsensus::parse(
+            "network-status-version 3 microdesc\nvote-status consensus\n\
+             consensus-method 33\nvalid-after 2024-02-29 12:00:00\n\
+             fresh-until 2024-02-29 13:00:00\nvalid-until 2024-02-29 15:00:00\n",
+        )
+        .unwrap();
+        assert_eq!(consensus.fresh_until - consensus.valid_after, 3600);
+        assert!(consensus.is_valid_at(consensus.fresh_until + 60));
+        assert!(!consensus.is_valid_at(consensus.valid_until));
+        assert!(!consensus.is_valid_at(consensus.valid_after - 1));
+        assert_eq!(
+            CachedConsensus::parse("valid-after 2024-02-29 12:00:00"),
+            None
+        );
+    }
+
+    #[test]
+    fn reads_and_removes_cached_consensus() {
+        let dir = std::env::temp_dir().join(format!("consensus-cache-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        assert_eq!(CachedConsensus::read(&dir), None);
+        fs::write(
+            dir.join("cached-microdesc-consensus"),
+            "network-status-version 3 microdesc\nvalid-after 2024-02-29 12:00:00\n\
+             fresh-until 2024-02-29 13:00:00\nvalid-until 2024-02-29 15:00:00\n",
+        )
+        .unwrap();
+        let consensus = CachedConsensus::read(&dir).unwrap();
+        assert!(!consensus.is_valid());
+
+        remove_consensus(&dir);
+        assert_eq!(CachedConsensus::read(&dir), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,123 @@
+use serde::{Deserialize, Serialize};
+
+/// A relay on a circuit path, e.g. "$9695DFC35FFEB861329B9F1AB04C46397020CE31~moria1"
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RelayInfo {
+    /// Identity fingerprint, without the leading '$'
+    pub fingerprint: String,
+    pub nickname: Option<String>,
+}
+
+/// One entry of `GETINFO circuit-status`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CircuitInfo {
+    pub id: String,
+    /// LAUNCHED, BUILT, GUARD_WAIT, EXTENDED, FAILED or CLOSED
+    pub status: String,
+    /// Entry guard first, empty while the first hop is being built
+    pub path: Vec<RelayInfo>,
+    /// e.g. ONEHOP_TUNNEL, IS_INTERNAL, NEED_CAPACITY, NEED_UPTIME
+    pub build_flags: Vec<String>,
+    /// e.g. GENERAL, HS_CLIENT_REND, HS_SERVICE_INTRO
+    pub purpose: Option<String>,
+    /// ISO time the circuit was created, e.g. "2025-01-31T10:12:01.123456"
+    pub time_created: Option<String>,
+}
+
+impl CircuitInfo {
+    /// Parses a circuit-status line: "ID STATUS [PATH] [KEY=VALUE ...]"
+    pub fn parse(line: &str) -> Option<CircuitInfo> {
+        let mut tokens = line.split_whitespace().peekable();
+        let id = tokens.next()?.to_string();
+        let status = tokens.next()?.to_string();
+        let path = match tokens.peek() {
+            Some(token) if !token.contains('=') || token.starts_with('$') => tokens
+                .next()?
+                .split(',')
+                .filter_map(RelayInfo::parse)
+                .collect(),
+            _ => Vec::new(),
+        };
+        let mut info = CircuitInfo {
+            id,
+            status,
+            path,
+            build_flags: Vec::new(),
+            purpose: None,
+            time_created: None,
+        };
+        for token in tokens {
+            match token.split_once('=') {
+                Some(("BUILD_FLAGS", flags)) => {
+                    info.build_flags = flags.split(',').map(String::from).collect()
+                }
+                Some(("PURPOSE", purpose)) => info.purpose = Some(purpose.to_string()),
+                Some(("TIME_CREATED", time)) => info.time_created = Some(time.to_string()),
+                _ => {}
+            }
+        }
+        Some(info)
+    }
+}
+
+impl RelayInfo {
+    /// "$FINGERPRINT~nickname", "$FINGERPRINT=nickname" or "$FINGERPRINT"
+    pub fn parse(relay: &str) -> Option<RelayInfo> {
+        let relay = relay.strip_prefix('$').unwrap_or(relay);
+        let (fingerprint, nickname) = match relay.split_once(['~', '=']) {
+            Some((fingerprint, nickname)) => (fingerprint, Some(nickname.to_string())),
+            None => (relay, None),
+        };
+        if fingerprint.is_empty() {
+            return None;
+        }
+        Some(RelayInfo {
+            fingerprint: fingerprint.to_string(),
+            nickname,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_circuit_status_lines() {
+        let built = CircuitInfo::parse(
+            "12 BUILT $AAAA~guard,$BBBB~middle,$CCCC BUILD_FLAGS=NEED_CAPACITY,NEED_UPTIME \
+             PURPOSE=GENERAL TIME_CREATED=2025-01-31T10:12:01.123456",
+        )
+        .unwrap();
+        assert_eq!(built.id, "12");
+        assert_eq!(built.status, "BUILT");
+        assert_eq!(
+            built.path,
+            vec![
+                RelayInfo {
+                    fingerprint: "AAAA".into(),
+                    nickname: Some("guard".into())
+                },
+                RelayInfo {
+                    fingerprint: "BBBB".into(),
+                    nickname: Some("middle".into())
+                },
+                RelayInfo {
+                    fingerprint: "CCCC".into(),
+                    nickname: None
+                },
+            ]
+        );
+        assert_eq!(built.build_flags, vec!["NEED_CAPACITY", "NEED_UPTIME"]);
+        assert_eq!(built.purpose.as_deref(), Some("GENERAL"));
+        assert_eq!(
+            built.time_created.as_deref(),
+            Some("2025-01-31T10:12:01.123456")
+        );
+
+        let launched = CircuitInfo::parse("13 LAUNCHED PURPOSE=GENERAL").unwrap();
+        assert!(launched.path.is_empty());
+        assert_eq!(launched.purpose.as_deref(), Some("GENERAL"));
+        assert_eq!(CircuitInfo::parse("13"), None);
+    }
+}
@@ -0,0 +1,81 @@
+use serde::{Deserialize, Serialize};
+
+/// Plaintext outer layer of a v3 onion service descriptor
+/// The introduction points live in the encrypted `superencrypted` blob, decrypting it needs
+/// the service's public key and is left to callers (e.g. an external load balancer)
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HsDescriptor {
+    /// Always 3 for descriptors Tor still serves
+    pub version: u8,
+    /// Minutes the descriptor is valid for
+    pub lifetime_minutes: u32,
+    /// Higher counters replace lower ones on the HSDirs
+    pub revision_counter: u64,
+    /// Base64 encrypted body, without the BEGIN/END MESSAGE armor
+    pub superencrypted: String,
+    /// Descriptor text as fetched, needed to re-post it unchanged
+    pub raw: String,
+}
+
+impl HsDescriptor {
+    pub fn parse(descriptor: &str) -> Option<HsDescriptor> {
+        let mut version = None;
+        let mut lifetime_minutes = None;
+        let mut revision_counter = None;
+        let mut superencrypted = String::new();
+        let mut in_superencrypted = false;
+        for line in descriptor.lines().map(str::trim) {
+            if in_superencrypted {
+                match line {
+                    "-----BEGIN MESSAGE-----" => {}
+                    "-----END MESSAGE-----" => in_superencrypted = false,
+                    _ => superencrypted.push_str(line),
+                }
+                continue;
+            }
+            let (keyword, value) = line.split_once(' ').unwrap_or((line, ""));
+            match keyword {
+                "hs-descriptor" => version = value.trim().parse().ok(),
+                "descriptor-lifetime" => lifetime_minutes = value.trim().parse().ok(),
+                "revision-counter" => revision_counter = value.trim().parse().ok(),
+                "superencrypted" => in_superencrypted = true,
+                _ => {}
+            }
+        }
+        Some(HsDescriptor {
+            version: version?,
+            lifetime_minutes: lifetime_minutes?,
+            revision_counter: revision_counter?,
+            superencrypted,
+            raw: descriptor.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_descriptor_outer_layer() {
+        let raw = "hs-descriptor 3\n\
+                   descriptor-lifetime 180\n\
+                   descriptor-signing-key-cert\n\
+                   -----BEGIN ED25519 CERT-----\n\
+                   AQgABl5/AZLmgPpX\n\
+                   -----END ED25519 CERT-----\n\
+                   revision-counter 42\n\
+                   superencrypted\n\
+                   -----BEGIN MESSAGE-----\n\
+                   YWJj\n\
+                   ZGVm\n\
+                   -----END MESSAGE-----\n\
+                   signature abcdef";
+        let descriptor = HsDescriptor::parse(raw).unwrap();
+        assert_eq!(descriptor.version, 3);
+        assert_eq!(descriptor.lifetime_minutes, 180);
+        assert_eq!(descriptor.revision_counter, 42);
+        assert_eq!(descriptor.superencrypted, "YWJjZGVm");
+        assert_eq!(HsDescriptor::parse("revision-counter 1"), None);
+    }
+}
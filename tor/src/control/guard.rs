@@ -0,0 +1,62 @@
+use serde::{Deserialize, Serialize};
+
+use super::RelayInfo;
+
+/// One entry of `GETINFO entry-guards`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GuardInfo {
+    /// Identity fingerprint, without the leading '$'. Only the nickname is known when Tor
+    /// lists the guard by name, the fingerprint is then the nickname
+    pub fingerprint: String,
+    pub nickname: Option<String>,
+    /// up, never-connected, down, unusable or unlisted (no longer in the consensus)
+    pub status: String,
+    /// When a down, unusable or unlisted guard got that status, e.g. "2025-01-31 10:12:01"
+    pub since: Option<String>,
+}
+
+impl GuardInfo {
+    /// Parses an entry-guards line: "$FINGERPRINT~nickname STATUS [ISOTIME]"
+    pub fn parse(line: &str) -> Option<GuardInfo> {
+        let mut tokens = line.split_whitespace();
+        let relay = RelayInfo::parse(tokens.next()?)?;
+        let status = tokens.next()?.to_string();
+        let since = tokens.collect::<Vec<_>>().join(" ");
+        Some(GuardInfo {
+            fingerprint: relay.fingerprint,
+            nickname: relay.nickname,
+            status,
+            since: (!since.is_empty()).then_some(since),
+        })
+    }
+
+    /// Whether Tor currently considers the guard usable
+    pub fn is_up(&self) -> bool {
+        self.status == "up"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_entry_guards_lines() {
+        let up = GuardInfo::parse("$9695DFC35FFEB861329B9F1AB04C46397020CE31~moria1 up").unwrap();
+        assert_eq!(up.fingerprint, "9695DFC35FFEB861329B9F1AB04C46397020CE31");
+        assert_eq!(up.nickname.as_deref(), Some("moria1"));
+        assert!(up.is_up());
+        assert_eq!(up.since, None);
+
+        let down = GuardInfo::parse("$AAAA=relay down 2025-01-31 10:12:01").unwrap();
+        assert_eq!(down.status, "down");
+        assert!(!down.is_up());
+        assert_eq!(down.since.as_deref(), Some("2025-01-31 10:12:01"));
+
+        let unlisted = GuardInfo::parse("$BBBB unlisted 2025-01-30 08:00:00").unwrap();
+        assert_eq!(unlisted.nickname, None);
+        assert_eq!(unlisted.status, "unlisted");
+
+        assert_eq!(GuardInfo::parse("$CCCC"), None);
+    }
+}
@@ -0,0 +1,348 @@
+//! Minimal blocking client for Tor's control protocol
+//! Torut's `AuthenticatedConn` only wraps a handful of commands, this covers the rest
+//! (CLOSECIRCUIT, MAPADDRESS, SIGNAL, ...) on a separate connection to the control port
+//! Spec: https://spec.torproject.org/control-spec/
+mod circuit;
+mod descriptor;
+mod guard;
+mod relay;
+
+pub use circuit::{CircuitInfo, RelayInfo};
+pub use descriptor::HsDescriptor;
+pub use guard::GuardInfo;
+pub use relay::RelayStatus;
+
+use crate::TorErrors;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+/// How long a single reply may take before the command fails
+const REPLY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Status code of an asynchronous event reply
+pub const EVENT_CODE: u16 = 650;
+
+/// One complete control reply
+/// Every line is the text after the status code and separator, data blocks (`250+key=`)
+/// are joined to their key line with '\n'
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlReply {
+    pub code: u16,
+    pub lines: Vec<String>,
+}
+
+impl ControlReply {
+    pub fn is_ok(&self) -> bool {
+        (200..300).contains(&self.code)
+    }
+}
+
+/// Authenticated control connection, closed when dropped
+pub struct ControlConn {
+    writer: TcpStream,
+    reader: BufReader<TcpStream>,
+    events: VecDeque<ControlReply>,
+}
+
+impl ControlConn {
+    /// Connects to `control_port` (host:port) and authenticates with the method advertised by
+    /// PROTOCOLINFO: no auth, or the cookie file Tor was started with
+    pub fn connect(control_port: &str) -> Result<Self, TorErrors> {
+        let stream = TcpStream::connect(control_port.trim())?;
+        stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+        let mut conn = ControlConn {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            events: VecDeque::new(),
+        };
+        let info = conn.command("PROTOCOLINFO 1")?;
+        let auth_line = info
+            .lines
+            .iter()
+            .find(|l| l.starts_with("AUTH "))
+            .ok_or_else(|| control_error(info.code, "No AUTH in PROTOCOLINFO reply"))?;
+        let auth = if auth_line.contains("NULL") {
+            String::from("AUTHENTICATE")
+        } else {
+            let cookie_file = parse_cookie_file(auth_line)
+                .ok_or_else(|| control_error(info.code, "No COOKIEFILE in PROTOCOLINFO reply"))?;
+            format!("AUTHENTICATE {}", to_hex(&fs::read(cookie_file)?))
+        };
+        conn.command(&auth)?;
+        Ok(conn)
+    }
+
+    /// Sends `command` and waits for its reply, errors with `TorErrors::ControlCommandError`
+    /// when Tor answers with a non 2xx code
+    /// Events that arrive in the meantime are kept for `next_event`
+    /// A CR, LF or NUL in `command` is refused: it would end the command there and let the
+    /// rest run as commands of their own
+    pub fn command(&mut self, command: &str) -> Result<ControlReply, TorErrors> {
+        check_line(command)?;
+        self.send(command)
+    }
+
+    /// Writes `payload` as is and reads the reply to it
+    fn send(&mut self, payload: &str) -> Result<ControlReply, TorErrors> {
+        self.writer
+            .write_all(format!("{}\r\n", payload).as_bytes())?;
+        self.writer.flush()?;
+        loop {
+            let reply = read_reply(&mut self.reader)?;
+            if reply.code == EVENT_CODE {
+                self.events.push_back(reply);
+                continue;
+            }
+            if !reply.is_ok() {
+                return Err(control_error(reply.code, &reply.lines.join(" ")));
+            }
+            return Ok(reply);
+        }
+    }
+
+    /// Sends a multi-line command ("+COMMAND" followed by `data` and a "." line), e.g. HSPOST
+    pub fn command_with_data(
+        &mut self,
+        command: &str,
+        data: &str,
+    ) -> Result<ControlReply, TorErrors> {
+        check_line(command)?;
+        let mut payload = format!("+{}\r\n", command);
+        for line in data.lines() {
+            check_line(line)?;
+            // Lines starting with '.' are escaped by doubling it
+            if line.starts_with('.') {
+                payload.push('.');
+            }
+            payload.push_str(line);
+            payload.push_str("\r\n");
+        }
+        payload.push('.');
+        self.send(&payload)
+    }
+
+    /// Sets torrc options at runtime with one SETCONF, values are quoted
+    pub fn set_conf(&mut self, options: &[(String, String)]) -> Result<(), TorErrors> {
+        let mut command = String::from("SETCONF");
+        for (name, value) in options {
+            command.push_str(&format!(" {}={}", name, quote(value)));
+        }
+        self.command(&command).map(|_| ())
+    }
+
+    /// Runs `GETINFO key`, returning the value
+    pub fn get_info(&mut self, key: &str) -> Result<String, TorErrors> {
+        let reply = self.command(&format!("GETINFO {}", key))?;
+        let prefix = format!("{}=", key);
+        reply
+            .lines
+            .iter()
+            .find_map(|l| l.strip_prefix(&prefix))
+            .map(|v| v.trim_start_matches('\n').to_string())
+            .ok_or_else(|| control_error(reply.code, &format!("No {} in GETINFO reply", key)))
+    }
+
+    /// Next asynchronous (650) event, blocking up to the reply timeout
+    pub fn next_event(&mut self) -> Result<ControlReply, TorErrors> {
+        if let Some(event) = self.events.pop_front() {
+            return Ok(event);
+        }
+        loop {
+            let reply = read_reply(&mut self.reader)?;
+            if reply.code == EVENT_CODE {
+                return Ok(reply);
+            }
+        }
+    }
+
+    /// Like `next_event` but gives up after `timeout`, returning `None` when nothing arrived
+    /// A reply cut off by the timeout is lost, so only use this to drain events before
+    /// dropping the connection
+    pub fn next_event_within(
+        &mut self,
+        timeout: Duration,
+    ) -> Result<Option<ControlReply>, TorErrors> {
+        if let Some(event) = self.events.pop_front() {
+            return Ok(Some(event));
+        }
+        if timeout.is_zero() {
+            return Ok(None);
+        }
+        self.writer.set_read_timeout(Some(timeout))?;
+        let result = loop {
+            match read_reply(&mut self.reader) {
+                Ok(reply) if reply.code == EVENT_CODE => break Ok(Some(reply)),
+                Ok(_) => continue,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    break Ok(None);
+                }
+                Err(e) => break Err(e.into()),
+            }
+        };
+        self.writer.set_read_timeout(Some(REPLY_TIMEOUT))?;
+        result
+    }
+}
+
+/// Rejects line breaks and NUL, which would split a command line in several
+fn check_line(line: &str) -> Result<(), TorErrors> {
+    if line.bytes().any(|b| matches!(b, b'\r' | b'\n' | b'\0')) {
+        return Err(TorErrors::InvalidConfig(format!(
+            "Control command with a CR, LF or NUL: {:?}",
+            line
+        )));
+    }
+    Ok(())
+}
+
+fn control_error(code: u16, message: &str) -> TorErrors {
+    TorErrors::ControlCommandError {
+        code,
+        message: message.to_string(),
+    }
+}
+
+/// Reads a single reply: "XYZ-" mid lines, "XYZ+" data lines terminated by ".", "XYZ " end line
+pub(crate) fn read_reply<R: BufRead>(reader: &mut R) -> io::Result<ControlReply> {
+    let mut lines = Vec::new();
+    loop {
+        let line = read_line(reader)?;
+        if line.len() < 4 || !line.is_char_boundary(4) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Malformed control reply line: {}", line),
+            ));
+        }
+        let code = line[..3].parse::<u16>().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Malformed control reply code: {}", line),
+            )
+        })?;
+        let text = line[4..].to_string();
+        match &line[3..4] {
+            "-" => lines.push(text),
+            "+" => {
+                let mut data = Vec::new();
+                loop {
+                    let data_line = read_line(reader)?;
+                    if data_line == "." {
+                        break;
+                    }
+                    // Leading dots are escaped by doubling them
+                    data.push(match data_line.strip_prefix('.') {
+                        Some(rest) => rest.to_string(),
+                        None => data_line,
+                    });
+                }
+                lines.push(format!("{}\n{}", text, data.join("\n")));
+            }
+            _ => {
+                lines.push(text);
+                return Ok(ControlReply { code, lines });
+            }
+        }
+    }
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Control connection closed",
+        ));
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Extracts the quoted `COOKIEFILE="..."` path from a PROTOCOLINFO AUTH line
+fn parse_cookie_file(auth_line: &str) -> Option<String> {
+    let start = auth_line.find("COOKIEFILE=\"")? + "COOKIEFILE=\"".len();
+    let mut path = String::new();
+    let mut chars = auth_line[start..].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => path.push(chars.next()?),
+            '"' => return Some(path),
+            c => path.push(c),
+        }
+    }
+    None
+}
+
+/// Quoted string as the control protocol expects it, escaping '\' and '"'
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn reads_multi_line_and_data_replies() {
+        let raw = "250-version=0.4.8.10\r\n250+circuit-status=\r\n1 BUILT $A~a,$B~b\r\n..2 dotted\r\n.\r\n250 OK\r\n";
+        let reply = read_reply(&mut Cursor::new(raw)).unwrap();
+        assert_eq!(reply.code, 250);
+        assert_eq!(
+            reply.lines,
+            vec![
+                "version=0.4.8.10",
+                "circuit-status=\n1 BUILT $A~a,$B~b\n.2 dotted",
+                "OK"
+            ]
+        );
+        assert!(reply.is_ok());
+
+        let error = read_reply(&mut Cursor::new("552 Unknown circuit \"7\"\r\n")).unwrap();
+        assert_eq!(error.code, 552);
+        assert!(!error.is_ok());
+    }
+
+    #[test]
+    fn refuses_command_injection() {
+        assert!(check_line("CLOSECIRCUIT 12").is_ok());
+        for line in [
+            "CLOSECIRCUIT 1\r\nSIGNAL HALT",
+            "CLOSECIRCUIT 1\nSIGNAL HALT",
+            "GETINFO a\0",
+        ] {
+            assert!(check_line(line).is_err(), "{:?} was accepted", line);
+        }
+    }
+
+    #[test]
+    fn quotes_values() {
+        assert_eq!(quote("{de},relay1"), "\"{de},relay1\"");
+        assert_eq!(quote(r#"a "b" \c"#), r#""a \"b\" \\c""#);
+    }
+
+    #[test]
+    fn parses_cookie_file() {
+        let auth =
+            r#"AUTH METHODS=COOKIE,SAFECOOKIE COOKIEFILE="/tmp/tor/data/control_auth_cookie""#;
+        assert_eq!(
+            parse_cookie_file(auth),
+            Some("/tmp/tor/data/control_auth_cookie".to_string())
+        );
+        assert_eq!(
+            parse_cookie_file(r#"AUTH METHODS=COOKIE COOKIEFILE="C:\\tor\\cookie""#),
+            Some(r"C:\tor\cookie".to_string())
+        );
+        assert_eq!(parse_cookie_file("AUTH METHODS=NULL"), None);
+    }
+}
@@ -0,0 +1,110 @@
+use base64::Engine;
+use base64::engine::general_purpose;
+use serde::{Deserialize, Serialize};
+
+/// A relay of the consensus, from `GETINFO ns/all`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct RelayStatus {
+    /// Identity fingerprint in hex, as used in ExitNodes and the other node lists
+    pub fingerprint: String,
+    pub nickname: String,
+    /// e.g. Exit, Fast, Guard, Running, Stable, Valid
+    pub flags: Vec<String>,
+    /// Consensus weight (`w Bandwidth=`), roughly the bandwidth measured for the relay
+    pub bandwidth: Option<u64>,
+}
+
+impl RelayStatus {
+    /// Whether clients pick the relay as exit: flagged Exit and not BadExit
+    pub fn is_exit(&self) -> bool {
+        self.has_flag("Exit") && !self.has_flag("BadExit")
+    }
+
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|f| f == flag)
+    }
+
+    /// Parses the router status entries of a network status document: an "r" line per relay
+    /// followed by its "s" (flags) and "w" (bandwidth) lines, other lines are skipped
+    pub fn parse_all(document: &str) -> Vec<RelayStatus> {
+        let mut relays: Vec<RelayStatus> = Vec::new();
+        // Whether the "s"/"w" lines belong to the last relay, false after an unparsable "r"
+        let mut in_entry = false;
+        for line in document.lines().map(str::trim) {
+            let mut tokens = line.split_whitespace();
+            match tokens.next() {
+                // "r nickname identity digest date time address or_port dir_port"
+                Some("r") => {
+                    let nickname = tokens.next();
+                    let fingerprint = tokens.next().and_then(identity_to_hex);
+                    in_entry = false;
+                    if let (Some(nickname), Some(fingerprint)) = (nickname, fingerprint) {
+                        relays.push(RelayStatus {
+                            fingerprint,
+                            nickname: nickname.to_string(),
+                            flags: Vec::new(),
+                            bandwidth: None,
+                        });
+                        in_entry = true;
+                    }
+                }
+                Some("s") if in_entry => {
+                    if let Some(relay) = relays.last_mut() {
+                        relay.flags = tokens.map(String::from).collect();
+                    }
+                }
+                Some("w") if in_entry => {
+                    if let Some(relay) = relays.last_mut() {
+                        relay.bandwidth = tokens
+                            .find_map(|t| t.strip_prefix("Bandwidth="))
+                            .and_then(|b| b.parse().ok());
+                    }
+                }
+                _ => {}
+            }
+        }
+        relays
+    }
+}
+
+/// Identity digest as it appears in "r" lines (base64 without padding) to hex
+fn identity_to_hex(identity: &str) -> Option<String> {
+    let bytes = general_purpose::STANDARD_NO_PAD
+        .decode(identity.trim_end_matches('='))
+        .ok()?;
+    (bytes.len() == 20).then(|| bytes.iter().map(|b| format!("{:02X}", b)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_router_status_entries() {
+        let document = "\
+r moria1 lpXfw1/+uGEym58asExGOXAgzjE digest 2025-01-31 10:00:00 128.31.0.39 9201 0
+s Authority Fast Running Stable V2Dir Valid
+w Bandwidth=20
+r fastexit qqqqqqqqqqqqqqqqqqqqqqqqqqo digest 2025-01-31 10:00:00 192.0.2.1 443 0
+a [2001:db8::1]:443
+s Exit Fast Running Stable Valid
+w Bandwidth=95000 Measured=95000
+r broken notbase64! digest 2025-01-31 10:00:00 192.0.2.2 443 0
+s BadExit Exit Running
+";
+        let relays = RelayStatus::parse_all(document);
+        assert_eq!(relays.len(), 2);
+        assert_eq!(
+            relays[0].fingerprint,
+            "9695DFC35FFEB861329B9F1AB04C46397020CE31"
+        );
+        assert_eq!(relays[0].nickname, "moria1");
+        assert_eq!(relays[0].bandwidth, Some(20));
+        assert!(!relays[0].is_exit());
+
+        assert_eq!(relays[1].fingerprint, "AA".repeat(20));
+        assert_eq!(relays[1].bandwidth, Some(95000));
+        assert!(relays[1].is_exit());
+        assert!(relays[1].has_flag("Stable"));
+    }
+}
@@ -0,0 +1,282 @@
+//! Minimal DNS client for Tor's DNSPort (`TorServiceParam::dns_port`)
+//! The SOCKS port only carries TCP streams, and its RESOLVE extension (`resolve_via_socks`)
+//! needs a SOCKS aware client and one connection per name. The DNSPort takes plain DNS
+//! queries over UDP and has an exit relay resolve them, so anything that speaks DNS can be
+//! pointed at it. Tor only answers A, AAAA and PTR queries there, no MX, TXT, ...
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::TorErrors;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+/// Largest reply we accept, Tor keeps its answers within classic UDP DNS size
+const MAX_MESSAGE: usize = 512;
+
+/// Resolves `hostname` to its IPv4 addresses through the DNS server at `dns_server`, falling
+/// back to IPv6 ones when there are none
+pub fn resolve(
+    dns_server: SocketAddr,
+    hostname: &str,
+    timeout: Duration,
+) -> Result<Vec<IpAddr>, TorErrors> {
+    let addresses = query(dns_server, hostname, TYPE_A, timeout)?;
+    if !addresses.is_empty() {
+        return Ok(addresses);
+    }
+    query(dns_server, hostname, TYPE_AAAA, timeout)
+}
+
+/// SOCKS command of Tor's RESOLVE extension
+const SOCKS_RESOLVE: u8 = 0xf0;
+
+/// Resolves `hostname` with Tor's SOCKS RESOLVE extension on the SOCKS port at `socks_proxy`
+/// The exit of a fresh stream resolves the name, nothing is connected. Tor answers with a
+/// single address
+pub fn resolve_via_socks(
+    socks_proxy: SocketAddr,
+    hostname: &str,
+    timeout: Duration,
+) -> Result<IpAddr, TorErrors> {
+    let name = hostname.trim().trim_end_matches('.');
+    if name.is_empty() || name.len() > 255 {
+        return Err(dns_error(format!("Invalid hostname: {:?}", hostname)));
+    }
+    crate::killswitch::check(&socks_proxy.to_string())?;
+    let mut stream = TcpStream::connect_timeout(&socks_proxy, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    // No authentication
+    stream.write_all(&[5, 1, 0])?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice)?;
+    if choice != [5, 0] {
+        return Err(dns_error(String::from(
+            "SOCKS proxy refused unauthenticated RESOLVE",
+        )));
+    }
+
+    let mut request = vec![5, SOCKS_RESOLVE, 0, 3, name.len() as u8];
+    request.extend_from_slice(name.as_bytes());
+    request.extend_from_slice(&[0, 0]);
+    stream.write_all(&request)?;
+
+    let mut head = [0u8; 4];
+    stream.read_exact(&mut head)?;
+    if head[1] != 0 {
+        return Err(dns_error(format!(
+            "Resolving {} over SOCKS failed with reply {}",
+            hostname, head[1]
+        )));
+    }
+    let address = match head[3] {
+        1 => {
+            let mut ip = [0u8; 4];
+            stream.read_exact(&mut ip)?;
+            IpAddr::from(ip)
+        }
+        4 => {
+            let mut ip = [0u8; 16];
+            stream.read_exact(&mut ip)?;
+            IpAddr::from(ip)
+        }
+        atyp => {
+            return Err(dns_error(format!(
+                "Unexpected address type {} in SOCKS RESOLVE reply",
+                atyp
+            )));
+        }
+    };
+    Ok(address)
+}
+
+fn query(
+    dns_server: SocketAddr,
+    hostname: &str,
+    qtype: u16,
+    timeout: Duration,
+) -> Result<Vec<IpAddr>, TorErrors> {
+    let bind: SocketAddr = match dns_server {
+        SocketAddr::V4(_) => (Ipv4Addr::LOCALHOST, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::LOCALHOST, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind)?;
+    socket.set_read_timeout(Some(timeout))?;
+    socket.connect(dns_server)?;
+
+    let id = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u16)
+        .unwrap_or_default();
+    socket.send(&build_query(id, hostname, qtype)?)?;
+    let mut reply = [0u8; MAX_MESSAGE];
+    loop {
+        let len = socket.recv(&mut reply)?;
+        // Late answers to an earlier query on the same port are skipped
+        if len >= 2 && u16::from_be_bytes([reply[0], reply[1]]) == id {
+            return parse_reply(&reply[..len], hostname);
+        }
+    }
+}
+
+/// Query message for `hostname`, recursion desired
+fn build_query(id: u16, hostname: &str, qtype: u16) -> Result<Vec<u8>, TorErrors> {
+    let name = hostname.trim().trim_end_matches('.');
+    if name.is_empty() || name.len() > 253 {
+        return Err(dns_error(format!("Invalid hostname: {:?}", hostname)));
+    }
+    let mut message = Vec::with_capacity(18 + name.len());
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&[0x01, 0x00]); // RD
+    message.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]); // 1 question
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(dns_error(format!("Invalid hostname: {:?}", hostname)));
+        }
+        message.push(label.len() as u8);
+        message.extend_from_slice(label.as_bytes());
+    }
+    message.push(0);
+    message.extend_from_slice(&qtype.to_be_bytes());
+    message.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(message)
+}
+
+/// Addresses in the answer section of `reply`
+fn parse_reply(reply: &[u8], hostname: &str) -> Result<Vec<IpAddr>, TorErrors> {
+    let truncated = || dns_error(String::from("Truncated DNS reply"));
+    if reply.len() < 12 {
+        return Err(truncated());
+    }
+    match reply[3] & 0x0f {
+        0 => {}
+        3 => return Err(dns_error(format!("{} does not exist", hostname))),
+        rcode => {
+            return Err(dns_error(format!(
+                "Resolving {} failed with DNS error {}",
+                hostname, rcode
+            )));
+        }
+    }
+    let questions = u16::from_be_bytes([reply[4], reply[5]]);
+    let answers = u16::from_be_bytes([reply[6], reply[7]]);
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(reply, pos).ok_or_else(truncated)? + 4;
+    }
+    let mut addresses = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(reply, pos).ok_or_else(truncated)?;
+        let record = reply.get(pos..pos + 10).ok_or_else(truncated)?;
+        let rtype = u16::from_be_bytes([record[0], record[1]]);
+        let len = u16::from_be_bytes([record[8], record[9]]) as usize;
+        let data = reply.get(pos + 10..pos + 10 + len).ok_or_else(truncated)?;
+        match (rtype, len) {
+            (TYPE_A, 4) => addresses.push(IpAddr::from(<[u8; 4]>::try_from(data).unwrap())),
+            (TYPE_AAAA, 16) => addresses.push(IpAddr::from(<[u8; 16]>::try_from(data).unwrap())),
+            // CNAMEs and anything else
+            _ => {}
+        }
+        pos += 10 + len;
+    }
+    Ok(addresses)
+}
+
+/// Position right after the (possibly compressed) name starting at `pos`
+fn skip_name(message: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(pos)?;
+        match len {
+            0 => return Some(pos + 1),
+            // Compression pointer, the name ends here
+            len if len & 0xc0 == 0xc0 => return Some(pos + 2),
+            len => pos += 1 + len as usize,
+        }
+    }
+}
+
+fn dns_error(message: String) -> TorErrors {
+    TorErrors::DnsError(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_through_dns_server() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap();
+        let responder = std::thread::spawn(move || {
+            let mut query = [0u8; MAX_MESSAGE];
+            let (len, client) = server.recv_from(&mut query).unwrap();
+            let mut reply = query[..len].to_vec();
+            reply[2] |= 0x80; // QR
+            reply[7] = 2; // 2 answers
+            // CNAME first, then an A record, both pointing back at the question name
+            reply.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xc0, 12]);
+            reply.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 7]);
+            server.send_to(&reply, client).unwrap();
+        });
+
+        let addresses = resolve(address, "example.com", Duration::from_secs(5)).unwrap();
+        responder.join().unwrap();
+        assert_eq!(addresses, vec![IpAddr::from([192, 0, 2, 7])]);
+    }
+
+    #[test]
+    fn resolves_through_socks_port() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let proxy = std::thread::spawn(move || {
+            for reply in [
+                vec![5, 0, 0, 1, 192, 0, 2, 9, 0, 0],
+                vec![5, 4, 0, 1, 0, 0, 0, 0, 0, 0],
+            ] {
+                let (mut client, _) = listener.accept().unwrap();
+                let mut greeting = [0u8; 3];
+                client.read_exact(&mut greeting).unwrap();
+                client.write_all(&[5, 0]).unwrap();
+                let mut head = [0u8; 5];
+                client.read_exact(&mut head).unwrap();
+                assert_eq!(&head[..4], &[5, SOCKS_RESOLVE, 0, 3]);
+                let mut name = vec![0u8; head[4] as usize + 2];
+                client.read_exact(&mut name).unwrap();
+                assert_eq!(&name[..name.len() - 2], b"example.com");
+                client.write_all(&reply).unwrap();
+            }
+        });
+
+        let timeout = Duration::from_secs(5);
+        assert_eq!(
+            resolve_via_socks(address, "example.com.", timeout).unwrap(),
+            IpAddr::from([192, 0, 2, 9])
+        );
+        assert!(matches!(
+            resolve_via_socks(address, "example.com", timeout),
+            Err(TorErrors::DnsError(_))
+        ));
+        proxy.join().unwrap();
+        assert!(resolve_via_socks(address, " ", timeout).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_names_and_errors() {
+        assert!(build_query(1, "", TYPE_A).is_err());
+        assert!(build_query(1, "a..b", TYPE_A).is_err());
+        assert!(build_query(1, &"a".repeat(64), TYPE_A).is_err());
+        assert_eq!(build_query(1, "ab.c.", TYPE_A).unwrap().len(), 12 + 6 + 4);
+
+        let mut nxdomain = build_query(7, "missing.example", TYPE_A).unwrap();
+        nxdomain[2] |= 0x80;
+        nxdomain[3] |= 3;
+        assert!(matches!(
+            parse_reply(&nxdomain, "missing.example"),
+            Err(TorErrors::DnsError(_))
+        ));
+        assert!(parse_reply(&[0, 1], "x").is_err());
+    }
+}
@@ -1,3 +1,9 @@
+mod request_buffer;
+
+pub use request_buffer::{
+    DEFAULT_MAX_REQUEST_BODY, HttpRequestBuffer, MAX_REQUEST_HEADERS, RequestBufferError,
+};
+
 use crate::tcp_stream::DataObserver;
 use crate::{ensure_runtime, TorErrors};
 use base64::engine::general_purpose;
@@ -59,61 +65,42 @@ impl HiddenServiceHandler {
                     info!("New client connection established from addr {:?}", addr);
                     let (mut rx, mut tx) = stream.split();
 
-                    let mut buffer = vec![0; 4096];
-                    let mut position = 0;
+                    let mut request = HttpRequestBuffer::new();
+                    let mut chunk = vec![0; 4096];
 
                     trace!("--> awaiting reading to end.");
                     loop {
-                        // drop header after checking http request is complete
-                        // TODO: httpparse api means it has to be dropped to avoid RwLock gymnastics on the buffer
-                        // Maybe it's more efficient to actually wrap buffer with an RwLock vs this drop and reparse ?
-                        {
-                            let mut headers = [httparse::EMPTY_HEADER; 16];
-                            let mut req = Request::new(&mut headers);
-                            match req.parse(&buffer) {
-                                Ok(status) => {
-                                    status.is_complete();
-                                    debug!("<-- parsed request!");
-                                    break;
-                                }
-                                Err(e) => {
-                                    match e {
-                                        // http parse expects new line to be read before sending it the buffer
-                                        // so ignore this error here
-                                        httparse::Error::Token => { trace!("got http parse token error, ignoring.") }
-                                        _ => {
-                                            error!("http Parsing error {:#?}", e);
-                                            break;
-                                        }
-                                    }
-                                }
-                            }
-                        }
-
-                        // resize buffer as needed
-                        if position == buffer.len() {
-                            buffer.resize(position * 2, 0)
-                        }
-                        // read
-                        let read_size = rx.read(&mut buffer[position..]).await.unwrap();
-                        trace!("Read buffer size {} call number {}", read_size, position);
-                        // break on some data but 0 data read (peer terminated connection)
-                        if position >= 1 && read_size == 0 {
+                        let read_size = rx.read(&mut chunk).await.unwrap();
+                        trace!("Read buffer size {}", read_size);
+                        // peer terminated connection
+                        if read_size == 0 {
                             warn!("<- peer terminated connection detected");
                             break;
                         }
-                        position += read_size;
+                        // only the new bytes are scanned, headers are parsed once
+                        match request.push(&chunk[..read_size]) {
+                            Ok(true) => {
+                                debug!("<-- parsed request!");
+                                break;
+                            }
+                            Ok(false) => {}
+                            Err(e) => {
+                                error!("http Parsing error {:#?}", e);
+                                break;
+                            }
+                        }
                     }
                     trace!("-- awaiting reading to end.");
 
                     trace!("-> parse body");
-                    let mut headers = [httparse::EMPTY_HEADER; 16];
+                    let buffer = request.as_bytes();
+                    let mut headers = [httparse::EMPTY_HEADER; MAX_REQUEST_HEADERS];
                     let mut req = Request::new(&mut headers);
-                    let status = req.parse(&buffer).unwrap();
+                    let status = req.parse(buffer).unwrap();
                     let body = {
                         if status.is_complete() {
                             let start_index = status.unwrap();
-                            let end_index = position;
+                            let end_index = buffer.len();
                             trace!("<- parse body from {} to {}", start_index, end_index);
                             let mut buf = String::new();
                             general_purpose::STANDARD.encode_string(&buffer[start_index..end_index], &mut buf);
@@ -180,6 +167,7 @@ mod tests {
             socks_port: Some(socks_port),
             data_dir: String::from("/tmp/sifir_rs_sdk"),
             bootstrap_timeout_ms: Some(45000),
+            ..Default::default()
         }
         .try_into()
         .unwrap();
@@ -187,7 +175,7 @@ mod tests {
             .create_hidden_service(TorHiddenServiceParam {
                 to_port: 20000,
                 hs_port: 20011,
-                secret_key: None,
+                ..Default::default()
             })
             .unwrap();
         assert!(service_key.onion_url.to_string().contains(".onion"));
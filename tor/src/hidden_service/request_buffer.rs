@@ -0,0 +1,291 @@
+use std::fmt;
+
+/// Most headers parsed from an incoming request
+pub const MAX_REQUEST_HEADERS: usize = 64;
+
+/// Largest request body `HttpRequestBuffer::new` accepts
+pub const DEFAULT_MAX_REQUEST_BODY: usize = 16 * 1024 * 1024;
+
+/// Longest chunk size line (size and extensions) accepted in a chunked body
+const MAX_CHUNK_LINE: usize = 1024;
+
+/// Why a request can't be collected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestBufferError {
+    /// Request line or headers that don't parse
+    Http(httparse::Error),
+    /// Chunk size line that isn't a hex number
+    InvalidChunkSize,
+    /// Content-Length or chunks adding up to more than the buffer's body limit
+    BodyTooLarge,
+}
+
+impl From<httparse::Error> for RequestBufferError {
+    fn from(e: httparse::Error) -> Self {
+        RequestBufferError::Http(e)
+    }
+}
+
+impl fmt::Display for RequestBufferError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestBufferError::Http(e) => write!(f, "{}", e),
+            RequestBufferError::InvalidChunkSize => f.write_str("invalid chunk size"),
+            RequestBufferError::BodyTooLarge => f.write_str("request body too large"),
+        }
+    }
+}
+
+/// How the end of the request body is found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BodyLength {
+    Fixed(usize),
+    /// Offset of the next chunk size line and data bytes of the chunks before it
+    Chunked {
+        next: usize,
+        data: usize,
+    },
+}
+
+/// Collects a single HTTP request from successive reads
+/// Only bytes that haven't been looked at yet are scanned on each push, the headers are
+/// parsed once when their end is found, so large requests arriving in many small reads
+/// (common over Tor) cost linear time
+#[derive(Debug)]
+pub struct HttpRequestBuffer {
+    buffer: Vec<u8>,
+    max_body: usize,
+    /// Bytes already searched for the end of the headers
+    scanned: usize,
+    header_len: Option<usize>,
+    body: Option<BodyLength>,
+    complete: bool,
+}
+
+impl Default for HttpRequestBuffer {
+    fn default() -> Self {
+        HttpRequestBuffer::with_max_body(DEFAULT_MAX_REQUEST_BODY)
+    }
+}
+
+impl HttpRequestBuffer {
+    pub fn new() -> HttpRequestBuffer {
+        HttpRequestBuffer::default()
+    }
+
+    /// Buffer refusing request bodies larger than `max_body` bytes
+    pub fn with_max_body(max_body: usize) -> HttpRequestBuffer {
+        HttpRequestBuffer {
+            buffer: Vec::new(),
+            max_body,
+            scanned: 0,
+            header_len: None,
+            body: None,
+            complete: false,
+        }
+    }
+
+    /// Appends newly read bytes, returns true once headers and body are complete
+    /// Fails on malformed headers or chunk sizes and on bodies over the limit, the request
+    /// should then be dropped
+    pub fn push(&mut self, data: &[u8]) -> Result<bool, RequestBufferError> {
+        self.buffer.extend_from_slice(data);
+        if self.header_len.is_none() {
+            // The terminator may straddle the previous push
+            let from = self.scanned.saturating_sub(3);
+            match find(&self.buffer[from..], b"\r\n\r\n") {
+                Some(i) => {
+                    let header_len = from + i + 4;
+                    self.header_len = Some(header_len);
+                    self.body = Some(self.body_length(header_len)?);
+                }
+                None => {
+                    self.scanned = self.buffer.len();
+                    return Ok(false);
+                }
+            }
+        }
+        self.complete = self.body_complete()?;
+        Ok(self.complete)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// Length of the request line and headers, once they have been received
+    pub fn header_len(&self) -> Option<usize> {
+        self.header_len
+    }
+
+    /// Everything received so far
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buffer
+    }
+
+    fn body_length(&self, header_len: usize) -> Result<BodyLength, RequestBufferError> {
+        let mut headers = [httparse::EMPTY_HEADER; MAX_REQUEST_HEADERS];
+        let mut req = httparse::Request::new(&mut headers);
+        req.parse(&self.buffer[..header_len])?;
+        let mut body = BodyLength::Fixed(0);
+        for header in req.headers.iter() {
+            let value = String::from_utf8_lossy(header.value);
+            if header.name.eq_ignore_ascii_case("transfer-encoding")
+                && value.to_ascii_lowercase().contains("chunked")
+            {
+                return Ok(BodyLength::Chunked {
+                    next: header_len,
+                    data: 0,
+                });
+            }
+            if header.name.eq_ignore_ascii_case("content-length") {
+                let len = value
+                    .trim()
+                    .parse::<usize>()
+                    .map_err(|_| httparse::Error::HeaderValue)?;
+                if len > self.max_body {
+                    return Err(RequestBufferError::BodyTooLarge);
+                }
+                body = BodyLength::Fixed(len);
+            }
+        }
+        Ok(body)
+    }
+
+    fn body_complete(&mut self) -> Result<bool, RequestBufferError> {
+        let header_len = self.header_len.unwrap_or(0);
+        match self.body {
+            Some(BodyLength::Fixed(len)) => Ok(self.buffer.len() >= header_len + len),
+            Some(BodyLength::Chunked { mut next, mut data }) => {
+                let complete = loop {
+                    let Some(line_len) = find(&self.buffer[next..], b"\r\n") else {
+                        if self.buffer.len() - next > MAX_CHUNK_LINE {
+                            return Err(RequestBufferError::InvalidChunkSize);
+                        }
+                        break false;
+                    };
+                    let size = parse_chunk_size(&self.buffer[next..next + line_len])?;
+                    let data_start = next + line_len + 2;
+                    if size == 0 {
+                        // Last chunk, then optional trailers ending with an empty line
+                        let rest = &self.buffer[data_start..];
+                        break rest.starts_with(b"\r\n") || find(rest, b"\r\n\r\n").is_some();
+                    }
+                    let body_len = data
+                        .checked_add(size)
+                        .filter(|len| *len <= self.max_body)
+                        .ok_or(RequestBufferError::BodyTooLarge)?;
+                    let chunk_end = data_start
+                        .checked_add(size)
+                        .and_then(|end| end.checked_add(2))
+                        .ok_or(RequestBufferError::BodyTooLarge)?;
+                    if self.buffer.len() < chunk_end {
+                        break false;
+                    }
+                    next = chunk_end;
+                    data = body_len;
+                };
+                self.body = Some(BodyLength::Chunked { next, data });
+                Ok(complete)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+/// Size in a chunk size line ("1a;ext=value"), hex digits only
+fn parse_chunk_size(line: &[u8]) -> Result<usize, RequestBufferError> {
+    let line = String::from_utf8_lossy(line);
+    let size_hex = line.split(';').next().unwrap_or("").trim();
+    if size_hex.is_empty() || !size_hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(RequestBufferError::InvalidChunkSize);
+    }
+    usize::from_str_radix(size_hex, 16).map_err(|_| RequestBufferError::InvalidChunkSize)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn completes_fixed_length_request_split_anywhere() {
+        let raw = b"POST /data HTTP/1.1\r\nHost: a.onion\r\nContent-Length: 5\r\n\r\nhello";
+        for split in 1..raw.len() {
+            let mut request = HttpRequestBuffer::new();
+            assert!(!request.push(&raw[..split]).unwrap());
+            assert!(request.push(&raw[split..]).unwrap());
+            assert_eq!(request.header_len(), Some(raw.len() - 5));
+        }
+    }
+
+    #[test]
+    fn completes_chunked_request_byte_by_byte() {
+        let raw = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n10\r\n\r\n0123456789abcd\r\n0\r\n\r\n";
+        let mut request = HttpRequestBuffer::new();
+        for (i, byte) in raw.iter().enumerate() {
+            let complete = request.push(&[*byte]).unwrap();
+            assert_eq!(complete, i == raw.len() - 1);
+        }
+        assert_eq!(request.as_bytes(), &raw[..]);
+    }
+
+    #[test]
+    fn rejects_bad_chunk_sizes() {
+        let head = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        for body in [
+            &b"zz\r\nhello\r\n0\r\n\r\n"[..],
+            b"\r\nhello\r\n",
+            b"+5\r\nhello\r\n",
+            b"ffffffffffffffffff\r\n",
+        ] {
+            let mut request = HttpRequestBuffer::new();
+            assert_eq!(
+                request.push(&[&head[..], body].concat()),
+                Err(RequestBufferError::InvalidChunkSize),
+                "{:?}",
+                String::from_utf8_lossy(body)
+            );
+        }
+    }
+
+    #[test]
+    fn enforces_the_body_limit() {
+        let head = b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let mut request = HttpRequestBuffer::new();
+        assert_eq!(
+            request.push(&[&head[..], b"ffffffffffffffff\r\n"].concat()),
+            Err(RequestBufferError::BodyTooLarge)
+        );
+
+        let mut request = HttpRequestBuffer::with_max_body(8);
+        assert!(
+            !request
+                .push(&[&head[..], b"5\r\nhello\r\n"].concat())
+                .unwrap()
+        );
+        assert_eq!(
+            request.push(b"4\r\n"),
+            Err(RequestBufferError::BodyTooLarge)
+        );
+
+        let mut request = HttpRequestBuffer::with_max_body(8);
+        assert_eq!(
+            request.push(b"POST / HTTP/1.1\r\nContent-Length: 9\r\n\r\n"),
+            Err(RequestBufferError::BodyTooLarge)
+        );
+    }
+
+    #[test]
+    fn request_without_body_completes_at_headers() {
+        let mut request = HttpRequestBuffer::new();
+        assert!(
+            request
+                .push(b"GET / HTTP/1.1\r\nHost: a.onion\r\n\r\n")
+                .unwrap()
+        );
+    }
+}
@@ -0,0 +1,399 @@
+//! Per request circuit notifications (`HttpRequestParams::circuit_callback`)
+//! The request gets a SOCKS password of its own. One control connection per Tor service
+//! listens to STREAM events for all watched requests, the ones carrying a request's password
+//! tell which circuit Tor attached its stream to, or why the stream failed
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use logger::log::{debug, warn};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+
+use super::HttpRequestParams;
+use crate::TorErrors;
+use crate::control::{CircuitInfo, ControlConn};
+
+/// How often the listening thread checks whether it should stop
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// What happened to the circuit carrying a request
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum CircuitEvent {
+    /// The request's stream was attached to this built circuit. Fired again with the new
+    /// circuit when Tor retries the stream on another one
+    Built(CircuitInfo),
+    /// The stream failed or was detached from its circuit, `reason` is Tor's (e.g. TIMEOUT,
+    /// DESTROY, RESOLVEFAILED). A detached stream may still be retried on another circuit
+    Failed {
+        circuit_id: Option<String>,
+        reason: String,
+    },
+}
+
+/// Called with the `CircuitEvent`s of one request, from a thread of its own
+#[derive(Clone)]
+pub struct CircuitCallback(Arc<Mutex<dyn FnMut(CircuitEvent) + Send>>);
+
+impl CircuitCallback {
+    pub fn new<F>(callback: F) -> CircuitCallback
+    where
+        F: FnMut(CircuitEvent) + Send + 'static,
+    {
+        CircuitCallback(Arc::new(Mutex::new(callback)))
+    }
+
+    fn report(&self, event: CircuitEvent) {
+        (self.0.lock().unwrap())(event);
+    }
+}
+
+impl fmt::Debug for CircuitCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CircuitCallback")
+    }
+}
+
+/// Control ports of the bootstrapped services by SOCKS port
+static CONTROL_PORTS: OnceCell<Mutex<HashMap<u16, String>>> = OnceCell::new();
+
+fn control_ports() -> &'static Mutex<HashMap<u16, String>> {
+    CONTROL_PORTS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Called once a service finished bootstrapping
+pub(crate) fn register_control_port(socks_port: u16, control_port: &str) {
+    control_ports()
+        .lock()
+        .unwrap()
+        .insert(socks_port, control_port.to_string());
+}
+
+/// Called when a service shuts down, its listener stops as well
+pub(crate) fn unregister_control_port(socks_port: u16) {
+    let Some(control_port) = control_ports().lock().unwrap().remove(&socks_port) else {
+        return;
+    };
+    if let Some(listener) = listeners().lock().unwrap().remove(&control_port) {
+        listener.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+/// How long a listener without watched requests keeps its connection before stopping
+const LISTENER_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// One STREAM event subscription per control port, shared by the watched requests and
+/// dispatched by their SOCKS password
+struct Listener {
+    watchers: Mutex<HashMap<String, Watcher>>,
+    stop: AtomicBool,
+}
+
+struct Watcher {
+    callback: CircuitCallback,
+    /// Circuit last reported as built
+    current: Option<String>,
+}
+
+/// Running listeners by control port
+static LISTENERS: OnceCell<Mutex<HashMap<String, Arc<Listener>>>> = OnceCell::new();
+
+fn listeners() -> &'static Mutex<HashMap<String, Arc<Listener>>> {
+    LISTENERS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Stops reporting to the request's callback when dropped, keep it until the request is done
+pub(crate) struct CircuitWatch {
+    listener: Arc<Listener>,
+    password: String,
+}
+
+impl Drop for CircuitWatch {
+    fn drop(&mut self) {
+        self.listener
+            .watchers
+            .lock()
+            .unwrap()
+            .remove(&self.password);
+    }
+}
+
+/// Gives `params` a SOCKS password of its own and reports its stream's events to `callback`
+/// `None` (and a warning) when `socks_proxy` isn't the SOCKS port of a service of this
+/// process or its control port can't be reached, the request then runs without callback
+pub(crate) fn watch(
+    params: &mut HttpRequestParams,
+    socks_proxy: &str,
+    callback: CircuitCallback,
+) -> Option<CircuitWatch> {
+    let control_port = socks_proxy
+        .rsplit_once(':')
+        .and_then(|(_, port)| port.parse::<u16>().ok())
+        .and_then(|port| control_ports().lock().unwrap().get(&port).cloned());
+    let Some(control_port) = control_port else {
+        warn!(
+            "No Tor service of this process on {}, circuit_callback ignored",
+            socks_proxy
+        );
+        return None;
+    };
+
+    // Held until the watcher is added, so the listener can't stop idle in between
+    let mut running = listeners().lock().unwrap();
+    let listener = match running.get(&control_port) {
+        Some(listener) => listener.clone(),
+        None => match start_listener(&control_port) {
+            Ok(listener) => {
+                running.insert(control_port, listener.clone());
+                listener
+            }
+            Err(e) => {
+                warn!(
+                    "Can't listen to stream events, circuit_callback ignored: {:?}",
+                    e
+                );
+                return None;
+            }
+        },
+    };
+
+    // Stricter than the isolation asked for, the caller's token keeps its effect
+    let password = unique_password(params.isolation_token.as_deref());
+    params.isolation_token = Some(password.clone());
+    listener.watchers.lock().unwrap().insert(
+        password.clone(),
+        Watcher {
+            callback,
+            current: None,
+        },
+    );
+    Some(CircuitWatch { listener, password })
+}
+
+/// Subscribes to STREAM events on `control_port` and dispatches them from a thread of their
+/// own, until the connection fails, the service goes away or no request was watched for
+/// `LISTENER_IDLE_TIMEOUT`
+fn start_listener(control_port: &str) -> Result<Arc<Listener>, TorErrors> {
+    let mut ctl = ControlConn::connect(control_port)?;
+    ctl.command("SETEVENTS STREAM")?;
+    let listener = Arc::new(Listener {
+        watchers: Mutex::new(HashMap::new()),
+        stop: AtomicBool::new(false),
+    });
+    let shared = listener.clone();
+    let control_port = control_port.to_string();
+    thread::spawn(move || {
+        let mut idle_since = Instant::now();
+        while !shared.stop.load(Ordering::SeqCst) {
+            if !shared.watchers.lock().unwrap().is_empty() {
+                idle_since = Instant::now();
+            } else if idle_since.elapsed() >= LISTENER_IDLE_TIMEOUT
+                && stop_if_idle(&control_port, &shared)
+            {
+                break;
+            }
+            let event = match ctl.next_event_within(POLL_INTERVAL) {
+                Ok(Some(event)) => event,
+                Ok(None) => continue,
+                Err(e) => {
+                    debug!("Stopped listening to stream events: {:?}", e);
+                    remove_listener(&mut listeners().lock().unwrap(), &control_port, &shared);
+                    break;
+                }
+            };
+            if let Some(stream) = event
+                .lines
+                .first()
+                .and_then(|line| StreamEvent::parse(line))
+            {
+                dispatch(&mut ctl, &shared, stream);
+            }
+        }
+    });
+    Ok(listener)
+}
+
+/// Reports `stream` to the request it belongs to, if it is watched
+fn dispatch(ctl: &mut ControlConn, listener: &Listener, stream: StreamEvent) {
+    let Some(password) = stream.socks_password else {
+        return;
+    };
+    // The callback is cloned out so it runs without the watchers lock
+    let (callback, failed) = {
+        let mut watchers = listener.watchers.lock().unwrap();
+        let Some(watcher) = watchers.get_mut(&password) else {
+            return;
+        };
+        let failed = match stream.status.as_str() {
+            "SENTCONNECT" | "SENTRESOLVE" | "SUCCEEDED"
+                if stream.circuit_id != "0"
+                    && watcher.current.as_deref() != Some(stream.circuit_id.as_str()) =>
+            {
+                watcher.current = Some(stream.circuit_id.clone());
+                false
+            }
+            "FAILED" | "DETACHED" => {
+                watcher.current = None;
+                true
+            }
+            _ => return,
+        };
+        (watcher.callback.clone(), failed)
+    };
+    let event = if failed {
+        CircuitEvent::Failed {
+            circuit_id: (stream.circuit_id != "0").then_some(stream.circuit_id),
+            reason: stream.reason.unwrap_or_else(|| String::from("UNKNOWN")),
+        }
+    } else {
+        CircuitEvent::Built(built_circuit(ctl, &stream.circuit_id))
+    };
+    callback.report(event);
+}
+
+/// Removes `listener` from the running ones when it still has no watcher, under the same
+/// lock `watch` adds watchers with
+fn stop_if_idle(control_port: &str, listener: &Arc<Listener>) -> bool {
+    let mut running = listeners().lock().unwrap();
+    if !listener.watchers.lock().unwrap().is_empty() {
+        return false;
+    }
+    remove_listener(&mut running, control_port, listener);
+    true
+}
+
+/// Removes `listener` unless another one already took its place
+fn remove_listener(
+    running: &mut HashMap<String, Arc<Listener>>,
+    control_port: &str,
+    listener: &Arc<Listener>,
+) {
+    if running
+        .get(control_port)
+        .is_some_and(|current| Arc::ptr_eq(current, listener))
+    {
+        running.remove(control_port);
+    }
+}
+
+/// `token` with a suffix no other request has
+fn unique_password(token: Option<&str>) -> String {
+    static WATCH_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    let id = WATCH_COUNTER.fetch_add(1, Ordering::SeqCst);
+    let unique = format!("circuit-watch-{}-{}", nanos, id);
+    match token {
+        Some(token) => format!("{}/{}", token, unique),
+        None => unique,
+    }
+}
+
+/// Circuit `circuit_id` from `GETINFO circuit-status`, only its id when it can't be read
+fn built_circuit(ctl: &mut ControlConn, circuit_id: &str) -> CircuitInfo {
+    ctl.get_info("circuit-status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .filter_map(CircuitInfo::parse)
+                .find(|circuit| circuit.id == circuit_id)
+        })
+        .unwrap_or_else(|| CircuitInfo {
+            id: circuit_id.to_string(),
+            status: String::from("BUILT"),
+            path: Vec::new(),
+            build_flags: Vec::new(),
+            purpose: None,
+            time_created: None,
+        })
+}
+
+/// "STREAM id status circuit_id target [KEY=VALUE ...]"
+#[derive(Debug, PartialEq, Eq)]
+struct StreamEvent {
+    status: String,
+    /// "0" while the stream isn't attached
+    circuit_id: String,
+    reason: Option<String>,
+    socks_password: Option<String>,
+}
+
+impl StreamEvent {
+    fn parse(line: &str) -> Option<StreamEvent> {
+        let mut tokens = line.split_whitespace();
+        if tokens.next()? != "STREAM" {
+            return None;
+        }
+        let _id = tokens.next()?;
+        let status = tokens.next()?.to_string();
+        let circuit_id = tokens.next()?.to_string();
+        let reason = line
+            .split_whitespace()
+            .find_map(|t| t.strip_prefix("REASON="))
+            .map(String::from);
+        Some(StreamEvent {
+            status,
+            circuit_id,
+            reason,
+            socks_password: quoted_field(line, "SOCKS_PASSWORD"),
+        })
+    }
+}
+
+/// Value of `key="..."` in an event line, with its backslash escapes resolved
+fn quoted_field(line: &str, key: &str) -> Option<String> {
+    let start = line.find(&format!(" {}=\"", key))? + key.len() + 3;
+    let mut value = String::new();
+    let mut chars = line[start..].chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => value.push(chars.next()?),
+            '"' => return Some(value),
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_stream_events() {
+        let line = "STREAM 52 SENTCONNECT 7 example.onion:80 SOURCE_ADDR=127.0.0.1:50000 \
+                    PURPOSE=USER SOCKS_USERNAME=\"shared\" \
+                    SOCKS_PASSWORD=\"my token/circuit-watch-1-0\" CLIENT_PROTOCOL=SOCKS5";
+        assert_eq!(
+            StreamEvent::parse(line),
+            Some(StreamEvent {
+                status: String::from("SENTCONNECT"),
+                circuit_id: String::from("7"),
+                reason: None,
+                socks_password: Some(String::from("my token/circuit-watch-1-0")),
+            })
+        );
+
+        let failed = StreamEvent::parse(
+            "STREAM 53 FAILED 0 example.onion:80 REASON=TIMEOUT SOCKS_PASSWORD=\"a\\\"b\"",
+        )
+        .unwrap();
+        assert_eq!(failed.reason.as_deref(), Some("TIMEOUT"));
+        assert_eq!(failed.socks_password.as_deref(), Some("a\"b"));
+        assert_eq!(StreamEvent::parse("CIRC 7 BUILT"), None);
+    }
+
+    #[test]
+    fn keeps_the_isolation_token() {
+        let password = unique_password(Some("wallet-1"));
+        assert!(password.starts_with("wallet-1/circuit-watch-"));
+        assert_ne!(unique_password(None), unique_password(None));
+    }
+}
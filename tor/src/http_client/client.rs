@@ -0,0 +1,188 @@
+//! `TorHttpClient`: the SOCKS proxy and `HttpClientConfig` held once instead of passed to
+//! every request function
+//!
+//! ```no_run
+//! # async fn example(service: &tor::OwnedTorService) -> Result<(), tor::TorErrors> {
+//! use tor::http_client::TorHttpClient;
+//!
+//! let client = TorHttpClient::from_service(service);
+//! let response = client.get("https://check.torproject.org/api/ip").await?;
+//! println!("{} {}", response.status_code, response.body);
+//!
+//! let response = client
+//!     .post("http://example.onion/api", r#"{"ping": true}"#)
+//!     .await?
+//!     .error_for_status()?;
+//! # Ok(())
+//! # }
+//! ```
+use serde_json::Value;
+
+use super::{
+    HttpClientConfig, HttpMethod, HttpRequestParams, HttpResponse, RawHttpResponse,
+    make_http_request_json_value_async, make_http_request_with_config_async,
+    make_raw_http_request_async, pipeline,
+};
+use crate::{OwnedTorService, TorErrors, runtime_handle};
+
+/// HTTP client sending every request through one Tor SOCKS proxy, running the interceptors of
+/// its `HttpClientConfig` around each of them. Cheap to clone
+/// Connections come from the same process wide pool as the free functions (`pool_stats`,
+/// `clear_pool`), so clients for the same proxy share them
+#[derive(Clone)]
+pub struct TorHttpClient {
+    socks_proxy: String,
+    config: HttpClientConfig,
+}
+
+impl TorHttpClient {
+    /// Client for the SOCKS proxy at `socks_proxy` (host:port)
+    ///
+    /// ```no_run
+    /// use std::sync::Arc;
+    /// use tor::http_client::{HttpClientConfig, RequestInterceptor, TorHttpClient};
+    ///
+    /// struct Logger;
+    /// impl RequestInterceptor for Logger {}
+    ///
+    /// let mut config = HttpClientConfig::new();
+    /// config.add_interceptor(Arc::new(Logger));
+    /// let client = TorHttpClient::new("127.0.0.1:9050").with_config(config);
+    /// let response = client.send_blocking(Default::default());
+    /// ```
+    pub fn new(socks_proxy: impl Into<String>) -> TorHttpClient {
+        TorHttpClient {
+            socks_proxy: socks_proxy.into(),
+            config: HttpClientConfig::new(),
+        }
+    }
+
+    /// Client for the SOCKS port of a running service
+    pub fn from_service(service: &OwnedTorService) -> TorHttpClient {
+        TorHttpClient::new(format!("127.0.0.1:{}", service.socks_port))
+    }
+
+    /// Replaces the config, e.g. to add interceptors
+    pub fn with_config(mut self, config: HttpClientConfig) -> TorHttpClient {
+        self.config = config;
+        self
+    }
+
+    pub fn config_mut(&mut self) -> &mut HttpClientConfig {
+        &mut self.config
+    }
+
+    pub fn socks_proxy(&self) -> &str {
+        &self.socks_proxy
+    }
+
+    /// Sends `params` like `make_http_request_with_config_async`
+    pub async fn send(&self, params: HttpRequestParams) -> Result<HttpResponse, TorErrors> {
+        make_http_request_with_config_async(params, self.socks_proxy.clone(), &self.config).await
+    }
+
+    /// Blocking `send`, must not be called from inside the async runtime
+    pub fn send_blocking(&self, params: HttpRequestParams) -> Result<HttpResponse, TorErrors> {
+        runtime_handle().block_on(self.send(params))
+    }
+
+    pub async fn get(&self, url: &str) -> Result<HttpResponse, TorErrors> {
+        self.send(request(HttpMethod::GET, url, None)).await
+    }
+
+    pub async fn head(&self, url: &str) -> Result<HttpResponse, TorErrors> {
+        self.send(request(HttpMethod::HEAD, url, None)).await
+    }
+
+    pub async fn delete(&self, url: &str) -> Result<HttpResponse, TorErrors> {
+        self.send(request(HttpMethod::DELETE, url, None)).await
+    }
+
+    pub async fn post(
+        &self,
+        url: &str,
+        body: impl Into<String>,
+    ) -> Result<HttpResponse, TorErrors> {
+        self.send(request(HttpMethod::POST, url, Some(body.into())))
+            .await
+    }
+
+    pub async fn put(&self, url: &str, body: impl Into<String>) -> Result<HttpResponse, TorErrors> {
+        self.send(request(HttpMethod::PUT, url, Some(body.into())))
+            .await
+    }
+
+    /// Sends `params` and parses the body like `make_http_request_json_value_async`
+    /// Interceptors only see the params, the response is never an `HttpResponse` here
+    pub async fn json_value(&self, mut params: HttpRequestParams) -> Result<Value, TorErrors> {
+        self.config.before(&mut params);
+        make_http_request_json_value_async(params, self.socks_proxy.clone()).await
+    }
+
+    /// Like `make_raw_http_request_async`, interceptors only see the params
+    pub async fn raw(&self, mut params: HttpRequestParams) -> Result<RawHttpResponse, TorErrors> {
+        self.config.before(&mut params);
+        make_raw_http_request_async(params, self.socks_proxy.clone()).await
+    }
+
+    /// Sends `requests` on one connection like `pipeline`, interceptors run around each
+    pub fn pipeline(
+        &self,
+        mut requests: Vec<HttpRequestParams>,
+    ) -> Result<Vec<HttpResponse>, TorErrors> {
+        for params in &mut requests {
+            self.config.before(params);
+        }
+        let responses = pipeline(requests, self.socks_proxy.clone())?;
+        for response in &responses {
+            self.config.after(response);
+        }
+        Ok(responses)
+    }
+}
+
+fn request(method: HttpMethod, url: &str, body: Option<String>) -> HttpRequestParams {
+    HttpRequestParams {
+        url: url.to_string(),
+        method,
+        body,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_client::{RequestInterceptor, StubTransport, TorTransport};
+    use std::sync::Arc;
+
+    struct AuthHeader;
+
+    impl RequestInterceptor for AuthHeader {
+        fn before(&self, params: &mut HttpRequestParams) {
+            params
+                .headers
+                .get_or_insert_with(Vec::new)
+                .push(("Authorization".into(), "Bearer token".into()));
+        }
+    }
+
+    #[test]
+    fn sends_requests_with_config() {
+        let transport = StubTransport::new();
+        transport.push_response("HTTP/1.1 201 Created\r\nContent-Length: 2\r\n\r\nok");
+        let mut client = TorHttpClient::new(transport.socks_proxy().unwrap());
+        client.config_mut().add_interceptor(Arc::new(AuthHeader));
+
+        let response = runtime_handle()
+            .block_on(client.post("http://example.onion/items", "{}"))
+            .unwrap();
+        assert_eq!(response.status_code, 201);
+        assert_eq!(response.body, "ok");
+
+        let sent = String::from_utf8(transport.requests().remove(0)).unwrap();
+        assert!(sent.starts_with("POST /items HTTP/1.1\r\n"));
+        assert!(sent.contains("authorization: Bearer token\r\n"));
+        assert!(sent.ends_with("\r\n\r\n{}"));
+    }
+}
@@ -0,0 +1,70 @@
+use std::io::{self, Write};
+
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+use super::HttpRequestParams;
+use crate::TorErrors;
+
+/// `body` gzipped when `compress_body` is set, `None` when it goes out as is
+/// Only in-memory bodies are compressed, and never when the caller already set a
+/// `Content-Encoding`, encoding twice would leave the server with garbage
+pub(crate) fn compressed_body(params: &HttpRequestParams) -> Result<Option<Vec<u8>>, TorErrors> {
+    if !params.compress_body.unwrap_or(false) {
+        return Ok(None);
+    }
+    if params.body_file.is_some() {
+        return Err(TorErrors::TcpStreamError(String::from(
+            "compress_body only applies to body, not body_file",
+        )));
+    }
+    let encoded = params
+        .headers
+        .iter()
+        .flatten()
+        .any(|(name, _)| name.trim().eq_ignore_ascii_case("content-encoding"));
+    if encoded {
+        return Err(TorErrors::TcpStreamError(String::from(
+            "compress_body can't be combined with a Content-Encoding header",
+        )));
+    }
+    match &params.body {
+        Some(body) => Ok(Some(gzip(body.as_bytes())?)),
+        None => Ok(None),
+    }
+}
+
+fn gzip(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn gzips_only_when_requested() {
+        let body = "{\"items\": [".to_string() + &"\"tor\",".repeat(200) + "\"end\"]}";
+        let mut params = HttpRequestParams {
+            body: Some(body.clone()),
+            ..Default::default()
+        };
+        assert_eq!(compressed_body(&params).unwrap(), None);
+
+        params.compress_body = Some(true);
+        let compressed = compressed_body(&params).unwrap().unwrap();
+        assert!(compressed.len() < body.len());
+        let mut decoded = String::new();
+        GzDecoder::new(compressed.as_slice())
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, body);
+
+        params.headers = Some(vec![("Content-Encoding".into(), "br".into())]);
+        assert!(compressed_body(&params).is_err());
+    }
+}
@@ -0,0 +1,232 @@
+use std::path::Path;
+
+use logger::log::*;
+use reqwest::header::{CONTENT_LENGTH, CONTENT_RANGE};
+use tokio::io::AsyncWriteExt;
+
+use super::{ContentRange, HttpRequestParams, TorTransport, build_request};
+use crate::TorErrors;
+
+/// How one download attempt ended
+enum Attempt {
+    Complete(u64),
+    /// Connection or circuit dropped, try again from what's on disk
+    Interrupted(String),
+}
+
+/// Downloads `params.url` into `dest`, resuming from whatever a previous attempt left there
+/// When `dest` already holds part of the file only the remaining bytes are requested (`Range`)
+/// and appended, a dropped circuit mid-transfer is retried the same way up to `max_attempts`
+/// times. The download is only complete once the size announced by `Content-Range` or
+/// `Content-Length` has been written
+/// `progress` is called with the bytes on disk and the total size when known
+/// Returns the final size of `dest`
+pub async fn download_file_resumable_async<P>(
+    params: HttpRequestParams,
+    dest: &Path,
+    max_attempts: u32,
+    socks_proxy: String,
+    mut progress: P,
+) -> Result<u64, TorErrors>
+where
+    P: FnMut(u64, Option<u64>) + Send,
+{
+    let mut last_error = String::from("No download attempt made");
+    for attempt in 1..=max_attempts.max(1) {
+        match download_attempt(&params, dest, &socks_proxy, &mut progress).await? {
+            Attempt::Complete(size) => return Ok(size),
+            Attempt::Interrupted(e) => {
+                debug!(
+                    "Download attempt {} of {} interrupted: {}",
+                    attempt,
+                    max_attempts,
+                    crate::safe_logging::scrub(&e)
+                );
+                last_error = e;
+            }
+        }
+    }
+    Err(TorErrors::TcpStreamError(format!(
+        "Download of {} incomplete after {} attempts: {}",
+        params.url, max_attempts, last_error
+    )))
+}
+
+/// Same as download_file_resumable_async but connects through `transport`
+pub async fn download_file_resumable_via_async<P>(
+    params: HttpRequestParams,
+    dest: &Path,
+    max_attempts: u32,
+    transport: &dyn TorTransport,
+    progress: P,
+) -> Result<u64, TorErrors>
+where
+    P: FnMut(u64, Option<u64>) + Send,
+{
+    download_file_resumable_async(
+        params,
+        dest,
+        max_attempts,
+        transport.socks_proxy()?,
+        progress,
+    )
+    .await
+}
+
+async fn download_attempt<P>(
+    params: &HttpRequestParams,
+    dest: &Path,
+    socks_proxy: &str,
+    progress: &mut P,
+) -> Result<Attempt, TorErrors>
+where
+    P: FnMut(u64, Option<u64>),
+{
+    let existing = match tokio::fs::metadata(dest).await {
+        Ok(metadata) => metadata.len(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e.into()),
+    };
+    let mut request = params.clone();
+    request.range = (existing > 0).then_some((existing, None));
+    request.download_to = None;
+
+    let mut response = match build_request(request, socks_proxy)?.send().await {
+        Ok(response) => response,
+        Err(e) => return Ok(Attempt::Interrupted(format!("Request failed: {}", e))),
+    };
+    let content_range = response
+        .headers()
+        .get(CONTENT_RANGE)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+
+    let (mut written, total, append) = match response.status().as_u16() {
+        206 => {
+            let range = content_range
+                .as_deref()
+                .and_then(ContentRange::parse)
+                .ok_or_else(|| download_error(params, "206 without a valid Content-Range"))?;
+            if range.start != existing {
+                return Err(download_error(
+                    params,
+                    &format!("asked for byte {} but got {}", existing, range.start),
+                ));
+            }
+            (existing, range.total, true)
+        }
+        // Range ignored (or nothing on disk yet), start over
+        200 => (0, content_length(&response), false),
+        // Nothing left to send, the file is complete when its size matches "bytes */<total>"
+        416 => {
+            let total = content_range
+                .as_deref()
+                .and_then(|value| value.strip_prefix("bytes */"))
+                .and_then(|total| total.trim().parse::<u64>().ok());
+            if total == Some(existing) {
+                progress(existing, total);
+                return Ok(Attempt::Complete(existing));
+            }
+            return Err(download_error(params, "416 Range Not Satisfiable"));
+        }
+        status => {
+            return Err(download_error(params, &format!("HTTP status {}", status)));
+        }
+    };
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(dest)
+        .await?;
+    progress(written, total);
+    loop {
+        match response.chunk().await {
+            Ok(Some(chunk)) => {
+                file.write_all(&chunk).await?;
+                written += chunk.len() as u64;
+                progress(written, total);
+            }
+            Ok(None) => break,
+            Err(e) => {
+                file.flush().await?;
+                return Ok(Attempt::Interrupted(format!(
+                    "Failed to read response body: {}",
+                    e
+                )));
+            }
+        }
+    }
+    file.flush().await?;
+
+    match total {
+        Some(total) if written != total => Ok(Attempt::Interrupted(format!(
+            "Got {} of {} bytes",
+            written, total
+        ))),
+        _ => Ok(Attempt::Complete(written)),
+    }
+}
+
+fn content_length(response: &reqwest::Response) -> Option<u64> {
+    response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+}
+
+fn download_error(params: &HttpRequestParams, message: &str) -> TorErrors {
+    TorErrors::TcpStreamError(format!("Download of {} failed: {}", params.url, message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_client::StubTransport;
+
+    #[test]
+    fn resumes_interrupted_download() {
+        let dest = std::env::temp_dir().join(format!("resumable-{}", std::process::id()));
+        std::fs::write(&dest, b"0123").unwrap();
+
+        let transport = StubTransport::new();
+        // Connection drops after 2 of the 6 remaining bytes
+        transport.push_response(
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 4-9/10\r\n\
+             Content-Length: 6\r\n\r\n45",
+        );
+        transport.push_response(
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 6-9/10\r\n\
+             Content-Length: 4\r\n\r\n6789",
+        );
+        let params = HttpRequestParams {
+            url: "http://example.onion/file".into(),
+            ..Default::default()
+        };
+        let mut reported = Vec::new();
+        let size = crate::runtime_handle()
+            .block_on(download_file_resumable_via_async(
+                params,
+                &dest,
+                3,
+                &transport,
+                |written, total| reported.push((written, total)),
+            ))
+            .unwrap();
+
+        assert_eq!(size, 10);
+        assert_eq!(std::fs::read(&dest).unwrap(), b"0123456789");
+        assert_eq!(reported.last(), Some(&(10, Some(10))));
+        let requests: Vec<String> = transport
+            .requests()
+            .into_iter()
+            .map(|r| String::from_utf8(r).unwrap())
+            .collect();
+        assert!(requests[0].contains("range: bytes=4-\r\n"));
+        assert!(requests[1].contains("range: bytes=6-\r\n"));
+        std::fs::remove_file(&dest).unwrap();
+    }
+}
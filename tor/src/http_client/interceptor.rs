@@ -0,0 +1,99 @@
+use std::sync::Arc;
+
+use super::{HttpRequestParams, HttpResponse};
+
+/// Hook run around every request made with an `HttpClientConfig`
+/// Use it for cross cutting concerns such as auth headers, logging or metrics
+pub trait RequestInterceptor: Send + Sync {
+    /// Runs before the request is built, may modify the params (e.g. add headers)
+    fn before(&self, _params: &mut HttpRequestParams) {}
+    /// Runs once the response (or the error response) is available
+    fn after(&self, _response: &HttpResponse) {}
+}
+
+/// Client side options shared by many requests
+#[derive(Clone, Default)]
+pub struct HttpClientConfig {
+    interceptors: Vec<Arc<dyn RequestInterceptor>>,
+}
+
+impl HttpClientConfig {
+    pub fn new() -> HttpClientConfig {
+        HttpClientConfig::default()
+    }
+
+    /// Registers an interceptor, `before` hooks run in registration order and `after` hooks
+    /// in reverse so the first interceptor wraps all the others
+    pub fn add_interceptor(&mut self, interceptor: Arc<dyn RequestInterceptor>) -> &mut Self {
+        self.interceptors.push(interceptor);
+        self
+    }
+
+    pub(crate) fn before(&self, params: &mut HttpRequestParams) {
+        for interceptor in &self.interceptors {
+            interceptor.before(params);
+        }
+    }
+
+    pub(crate) fn after(&self, response: &HttpResponse) {
+        for interceptor in self.interceptors.iter().rev() {
+            interceptor.after(response);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    struct Recorder {
+        name: &'static str,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl RequestInterceptor for Recorder {
+        fn before(&self, params: &mut HttpRequestParams) {
+            params
+                .headers
+                .get_or_insert_with(Vec::new)
+                .push(("X-Seen-By".into(), self.name.into()));
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("before {}", self.name));
+        }
+        fn after(&self, _response: &HttpResponse) {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("after {}", self.name));
+        }
+    }
+
+    #[test]
+    fn runs_interceptors_around_request() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut config = HttpClientConfig::new();
+        for name in ["outer", "inner"] {
+            config.add_interceptor(Arc::new(Recorder {
+                name,
+                calls: calls.clone(),
+            }));
+        }
+        let mut params = HttpRequestParams::default();
+        config.before(&mut params);
+        config.after(&HttpResponse::default());
+        assert_eq!(
+            params.headers.unwrap(),
+            vec![
+                ("X-Seen-By".to_string(), "outer".to_string()),
+                ("X-Seen-By".to_string(), "inner".to_string())
+            ]
+        );
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec!["before outer", "before inner", "after inner", "after outer"]
+        );
+    }
+}
@@ -0,0 +1,61 @@
+//! Random delay before sending a request (`HttpRequestParams::jitter_ms`)
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::TorErrors;
+
+/// Picks a delay uniformly from the inclusive `(min, max)` millisecond range
+pub(crate) fn jitter_delay(range: Option<(u64, u64)>) -> Result<Option<Duration>, TorErrors> {
+    let Some((min, max)) = range else {
+        return Ok(None);
+    };
+    if min > max {
+        return Err(TorErrors::TcpStreamError(format!(
+            "Invalid jitter_ms range: {} is larger than {}",
+            min, max
+        )));
+    }
+    let span = max - min;
+    let offset = match span.checked_add(1) {
+        Some(len) => random_u64() % len,
+        None => random_u64(),
+    };
+    Ok(Some(Duration::from_millis(min + offset)))
+}
+
+/// Random number from a SipHash keyed with the OS seeded keys of `RandomState`, over a counter
+/// so back to back calls differ. Not for cryptographic use, only hides timing patterns
+fn random_u64() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_delays_within_range() {
+        assert_eq!(jitter_delay(None).unwrap(), None);
+        assert_eq!(
+            jitter_delay(Some((250, 250))).unwrap(),
+            Some(Duration::from_millis(250))
+        );
+        assert!(matches!(
+            jitter_delay(Some((10, 5))),
+            Err(TorErrors::TcpStreamError(_))
+        ));
+
+        let delays: Vec<u64> = (0..200)
+            .map(|_| jitter_delay(Some((100, 110))).unwrap().unwrap().as_millis() as u64)
+            .collect();
+        assert!(delays.iter().all(|d| (100..=110).contains(d)));
+        assert!(delays.iter().any(|d| *d != delays[0]));
+        assert!(jitter_delay(Some((0, u64::MAX))).unwrap().is_some());
+    }
+}
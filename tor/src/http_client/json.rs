@@ -0,0 +1,170 @@
+//! Requests whose response body is read as an untyped JSON value
+use serde_json::Value;
+
+use super::transport::{SocksTransport, TorTransport};
+use super::{HttpRequestParams, HttpResponse, make_http_request_via_async};
+use crate::TorErrors;
+
+/// Characters of body shown on each side of a parse error
+const SNIPPET_CONTEXT: usize = 40;
+
+/// Makes the request and parses the response body as JSON
+/// Fails with `TorErrors::JsonParse` when the body isn't valid JSON, or when the response
+/// Content-Type isn't JSON (`application/json`, `text/json` or `application/*+json`)
+/// unless `params.lenient_content_type` is set. The status code isn't checked, error bodies
+/// of JSON APIs are returned like any other
+pub async fn make_http_request_json_value_async(
+    params: HttpRequestParams,
+    socks_proxy: String,
+) -> Result<Value, TorErrors> {
+    make_http_request_json_value_via_async(params, &SocksTransport::new(socks_proxy)).await
+}
+
+/// Same as make_http_request_json_value_async but connects through `transport`
+pub async fn make_http_request_json_value_via_async(
+    params: HttpRequestParams,
+    transport: &dyn TorTransport,
+) -> Result<Value, TorErrors> {
+    let lenient = params.lenient_content_type.unwrap_or(false);
+    let response = make_http_request_via_async(params, transport).await?;
+    parse_json_response(&response, lenient)
+}
+
+/// Synchronous wrapper for make_http_request_json_value_async
+pub fn make_http_request_json_value(
+    params: HttpRequestParams,
+    socks_proxy: String,
+) -> Result<Value, TorErrors> {
+    make_http_request_json_value_via(params, &SocksTransport::new(socks_proxy))
+}
+
+/// Synchronous wrapper for make_http_request_json_value_via_async
+pub fn make_http_request_json_value_via(
+    params: HttpRequestParams,
+    transport: &dyn TorTransport,
+) -> Result<Value, TorErrors> {
+    use crate::runtime_handle;
+
+    runtime_handle()
+        .block_on(async { make_http_request_json_value_via_async(params, transport).await })
+}
+
+fn parse_json_response(response: &HttpResponse, lenient: bool) -> Result<Value, TorErrors> {
+    if let Some(error) = &response.error {
+        return Err(TorErrors::TcpStreamError(error.clone()));
+    }
+    if !lenient {
+        let content_type = response.headers.get("content-type");
+        if !content_type.is_some_and(|c| is_json_content_type(c)) {
+            return Err(TorErrors::JsonParse {
+                message: format!(
+                    "Expected a JSON Content-Type, got {}",
+                    content_type.map_or("none", String::as_str)
+                ),
+                snippet: snippet(&response.body, 0),
+            });
+        }
+    }
+    serde_json::from_str(&response.body).map_err(|e| TorErrors::JsonParse {
+        message: e.to_string(),
+        snippet: snippet(
+            &response.body,
+            error_offset(&response.body, e.line(), e.column()),
+        ),
+    })
+}
+
+/// `application/json`, `text/json` or a `+json` suffix type, parameters ignored
+fn is_json_content_type(content_type: &str) -> bool {
+    let media_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    media_type == "application/json"
+        || media_type == "text/json"
+        || (media_type.starts_with("application/") && media_type.ends_with("+json"))
+}
+
+/// Byte offset of serde_json's 1-based line and column
+fn error_offset(body: &str, line: usize, column: usize) -> usize {
+    let line_start: usize = body
+        .split_inclusive('\n')
+        .take(line.saturating_sub(1))
+        .map(str::len)
+        .sum();
+    (line_start + column.saturating_sub(1)).min(body.len())
+}
+
+/// Up to `SNIPPET_CONTEXT` characters of `body` before and after byte `offset`
+fn snippet(body: &str, offset: usize) -> String {
+    let mut start = offset.saturating_sub(SNIPPET_CONTEXT);
+    while !body.is_char_boundary(start) {
+        start -= 1;
+    }
+    let mut end = (offset + SNIPPET_CONTEXT).min(body.len());
+    while !body.is_char_boundary(end) {
+        end += 1;
+    }
+    body[start..end].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_client::StubTransport;
+
+    #[test]
+    fn parses_json_bodies() {
+        let transport = StubTransport::new();
+        transport.push_response(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json; charset=utf-8\r\n\
+             Content-Length: 21\r\n\r\n{\"IsTor\":true,\"n\":1}\n",
+        );
+        transport.push_response(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: 2\r\n\r\n[]",
+        );
+        transport.push_response(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: 2\r\n\r\n[]",
+        );
+        transport.push_response(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/problem+json\r\n\
+             Content-Length: 25\r\n\r\n{\"a\": [1, 2,, 3], \"b\": 4}",
+        );
+        let params = || HttpRequestParams {
+            url: "http://example.onion/api".into(),
+            ..Default::default()
+        };
+
+        let value = make_http_request_json_value_via(params(), &transport).unwrap();
+        assert_eq!(value["IsTor"], Value::Bool(true));
+
+        assert!(matches!(
+            make_http_request_json_value_via(params(), &transport),
+            Err(TorErrors::JsonParse { .. })
+        ));
+        let lenient = HttpRequestParams {
+            lenient_content_type: Some(true),
+            ..params()
+        };
+        assert_eq!(
+            make_http_request_json_value_via(lenient, &transport).unwrap(),
+            Value::Array(Vec::new())
+        );
+
+        match make_http_request_json_value_via(params(), &transport) {
+            Err(TorErrors::JsonParse { snippet, .. }) => assert!(snippet.contains("2,, 3")),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn snippets_respect_char_boundaries() {
+        let body = "é".repeat(50);
+        assert_eq!(snippet(&body, 51).chars().count(), 41);
+        assert_eq!(error_offset("ab\ncd", 2, 2), 4);
+        assert!(is_json_content_type("Application/JSON"));
+        assert!(!is_json_content_type("application/jsonp"));
+    }
+}
@@ -0,0 +1,214 @@
+//! Process wide request metrics rendered in the Prometheus text exposition format
+//! (`metrics_prometheus`), for deployments that scrape them from an endpoint of their own
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use once_cell::sync::OnceCell;
+
+use super::{HttpMethod, HttpResponse, RawHttpResponse, pool_stats};
+use crate::TorErrors;
+
+/// Upper bounds (seconds) of the latency histogram buckets, wide since circuits are slow
+const LATENCY_BUCKETS: [f64; 10] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 60.0];
+
+#[derive(Default)]
+struct Metrics {
+    requests: BTreeMap<String, u64>,
+    /// By status class ("2xx", ...), only requests that got a response
+    responses: BTreeMap<String, u64>,
+    errors: u64,
+    /// Requests per `LATENCY_BUCKETS` bucket, not cumulative
+    latency_buckets: [u64; LATENCY_BUCKETS.len()],
+    latency_sum: f64,
+    latency_count: u64,
+}
+
+static METRICS: OnceCell<Mutex<Metrics>> = OnceCell::new();
+
+fn metrics() -> &'static Mutex<Metrics> {
+    METRICS.get_or_init(|| Mutex::new(Metrics::default()))
+}
+
+/// Counts a finished `make_http_request*` request. A request is an error when it failed or
+/// its response carries an `error` (e.g. a read failure), not for a 4xx or 5xx status
+pub(crate) fn record(
+    method: HttpMethod,
+    elapsed: Duration,
+    result: &Result<HttpResponse, TorErrors>,
+) {
+    let outcome = match result {
+        Ok(response) => (response.status_code, response.error.is_some()),
+        Err(_) => (0, true),
+    };
+    count(method, elapsed, outcome);
+}
+
+/// Same as `record` for a `make_raw_http_request*` request
+pub(crate) fn record_raw(
+    method: HttpMethod,
+    elapsed: Duration,
+    result: &Result<RawHttpResponse, TorErrors>,
+) {
+    let outcome = match result {
+        Ok(response) => (response.status_code, response.error.is_some()),
+        Err(_) => (0, true),
+    };
+    count(method, elapsed, outcome);
+}
+
+/// `status_code` is 0 when no response arrived
+fn count(method: HttpMethod, elapsed: Duration, (status_code, failed): (u16, bool)) {
+    let mut metrics = metrics().lock().unwrap();
+    *metrics.requests.entry(format!("{:?}", method)).or_insert(0) += 1;
+    if status_code != 0 {
+        let class = format!("{}xx", status_code / 100);
+        *metrics.responses.entry(class).or_insert(0) += 1;
+    }
+    if failed || status_code == 0 {
+        metrics.errors += 1;
+    }
+    let seconds = elapsed.as_secs_f64();
+    if let Some(bucket) = LATENCY_BUCKETS.iter().position(|&bound| seconds <= bound) {
+        metrics.latency_buckets[bucket] += 1;
+    }
+    metrics.latency_sum += seconds;
+    metrics.latency_count += 1;
+}
+
+/// Request counts by method and status class, errors, latency histogram and connection pool
+/// counters of `make_http_request*` and `make_raw_http_request*` since the process started,
+/// in the Prometheus text format. Tor's own circuit and traffic metrics are added by
+/// `OwnedTorService::metrics_prometheus`
+pub fn metrics_prometheus() -> String {
+    let mut out = String::new();
+    let metrics = metrics().lock().unwrap();
+
+    header(
+        &mut out,
+        "tor_http_requests_total",
+        "counter",
+        "HTTP requests made through Tor, by method",
+    );
+    for (method, count) in &metrics.requests {
+        let _ = writeln!(
+            out,
+            "tor_http_requests_total{{method=\"{}\"}} {}",
+            method, count
+        );
+    }
+    header(
+        &mut out,
+        "tor_http_responses_total",
+        "counter",
+        "HTTP responses received, by status class",
+    );
+    for (class, count) in &metrics.responses {
+        let _ = writeln!(
+            out,
+            "tor_http_responses_total{{class=\"{}\"}} {}",
+            class, count
+        );
+    }
+    header(
+        &mut out,
+        "tor_http_request_errors_total",
+        "counter",
+        "HTTP requests that failed without a complete response",
+    );
+    let _ = writeln!(out, "tor_http_request_errors_total {}", metrics.errors);
+
+    header(
+        &mut out,
+        "tor_http_request_duration_seconds",
+        "histogram",
+        "Time from sending an HTTP request to having its response",
+    );
+    let mut cumulative = 0;
+    for (bound, count) in LATENCY_BUCKETS.iter().zip(metrics.latency_buckets) {
+        cumulative += count;
+        let _ = writeln!(
+            out,
+            "tor_http_request_duration_seconds_bucket{{le=\"{}\"}} {}",
+            bound, cumulative
+        );
+    }
+    let _ = writeln!(
+        out,
+        "tor_http_request_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+        metrics.latency_count
+    );
+    let _ = writeln!(
+        out,
+        "tor_http_request_duration_seconds_sum {}",
+        metrics.latency_sum
+    );
+    let _ = writeln!(
+        out,
+        "tor_http_request_duration_seconds_count {}",
+        metrics.latency_count
+    );
+
+    let pool = pool_stats();
+    header(
+        &mut out,
+        "tor_http_pool_hits_total",
+        "counter",
+        "Requests served by an already pooled client",
+    );
+    let _ = writeln!(out, "tor_http_pool_hits_total {}", pool.hits);
+    header(
+        &mut out,
+        "tor_http_pool_misses_total",
+        "counter",
+        "Requests that needed a new client",
+    );
+    let _ = writeln!(out, "tor_http_pool_misses_total {}", pool.misses);
+    out
+}
+
+/// `# HELP` and `# TYPE` lines of a metric
+pub(crate) fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Value of the sample line starting with `sample`, 0 when there is none
+    fn sample(text: &str, sample: &str) -> f64 {
+        text.lines()
+            .find_map(|line| line.strip_prefix(sample)?.strip_prefix(' '))
+            .map_or(0.0, |value| value.parse().unwrap())
+    }
+
+    #[test]
+    fn counts_requests() {
+        let before = metrics_prometheus();
+        record(
+            HttpMethod::DELETE,
+            Duration::from_millis(300),
+            &Ok(HttpResponse {
+                status_code: 404,
+                ..Default::default()
+            }),
+        );
+        record(
+            HttpMethod::DELETE,
+            Duration::from_secs(90),
+            &Err(TorErrors::TcpStreamError(String::from("refused"))),
+        );
+        let after = metrics_prometheus();
+
+        let delta = |name: &str| sample(&after, name) - sample(&before, name);
+        assert!(delta("tor_http_requests_total{method=\"DELETE\"}") >= 2.0);
+        assert!(delta("tor_http_responses_total{class=\"4xx\"}") >= 1.0);
+        assert!(delta("tor_http_request_errors_total") >= 1.0);
+        assert!(delta("tor_http_request_duration_seconds_bucket{le=\"0.5\"}") >= 1.0);
+        assert!(delta("tor_http_request_duration_seconds_bucket{le=\"+Inf\"}") >= 2.0);
+        assert!(after.contains("# TYPE tor_http_request_duration_seconds histogram\n"));
+    }
+}
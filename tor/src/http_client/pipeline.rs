@@ -0,0 +1,752 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+
+use url::Url;
+
+use super::compress;
+use super::resolver::resolve_request;
+use super::transport::{ReadWrite, SocksTransport, TorTransport};
+use super::validate::validate_request;
+use super::{
+    CONTENT_LENGTH_MISMATCH, DEFAULT_MAX_HEADER_BYTES, HttpMethod, HttpRequestParams, HttpResponse,
+    HttpVersion, accept_header, is_stripped_header,
+};
+use crate::TorErrors;
+
+/// Upper bound on response headers parsed per response
+const MAX_RESPONSE_HEADERS: usize = 64;
+
+/// Interim (1xx) responses skipped before a final one, a server sending more is broken
+const MAX_INTERIM_RESPONSES: usize = 16;
+
+/// Longest chunk size or trailer line read
+const MAX_LINE_BYTES: usize = 8192;
+
+/// Sends `requests` back to back on a single connection through the Tor SOCKS proxy (HTTP/1.1
+/// pipelining) and returns their responses in the same order
+/// Every request has to target the same plain `http://` origin (typically an onion service,
+/// TLS isn't supported here) and use an idempotent method, a POST could otherwise be applied
+/// twice when the connection drops and the caller retries. Requests left unanswered when the
+/// server closes the connection early get a response with status 0 and an error, they are safe
+/// to send again. A body cut short of its `Content-Length` is kept, flagged with a
+/// `CONTENT_LENGTH_MISMATCH` error, bytes past it are never part of the body
+pub fn pipeline(
+    requests: Vec<HttpRequestParams>,
+    socks_proxy: String,
+) -> Result<Vec<HttpResponse>, TorErrors> {
+    crate::killswitch::check(&socks_proxy)?;
+    pipeline_via(requests, &SocksTransport::new(socks_proxy))
+}
+
+/// Same as pipeline but connects through `transport`
+pub fn pipeline_via(
+    requests: Vec<HttpRequestParams>,
+    transport: &dyn TorTransport,
+) -> Result<Vec<HttpResponse>, TorErrors> {
+    if requests.is_empty() {
+        return Ok(Vec::new());
+    }
+    let requests = requests
+        .into_iter()
+        .map(resolve_request)
+        .collect::<Result<Vec<_>, _>>()?;
+    let origin = check_pipelinable(&requests)?;
+
+    let mut payload = Vec::new();
+    for (i, request) in requests.iter().enumerate() {
+        write_request(&mut payload, request, &origin, i + 1 == requests.len())?;
+    }
+    let mut stream = transport.connect(&origin.authority)?;
+    // A server may answer and close before reading everything, e.g. with a final response to
+    // `Expect: 100-continue` sent without waiting for the body. What it answered until then
+    // is still readable, the requests it never saw get the status 0 error below
+    if let Err(e) = stream.write_all(&payload).and_then(|_| stream.flush()) {
+        if !closed_by_peer(&e) {
+            return Err(e.into());
+        }
+    }
+
+    let mut reader = ResponseReader::new(stream);
+    let mut responses = Vec::with_capacity(requests.len());
+    for request in &requests {
+        match reader.read_response(request) {
+            Ok(response) => responses.push(response),
+            Err(e) => {
+                // Nothing after a broken response can be matched to its request anymore
+                let unanswered = requests.len() - responses.len();
+                responses.extend((0..unanswered).map(|_| HttpResponse {
+                    error: Some(e.clone()),
+                    ..Default::default()
+                }));
+                break;
+            }
+        }
+    }
+    Ok(responses)
+}
+
+fn closed_by_peer(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+    )
+}
+
+struct Origin {
+    /// host:port to connect to
+    authority: String,
+    /// Host header value
+    host: String,
+}
+
+/// Checks every request can be pipelined and they all share one origin
+fn check_pipelinable(requests: &[HttpRequestParams]) -> Result<Origin, TorErrors> {
+    let mut origin: Option<(String, u16)> = None;
+    for request in requests {
+        validate_request(request)?;
+        if matches!(request.method, HttpMethod::CONNECT) {
+            // Whatever follows a granted CONNECT is tunnel data, not another response
+            if requests.len() > 1 || request.tunnel_target.is_none() || request.body.is_some() {
+                return Err(pipeline_error(String::from(
+                    "A CONNECT request goes alone, with a tunnel_target and no body",
+                )));
+            }
+        } else if !matches!(
+            request.method,
+            HttpMethod::GET
+                | HttpMethod::HEAD
+                | HttpMethod::PUT
+                | HttpMethod::DELETE
+                | HttpMethod::OPTIONS
+        ) {
+            return Err(pipeline_error(format!(
+                "{:?} is not idempotent and can't be pipelined",
+                request.method
+            )));
+        }
+        if request.http_version != HttpVersion::Http1 {
+            return Err(pipeline_error(format!(
+                "Only HTTP/1.1 requests can be pipelined, got {:?}",
+                request.http_version
+            )));
+        }
+        if request.asterisk_form == Some(true) && !matches!(request.method, HttpMethod::OPTIONS) {
+            return Err(pipeline_error(format!(
+                "Only OPTIONS can target *, got {:?}",
+                request.method
+            )));
+        }
+        if request.body_file.is_some() || request.download_to.is_some() {
+            return Err(pipeline_error(String::from(
+                "File uploads and downloads can't be pipelined",
+            )));
+        }
+        let url = Url::parse(&request.url)
+            .map_err(|e| pipeline_error(format!("Invalid url {}: {}", request.url, e)))?;
+        if url.scheme() != "http" {
+            return Err(pipeline_error(format!(
+                "Only http:// urls can be pipelined, got {}",
+                request.url
+            )));
+        }
+        let this = (
+            url.host_str().unwrap_or_default().to_string(),
+            url.port_or_known_default().unwrap_or(80),
+        );
+        match &origin {
+            Some(first) if *first != this => {
+                return Err(pipeline_error(format!(
+                    "Pipelined requests must share one origin, {} differs",
+                    request.url
+                )));
+            }
+            _ => origin = Some(this),
+        }
+    }
+    let (host, port) = origin.unwrap_or_default();
+    Ok(Origin {
+        authority: format!("{}:{}", host, port),
+        host: if port == 80 {
+            host
+        } else {
+            format!("{}:{}", host, port)
+        },
+    })
+}
+
+/// Serializes one request, the last one asks the server to close the connection afterwards
+fn write_request(
+    out: &mut Vec<u8>,
+    request: &HttpRequestParams,
+    origin: &Origin,
+    last: bool,
+) -> Result<(), TorErrors> {
+    let url = Url::parse(&request.url)
+        .map_err(|e| pipeline_error(format!("Invalid url {}: {}", request.url, e)))?;
+    let target = match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    };
+    let connect = matches!(request.method, HttpMethod::CONNECT);
+    let (target, host) = match &request.tunnel_target {
+        // Authority-form, the Host of a CONNECT is the tunnel target too
+        Some(tunnel_target) if connect => (tunnel_target.clone(), tunnel_target.as_str()),
+        _ if request.asterisk_form == Some(true) => (String::from("*"), origin.host.as_str()),
+        _ => (target, origin.host.as_str()),
+    };
+    write!(
+        out,
+        "{:?} {} HTTP/1.1\r\nHost: {}\r\n",
+        request.method, target, host
+    )?;
+    if let Some(accept) = accept_header(request) {
+        write!(out, "Accept: {}\r\n", accept)?;
+    }
+    for (name, value) in request.headers.iter().flatten() {
+        let reserved = ["host", "connection", "content-length", "transfer-encoding"]
+            .iter()
+            .any(|reserved| name.trim().eq_ignore_ascii_case(reserved))
+            || (request.accept.is_some() && name.trim().eq_ignore_ascii_case("accept"));
+        if reserved || is_stripped_header(name, &request.strip_headers) {
+            continue;
+        }
+        write!(out, "{}: {}\r\n", name.trim(), value.trim())?;
+    }
+    if last && !connect {
+        out.extend_from_slice(b"Connection: close\r\n");
+    }
+    if let Some(compressed) = compress::compressed_body(request)? {
+        write!(
+            out,
+            "Content-Encoding: gzip\r\nContent-Length: {}\r\n\r\n",
+            compressed.len()
+        )?;
+        out.extend_from_slice(&compressed);
+        return Ok(());
+    }
+    match &request.body {
+        Some(body) => {
+            write!(out, "Content-Length: {}\r\n\r\n", body.len())?;
+            out.extend_from_slice(body.as_bytes());
+        }
+        None => out.extend_from_slice(b"\r\n"),
+    }
+    Ok(())
+}
+
+/// Reads consecutive responses off one connection
+struct ResponseReader {
+    stream: Box<dyn ReadWrite>,
+    buffer: Vec<u8>,
+    eof: bool,
+}
+
+impl ResponseReader {
+    fn new(stream: Box<dyn ReadWrite>) -> ResponseReader {
+        ResponseReader {
+            stream,
+            buffer: Vec::new(),
+            eof: false,
+        }
+    }
+
+    /// Reads more bytes into the buffer, false once the server closed the connection
+    fn fill(&mut self) -> Result<bool, String> {
+        if self.eof {
+            return Ok(false);
+        }
+        let mut chunk = [0u8; 8192];
+        let read = self
+            .stream
+            .read(&mut chunk)
+            .map_err(|e| format!("Failed to read response: {}", e))?;
+        self.eof = read == 0;
+        self.buffer.extend_from_slice(&chunk[..read]);
+        Ok(read > 0)
+    }
+
+    fn take(&mut self, len: usize) -> Vec<u8> {
+        self.buffer.drain(..len).collect()
+    }
+
+    /// Status and headers of the final response, interim (1xx) responses before it are
+    /// skipped up to `MAX_INTERIM_RESPONSES`
+    fn read_head(
+        &mut self,
+        request: &HttpRequestParams,
+    ) -> Result<(u16, HashMap<String, String>), String> {
+        let max_header_bytes = request.max_header_bytes.unwrap_or(DEFAULT_MAX_HEADER_BYTES);
+        for _ in 0..=MAX_INTERIM_RESPONSES {
+            let (status_code, headers, head_len) = loop {
+                let mut parsed = [httparse::EMPTY_HEADER; MAX_RESPONSE_HEADERS];
+                let mut response = httparse::Response::new(&mut parsed);
+                match response.parse(&self.buffer) {
+                    Ok(httparse::Status::Complete(head_len)) => {
+                        let mut headers: HashMap<String, String> = HashMap::new();
+                        for header in response.headers.iter() {
+                            merge_header(
+                                &mut headers,
+                                header.name,
+                                String::from_utf8_lossy(header.value).into_owned(),
+                            );
+                        }
+                        break (response.code.unwrap_or_default(), headers, head_len);
+                    }
+                    Ok(httparse::Status::Partial) => {
+                        if self.buffer.len() > max_header_bytes {
+                            return Err(format!(
+                                "Response headers exceed {} bytes",
+                                max_header_bytes
+                            ));
+                        }
+                        if !self.fill()? {
+                            return Err(String::from("Connection closed before the response"));
+                        }
+                    }
+                    Err(e) => return Err(format!("Malformed response: {}", e)),
+                }
+            };
+            self.take(head_len);
+            // Interim responses (100 Continue) precede the real one
+            if !(100..200).contains(&status_code) {
+                return Ok((status_code, headers));
+            }
+        }
+        Err(format!(
+            "More than {} interim responses",
+            MAX_INTERIM_RESPONSES
+        ))
+    }
+
+    fn read_response(&mut self, request: &HttpRequestParams) -> Result<HttpResponse, String> {
+        let (status_code, mut headers) = self.read_head(request)?;
+        let limit = request.max_response_bytes;
+
+        let mut error = None;
+        // A granted CONNECT turns the connection into the tunnel, it has no body
+        let body = if matches!(request.method, HttpMethod::HEAD)
+            || status_code == 204
+            || status_code == 304
+            || (matches!(request.method, HttpMethod::CONNECT) && (200..300).contains(&status_code))
+        {
+            Vec::new()
+        } else if headers
+            .get("transfer-encoding")
+            .is_some_and(|te| te.to_ascii_lowercase().contains("chunked"))
+        {
+            self.read_chunked(&mut headers, limit)?
+        } else if let Some(length) = headers.get("content-length") {
+            let length: usize = length
+                .trim()
+                .parse()
+                .map_err(|_| format!("Invalid Content-Length: {}", length))?;
+            check_body_limit(length, limit)?;
+            let body = self.read_up_to(length)?;
+            if body.len() < length {
+                error = Some(format!(
+                    "{}: connection closed after {} of {} body bytes",
+                    CONTENT_LENGTH_MISMATCH,
+                    body.len(),
+                    length
+                ));
+            }
+            body
+        } else {
+            // Delimited by the connection closing, nothing can follow it
+            while self.fill()? {
+                check_body_limit(self.buffer.len(), limit)?;
+            }
+            let len = self.buffer.len();
+            self.take(len)
+        };
+        Ok(HttpResponse {
+            status_code,
+            body: String::from_utf8_lossy(&body).into_owned(),
+            headers,
+            error,
+            ..Default::default()
+        })
+    }
+
+    /// Reads `len` bytes, or fewer when the connection closes first
+    fn read_up_to(&mut self, len: usize) -> Result<Vec<u8>, String> {
+        while self.buffer.len() < len && self.fill()? {}
+        Ok(self.take(len.min(self.buffer.len())))
+    }
+
+    fn read_exact(&mut self, len: usize) -> Result<Vec<u8>, String> {
+        while self.buffer.len() < len {
+            if !self.fill()? {
+                return Err(format!(
+                    "Connection closed after {} of {} body bytes",
+                    self.buffer.len(),
+                    len
+                ));
+            }
+        }
+        Ok(self.take(len))
+    }
+
+    fn read_line(&mut self) -> Result<String, String> {
+        loop {
+            if let Some(end) = self.buffer.windows(2).position(|w| w == b"\r\n") {
+                let line = self.take(end + 2);
+                return Ok(String::from_utf8_lossy(&line[..end]).into_owned());
+            }
+            if self.buffer.len() > MAX_LINE_BYTES {
+                return Err(format!(
+                    "Chunk size or trailer line over {} bytes",
+                    MAX_LINE_BYTES
+                ));
+            }
+            if !self.fill()? {
+                return Err(String::from("Connection closed inside a chunked body"));
+            }
+        }
+    }
+
+    /// Decodes a chunked body, trailer fields after the last chunk are merged into `headers`
+    /// Fails as soon as a chunk would take the body past `limit`
+    fn read_chunked(
+        &mut self,
+        headers: &mut HashMap<String, String>,
+        limit: Option<usize>,
+    ) -> Result<Vec<u8>, String> {
+        let mut body = Vec::new();
+        loop {
+            let size_line = self.read_line()?;
+            let size = size_line.split(';').next().unwrap_or_default().trim();
+            let size = usize::from_str_radix(size, 16)
+                .map_err(|_| format!("Invalid chunk size: {}", size_line))?;
+            if size == 0 {
+                // The body ends here, trailers follow up to the empty line
+                loop {
+                    let line = self.read_line()?;
+                    if line.is_empty() {
+                        return Ok(body);
+                    }
+                    let Some((name, value)) = line.split_once(':') else {
+                        return Err(format!("Malformed trailer: {}", line));
+                    };
+                    let name = name.trim();
+                    if !is_forbidden_trailer(name) {
+                        merge_header(headers, name, value.trim().to_string());
+                    }
+                }
+            }
+            let body_len = body
+                .len()
+                .checked_add(size)
+                .ok_or_else(|| format!("Invalid chunk size: {}", size_line))?;
+            check_body_limit(body_len, limit)?;
+            body.extend(self.read_exact(size)?);
+            self.read_line()?;
+        }
+    }
+}
+
+/// Errors once a body grows to `len` bytes past `max_response_bytes`
+fn check_body_limit(len: usize, limit: Option<usize>) -> Result<(), String> {
+    match limit {
+        Some(limit) if len > limit => Err(format!("Response body exceeds {} bytes", limit)),
+        _ => Ok(()),
+    }
+}
+
+/// Adds a header under its lowercase name, joining repeated ones with ", "
+fn merge_header(headers: &mut HashMap<String, String>, name: &str, value: String) {
+    headers
+        .entry(name.to_ascii_lowercase())
+        .and_modify(|existing| {
+            existing.push_str(", ");
+            existing.push_str(&value);
+        })
+        .or_insert(value);
+}
+
+/// Fields a trailer must not carry (RFC 9110 6.5.1): framing, routing and content metadata
+/// the body was already handled with. They are dropped instead of overriding the headers
+fn is_forbidden_trailer(name: &str) -> bool {
+    [
+        "transfer-encoding",
+        "content-length",
+        "content-encoding",
+        "content-type",
+        "content-range",
+        "host",
+        "trailer",
+    ]
+    .iter()
+    .any(|forbidden| forbidden.eq_ignore_ascii_case(name))
+}
+
+fn pipeline_error(message: String) -> TorErrors {
+    TorErrors::TcpStreamError(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_client::{ReadWrite, StubTransport, TorTransport};
+    use std::io::Cursor;
+
+    fn get(url: &str) -> HttpRequestParams {
+        HttpRequestParams {
+            url: url.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn pipelines_requests_in_order() {
+        let transport = StubTransport::new();
+        transport.push_response(
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nfirst\
+             HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nsec\r\n3\r\nond\r\n0\r\n\r\n\
+             HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        );
+        let responses = pipeline_via(
+            vec![
+                get("http://example.onion/a"),
+                get("http://example.onion/b?x=1"),
+                get("http://example.onion/c"),
+            ],
+            &transport,
+        )
+        .unwrap();
+
+        let bodies: Vec<_> = responses.iter().map(|r| r.body.as_str()).collect();
+        assert_eq!(bodies, vec!["first", "second", ""]);
+        assert_eq!(responses[2].status_code, 404);
+        let sent = String::from_utf8(transport.requests().remove(0)).unwrap();
+        assert_eq!(sent.matches("Host: example.onion\r\n").count(), 3);
+        assert!(sent.contains("GET /b?x=1 HTTP/1.1\r\n"));
+        assert!(sent.ends_with(
+            "GET /c HTTP/1.1\r\nHost: example.onion\r\nAccept: */*\r\nConnection: close\r\n\r\n"
+        ));
+    }
+
+    /// Server that stops reading after `accept` bytes, answers `response` and closes
+    struct EarlyResponse {
+        response: &'static str,
+        accept: usize,
+    }
+
+    struct EarlyResponseStream {
+        response: Cursor<Vec<u8>>,
+        accept: usize,
+    }
+
+    impl TorTransport for EarlyResponse {
+        fn connect(&self, _target: &str) -> io::Result<Box<dyn ReadWrite>> {
+            Ok(Box::new(EarlyResponseStream {
+                response: Cursor::new(self.response.as_bytes().to_vec()),
+                accept: self.accept,
+            }))
+        }
+        fn socks_proxy(&self) -> Result<String, TorErrors> {
+            Err(pipeline_error(String::from("No SOCKS proxy")))
+        }
+    }
+
+    impl Read for EarlyResponseStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.response.read(buf)
+        }
+    }
+
+    impl Write for EarlyResponseStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.accept == 0 {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            let written = buf.len().min(self.accept);
+            self.accept -= written;
+            Ok(written)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl ReadWrite for EarlyResponseStream {
+        fn try_clone_box(&self) -> io::Result<Box<dyn ReadWrite>> {
+            Err(io::ErrorKind::Unsupported.into())
+        }
+    }
+
+    #[test]
+    fn reads_response_sent_before_the_body() {
+        let transport = EarlyResponse {
+            response: "HTTP/1.1 417 Expectation Failed\r\nContent-Length: 0\r\n\
+                       Connection: close\r\n\r\n",
+            accept: 128,
+        };
+        let upload = HttpRequestParams {
+            url: "http://example.onion/upload".into(),
+            method: HttpMethod::PUT,
+            headers: Some(vec![("Expect".into(), "100-continue".into())]),
+            body: Some("x".repeat(4096)),
+            ..Default::default()
+        };
+        let responses =
+            pipeline_via(vec![upload, get("http://example.onion/after")], &transport).unwrap();
+        assert_eq!(responses[0].status_code, 417);
+        assert!(responses[0].error.is_none());
+        assert_eq!(responses[1].status_code, 0);
+        assert!(responses[1].error.is_some());
+    }
+
+    #[test]
+    fn enforces_max_response_bytes_while_reading() {
+        let limited = |url: &str| HttpRequestParams {
+            max_response_bytes: Some(4),
+            ..get(url)
+        };
+        for response in [
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello",
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nffffffffffffffff\r\n",
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nhe\r\n3\r\nllo\r\n",
+            "HTTP/1.1 200 OK\r\n\r\nhello",
+        ] {
+            let transport = StubTransport::new();
+            transport.push_response(response);
+            let responses =
+                pipeline_via(vec![limited("http://example.onion/")], &transport).unwrap();
+            let error = responses[0].error.clone().unwrap_or_default();
+            assert!(
+                error.contains("exceeds 4 bytes"),
+                "{:?}: {:?}",
+                response,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn enforces_max_header_bytes_while_reading() {
+        // A head that never ends, only the part up to the limit gets buffered
+        let transport = StubTransport::new();
+        transport.push_response(format!(
+            "HTTP/1.1 200 OK\r\nX-Padding: {}",
+            "a".repeat(1024 * 1024)
+        ));
+        let params = HttpRequestParams {
+            max_header_bytes: Some(1024),
+            ..get("http://example.onion/")
+        };
+        let responses = pipeline_via(vec![params], &transport).unwrap();
+        assert_eq!(
+            responses[0].error.as_deref(),
+            Some("Response headers exceed 1024 bytes")
+        );
+    }
+
+    #[test]
+    fn caps_interim_responses() {
+        let transport = StubTransport::new();
+        transport.push_response(format!(
+            "{}HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok",
+            "HTTP/1.1 100 Continue\r\n\r\n".repeat(MAX_INTERIM_RESPONSES + 1)
+        ));
+        let responses = pipeline_via(vec![get("http://example.onion/")], &transport).unwrap();
+        assert_eq!(responses[0].status_code, 0);
+
+        let transport = StubTransport::new();
+        transport.push_response(format!(
+            "{}HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok",
+            "HTTP/1.1 100 Continue\r\n\r\n".repeat(MAX_INTERIM_RESPONSES)
+        ));
+        let responses = pipeline_via(vec![get("http://example.onion/")], &transport).unwrap();
+        assert_eq!(responses[0].body, "ok");
+    }
+
+    #[test]
+    fn merges_chunked_trailers() {
+        let transport = StubTransport::new();
+        transport.push_response(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nTrailer: Digest\r\n\r\n\
+             5\r\nhello\r\n0\r\nDigest: sha-256=abc\r\nContent-Length: 99\r\n\r\n\
+             HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok",
+        );
+        let responses = pipeline_via(
+            vec![get("http://example.onion/a"), get("http://example.onion/b")],
+            &transport,
+        )
+        .unwrap();
+        assert_eq!(responses[0].body, "hello");
+        assert_eq!(
+            responses[0].headers.get("digest"),
+            Some(&String::from("sha-256=abc"))
+        );
+        assert_eq!(responses[0].headers.get("content-length"), None);
+        // The next response starts right after the trailer section
+        assert_eq!(responses[1].body, "ok");
+    }
+
+    #[test]
+    fn reports_unanswered_requests() {
+        let transport = StubTransport::new();
+        transport.push_response("HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok");
+        let responses = pipeline_via(
+            vec![get("http://example.onion/a"), get("http://example.onion/b")],
+            &transport,
+        )
+        .unwrap();
+        assert_eq!(responses[0].body, "ok");
+        assert_eq!(responses[1].status_code, 0);
+        assert!(responses[1].error.is_some());
+    }
+
+    #[test]
+    fn keeps_bodies_shorter_than_content_length() {
+        let transport = StubTransport::new();
+        transport.push_response("HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\npartial");
+        let responses = pipeline_via(
+            vec![get("http://example.onion/a"), get("http://example.onion/b")],
+            &transport,
+        )
+        .unwrap();
+        assert_eq!(responses[0].status_code, 200);
+        assert_eq!(responses[0].body, "partial");
+        assert!(responses[0].is_truncated());
+        assert!(
+            responses[0]
+                .error
+                .as_ref()
+                .unwrap()
+                .contains("7 of 10 body bytes")
+        );
+        assert_eq!(responses[1].status_code, 0);
+        assert!(!responses[1].is_truncated());
+    }
+
+    #[test]
+    fn cuts_bodies_longer_than_content_length() {
+        let transport = StubTransport::new();
+        transport.push_response(
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nokextra bytes",
+        );
+        let responses = pipeline_via(vec![get("http://example.onion/a")], &transport).unwrap();
+        assert_eq!(responses[0].body, "ok");
+        assert!(responses[0].error.is_none());
+    }
+
+    #[test]
+    fn rejects_unsafe_pipelines() {
+        let transport = StubTransport::new();
+        let post = HttpRequestParams {
+            method: HttpMethod::POST,
+            ..get("http://example.onion/a")
+        };
+        for requests in [
+            vec![get("http://example.onion/a"), post],
+            vec![get("http://a.onion/"), get("http://b.onion/")],
+            vec![get("https://example.onion/")],
+        ] {
+            assert!(pipeline_via(requests, &transport).is_err());
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use futures::{Stream, StreamExt};
+
+/// Transfer progress callback, called with the bytes sent or received so far and the total
+/// when it is known (`None` e.g. for chunked responses)
+#[derive(Clone)]
+pub struct ProgressCallback(Arc<Mutex<dyn FnMut(u64, Option<u64>) + Send>>);
+
+impl ProgressCallback {
+    pub fn new<F>(callback: F) -> ProgressCallback
+    where
+        F: FnMut(u64, Option<u64>) + Send + 'static,
+    {
+        ProgressCallback(Arc::new(Mutex::new(callback)))
+    }
+
+    pub(crate) fn report(&self, done: u64, total: Option<u64>) {
+        (self.0.lock().unwrap())(done, total);
+    }
+}
+
+impl fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressCallback")
+    }
+}
+
+/// Reports every chunk of an upload body stream as it is handed to the connection
+pub(crate) fn track_upload<S, B, E>(
+    stream: S,
+    total: u64,
+    progress: ProgressCallback,
+) -> impl Stream<Item = Result<B, E>>
+where
+    S: Stream<Item = Result<B, E>>,
+    B: AsRef<[u8]>,
+{
+    let mut done = 0u64;
+    stream.map(move |chunk| {
+        if let Ok(chunk) = &chunk {
+            done += chunk.as_ref().len() as u64;
+            progress.report(done, Some(total));
+        }
+        chunk
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_upload_chunks() {
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let recorded = reports.clone();
+        let progress = ProgressCallback::new(move |done, total| {
+            recorded.lock().unwrap().push((done, total));
+        });
+        let chunks: Vec<Result<&[u8], ()>> = vec![Ok(b"abc"), Ok(b"de"), Err(())];
+        let stream = track_upload(futures::stream::iter(chunks), 5, progress);
+        let collected: Vec<_> = crate::runtime_handle().block_on(stream.collect());
+
+        assert_eq!(collected.len(), 3);
+        assert_eq!(*reports.lock().unwrap(), vec![(3, Some(5)), (5, Some(5))]);
+    }
+}
@@ -0,0 +1,76 @@
+use serde::{Deserialize, Serialize};
+
+/// Byte span of a `206 Partial Content` response, from its `Content-Range` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ContentRange {
+    /// First byte returned, zero based
+    pub start: u64,
+    /// Last byte returned, inclusive
+    pub end: u64,
+    /// Full size of the resource, `None` when the server answered `*`
+    pub total: Option<u64>,
+}
+
+impl ContentRange {
+    /// Parses `bytes <start>-<end>/<total|*>`
+    pub fn parse(value: &str) -> Option<ContentRange> {
+        let (span, total) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+        let (start, end) = span.trim().split_once('-')?;
+        let range = ContentRange {
+            start: start.trim().parse().ok()?,
+            end: end.trim().parse().ok()?,
+            total: match total.trim() {
+                "*" => None,
+                total => Some(total.parse().ok()?),
+            },
+        };
+        (range.start <= range.end).then_some(range)
+    }
+
+    /// Number of bytes in the span
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// Whether the span reaches the end of the resource
+    pub fn is_last(&self) -> bool {
+        self.total.is_some_and(|total| self.end + 1 >= total)
+    }
+}
+
+/// `Range` request header value for `start` up to `end` (inclusive), or to the end
+pub(crate) fn range_header(start: u64, end: Option<u64>) -> String {
+    match end {
+        Some(end) => format!("bytes={}-{}", start, end),
+        None => format!("bytes={}-", start),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_content_range() {
+        let range = ContentRange::parse("bytes 10-19/100").unwrap();
+        assert_eq!(
+            range,
+            ContentRange {
+                start: 10,
+                end: 19,
+                total: Some(100)
+            }
+        );
+        assert_eq!(range.len(), 10);
+        assert!(!range.is_last());
+        assert!(ContentRange::parse("bytes 90-99/100").unwrap().is_last());
+        assert_eq!(ContentRange::parse("bytes 0-4/*").unwrap().total, None);
+
+        assert_eq!(ContentRange::parse("bytes */100"), None);
+        assert_eq!(ContentRange::parse("bytes 9-1/100"), None);
+        assert_eq!(ContentRange::parse("items 0-4/5"), None);
+
+        assert_eq!(range_header(0, Some(9)), "bytes=0-9");
+        assert_eq!(range_header(512, None), "bytes=512-");
+    }
+}
@@ -0,0 +1,143 @@
+//! Hook mapping human-readable hostnames to onion addresses (`set_onion_resolver`), the
+//! integration point for naming layers built on top of Tor
+use std::sync::{Arc, RwLock};
+
+use logger::log::debug;
+use once_cell::sync::OnceCell;
+use url::Url;
+
+use super::HttpRequestParams;
+use crate::{TorErrors, safe_logging};
+
+/// Length of a v3 onion service id (the part before ".onion")
+const ONION_V3_ID_LEN: usize = 56;
+
+/// Maps a hostname to the onion service it stands for
+/// Called for every request to a host that isn't already an onion, so the lookup should be
+/// quick (e.g. an in-memory map), fetch anything remote ahead of time
+pub trait OnionResolver: Send + Sync {
+    /// Onion address ("<56 chars>.onion") to connect to instead of `host`, `None` to
+    /// connect to `host` as is
+    fn resolve(&self, host: &str) -> Option<String>;
+}
+
+/// Resolves nothing, the default
+pub struct NoopResolver;
+
+impl OnionResolver for NoopResolver {
+    fn resolve(&self, _host: &str) -> Option<String> {
+        None
+    }
+}
+
+static RESOLVER: OnceCell<RwLock<Arc<dyn OnionResolver>>> = OnceCell::new();
+
+fn resolver() -> &'static RwLock<Arc<dyn OnionResolver>> {
+    RESOLVER.get_or_init(|| RwLock::new(Arc::new(NoopResolver)))
+}
+
+/// Makes `make_http_request*` and `pipeline` send requests for the hosts `resolver` knows to
+/// their onion address instead. The url host, and with it the Host header, is replaced, the
+/// scheme, port, path and query are kept. Applies process wide to requests made afterwards
+pub fn set_onion_resolver(resolver_impl: Arc<dyn OnionResolver>) {
+    *resolver().write().unwrap() = resolver_impl;
+}
+
+/// Goes back to the `NoopResolver`
+pub fn reset_onion_resolver() {
+    set_onion_resolver(Arc::new(NoopResolver));
+}
+
+/// `params` with its url pointed at the onion address the resolver gives for its host
+/// Errors with `TorErrors::InvalidConfig` when the resolver answers with something that
+/// isn't a v3 onion address, rather than sending the request elsewhere
+pub(crate) fn resolve_request(
+    mut params: HttpRequestParams,
+) -> Result<HttpRequestParams, TorErrors> {
+    let Ok(mut url) = Url::parse(&params.url) else {
+        return Ok(params);
+    };
+    let Some(host) = url.host_str().map(str::to_ascii_lowercase) else {
+        return Ok(params);
+    };
+    if host.ends_with(".onion") {
+        return Ok(params);
+    }
+    let resolved = resolver().read().unwrap().resolve(&host);
+    let Some(onion) = resolved else {
+        return Ok(params);
+    };
+    let onion = normalize_onion(&onion).ok_or_else(|| {
+        TorErrors::InvalidConfig(format!(
+            "Resolver returned an invalid onion address for {}: {}",
+            host, onion
+        ))
+    })?;
+    url.set_host(Some(&onion))
+        .map_err(|e| TorErrors::InvalidConfig(format!("Can't use {}: {}", onion, e)))?;
+    debug!(
+        "{}",
+        safe_logging::scrub(&format!("Resolved {} to {}", host, onion))
+    );
+    params.url = url.to_string();
+    Ok(params)
+}
+
+/// Lowercase "<id>.onion" when `onion` is a v3 onion address, with or without the suffix
+fn normalize_onion(onion: &str) -> Option<String> {
+    let onion = onion.trim().to_ascii_lowercase();
+    let id = onion.strip_suffix(".onion").unwrap_or(&onion);
+    let valid = id.len() == ONION_V3_ID_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || (b'2'..=b'7').contains(&b));
+    valid.then(|| format!("{}.onion", id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    struct MapResolver(HashMap<String, String>);
+
+    impl OnionResolver for MapResolver {
+        fn resolve(&self, host: &str) -> Option<String> {
+            self.0.get(host).cloned()
+        }
+    }
+
+    fn request(url: &str) -> HttpRequestParams {
+        HttpRequestParams {
+            url: url.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn resolves_names_to_onions() {
+        let onion = "a".repeat(ONION_V3_ID_LEN);
+        // Names no other test requests, the resolver is process wide
+        set_onion_resolver(Arc::new(MapResolver(HashMap::from([
+            ("wallet.resolver-test".to_string(), onion.clone()),
+            (
+                "broken.resolver-test".to_string(),
+                "not-an-onion".to_string(),
+            ),
+        ]))));
+
+        let resolved =
+            resolve_request(request("http://Wallet.resolver-test:8080/api?x=1")).unwrap();
+        assert_eq!(resolved.url, format!("http://{}.onion:8080/api?x=1", onion));
+        let unknown = resolve_request(request("https://other.resolver-test/")).unwrap();
+        assert_eq!(unknown.url, "https://other.resolver-test/");
+        assert!(matches!(
+            resolve_request(request("http://broken.resolver-test/")),
+            Err(TorErrors::InvalidConfig(_))
+        ));
+
+        reset_onion_resolver();
+        let reset = resolve_request(request("http://wallet.resolver-test/")).unwrap();
+        assert_eq!(reset.url, "http://wallet.resolver-test/");
+    }
+}
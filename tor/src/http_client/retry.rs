@@ -0,0 +1,241 @@
+//! Sending a request again when it is answered with a transient status, e.g. 429 from a
+//! rate-limited onion API (`make_http_request_with_retry`)
+use std::future::Future;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use logger::log::*;
+use serde::{Deserialize, Serialize};
+
+use super::{HttpRequestParams, HttpResponse, make_http_request_async};
+use crate::TorErrors;
+use crate::date::parse_http_date;
+
+/// When `make_http_request_with_retry` sends a request again
+/// Only responses with a status in `retry_on_status` are retried, a request that fails
+/// without a response (`Err`, or `HttpResponse::error` set) is returned as is
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Attempts in total, the first one included
+    pub max_attempts: u32,
+    /// Statuses worth another attempt, e.g. 429 Too Many Requests or 503 Service Unavailable
+    pub retry_on_status: Vec<u16>,
+    /// Wait before the second attempt, doubled for every further one up to `max_backoff_ms`
+    /// A `Retry-After` in the response replaces it, capped at `max_backoff_ms` as well so a
+    /// server can't hold the caller for longer
+    pub backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// Time for all attempts and the waits between them. A retry whose wait would end past
+    /// it isn't made, the last response is returned instead. Unlimited when unset
+    pub budget_ms: Option<u64>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            retry_on_status: vec![429, 503],
+            backoff_ms: 1000,
+            max_backoff_ms: 60_000,
+            budget_ms: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Wait after the `attempt`th attempt when the response has no usable `Retry-After`
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 1u64 << attempt.saturating_sub(1).min(20);
+        Duration::from_millis(
+            self.backoff_ms
+                .saturating_mul(factor)
+                .min(self.max_backoff_ms),
+        )
+    }
+}
+
+/// Makes the request like `make_http_request_async`, sending it again while the status is in
+/// `policy.retry_on_status` (see `RetryPolicy`). Returns the last response
+pub async fn make_http_request_with_retry_async(
+    params: HttpRequestParams,
+    socks_proxy: String,
+    policy: &RetryPolicy,
+) -> Result<HttpResponse, TorErrors> {
+    retry_with(params, policy, |params| {
+        make_http_request_async(params, socks_proxy.clone())
+    })
+    .await
+}
+
+/// Synchronous wrapper for make_http_request_with_retry_async
+pub fn make_http_request_with_retry(
+    params: HttpRequestParams,
+    socks_proxy: String,
+    policy: &RetryPolicy,
+) -> Result<HttpResponse, TorErrors> {
+    crate::runtime_handle().block_on(make_http_request_with_retry_async(
+        params,
+        socks_proxy,
+        policy,
+    ))
+}
+
+async fn retry_with<F, R>(
+    params: HttpRequestParams,
+    policy: &RetryPolicy,
+    mut send: F,
+) -> Result<HttpResponse, TorErrors>
+where
+    F: FnMut(HttpRequestParams) -> R,
+    R: Future<Output = Result<HttpResponse, TorErrors>>,
+{
+    let start = Instant::now();
+    let budget = policy.budget_ms.map(Duration::from_millis);
+    let mut attempt = 1;
+    loop {
+        let response = send(params.clone()).await?;
+        if attempt >= policy.max_attempts
+            || response.error.is_some()
+            || !policy.retry_on_status.contains(&response.status_code)
+        {
+            return Ok(response);
+        }
+        let wait = response
+            .headers
+            .get("retry-after")
+            .and_then(|value| parse_retry_after(value, SystemTime::now()))
+            .map(|wait| wait.min(Duration::from_millis(policy.max_backoff_ms)))
+            .unwrap_or_else(|| policy.backoff(attempt));
+        if budget.is_some_and(|budget| start.elapsed().saturating_add(wait) > budget) {
+            debug!(
+                "Not retrying status {}, waiting {:?} would exceed the budget",
+                response.status_code, wait
+            );
+            return Ok(response);
+        }
+        debug!(
+            "Status {} on attempt {} of {}, retrying in {:?}",
+            response.status_code, attempt, policy.max_attempts, wait
+        );
+        tokio::time::sleep(wait).await;
+        attempt += 1;
+    }
+}
+
+/// `Retry-After` as a wait from `now`: delay-seconds ("120") or an HTTP-date
+/// ("Sun, 06 Nov 1994 08:49:37 GMT"), a date already passed means no wait
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = parse_http_date(value)?;
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    /// Runs `retry_with` against canned (status, Retry-After) responses, returns the final
+    /// status and the number of requests sent
+    fn run(policy: &RetryPolicy, responses: &[(u16, Option<&str>)]) -> (u16, usize) {
+        let queue = Mutex::new(VecDeque::from(responses.to_vec()));
+        let sent = Mutex::new(0);
+        let response = crate::runtime_handle()
+            .block_on(retry_with(HttpRequestParams::default(), policy, |_| {
+                *sent.lock().unwrap() += 1;
+                let (status_code, retry_after) = queue.lock().unwrap().pop_front().unwrap();
+                let mut response = HttpResponse {
+                    status_code,
+                    ..Default::default()
+                };
+                if let Some(retry_after) = retry_after {
+                    response
+                        .headers
+                        .insert("retry-after".into(), retry_after.into());
+                }
+                async move { Ok(response) }
+            }))
+            .unwrap();
+        (response.status_code, sent.into_inner().unwrap())
+    }
+
+    #[test]
+    fn retries_listed_statuses() {
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            backoff_ms: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            run(&policy, &[(503, None), (429, Some("0")), (200, None)]),
+            (200, 3)
+        );
+        assert_eq!(run(&policy, &[(500, None)]), (500, 1));
+
+        let two_attempts = RetryPolicy {
+            max_attempts: 2,
+            ..policy.clone()
+        };
+        assert_eq!(run(&two_attempts, &[(503, None), (503, None)]), (503, 2));
+
+        let budget = RetryPolicy {
+            budget_ms: Some(1000),
+            ..policy
+        };
+        assert_eq!(run(&budget, &[(429, Some("120")), (200, None)]), (429, 1));
+        let huge = format!("{}", u64::MAX);
+        assert_eq!(run(&budget, &[(429, Some(&huge)), (200, None)]), (429, 1));
+
+        // Retry-After is capped at max_backoff_ms
+        let capped = RetryPolicy {
+            max_backoff_ms: 1,
+            budget_ms: Some(60_000),
+            ..budget
+        };
+        assert_eq!(run(&capped, &[(429, Some("120")), (200, None)]), (200, 2));
+    }
+
+    #[test]
+    fn parses_retry_after() {
+        let now = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(
+            parse_retry_after(" 120 ", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:50:07 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_retry_after("Sun, 06 Nov 1994 08:49:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            parse_retry_after("Sunday, 06-Nov-94 08:49:37 GMT", now),
+            None
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+        assert_eq!(
+            parse_retry_after("Fri, 31 Dec 99999999999999 23:59:59 GMT", now),
+            None
+        );
+    }
+
+    #[test]
+    fn doubles_backoff_up_to_the_cap() {
+        let policy = RetryPolicy {
+            backoff_ms: 500,
+            max_backoff_ms: 3000,
+            ..Default::default()
+        };
+        let waits: Vec<_> = (1..=5).map(|attempt| policy.backoff(attempt)).collect();
+        assert_eq!(
+            waits,
+            [500, 1000, 2000, 3000, 3000].map(Duration::from_millis)
+        );
+    }
+}
@@ -0,0 +1,106 @@
+//! Process wide cap on concurrent HTTP requests (`set_max_concurrent_requests`)
+//! Requests over the limit wait for a slot instead of piling more streams onto the circuits
+use std::sync::{Arc, Mutex};
+
+use once_cell::sync::OnceCell;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+static THROTTLE: OnceCell<Throttle> = OnceCell::new();
+
+fn throttle() -> &'static Throttle {
+    THROTTLE.get_or_init(Throttle::default)
+}
+
+/// Lets at most `max` requests made with `make_http_request*` / `make_raw_http_request*` run
+/// at once, the others are queued in order. 0 removes the limit, which is the default
+/// Requests already running when the limit changes keep their slot under the old limit
+pub fn set_max_concurrent_requests(max: usize) {
+    throttle().set_limit(max);
+}
+
+/// Current limit, 0 when unlimited
+pub fn max_concurrent_requests() -> usize {
+    throttle().limit()
+}
+
+/// Waits for a slot, held until the returned permit is dropped. `None` when unlimited
+pub(crate) async fn acquire() -> Option<OwnedSemaphorePermit> {
+    throttle().acquire().await
+}
+
+#[derive(Default)]
+struct Throttle {
+    /// The limit and the semaphore handing out its slots
+    semaphore: Mutex<Option<(usize, Arc<Semaphore>)>>,
+}
+
+impl Throttle {
+    fn set_limit(&self, max: usize) {
+        let max = max.min(Semaphore::MAX_PERMITS);
+        *self.semaphore.lock().unwrap() = (max > 0).then(|| (max, Arc::new(Semaphore::new(max))));
+    }
+
+    fn limit(&self) -> usize {
+        self.semaphore
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(0, |(max, _)| *max)
+    }
+
+    async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+        let semaphore = self.semaphore.lock().unwrap().as_ref()?.1.clone();
+        // The semaphore is never closed
+        semaphore.acquire_owned().await.ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// Runs `requests` tasks holding a slot for a few milliseconds, returns the most that ran
+    /// at the same time
+    fn peak_concurrency(throttle: Arc<Throttle>, requests: usize) -> usize {
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        crate::runtime_handle().block_on(async {
+            let tasks: Vec<_> = (0..requests)
+                .map(|_| {
+                    let (throttle, running, peak) =
+                        (throttle.clone(), running.clone(), peak.clone());
+                    tokio::spawn(async move {
+                        let _permit = throttle.acquire().await;
+                        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        peak.fetch_max(now, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(20)).await;
+                        running.fetch_sub(1, Ordering::SeqCst);
+                    })
+                })
+                .collect();
+            for task in tasks {
+                task.await.unwrap();
+            }
+        });
+        peak.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn bounds_concurrent_requests() {
+        let throttle = Arc::new(Throttle::default());
+        assert_eq!(throttle.limit(), 0);
+        assert!(peak_concurrency(throttle.clone(), 16) > 3);
+
+        throttle.set_limit(3);
+        assert_eq!(throttle.limit(), 3);
+        assert_eq!(peak_concurrency(throttle.clone(), 16), 3);
+
+        throttle.set_limit(1);
+        assert_eq!(peak_concurrency(throttle.clone(), 8), 1);
+
+        throttle.set_limit(0);
+        assert_eq!(throttle.limit(), 0);
+    }
+}
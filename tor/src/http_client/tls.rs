@@ -0,0 +1,89 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// TLS parameters of an HTTPS response
+/// Only what the session really reports: the platform TLS backend (native-tls) doesn't expose
+/// the protocol version, cipher or negotiated ALPN protocol of a session
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TlsInfo {
+    /// SHA-256 of the DER encoded leaf certificate, lowercase hex
+    pub peer_certificate_sha256: Option<String>,
+}
+
+/// Collects what the TLS session of `response` exposes, `None` for plain HTTP
+pub(crate) fn tls_info(response: &reqwest::Response) -> Option<TlsInfo> {
+    if response.url().scheme() != "https" {
+        return None;
+    }
+    let peer_certificate_sha256 = response
+        .extensions()
+        .get::<reqwest::tls::TlsInfo>()
+        .and_then(|info| info.peer_certificate())
+        .map(sha256_hex);
+    Some(TlsInfo {
+        peer_certificate_sha256,
+    })
+}
+
+/// Pins are hex SHA-256 fingerprints, case and ':' separators are ignored
+fn normalize_pin(pin: &str) -> String {
+    pin.chars()
+        .filter(|c| *c != ':' && !c.is_whitespace())
+        .collect::<String>()
+        .to_ascii_lowercase()
+}
+
+/// Fails unless the leaf certificate fingerprint matches one of `pins`
+pub(crate) fn check_pins(tls_info: Option<&TlsInfo>, pins: &[String]) -> Result<(), String> {
+    let fingerprint = tls_info
+        .and_then(|info| info.peer_certificate_sha256.as_deref())
+        .ok_or_else(|| String::from("Certificate pinning failed: no TLS peer certificate"))?;
+    if pins.iter().any(|pin| normalize_pin(pin) == fingerprint) {
+        Ok(())
+    } else {
+        Err(format!(
+            "Certificate pinning failed: {} matches none of the pins",
+            fingerprint
+        ))
+    }
+}
+
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_certificates() {
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn checks_certificate_pins() {
+        let info = TlsInfo {
+            peer_certificate_sha256: Some(sha256_hex(b"leaf certificate")),
+            ..Default::default()
+        };
+        let good = sha256_hex(b"leaf certificate").to_ascii_uppercase();
+        let bad = sha256_hex(b"forged certificate");
+        assert!(check_pins(Some(&info), &[bad.clone(), good]).is_ok());
+        assert!(check_pins(Some(&info), &[bad.clone()]).is_err());
+        assert!(check_pins(None, &[bad]).is_err());
+
+        let colons = "BA:78:16:BF:8F:01:CF:EA:41:41:40:DE:5D:AE:22:23:B0:03:61:A3:96:17:7A:9C:B4:10:FF:61:F2:00:15:AD";
+        let abc = TlsInfo {
+            peer_certificate_sha256: Some(sha256_hex(b"abc")),
+            ..Default::default()
+        };
+        assert!(check_pins(Some(&abc), &[colons.to_string()]).is_ok());
+    }
+}
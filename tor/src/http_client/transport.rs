@@ -0,0 +1,445 @@
+use std::collections::VecDeque;
+use std::io::{self, Cursor, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use socks::{Socks4Stream, Socks5Stream};
+
+use crate::TorErrors;
+
+/// A bidirectional byte stream handed out by a `TorTransport`
+pub trait ReadWrite: Read + Write + Send {
+    /// Second handle to the same stream so both directions can be pumped at once
+    fn try_clone_box(&self) -> io::Result<Box<dyn ReadWrite>>;
+}
+
+impl ReadWrite for TcpStream {
+    fn try_clone_box(&self) -> io::Result<Box<dyn ReadWrite>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+}
+
+/// How HTTP requests and TCP streams reach their target
+/// `SocksTransport` goes through the Tor SOCKS port, `StubTransport` serves canned responses
+/// so the HTTP handling can be tested without a running Tor
+/// Pipelines use `connect` directly. The HTTP client (`make_http_request_via`) and
+/// `TcpSocksStream::new_timeout_via` only speak SOCKS5, they connect through `socks_proxy`,
+/// which for anything but a plain SOCKS5 proxy is a `SocksBridge` handing every connection
+/// to `connect`
+pub trait TorTransport: Send + Sync {
+    /// Opens a stream to `target` (host:port)
+    fn connect(&self, target: &str) -> io::Result<Box<dyn ReadWrite>>;
+    /// SOCKS5 proxy (host:port) the HTTP client is pointed at, only valid while the
+    /// transport is alive
+    fn socks_proxy(&self) -> Result<String, TorErrors>;
+}
+
+/// SOCKS protocol spoken to the proxy
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SocksVersion {
+    /// What Tor speaks natively
+    #[default]
+    Socks5,
+    /// For intermediary proxies that only speak SOCKS4a. Hostnames are still sent to the
+    /// proxy unresolved, so DNS stays remote
+    Socks4a,
+    /// SOCKS5, retried with SOCKS4a when the proxy doesn't understand the SOCKS5 handshake
+    Socks5Fallback4a,
+}
+
+/// Connects through a Tor SOCKS proxy, the default transport
+pub struct SocksTransport {
+    socks_proxy: String,
+    version: SocksVersion,
+    bridge: OnceCell<SocksBridge>,
+}
+
+impl SocksTransport {
+    pub fn new(socks_proxy: String) -> SocksTransport {
+        SocksTransport::with_version(socks_proxy, SocksVersion::Socks5)
+    }
+
+    /// Transport speaking `version` to the proxy. The HTTP client only speaks SOCKS5, with
+    /// another version its requests go through a local bridge that connects with `version`
+    /// (circuit isolation credentials of the request are then not passed on)
+    pub fn with_version(socks_proxy: String, version: SocksVersion) -> SocksTransport {
+        SocksTransport {
+            socks_proxy,
+            version,
+            bridge: OnceCell::new(),
+        }
+    }
+}
+
+impl TorTransport for SocksTransport {
+    fn connect(&self, target: &str) -> io::Result<Box<dyn ReadWrite>> {
+        socks_connect(&self.socks_proxy, self.version, target)
+    }
+    fn socks_proxy(&self) -> Result<String, TorErrors> {
+        if self.version == SocksVersion::Socks5 {
+            return Ok(self.socks_proxy.clone());
+        }
+        let (socks_proxy, version) = (self.socks_proxy.clone(), self.version);
+        self.bridge
+            .get_or_try_init(|| {
+                let bridge =
+                    start_socks_bridge(move |target| socks_connect(&socks_proxy, version, target))?;
+                // Its connections go through socks_connect, which applies the kill switch
+                crate::killswitch::mark_bridge(bridge.port);
+                Ok(bridge)
+            })
+            .map(|bridge| bridge.address().to_string())
+            .map_err(TorErrors::IoError)
+    }
+}
+
+/// Connects to `target` through `socks_proxy`, refused with `PermissionDenied` when the kill
+/// switch blocks the proxy
+fn socks_connect(
+    socks_proxy: &str,
+    version: SocksVersion,
+    target: &str,
+) -> io::Result<Box<dyn ReadWrite>> {
+    crate::killswitch::check(socks_proxy)
+        .map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, e.to_string()))?;
+    let socks4a = || -> io::Result<Box<dyn ReadWrite>> {
+        // A domain target makes the socks crate send a SOCKS4a request
+        let stream = Socks4Stream::connect(socks_proxy, target, "")?;
+        Ok(Box::new(stream.into_inner()))
+    };
+    if version == SocksVersion::Socks4a {
+        return socks4a();
+    }
+    match Socks5Stream::connect(socks_proxy, target) {
+        Ok(stream) => Ok(Box::new(stream.into_inner())),
+        // Only a proxy failing the handshake is retried, not a proxy refusing the target
+        Err(e)
+            if version == SocksVersion::Socks5Fallback4a
+                && matches!(
+                    e.kind(),
+                    io::ErrorKind::InvalidData
+                        | io::ErrorKind::UnexpectedEof
+                        | io::ErrorKind::ConnectionReset
+                        | io::ErrorKind::ConnectionAborted
+                ) =>
+        {
+            socks4a()
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// In memory transport for tests: every connection is answered with the next canned response
+/// once the request head has arrived, and what the client sent is recorded
+/// No network or Tor involved
+#[derive(Default)]
+pub struct StubTransport {
+    state: Arc<StubState>,
+    bridge: OnceCell<SocksBridge>,
+}
+
+#[derive(Default)]
+struct StubState {
+    responses: Mutex<VecDeque<Vec<u8>>>,
+    requests: Mutex<Vec<Arc<Written>>>,
+}
+
+impl StubState {
+    fn connect(&self) -> io::Result<Box<dyn ReadWrite>> {
+        let response = self.responses.lock().unwrap().pop_front().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::ConnectionRefused, "No canned response left")
+        })?;
+        let written = Arc::new(Written::default());
+        self.requests.lock().unwrap().push(written.clone());
+        Ok(Box::new(StubStream {
+            response: Arc::new(Mutex::new(Cursor::new(response))),
+            written,
+        }))
+    }
+}
+
+impl StubTransport {
+    pub fn new() -> StubTransport {
+        StubTransport::default()
+    }
+
+    /// Queues the raw bytes (status line, headers, body) sent back on the next connection
+    pub fn push_response(&self, response: impl Into<Vec<u8>>) {
+        self.state
+            .responses
+            .lock()
+            .unwrap()
+            .push_back(response.into());
+    }
+
+    /// Raw bytes written by the client, one entry per connection
+    pub fn requests(&self) -> Vec<Vec<u8>> {
+        self.state
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|written| written.bytes.lock().unwrap().clone())
+            .collect()
+    }
+}
+
+impl TorTransport for StubTransport {
+    fn connect(&self, _target: &str) -> io::Result<Box<dyn ReadWrite>> {
+        self.state.connect()
+    }
+    fn socks_proxy(&self) -> Result<String, TorErrors> {
+        let state = self.state.clone();
+        self.bridge
+            .get_or_try_init(|| start_socks_bridge(move |_| state.connect()))
+            .map(|bridge| bridge.address().to_string())
+            .map_err(TorErrors::IoError)
+    }
+}
+
+/// Bytes written by the client, readers wait on `head_done` for the request head
+#[derive(Default)]
+struct Written {
+    bytes: Mutex<Vec<u8>>,
+    head_done: Condvar,
+}
+
+struct StubStream {
+    response: Arc<Mutex<Cursor<Vec<u8>>>>,
+    written: Arc<Written>,
+}
+
+impl Read for StubStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes = self.written.bytes.lock().unwrap();
+        let _bytes = self
+            .written
+            .head_done
+            .wait_while(bytes, |b| !b.windows(4).any(|w| w == b"\r\n\r\n"))
+            .unwrap();
+        self.response.lock().unwrap().read(buf)
+    }
+}
+
+impl Write for StubStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.written.bytes.lock().unwrap().extend_from_slice(buf);
+        self.written.head_done.notify_all();
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ReadWrite for StubStream {
+    fn try_clone_box(&self) -> io::Result<Box<dyn ReadWrite>> {
+        Ok(Box::new(StubStream {
+            response: self.response.clone(),
+            written: self.written.clone(),
+        }))
+    }
+}
+
+/// Loopback SOCKS5 server started by `start_socks_bridge`
+/// Stops accepting connections once dropped, connections already bridged run until either
+/// side closes them
+pub struct SocksBridge {
+    address: String,
+    port: u16,
+    stop: Arc<AtomicBool>,
+}
+
+impl SocksBridge {
+    /// Address to use as SOCKS proxy
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+}
+
+impl Drop for SocksBridge {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        crate::killswitch::unmark_bridge(self.port);
+        // Wakes the accept loop up so it sees `stop`
+        let _ = TcpStream::connect(&self.address);
+    }
+}
+
+/// Runs a loopback SOCKS5 server (no auth, CONNECT only) that hands each connection to
+/// `connect` and pumps bytes both ways, lets any `TorTransport` sit behind the HTTP client
+pub fn start_socks_bridge<C>(connect: C) -> io::Result<SocksBridge>
+where
+    C: Fn(&str) -> io::Result<Box<dyn ReadWrite>> + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let local = listener.local_addr()?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let connect = Arc::new(connect);
+    let stopped = stop.clone();
+    std::thread::spawn(move || {
+        for client in listener.incoming().flatten() {
+            if stopped.load(Ordering::SeqCst) {
+                break;
+            }
+            let connect = connect.clone();
+            std::thread::spawn(move || {
+                let _ = bridge_connection(client, connect.as_ref());
+            });
+        }
+    });
+    Ok(SocksBridge {
+        address: local.to_string(),
+        port: local.port(),
+        stop,
+    })
+}
+
+fn bridge_connection<C>(mut client: TcpStream, connect: &C) -> io::Result<()>
+where
+    C: Fn(&str) -> io::Result<Box<dyn ReadWrite>>,
+{
+    let target = socks5_accept(&mut client)?;
+    let upstream = match connect(&target) {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            // 0x05: connection refused
+            client.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0])?;
+            return Err(e);
+        }
+    };
+    client.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])?;
+
+    let mut upstream_writer = upstream.try_clone_box()?;
+    let mut client_reader = client.try_clone()?;
+    std::thread::spawn(move || {
+        let _ = io::copy(&mut client_reader, &mut upstream_writer);
+    });
+    let mut upstream_reader = upstream;
+    io::copy(&mut upstream_reader, &mut client)?;
+    // Only signal EOF, the pump thread keeps the socket open until the client is done writing
+    client.shutdown(Shutdown::Write)
+}
+
+/// Server side of the SOCKS5 handshake, returns the requested host:port
+pub(crate) fn socks5_accept(client: &mut TcpStream) -> io::Result<String> {
+    let mut greeting = [0u8; 2];
+    client.read_exact(&mut greeting)?;
+    let mut methods = vec![0u8; greeting[1] as usize];
+    client.read_exact(&mut methods)?;
+    if methods.contains(&2) {
+        // Username/password carry circuit isolation only, accept anything
+        client.write_all(&[5, 2])?;
+        let mut version_and_len = [0u8; 2];
+        client.read_exact(&mut version_and_len)?;
+        let mut username = vec![0u8; version_and_len[1] as usize];
+        client.read_exact(&mut username)?;
+        let mut password_len = [0u8; 1];
+        client.read_exact(&mut password_len)?;
+        let mut password = vec![0u8; password_len[0] as usize];
+        client.read_exact(&mut password)?;
+        client.write_all(&[1, 0])?;
+    } else {
+        client.write_all(&[5, 0])?;
+    }
+
+    let mut request = [0u8; 4];
+    client.read_exact(&mut request)?;
+    let host = match request[3] {
+        1 => {
+            let mut ip = [0u8; 4];
+            client.read_exact(&mut ip)?;
+            std::net::Ipv4Addr::from(ip).to_string()
+        }
+        3 => {
+            let mut len = [0u8; 1];
+            client.read_exact(&mut len)?;
+            let mut name = vec![0u8; len[0] as usize];
+            client.read_exact(&mut name)?;
+            String::from_utf8_lossy(&name).into_owned()
+        }
+        4 => {
+            let mut ip = [0u8; 16];
+            client.read_exact(&mut ip)?;
+            format!("[{}]", std::net::Ipv6Addr::from(ip))
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unsupported SOCKS address type",
+            ));
+        }
+    };
+    let mut port = [0u8; 2];
+    client.read_exact(&mut port)?;
+    Ok(format!("{}:{}", host, u16::from_be_bytes(port)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Proxy whose first connection fails the SOCKS5 greeting (when `reject_socks5`), then
+    /// answers SOCKS4a requests with "granted" and returns the requested host:port
+    fn socks4a_proxy(reject_socks5: bool) -> (String, std::thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || {
+            if reject_socks5 {
+                let (mut conn, _) = listener.accept().unwrap();
+                let mut greeting = [0u8; 3];
+                conn.read_exact(&mut greeting).unwrap();
+                assert_eq!(greeting[0], 5);
+            }
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut head = [0u8; 8];
+            conn.read_exact(&mut head).unwrap();
+            // VN 4, CD 1 (connect), then 0.0.0.x: the host follows the user id
+            assert_eq!((head[0], head[1], &head[4..7]), (4, 1, &[0u8, 0, 0][..]));
+            assert_ne!(head[7], 0);
+            let mut rest = Vec::new();
+            let mut byte = [0u8; 1];
+            while rest.iter().filter(|b| **b == 0).count() < 2 {
+                conn.read_exact(&mut byte).unwrap();
+                rest.push(byte[0]);
+            }
+            conn.write_all(&[0, 0x5a, 0, 0, 0, 0, 0, 0]).unwrap();
+            let host = String::from_utf8_lossy(
+                &rest[rest.iter().position(|b| *b == 0).unwrap() + 1..rest.len() - 1],
+            )
+            .into_owned();
+            format!("{}:{}", host, u16::from_be_bytes([head[2], head[3]]))
+        });
+        (address, server)
+    }
+
+    #[test]
+    fn connects_with_socks4a() {
+        let (proxy, server) = socks4a_proxy(false);
+        let transport = SocksTransport::with_version(proxy, SocksVersion::Socks4a);
+        transport.connect("example.onion:80").unwrap();
+        assert_eq!(server.join().unwrap(), "example.onion:80");
+
+        let (proxy, server) = socks4a_proxy(true);
+        let transport = SocksTransport::with_version(proxy, SocksVersion::Socks5Fallback4a);
+        transport.connect("example.com:443").unwrap();
+        assert_eq!(server.join().unwrap(), "example.com:443");
+    }
+
+    #[test]
+    fn stops_the_bridge_on_drop() {
+        let bridge = start_socks_bridge(|_| Err(io::ErrorKind::ConnectionRefused.into())).unwrap();
+        let address = bridge.address().to_string();
+        assert!(TcpStream::connect(&address).is_ok());
+
+        drop(bridge);
+        let started = std::time::Instant::now();
+        while TcpStream::connect(&address).is_ok() {
+            assert!(started.elapsed() < std::time::Duration::from_secs(5));
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+}
@@ -0,0 +1,180 @@
+//! Checks on caller supplied request parts before anything is sent
+//! `pipeline` writes the request text itself, a CR or LF in a header would end the header
+//! there and let the rest of the value add headers or whole requests (request smuggling)
+use std::net::Ipv6Addr;
+
+use url::Url;
+
+use super::HttpRequestParams;
+use crate::TorErrors;
+
+/// Rejects header names that aren't an RFC 9110 token, header values (including `accept`)
+/// holding CR, LF or NUL, and urls that aren't plain http(s) urls
+/// The method needs no check, `HttpMethod` only has valid ones
+pub(crate) fn validate_request(params: &HttpRequestParams) -> Result<(), TorErrors> {
+    validate_url(&params.url)?;
+    for (name, value) in params.headers.iter().flatten() {
+        validate_header(name, value)?;
+    }
+    if let Some(accept) = &params.accept {
+        validate_header("Accept", accept)?;
+    }
+    if let Some(tunnel_target) = &params.tunnel_target {
+        validate_authority(tunnel_target)?;
+    }
+    Ok(())
+}
+
+/// host:port of a CONNECT tunnel: a hostname, IPv4 or bracketed IPv6 address and a port,
+/// nothing that could end the request line
+pub(crate) fn validate_authority(target: &str) -> Result<(), TorErrors> {
+    let invalid = || TorErrors::InvalidHeader(format!("Invalid host:port {:?}", target));
+    let (host, port) = target.rsplit_once(':').ok_or_else(invalid)?;
+    let host_ok = match host.strip_prefix('[').and_then(|ip| ip.strip_suffix(']')) {
+        Some(ip) => ip.parse::<Ipv6Addr>().is_ok(),
+        None => {
+            !host.is_empty()
+                && host
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_'))
+        }
+    };
+    let port_ok =
+        port.bytes().all(|b| b.is_ascii_digit()) && port.parse::<u16>().is_ok_and(|port| port != 0);
+    if host_ok && port_ok {
+        Ok(())
+    } else {
+        Err(invalid())
+    }
+}
+
+fn validate_header(name: &str, value: &str) -> Result<(), TorErrors> {
+    let name = name.trim();
+    if name.is_empty() || !name.bytes().all(is_token_char) {
+        return Err(TorErrors::InvalidHeader(format!(
+            "Invalid header name {:?}",
+            name
+        )));
+    }
+    if value.bytes().any(|b| matches!(b, b'\r' | b'\n' | b'\0')) {
+        return Err(TorErrors::InvalidHeader(format!(
+            "Header {} has a CR, LF or NUL in its value",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// The url parser silently drops tabs and newlines, they are refused here instead
+fn validate_url(url: &str) -> Result<(), TorErrors> {
+    if url.bytes().any(|b| b.is_ascii_control() || b == b' ') {
+        return Err(TorErrors::InvalidHeader(format!(
+            "Url {:?} has whitespace or control characters",
+            url
+        )));
+    }
+    let parsed = Url::parse(url)
+        .map_err(|e| TorErrors::InvalidHeader(format!("Invalid url {}: {}", url, e)))?;
+    if !matches!(parsed.scheme(), "http" | "https") || parsed.host_str().is_none() {
+        return Err(TorErrors::InvalidHeader(format!(
+            "Only http:// and https:// urls with a host are supported, got {}",
+            url
+        )));
+    }
+    Ok(())
+}
+
+/// tchar of RFC 9110 5.6.2
+fn is_token_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_header(name: &str, value: &str) -> HttpRequestParams {
+        HttpRequestParams {
+            url: "http://example.onion/".into(),
+            headers: Some(vec![(name.into(), value.into())]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rejects_header_injection() {
+        assert!(validate_request(&with_header("X-Token", "abc")).is_ok());
+        for (name, value) in [
+            ("X-Token", "abc\r\nX-Admin: 1"),
+            ("X-Token", "abc\nX-Admin: 1"),
+            ("X-Token", "abc\0"),
+            (
+                "X-Token",
+                "1\r\n\r\nGET /admin HTTP/1.1\r\nHost: example.onion",
+            ),
+            ("X-Token: a\r\nX-Admin", "1"),
+            ("X Token", "abc"),
+            ("", "abc"),
+        ] {
+            match validate_request(&with_header(name, value)) {
+                Err(TorErrors::InvalidHeader(_)) => {}
+                other => panic!("{:?}: {:?} was accepted: {:?}", name, value, other),
+            }
+        }
+        let accept = HttpRequestParams {
+            accept: Some("text/html\r\nCookie: a=b".into()),
+            ..with_header("X-Token", "abc")
+        };
+        assert!(validate_request(&accept).is_err());
+    }
+
+    #[test]
+    fn checks_tunnel_targets() {
+        for valid in [
+            "example.com:443",
+            "10.0.0.1:22",
+            "[::1]:8080",
+            "abc.onion:80",
+        ] {
+            assert!(validate_authority(valid).is_ok(), "{}", valid);
+        }
+        for invalid in [
+            "example.com",
+            "example.com:",
+            "example.com:0",
+            "example.com:+80",
+            "example.com:65536",
+            ":443",
+            "[::1:443",
+            "example.com:443\r\nX-Admin: 1",
+            "example.com:443 HTTP/1.0",
+            "user@example.com:443",
+        ] {
+            assert!(
+                matches!(
+                    validate_authority(invalid),
+                    Err(TorErrors::InvalidHeader(_))
+                ),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_bad_urls() {
+        for url in [
+            "http://example.onion/a\r\nX-Admin: 1",
+            "http://example.onion/a b",
+            "ftp://example.onion/",
+            "not a url",
+            "example.onion/path",
+        ] {
+            let params = HttpRequestParams {
+                url: url.into(),
+                ..Default::default()
+            };
+            assert!(validate_request(&params).is_err(), "{:?} was accepted", url);
+        }
+    }
+}
@@ -0,0 +1,142 @@
+//! Kill switch: when enabled, connections made by this crate (HTTP requests, SOCKS streams,
+//! pipelines) are refused unless they go through the SOCKS port of a bootstrapped
+//! `OwnedTorService` of this process
+//! Guards against a request slipping out while Tor is still bootstrapping or after it went
+//! away, and against a misconfigured proxy (`socks_override`, a stale port) that isn't Tor
+//! The local SOCKS bridges of `SocksTransport` (SOCKS4a proxies) are let through, every
+//! connection they forward is checked against the real proxy
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use once_cell::sync::OnceCell;
+
+use crate::TorErrors;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// SOCKS ports of the bootstrapped services still running
+static READY_PORTS: OnceCell<Mutex<HashSet<u16>>> = OnceCell::new();
+
+/// Ports of the loopback bridges started by `SocksTransport`, they check each connection
+static BRIDGE_PORTS: OnceCell<Mutex<HashSet<u16>>> = OnceCell::new();
+
+fn ready_ports() -> &'static Mutex<HashSet<u16>> {
+    READY_PORTS.get_or_init(|| Mutex::new(HashSet::new()))
+}
+
+fn bridge_ports() -> &'static Mutex<HashSet<u16>> {
+    BRIDGE_PORTS.get_or_init(|| Mutex::new(HashSet::new()))
+}
+
+/// Turns the kill switch on or off for the whole process, it is off by default
+pub fn set_killswitch(enabled: bool) {
+    ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+pub fn killswitch_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Called once a service finished bootstrapping
+pub(crate) fn mark_ready(socks_port: u16) {
+    ready_ports().lock().unwrap().insert(socks_port);
+}
+
+/// Called when a service shuts down
+pub(crate) fn mark_down(socks_port: u16) {
+    ready_ports().lock().unwrap().remove(&socks_port);
+}
+
+/// Called for a loopback bridge whose connect function runs `check` on the real proxy
+pub(crate) fn mark_bridge(port: u16) {
+    bridge_ports().lock().unwrap().insert(port);
+}
+
+/// Called when a bridge stops, its port may be reused by anything
+pub(crate) fn unmark_bridge(port: u16) {
+    bridge_ports().lock().unwrap().remove(&port);
+}
+
+/// Errors with `TorErrors::BlockedByKillSwitch` when the kill switch is on and `socks_proxy`
+/// (host:port) is neither the SOCKS port of a bootstrapped service nor a checking bridge
+pub(crate) fn check(socks_proxy: &str) -> Result<(), TorErrors> {
+    if !killswitch_enabled() {
+        return Ok(());
+    }
+    let ready = ready_ports().lock().unwrap();
+    let bridges = bridge_ports().lock().unwrap();
+    check_proxy(socks_proxy, &ready, &bridges)
+}
+
+fn check_proxy(
+    socks_proxy: &str,
+    ready: &HashSet<u16>,
+    bridges: &HashSet<u16>,
+) -> Result<(), TorErrors> {
+    let Some((host, port)) = socks_proxy.rsplit_once(':') else {
+        return Err(blocked(format!(
+            "{} is not a SOCKS proxy address",
+            socks_proxy
+        )));
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let loopback = host == "localhost" || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback());
+    match port.parse::<u16>() {
+        Ok(port) if loopback && (ready.contains(&port) || bridges.contains(&port)) => Ok(()),
+        _ if ready.is_empty() => Err(blocked(String::from("Tor is not bootstrapped"))),
+        _ => Err(blocked(format!(
+            "{} is not the SOCKS port of a running Tor service",
+            socks_proxy
+        ))),
+    }
+}
+
+fn blocked(reason: String) -> TorErrors {
+    TorErrors::BlockedByKillSwitch(reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_lets_bootstrapped_socks_ports_through() {
+        let mut ready = HashSet::new();
+        let bridges = HashSet::from([41000]);
+        assert!(matches!(
+            check_proxy("127.0.0.1:19050", &ready, &bridges),
+            Err(TorErrors::BlockedByKillSwitch(reason)) if reason.contains("not bootstrapped")
+        ));
+
+        ready.insert(19050);
+        for allowed in [
+            "127.0.0.1:19050",
+            "localhost:19050",
+            "[::1]:19050",
+            "127.0.0.1:41000",
+        ] {
+            assert!(
+                check_proxy(allowed, &ready, &bridges).is_ok(),
+                "{}",
+                allowed
+            );
+        }
+        for blocked in [
+            "127.0.0.1:9050",
+            "192.0.2.1:19050",
+            "example.com:19050",
+            "192.0.2.1:41000",
+            "19050",
+        ] {
+            assert!(
+                matches!(
+                    check_proxy(blocked, &ready, &bridges),
+                    Err(TorErrors::BlockedByKillSwitch(_))
+                ),
+                "{}",
+                blocked
+            );
+        }
+    }
+}
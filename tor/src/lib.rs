@@ -1,20 +1,39 @@
+pub mod bootstrap;
+pub mod cache;
+pub mod control;
+mod date;
+pub mod dns;
 pub mod hidden_service;
 pub mod http_client;
+pub mod killswitch;
+pub mod prelude;
+mod priority;
+mod process;
+pub mod safe_logging;
 pub mod tcp_stream;
+pub mod torrc;
+pub mod vanity;
+use bootstrap::CancelToken;
+use control::{CircuitInfo, ControlConn, GuardInfo, HsDescriptor, RelayStatus};
+use process::TorProcess;
 use futures::Future;
 use libtor::{Tor, TorAddress, TorFlag};
 use logger::log::*;
 use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
-use std::cell::RefCell;
+use socks::Socks5Stream;
+use std::cell::{Cell, RefCell};
 use std::convert::{TryFrom, TryInto};
+use std::fmt;
 use std::fs;
 use std::io;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Mutex;
-use std::thread::JoinHandle;
+use std::time::Instant;
+use tempfile::TempDir;
 use thiserror::Error;
 use tokio::net::TcpStream;
 use tokio::task::JoinError;
@@ -33,10 +52,57 @@ type G = AuthenticatedConn<TcpStream, F>;
 // Replace lazy_static with once_cell for better initialization control
 static RUNTIME: OnceCell<Mutex<tokio::runtime::Runtime>> = OnceCell::new();
 
+/// Worker threads of the shared runtime when neither `set_runtime_worker_threads` nor
+/// `WORKER_THREADS_ENV` set them. Requests mostly wait on the network and blocking calls run
+/// on the caller's thread, so two are enough for a phone app. Raise it for servers making
+/// many concurrent requests or serving busy onion services, each thread costs a stack
+/// (2 MiB reserved) and wakeups
+pub const DEFAULT_WORKER_THREADS: usize = 2;
+
+/// Environment variable with the worker thread count, e.g. `SIFIR_TOR_WORKER_THREADS=8`
+pub const WORKER_THREADS_ENV: &str = "SIFIR_TOR_WORKER_THREADS";
+
+/// Set by `set_runtime_worker_threads`, 0 when unset
+static WORKER_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets the worker thread count of the shared runtime, taking precedence over
+/// `WORKER_THREADS_ENV`. The runtime is built on first use (`ensure_runtime`, any service
+/// or HTTP call) and keeps its threads, so this errors once it is running
+pub fn set_runtime_worker_threads(threads: usize) -> Result<(), TorErrors> {
+    if threads == 0 {
+        return Err(TorErrors::InvalidConfig(String::from(
+            "The runtime needs at least one worker thread",
+        )));
+    }
+    if RUNTIME.get().is_some() {
+        return Err(TorErrors::InvalidConfig(String::from(
+            "The runtime is already running, set its worker threads before using the library",
+        )));
+    }
+    WORKER_THREADS.store(threads, Ordering::SeqCst);
+    Ok(())
+}
+
+/// `set_runtime_worker_threads`, else a valid `WORKER_THREADS_ENV`, else the default
+fn worker_threads(configured: usize, env: Option<String>) -> usize {
+    if configured > 0 {
+        return configured;
+    }
+    env.and_then(|threads| threads.trim().parse().ok())
+        .filter(|threads| *threads > 0)
+        .unwrap_or(DEFAULT_WORKER_THREADS)
+}
+
 pub fn ensure_runtime() -> &'static Mutex<tokio::runtime::Runtime> {
     RUNTIME.get_or_init(|| {
+        let threads = worker_threads(
+            WORKER_THREADS.load(Ordering::SeqCst),
+            std::env::var(WORKER_THREADS_ENV).ok(),
+        );
+        debug!("Starting runtime with {} worker threads", threads);
         Mutex::new(
             tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(threads)
                 .max_blocking_threads(num_cpus::get() / 2)
                 .thread_name_fn(|| {
                     static ATOMIC_ID: AtomicUsize = AtomicUsize::new(0);
@@ -52,12 +118,96 @@ pub fn ensure_runtime() -> &'static Mutex<tokio::runtime::Runtime> {
     })
 }
 
+/// Handle to the shared runtime, blocking on it does not hold the runtime lock
+/// so long running work (e.g. HTTP requests) doesn't stall other callers
+pub fn runtime_handle() -> tokio::runtime::Handle {
+    ensure_runtime().lock().unwrap().handle().clone()
+}
+
 #[repr(C)]
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
 pub struct TorServiceParam {
     pub socks_port: Option<u16>,
     pub data_dir: String,
     pub bootstrap_timeout_ms: Option<u64>,
+    /// UseEntryGuards, on by default in Tor. Guards are kept in the data dir so the same few
+    /// relays stay first hop across restarts, without them every circuit picks a new first
+    /// hop and a hostile relay will sooner or later see the client's IP. Only disable for
+    /// testing or when the network path is already anonymized (e.g. a VPN you trust)
+    pub use_entry_guards: Option<bool>,
+    /// EntryNodes: restricts guards to these relays (fingerprints, nicknames, `{cc}` country
+    /// codes or IPv4 ranges, see `torrc::is_valid_node_spec`). A small pinned set makes the
+    /// client easier to recognize and to block, prefer leaving it unset
+    pub entry_nodes: Option<Vec<String>>,
+    /// NumEntryGuards (1 to 10): guards used in parallel, unset follows the consensus (1)
+    pub num_entry_guards: Option<u8>,
+    /// Set to false to stop Tor building circuits ahead of time for the ports it predicts
+    /// will be used (`__DisablePredictedCircuits`). Saves bandwidth, battery and CPU on
+    /// constrained devices, but every request then waits for a circuit to be built first,
+    /// typically adding a few hundred milliseconds to a few seconds
+    pub predicted_circuits: Option<bool>,
+    /// Runs Tor on a lower priority thread so bootstrap and relay crypto yield to the UI
+    /// (nice 10 on Linux/Android, utility QoS on Apple platforms, below normal on Windows)
+    /// Only applies to the embedded Tor, not to `tor_binary`
+    pub low_priority: Option<bool>,
+    /// When the SOCKS port is already taken (e.g. by a Tor left over from a crash) let Tor pick
+    /// a free one instead of failing with `TorErrors::SocksPortInUse`, the service's
+    /// `socks_port` tells which
+    pub auto_socks_port: Option<bool>,
+    /// Runs this `tor` executable as a child process instead of the linked libtor, e.g. for a
+    /// newer or custom build. It must exist and be executable, it is configured and
+    /// controlled exactly like the embedded Tor
+    pub tor_binary: Option<PathBuf>,
+    /// Starts from the consensus cached in the data dir by a previous run when it is still
+    /// valid, bootstrap then skips fetching the directory. An expired consensus is deleted
+    /// instead of trusted. `OwnedTorService::is_warm_start` tells which way it went
+    pub warm_start: Option<bool>,
+    /// DNSPort: Tor also answers plain UDP DNS queries on 127.0.0.1 at this port, resolved
+    /// through an exit like SOCKS hostnames are. Lets code that can't speak SOCKS look names
+    /// up without leaking them to the local resolver, see `OwnedTorService::resolve_via_dns_port`
+    /// Only A/AAAA/PTR lookups are answered and .onion names never resolve
+    pub dns_port: Option<u16>,
+    /// TransPort: Tor accepts connections redirected to 127.0.0.1 at this port by the
+    /// firewall and forwards them to their original destination, so apps with no proxy
+    /// support go through Tor too. Only useful as root with matching rules (iptables
+    /// `REDIRECT --to-ports`, usually together with `dns_port` for DNS), and only on
+    /// Linux, the BSDs and macOS
+    pub trans_port: Option<u16>,
+    /// SafeLogging: whether Tor replaces addresses in its own log with `[scrubbed]`, on by
+    /// default in Tor. This crate's log lines are scrubbed separately with
+    /// `safe_logging::set_safe_logging`
+    pub safe_logging: Option<bool>,
+    /// Environment variables added to the `tor_binary` child's (e.g. `LANG`, `TZ`, or
+    /// `http_proxy` for a pluggable transport), not available for the embedded Tor
+    pub tor_env: Option<Vec<(String, String)>>,
+    /// Starts the `tor_binary` child with `Sandbox 1`: a seccomp filter limits the syscalls
+    /// and files Tor can use, so a compromised Tor can do much less. Linux only and the
+    /// binary must be built with seccomp support, otherwise it fails to start. The embedded
+    /// Tor shares this process and can't be sandboxed
+    pub sandbox: Option<bool>,
+    /// DirAuthority lines (without the option name) of a private Tor network, e.g. one run
+    /// with chutney for integration tests, replacing the public authorities. Validated with
+    /// `torrc::is_valid_dir_authority`
+    pub dir_authorities: Option<Vec<String>>,
+    /// FallbackDir lines (without the option name), directory mirrors tried before the
+    /// authorities while bootstrapping. Validated with `torrc::is_valid_fallback_dir`
+    pub fallback_dirs: Option<Vec<String>>,
+    /// TestingTorNetwork: relaxes timings and checks for small private networks, needs
+    /// `dir_authorities`. Never use it on the public network
+    pub testing_tor_network: Option<bool>,
+    /// Temp directory created by `TorServiceParam::ephemeral` as `data_dir`, deleted once the
+    /// service has shut down (`OwnedTorService::shutdown` or drop) or failed to start
+    /// Can't be set from JSON and only `ephemeral` creates one, a caller supplied `data_dir`
+    /// is never deleted
+    #[serde(skip)]
+    pub ephemeral: Option<EphemeralDir>,
+    /// Once bootstrapped, restricts exits to the fastest quarter of the consensus (see
+    /// `OwnedTorService::prefer_fast_exits`) for more throughput, e.g. for large downloads
+    /// Tor already favors fast relays in proportion to their bandwidth, this goes further at
+    /// the cost of anonymity: fewer exits see more of the client's traffic, and a client using
+    /// only top exits stands out. Onion service circuits are not affected
+    pub prefer_fast_exits: Option<bool>,
 }
 
 impl TorServiceParam {
@@ -66,35 +216,178 @@ impl TorServiceParam {
             data_dir: String::from(data_dir),
             socks_port: Some(socks_port),
             bootstrap_timeout_ms: Some(bootstap_timeout_ms),
+            ..Default::default()
         }
     }
+
+    /// Params for a throwaway service: a new directory under the system temp dir that only the
+    /// current user can access as `data_dir`, deleted again on shutdown, and a free SOCKS port
+    /// Nothing (guards, consensus) carries over between runs so every start bootstraps from
+    /// scratch. Meant for tests running in parallel and for sessions that leave no state behind
+    pub fn ephemeral() -> Result<TorServiceParam, TorErrors> {
+        let dir = EphemeralDir::create()?;
+        Ok(TorServiceParam {
+            data_dir: dir.0.path().to_string_lossy().into_owned(),
+            auto_socks_port: Some(true),
+            ephemeral: Some(dir),
+            ..Default::default()
+        })
+    }
+
+    /// Parses a JSON config, e.g. `{"data_dir": "/tmp/tor", "socks_port": 19050}`
+    /// Unknown fields are rejected so typos don't silently fall back to defaults
+    pub fn from_json(config: &str) -> Result<TorServiceParam, TorErrors> {
+        serde_json::from_str(config).map_err(|e| TorErrors::InvalidConfig(e.to_string()))
+    }
 }
 
 pub struct TorService {
     socks_port: u16,
     control_port: String,
     bootstrap_timeout_ms: u64,
-    _handle: Option<JoinHandle<Result<u8, libtor::Error>>>,
+    /// Options from `torrc::options` still to be sent with SETCONF
+    conf: Vec<(String, String)>,
+    warm_start: bool,
+    dns_port: Option<u16>,
+    /// `TorServiceParam::ephemeral`, deleted on shutdown
+    ephemeral_dir: Option<EphemeralDir>,
+    prefer_fast_exits: bool,
+    _handle: Option<TorProcess>,
 }
 
 pub struct OwnedTorService {
     pub socks_port: u16,
     pub control_port: String,
-    _handle: Option<JoinHandle<Result<u8, libtor::Error>>>,
+    /// `TorServiceParam::dns_port`
+    pub dns_port: Option<u16>,
+    /// Options from `torrc::options`, sent again after `reload_config`
+    conf: Vec<(String, String)>,
+    warm_start: bool,
+    ephemeral_dir: Option<EphemeralDir>,
+    _handle: Option<TorProcess>,
     _ctl: RefCell<Option<G>>,
+    /// Control connection reused by `with_control`, opened on first use
+    raw_ctl: RefCell<Option<ControlConn>>,
+    /// Onion services added on `_ctl`, they go away with it and are added again with the same
+    /// key by `reconnect_control`
+    onions: RefCell<Vec<OwnedOnion>>,
+    /// `GETINFO version`, asked once per control connection
+    version: RefCell<Option<String>>,
+}
+
+/// Arguments of an `ADD_ONION` made on the owned control connection
+struct OwnedOnion {
+    secret_key: [u8; 64],
+    listeners: Vec<(u16, SocketAddr)>,
+    max_streams_close_circuit: bool,
+    max_streams: Option<u16>,
 }
 
 #[repr(C)]
+#[derive(Default)]
 pub struct TorHiddenServiceParam {
     pub to_port: u16,
     pub hs_port: u16,
     pub secret_key: Option<[u8; 64]>,
+    /// Don't keep the service's secret key, for one-shot addresses that are never restored
+    /// Without `secret_key` Tor generates the key and discards it (`Flags=DiscardPK`), so it
+    /// never reaches this process. Such services are detached from the owning control
+    /// connection and live until `delete_hidden_service` or daemon shutdown
+    pub discard_key: bool,
+    /// Host `to_port` is forwarded to, 127.0.0.1 by default. An IP address (IPv6 with or
+    /// without brackets) or a hostname, e.g. a backend on the LAN or another container
+    /// Hostnames are resolved once here, outside of Tor, as Tor only takes addresses
+    pub target_host: Option<String>,
+    /// Most streams a single rendezvous circuit may open to the service at once
+    /// (`HiddenServiceMaxStreams`), unlimited when `None`
+    pub max_streams: Option<u16>,
+    /// Close the whole circuit instead of only refusing the stream once a client goes over
+    /// `max_streams` (`HiddenServiceMaxStreamsCloseCircuit`), needs `max_streams`
+    pub max_streams_close_circuit: bool,
+}
+
+impl TorHiddenServiceParam {
+    /// Checks `max_streams` and `max_streams_close_circuit` before they go to ADD_ONION
+    fn validate_streams(&self) -> Result<(), TorErrors> {
+        match self.max_streams {
+            // Tor reads 0 as no limit, which is what `None` is for
+            Some(0) => Err(TorErrors::InvalidConfig(String::from(
+                "max_streams must be at least 1, use None for no limit",
+            ))),
+            None if self.max_streams_close_circuit => Err(TorErrors::InvalidConfig(String::from(
+                "max_streams_close_circuit needs max_streams",
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// `ADD_ONION` for Tor to generate a key it discards, see `discard_key`
+    fn discarded_key_command(&self, target: SocketAddr) -> String {
+        let mut flags = String::from("DiscardPK,Detach");
+        if self.max_streams_close_circuit {
+            flags.push_str(",MaxStreamsCloseCircuit");
+        }
+        let max_streams = self
+            .max_streams
+            .map(|max| format!(" MaxStreams={}", max))
+            .unwrap_or_default();
+        format!(
+            "ADD_ONION NEW:ED25519-V3 Flags={}{} Port={},{}",
+            flags, max_streams, self.hs_port, target
+        )
+    }
+
+    /// Address the service forwards to, from `target_host` and `to_port`
+    fn target(&self) -> Result<SocketAddr, TorErrors> {
+        let Some(host) = &self.target_host else {
+            return Ok(SocketAddr::new(
+                IpAddr::from(Ipv4Addr::new(127, 0, 0, 1)),
+                self.to_port,
+            ));
+        };
+        let host = host.trim();
+        let unbracketed = host
+            .strip_prefix('[')
+            .and_then(|h| h.strip_suffix(']'))
+            .unwrap_or(host);
+        if let Ok(ip) = unbracketed.parse::<IpAddr>() {
+            return Ok(SocketAddr::new(ip, self.to_port));
+        }
+        if !is_valid_hostname(host) {
+            return Err(TorErrors::InvalidConfig(format!(
+                "Invalid hidden service target_host {:?}",
+                host
+            )));
+        }
+        (host, self.to_port)
+            .to_socket_addrs()
+            .map_err(|e| {
+                TorErrors::InvalidConfig(format!("Can't resolve target_host {}: {}", host, e))
+            })?
+            .next()
+            .ok_or_else(|| TorErrors::InvalidConfig(format!("target_host {} has no address", host)))
+    }
+}
+
+/// Letters, digits and '-' in dot separated labels of at most 63 characters, not starting or
+/// ending with '-'
+fn is_valid_hostname(host: &str) -> bool {
+    !host.is_empty()
+        && host.len() <= 253
+        && host.trim_end_matches('.').split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
 }
 
 #[derive(Debug)]
 pub struct TorHiddenService {
     pub onion_url: TorAddress,
-    pub secret_key: [u8; 64],
+    /// `None` when created with `discard_key`
+    pub secret_key: Option<[u8; 64]>,
 }
 /// The Phases of a Boostraping node
 /// From https://github.com/torproject/torspec/blob/master/proposals/137-bootstrap-phases.txt
@@ -112,6 +405,68 @@ pub enum OwnedTorServiceBootstrapPhase {
     // Still bootstraping or error
     Other(BootstrapPhase),
 }
+/// Snapshot of a running service for monitoring, see `OwnedTorService::service_health`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ServiceHealth {
+    pub bootstrap: OwnedTorServiceBootstrapPhase,
+    /// As reported by `GETINFO version`
+    pub version: String,
+    /// Circuits built and ready for streams
+    pub built_circuits: usize,
+    /// Bytes Tor read and wrote on the network since it started
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Onion services created on this instance that are still up
+    pub hidden_services: usize,
+    pub socks_port: u16,
+}
+
+/// Version of the linked Tor daemon, e.g. `0.4.8.10`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TorVersion {
+    pub major: u16,
+    pub minor: u16,
+    pub micro: u16,
+    pub patch: u16,
+}
+
+/// Oldest Tor accepting ED25519-V3 keys in ADD_ONION
+pub const ONION_V3_MIN_VERSION: TorVersion = TorVersion::new(0, 3, 3, 1);
+
+impl TorVersion {
+    pub const fn new(major: u16, minor: u16, micro: u16, patch: u16) -> TorVersion {
+        TorVersion {
+            major,
+            minor,
+            micro,
+            patch,
+        }
+    }
+    /// Parses the output of `GETINFO version`, e.g. "0.4.8.10 (git-6a9a5f4e)" or "0.4.9.1-alpha"
+    pub fn parse(version: &str) -> Option<TorVersion> {
+        let release = version.split_whitespace().next()?.split('-').next()?;
+        let mut parts = release.split('.').map(|p| p.parse::<u16>());
+        let major = parts.next()?.ok()?;
+        let minor = parts.next()?.ok()?;
+        let micro = parts.next()?.ok()?;
+        let patch = match parts.next() {
+            Some(p) => p.ok()?,
+            None => 0,
+        };
+        Some(TorVersion::new(major, minor, micro, patch))
+    }
+}
+
+impl fmt::Display for TorVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{}.{}.{}",
+            self.major, self.minor, self.micro, self.patch
+        )
+    }
+}
+
 /// High level API for Torut's AuthenticatedConnection used internally by TorService to expose
 /// note control functions to FFI and user
 trait TorControlApi {
@@ -120,6 +475,12 @@ trait TorControlApi {
         &mut self,
         timeout_ms: Option<u64>,
     ) -> Pin<Box<dyn Future<Output = Result<bool, TorErrors>> + '_>>;
+    /// wait_bootstrap that gives up with `TorErrors::BootstrapCancelled` once `cancel` is set
+    fn wait_bootstrap_cancellable(
+        &mut self,
+        timeout_ms: Option<u64>,
+        cancel: CancelToken,
+    ) -> Pin<Box<dyn Future<Output = Result<bool, TorErrors>> + '_>>;
     fn shutdown(self);
     fn get_status(
         &mut self,
@@ -140,6 +501,232 @@ pub enum TorErrors {
     ThreadingError(#[from] JoinError),
     #[error("Error TcpStream:")]
     TcpStreamError(String),
+    #[error("Bootstrap cancelled")]
+    BootstrapCancelled,
+    #[error("Invalid config: {0}")]
+    InvalidConfig(String),
+    #[error("Control command failed ({code}): {message}")]
+    ControlCommandError { code: u16, message: String },
+    #[error("{feature} requires Tor {required} or newer, linked Tor is {found}")]
+    UnsupportedTorVersion {
+        feature: String,
+        required: TorVersion,
+        found: String,
+    },
+    #[error("SOCKS port {0} is already in use")]
+    SocksPortInUse(u16),
+    /// Tor saw the device clock is this many seconds off (positive when ahead) and can't
+    /// bootstrap until it is corrected. Returned once Tor reported it repeatedly
+    /// (`bootstrap::CLOCK_SKEW_REPORTS`), or when bootstrap timed out after a report
+    #[error("Clock skew: the system clock is {offset_seconds}s off, fix the date and time")]
+    ClockSkew { offset_seconds: i64 },
+    #[error("DNS error: {0}")]
+    DnsError(String),
+    /// A response body that isn't the expected JSON, `snippet` is the body around the error
+    #[error("Invalid JSON: {message} near {snippet:?}")]
+    JsonParse { message: String, snippet: String },
+    /// Refused by `killswitch` because the connection wouldn't go through a bootstrapped Tor
+    #[error("Blocked by kill switch: {0}")]
+    BlockedByKillSwitch(String),
+    /// A response with a non 2xx status, from `HttpResponse::error_for_status`
+    #[error("HTTP status {code}")]
+    HttpStatus { code: u16, body: String },
+    /// The SOCKS proxy failed the CONNECT for another reason than the ones below, e.g. an exit
+    /// policy refusing the port (not allowed by ruleset) or an unsupported command
+    #[error("SOCKS connect to {target} failed: {reason}")]
+    SocksGeneralFailure { target: String, reason: String },
+    /// Network or host unreachable: the hostname didn't resolve, or the onion service is down
+    /// or its descriptor can't be found
+    #[error("SOCKS connect to {0} failed: host unreachable")]
+    SocksHostUnreachable(String),
+    /// The circuit was built but the target refused the connection
+    #[error("SOCKS connect to {0} failed: connection refused")]
+    SocksConnectionRefused(String),
+    /// The stream or circuit timed out before the target answered
+    #[error("SOCKS connect to {0} failed: TTL expired")]
+    SocksTtlExpired(String),
+    /// `vanity::generate_vanity_onion` tried `attempts` keys without matching the prefix
+    #[error("No onion address with the requested prefix after {attempts} attempts")]
+    VanityNotFound { attempts: u64 },
+    /// The owned control connection dropped and connecting to Tor again failed, the daemon
+    /// most likely exited
+    #[error("Control connection lost and reconnecting failed: {0}")]
+    ControlReconnectFailed(String),
+    /// A request header or url with characters that would break the request framing, e.g. a
+    /// CR or LF in a header value, refused before anything is sent
+    #[error("Invalid request: {0}")]
+    InvalidHeader(String),
+}
+
+impl TorErrors {
+    /// Stable number per variant for callers that can't match on the enum (FFI)
+    pub fn code(&self) -> i32 {
+        match self {
+            TorErrors::ControlConnectionError(_) => 1,
+            TorErrors::TorLibError(_) => 2,
+            TorErrors::BootStrapError(_) => 3,
+            TorErrors::IoError(_) => 4,
+            TorErrors::ThreadingError(_) => 5,
+            TorErrors::TcpStreamError(_) => 6,
+            TorErrors::BootstrapCancelled => 7,
+            TorErrors::InvalidConfig(_) => 8,
+            TorErrors::ControlCommandError { .. } => 9,
+            TorErrors::UnsupportedTorVersion { .. } => 10,
+            TorErrors::SocksPortInUse(_) => 11,
+            TorErrors::ClockSkew { .. } => 12,
+            TorErrors::DnsError(_) => 13,
+            TorErrors::JsonParse { .. } => 14,
+            TorErrors::BlockedByKillSwitch(_) => 15,
+            TorErrors::HttpStatus { .. } => 16,
+            TorErrors::SocksGeneralFailure { .. } => 17,
+            TorErrors::SocksHostUnreachable(_) => 18,
+            TorErrors::SocksConnectionRefused(_) => 19,
+            TorErrors::SocksTtlExpired(_) => 20,
+            TorErrors::VanityNotFound { .. } => 21,
+            TorErrors::ControlReconnectFailed(_) => 22,
+            TorErrors::InvalidHeader(_) => 23,
+        }
+    }
+
+    /// Maps a failed SOCKS5 connect to `target`: a failure reply from the proxy becomes the
+    /// matching `Socks*` variant, anything else (proxy unreachable, broken handshake) stays
+    /// an `IoError`
+    /// The socks crate only reports the reply code through the message of an `Other` error
+    pub fn from_socks_connect(error: io::Error, target: &str) -> TorErrors {
+        if error.kind() != io::ErrorKind::Other {
+            return TorErrors::IoError(error);
+        }
+        let target = target.to_string();
+        match error.to_string().as_str() {
+            "network unreachable" | "host unreachable" => TorErrors::SocksHostUnreachable(target),
+            "connection refused" => TorErrors::SocksConnectionRefused(target),
+            "TTL expired" => TorErrors::SocksTtlExpired(target),
+            reason @ ("general SOCKS server failure"
+            | "connection not allowed by ruleset"
+            | "command not supported"
+            | "address kind not supported"
+            | "unknown error") => TorErrors::SocksGeneralFailure {
+                target,
+                reason: reason.to_string(),
+            },
+            _ => TorErrors::IoError(error),
+        }
+    }
+}
+
+/// Data dir of an ephemeral service (`TorServiceParam::ephemeral`), a uniquely named directory
+/// under the temp dir private to the user on unix (0700). Deleted when dropped
+#[derive(Debug)]
+pub struct EphemeralDir(TempDir);
+
+impl EphemeralDir {
+    fn create() -> io::Result<EphemeralDir> {
+        tempfile::Builder::new()
+            .prefix("sifir-tor-")
+            .tempdir()
+            .map(EphemeralDir)
+    }
+}
+
+/// Deletes the data dir of an ephemeral service once Tor exited
+fn remove_ephemeral_dir(dir: Option<EphemeralDir>) {
+    if let Some(EphemeralDir(dir)) = dir {
+        if let Err(e) = dir.close() {
+            warn!("Failed to remove ephemeral data dir: {}", e);
+        }
+    }
+}
+
+/// Checks nothing listens on the SOCKS port yet, Tor would otherwise fail to bind it and exit
+/// With `auto` a taken port gives `None`, Tor then binds a free port itself (`SocksPort auto`)
+/// and `read_socks_port` tells which, so nothing can take it in between
+fn resolve_socks_port(port: u16, auto: bool) -> Result<Option<u16>, TorErrors> {
+    match std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, port)) {
+        Ok(_) => Ok(Some(port)),
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse => {
+            if !auto {
+                return Err(TorErrors::SocksPortInUse(port));
+            }
+            warn!("SOCKS port {} is in use, letting Tor pick one", port);
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Port of the SOCKS listener Tor opened, asked over the control port
+fn read_socks_port(control_port: &str) -> Result<u16, TorErrors> {
+    let listeners = ControlConn::connect(control_port)?.get_info("net/listeners/socks")?;
+    parse_listener_port(&listeners)
+        .ok_or_else(|| TorErrors::BootStrapError(format!("No SOCKS listener in {:?}", listeners)))
+}
+
+/// First port of a `net/listeners/*` value, quoted addresses separated by spaces
+/// e.g. "127.0.0.1:9050" "[::1]:9050"
+fn parse_listener_port(listeners: &str) -> Option<u16> {
+    listeners
+        .split_whitespace()
+        .find_map(|listener| listener.trim_matches('"').rsplit_once(':')?.1.parse().ok())
+}
+
+/// Waits for Tor to write its control port to `ctl_file_path` and reads it
+/// `removed_stale` says the file of a previous run was deleted before starting Tor, so it can
+/// be polled right away instead of waiting for it to be rewritten
+fn read_control_port(ctl_file_path: &str, removed_stale: bool) -> Result<String, TorErrors> {
+    let mut is_ready = false;
+    let mut control_port = String::new();
+    let mut try_times = 0;
+    let (max_tries, poll_ms) = if removed_stale { (100, 100) } else { (10, 900) };
+    // We wait for Tor to write the new config file otherwise we risk reading the old config and port.
+    // Anything less than a second and iOS errors out
+    // TODO Anyway to *know* when the new config has been written besides checking config file modifed after starting process?
+    if !removed_stale {
+        std::thread::sleep(std::time::Duration::from_millis(1000));
+    }
+    while !is_ready {
+        let contents = fs::read_to_string(ctl_file_path);
+        match contents {
+            Ok(t) => {
+                if !t.contains("PORT=") {
+                    return Err(TorErrors::BootStrapError(String::from("No port in config")));
+                };
+                let data: Vec<&str> = t.split("PORT=").collect();
+                control_port = data[1].into();
+                info!("success with config port {}!", control_port);
+                is_ready = true;
+            }
+            Err(_) => {
+                try_times += 1;
+                if try_times > max_tries {
+                    return Err(TorErrors::BootStrapError(String::from(
+                        "Unable to read daemon control info",
+                    )));
+                }
+            }
+        }
+        std::thread::sleep(std::time::Duration::from_millis(poll_ms));
+    }
+    Ok(control_port)
+}
+
+/// Checks the consensus a previous run cached in `cache_dir`, returns whether Tor can start
+/// from it. An expired one is deleted so Tor fetches a new directory instead of trusting it
+fn prepare_warm_start(cache_dir: &str) -> bool {
+    match cache::CachedConsensus::read(Path::new(cache_dir)) {
+        Some(consensus) if consensus.is_valid() => {
+            info!(
+                "Warm start, cached consensus valid until {}",
+                consensus.valid_until
+            );
+            true
+        }
+        Some(_) => {
+            info!("Cached consensus expired, bootstrapping from scratch");
+            cache::remove_consensus(Path::new(cache_dir));
+            false
+        }
+        None => false,
+    }
 }
 
 /// Convert Torservice Param into an Unauthentication TorService:
@@ -149,12 +736,19 @@ pub enum TorErrors {
 impl TryFrom<TorServiceParam> for TorService {
     type Error = TorErrors;
     fn try_from(param: TorServiceParam) -> Result<Self, Self::Error> {
+        let conf = torrc::options(&param)?;
+        let mut startup_lines = torrc::startup_lines(&param)?;
+        process::validate_options(&param)?;
         let mut service = Tor::new();
-        let socks_port = param.socks_port.unwrap_or(19051);
+        let requested_socks_port = resolve_socks_port(
+            param.socks_port.unwrap_or(19051),
+            param.auto_socks_port.unwrap_or(false),
+        )?;
         let base_dir = format!("{}/sifir_sdk/tor", param.data_dir);
         let data_dir = format!("{}/data", base_dir);
         let cache_dir = format!("{}/cache", base_dir);
         let ctl_file_path = format!("{}/ctl.info", base_dir);
+        let torrc_path = format!("{}/torrc", base_dir);
         let info_log_path = format!("{}/logs/sifir_tor_log.info", base_dir);
         let error_log_path = format!("{}/logs/sifir_tor_log.err", base_dir);
         // Create directories
@@ -185,13 +779,31 @@ impl TryFrom<TorServiceParam> for TorService {
             })
             .collect();
         let _ = logfiles_check?;
+        let warm_start = param.warm_start.unwrap_or(false) && prepare_warm_start(&cache_dir);
+        let removed_stale = warm_start && {
+            let _ = fs::remove_file(&ctl_file_path);
+            !Path::new(&ctl_file_path).exists()
+        };
+        match requested_socks_port {
+            Some(port) => {
+                service.flag(TorFlag::SocksPort(port));
+            }
+            None => startup_lines.push(String::from("SocksPort auto")),
+        }
+        // Only used for the startup options, Tor otherwise runs from flags alone
+        let torrc_file = if startup_lines.is_empty() {
+            None
+        } else {
+            fs::write(&torrc_path, startup_lines.join("\n") + "\n")?;
+            service.flag(TorFlag::ConfigFile(torrc_path.clone()));
+            Some(torrc_path.as_str())
+        };
         service
-            .flag(TorFlag::DataDirectory(data_dir))
+            .flag(TorFlag::DataDirectory(data_dir.clone()))
             // Note: Making data dir group readble breaks android
             //.flag(TorFlag::DataDirectoryGroupReadable(TorBool::True))
-            .flag(TorFlag::CacheDirectory(cache_dir))
+            .flag(TorFlag::CacheDirectory(cache_dir.clone()))
             //.flag(TorFlag::CacheDirectoryGroupReadable("1".into()))
-            .flag(TorFlag::SocksPort(socks_port))
             .flag(TorFlag::ControlPortAuto)
             .flag(TorFlag::CookieAuthentication(libtor::TorBool::True))
             .flag(TorFlag::ControlPortWriteToFile(ctl_file_path.clone()))
@@ -207,53 +819,419 @@ impl TryFrom<TorServiceParam> for TorService {
         //         ));
         // }
 
-        let handle = service.start_background();
-
-        let mut is_ready = false;
-        let mut control_port = String::new();
-        let mut try_times = 0;
-        // We wait for Tor to write the new config file otherwise we risk reading the old config and port.
-        // Anything less than a second and iOS errors out
-        // TODO Anyway to *know* when the new config has been written besides checking config file modifed after starting process?
-        std::thread::sleep(std::time::Duration::from_millis(1000));
-        while !is_ready {
-            let contents = fs::read_to_string(ctl_file_path.clone());
-            match contents {
-                Ok(t) => {
-                    if !t.contains("PORT=") {
-                        return Err(TorErrors::BootStrapError(String::from("No port in config")));
-                    };
-                    let data: Vec<&str> = t.split("PORT=").collect();
-                    control_port = data[1].into();
-                    info!("success with config port {}!", control_port);
-                    is_ready = true;
-                }
-                Err(_) => {
-                    try_times += 1;
-                    if try_times > 10 {
-                        return Err(TorErrors::BootStrapError(String::from(
-                            "Unable to read daemon control info",
-                        )));
-                    }
+        bootstrap::reset();
+        let handle = if let Some(binary) = &param.tor_binary {
+            process::spawn_external(
+                binary,
+                &data_dir,
+                &cache_dir,
+                requested_socks_port,
+                &ctl_file_path,
+                torrc_file,
+                &param,
+            )?
+        } else if param.low_priority.unwrap_or(false) {
+            TorProcess::Embedded(std::thread::spawn(move || {
+                if !priority::lower_current_thread() {
+                    warn!("Could not lower the Tor thread priority");
                 }
+                service.start()
+            }))
+        } else {
+            TorProcess::Embedded(service.start_background())
+        };
+
+        let ports = read_control_port(&ctl_file_path, removed_stale).and_then(|control_port| {
+            let socks_port = match requested_socks_port {
+                Some(port) => port,
+                None => read_socks_port(&control_port)?,
+            };
+            Ok((control_port, socks_port))
+        });
+        let (control_port, socks_port) = match ports {
+            Ok(ports) => ports,
+            Err(e) => {
+                handle.abort();
+                return Err(e);
             }
-            std::thread::sleep(std::time::Duration::from_millis(900));
+        };
+        if requested_socks_port.is_none() {
+            info!("Tor picked SOCKS port {}", socks_port);
         }
 
         Ok(TorService {
             socks_port,
             control_port,
             bootstrap_timeout_ms: param.bootstrap_timeout_ms.unwrap_or(45000),
+            conf,
+            warm_start,
+            dns_port: param.dns_port,
+            ephemeral_dir: param.ephemeral,
+            prefer_fast_exits: param.prefer_fast_exits.unwrap_or(false),
             _handle: Some(handle),
         })
     }
 }
+/// Generates a v3 onion identity without needing a running Tor instance
+/// Returns the onion address and the secret key, the key can later be handed to
+/// `OwnedTorService::create_hidden_service` to publish the service under that address
+pub fn generate_onion_keypair() -> (String, [u8; 64]) {
+    let service_key = TorSecretKeyV3::generate();
+    (
+        service_key.public().get_onion_address().to_string(),
+        service_key.as_bytes(),
+    )
+}
+
+/// First 32 bytes of an `hs_ed25519_secret_key` file, NUL padded
+const HS_SECRET_KEY_HEADER: &[u8] = b"== ed25519v1-secret: type0 ==";
+const HS_SECRET_KEY_HEADER_LEN: usize = 32;
+
+/// Reads the `hs_ed25519_secret_key` file of a v3 onion service directory (as written by a
+/// standalone Tor) into the 64 byte expanded key `TorHiddenServiceParam::secret_key` takes
+/// Errors with `TorErrors::InvalidConfig` when the file isn't such a key, e.g. a v2 key or
+/// the `hs_ed25519_public_key` file
+pub fn read_hs_secret_key_file(path: impl AsRef<Path>) -> Result<[u8; 64], TorErrors> {
+    let path = path.as_ref();
+    let bytes = fs::read(path)?;
+    parse_hs_secret_key(&bytes)
+        .map_err(|reason| TorErrors::InvalidConfig(format!("{}: {}", path.display(), reason)))
+}
+
+fn parse_hs_secret_key(bytes: &[u8]) -> Result<[u8; 64], String> {
+    if bytes.len() != HS_SECRET_KEY_HEADER_LEN + 64 {
+        return Err(format!(
+            "not an hs_ed25519_secret_key file, expected {} bytes and got {}",
+            HS_SECRET_KEY_HEADER_LEN + 64,
+            bytes.len()
+        ));
+    }
+    let (header, key) = bytes.split_at(HS_SECRET_KEY_HEADER_LEN);
+    let padding = &header[HS_SECRET_KEY_HEADER.len()..];
+    if !header.starts_with(HS_SECRET_KEY_HEADER) || padding.iter().any(|b| *b != 0) {
+        return Err(format!(
+            "unexpected header {:?}, expected {:?}",
+            String::from_utf8_lossy(header).trim_end_matches('\0'),
+            String::from_utf8_lossy(HS_SECRET_KEY_HEADER)
+        ));
+    }
+    // The scalar half of an expanded ed25519 key is clamped, anything else is corrupted
+    if key[0] & 7 != 0 || key[31] & 0xC0 != 0x40 {
+        return Err(String::from("corrupted key, the scalar isn't clamped"));
+    }
+    let mut secret_key = [0u8; 64];
+    secret_key.copy_from_slice(key);
+    Ok(secret_key)
+}
+
+/// `OwnedTorService::prefer_fast_exits` keeps at least this many exits
+pub const MIN_FAST_EXITS: usize = 20;
+
+/// Pause between two probes of `wait_until_onion_reachable`
+const REACHABILITY_RETRY: Duration = Duration::from_secs(2);
+
+/// Probes `onion_address` (`name.onion`, or `name.onion:port` when not 80) with SOCKS connects
+/// through the Tor SOCKS port `socks_port` until one succeeds, false when `timeout_ms` passed
+/// first. A success means the service works end to end for clients: its descriptor can be
+/// fetched, introduction and rendezvous complete and something accepts on the port, which a
+/// published descriptor alone doesn't guarantee. Nothing is sent over the connection
+pub fn wait_until_onion_reachable(
+    socks_port: u16,
+    onion_address: &str,
+    timeout_ms: u64,
+) -> Result<bool, TorErrors> {
+    let invalid = || TorErrors::InvalidConfig(format!("Invalid onion address: {}", onion_address));
+    let (host, port) = match onion_address.trim().rsplit_once(':') {
+        Some((host, port)) => (host, port.parse::<u16>().map_err(|_| invalid())?),
+        None => (onion_address.trim(), 80),
+    };
+    if host.len() <= ".onion".len() || !host.ends_with(".onion") {
+        return Err(invalid());
+    }
+    killswitch::check(&format!("127.0.0.1:{}", socks_port))?;
+
+    let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(false);
+        }
+        // The SOCKS connect has no timeout of its own, a probe still pending at the deadline
+        // is left to finish on its thread
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let target = host.to_string();
+        std::thread::spawn(move || {
+            let probe = Socks5Stream::connect(("127.0.0.1", socks_port), (target.as_str(), port))
+                .map(|stream| {
+                    let _ = stream.get_ref().shutdown(Shutdown::Both);
+                });
+            let _ = sender.send(probe);
+        });
+        match receiver.recv_timeout(remaining) {
+            Ok(Ok(())) => return Ok(true),
+            Ok(Err(e)) => debug!(
+                "{}",
+                safe_logging::scrub(&format!("{} not reachable yet: {}", host, e))
+            ),
+            Err(_) => return Ok(false),
+        }
+        std::thread::sleep(
+            REACHABILITY_RETRY.min(deadline.saturating_duration_since(Instant::now())),
+        );
+    }
+}
+
+/// Timeout of the latency probe behind the FFI `measure_circuit_latency`
+pub const DEFAULT_LATENCY_TIMEOUT_MS: u64 = 30_000;
+
+/// Times a SOCKS connect to `host:port` through the Tor SOCKS port `socks_port` to gauge the
+/// current circuit latency. Only connection setup is measured, no data is exchanged with the
+/// target. Fails once `timeout_ms` passed, the pending connect is left to finish on its thread
+pub fn measure_circuit_latency(
+    socks_port: u16,
+    host: &str,
+    port: u16,
+    timeout_ms: u64,
+) -> Result<Duration, TorErrors> {
+    killswitch::check(&format!("127.0.0.1:{}", socks_port))?;
+    let target = format!("{}:{}", host, port);
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let started = Instant::now();
+    let (host, probe_target) = (host.to_string(), target.clone());
+    std::thread::spawn(move || {
+        let probe = Socks5Stream::connect(("127.0.0.1", socks_port), (host.as_str(), port))
+            .map(|stream| {
+                let latency = started.elapsed();
+                let _ = stream.get_ref().shutdown(Shutdown::Both);
+                latency
+            })
+            .map_err(|e| TorErrors::from_socks_connect(e, &probe_target));
+        let _ = sender.send(probe);
+    });
+    receiver
+        .recv_timeout(Duration::from_millis(timeout_ms))
+        .unwrap_or_else(|_| {
+            Err(TorErrors::TcpStreamError(format!(
+                "Connecting to {} took longer than {} ms",
+                target, timeout_ms
+            )))
+        })
+}
+
+/// Service ids of an `onions/current` or `onions/detached` reply, one per line
+fn parse_onion_list(list: &str) -> impl Iterator<Item = String> + '_ {
+    list.split_whitespace()
+        .map(|id| format!("{}.onion", id.trim_end_matches(".onion")))
+}
+
+/// Left hand side of the "from=to" line in a MAPADDRESS reply
+fn parse_mapped_address(lines: &[String]) -> Option<String> {
+    lines
+        .iter()
+        .find_map(|l| l.split_once('='))
+        .map(|(from, _)| from.to_string())
+}
+
+/// `circuit_id` trimmed, when it is a circuit id (digits only) that can go in a command
+fn check_circuit_id(circuit_id: &str) -> Result<&str, TorErrors> {
+    let circuit_id = circuit_id.trim();
+    if circuit_id.is_empty() || !circuit_id.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(TorErrors::InvalidConfig(format!(
+            "Invalid circuit id {:?}",
+            circuit_id
+        )));
+    }
+    Ok(circuit_id)
+}
+
+/// `address` trimmed, when it is a hostname (optionally a `*.` wildcard), an IP address
+/// (IPv6 with or without brackets) or "." as MAPADDRESS takes them
+fn check_map_address(address: &str) -> Result<&str, TorErrors> {
+    let address = address.trim();
+    let unbracketed = address
+        .strip_prefix('[')
+        .and_then(|a| a.strip_suffix(']'))
+        .unwrap_or(address);
+    let hostname = address.strip_prefix("*.").unwrap_or(address);
+    let valid = address == "."
+        || unbracketed.parse::<IpAddr>().is_ok()
+        || (!hostname.is_empty()
+            && hostname
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_')));
+    if !valid {
+        return Err(TorErrors::InvalidConfig(format!(
+            "Invalid address to map {:?}",
+            address
+        )));
+    }
+    Ok(address)
+}
+
+/// Length of a v3 onion service id (the address without ".onion")
+const ONION_V3_ID_LEN: usize = 56;
+
+/// Service id of `onion_address` (lowercase, without ".onion"), when it is a v3 address
+fn check_service_id(onion_address: &str) -> Result<String, TorErrors> {
+    let address = onion_address.trim().to_ascii_lowercase();
+    let service_id = address.strip_suffix(".onion").unwrap_or(&address);
+    let valid = service_id.len() == ONION_V3_ID_LEN
+        && service_id
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || (b'2'..=b'7').contains(&b));
+    if !valid {
+        return Err(TorErrors::InvalidConfig(format!(
+            "Not a v3 onion address: {:?}",
+            onion_address
+        )));
+    }
+    Ok(service_id.to_string())
+}
+
+/// `fingerprint` trimmed, when it is a relay fingerprint (40 hex digits, optionally after '$')
+fn check_fingerprint(fingerprint: &str) -> Result<&str, TorErrors> {
+    let fingerprint = fingerprint.trim();
+    let hex = fingerprint.strip_prefix('$').unwrap_or(fingerprint);
+    if hex.len() != 40 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(TorErrors::InvalidConfig(format!(
+            "Invalid relay fingerprint {:?}",
+            fingerprint
+        )));
+    }
+    Ok(fingerprint)
+}
+
+/// Id of the circuit in an EXTENDCIRCUIT reply ("EXTENDED id")
+fn parse_extended_circuit(lines: &[String]) -> Option<String> {
+    lines
+        .iter()
+        .find_map(|l| l.strip_prefix("EXTENDED "))
+        .map(|id| id.trim().to_string())
+}
+
+/// Launches `count` circuits on paths Tor picks (`EXTENDCIRCUIT 0`), returns their ids
+fn launch_circuits(ctl: &mut ControlConn, count: usize) -> Result<Vec<String>, TorErrors> {
+    (0..count)
+        .map(|_| {
+            let reply = ctl.command("EXTENDCIRCUIT 0")?;
+            parse_extended_circuit(&reply.lines).ok_or(TorErrors::ControlCommandError {
+                code: reply.code,
+                message: format!("Unexpected EXTENDCIRCUIT reply: {}", reply.lines.join(" ")),
+            })
+        })
+        .collect()
+}
+
+/// Errors with `TorErrors::UnsupportedTorVersion` when `found` is older than `required`
+/// A version that doesn't parse (a custom build, a future format) is let through with a
+/// warning, Tor itself refuses commands it doesn't know
+fn check_tor_version(feature: &str, required: TorVersion, found: String) -> Result<(), TorErrors> {
+    match TorVersion::parse(&found) {
+        Some(version) if version < required => Err(TorErrors::UnsupportedTorVersion {
+            feature: feature.into(),
+            required,
+            found,
+        }),
+        Some(_) => Ok(()),
+        None => {
+            warn!(
+                "Unrecognized Tor version {:?}, assuming it supports {}",
+                found, feature
+            );
+            Ok(())
+        }
+    }
+}
+
+/// How long `fetch_descriptor` waits for the HSDirs to answer
+const DESCRIPTOR_FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long `reload_config` listens for the warnings Tor logs while re-reading its torrc
+const RELOAD_LOG_WINDOW: Duration = Duration::from_secs(2);
+
+/// Messages of the WARN and ERR events collected during a reload, or `None` when none of them
+/// is an ERR. A torrc Tor can't use is reported as "Reading config failed--see warnings
+/// above", the WARNs before it carry the actual parse errors
+fn reload_failure(events: &[control::ControlReply]) -> Option<String> {
+    let messages: Vec<(&str, &str)> = events
+        .iter()
+        .filter_map(|event| event.lines.first()?.split_once(' '))
+        .collect();
+    if !messages.iter().any(|(severity, _)| *severity == "ERR") {
+        return None;
+    }
+    Some(
+        messages
+            .iter()
+            .map(|(_, message)| message.trim())
+            .collect::<Vec<_>>()
+            .join("; "),
+    )
+}
+
+#[derive(Debug, PartialEq)]
+enum DescriptorEvent {
+    Content(String),
+    Failed(String),
+}
+
+/// Interprets an HS_DESC / HS_DESC_CONTENT event for `service_id`
+/// "HS_DESC FAILED <addr> NO_AUTH <hsdir> <descid> REASON=NOT_FOUND"
+/// "HS_DESC_CONTENT <addr> <descid> <hsdir>\n<descriptor>", empty on failure
+fn parse_descriptor_event(event: &str, service_id: &str) -> Option<DescriptorEvent> {
+    let (head, body) = event.split_once('\n').unwrap_or((event, ""));
+    let mut fields = head.split_whitespace();
+    match (fields.next()?, fields.next()?) {
+        ("HS_DESC_CONTENT", address) if address == service_id && !body.trim().is_empty() => {
+            Some(DescriptorEvent::Content(body.to_string()))
+        }
+        ("HS_DESC", "FAILED") if fields.next()? == service_id => {
+            let hsdir = fields.nth(1).unwrap_or("unknown HSDir");
+            let reason = head
+                .split_whitespace()
+                .find_map(|f| f.strip_prefix("REASON="))
+                .unwrap_or("UNKNOWN");
+            Some(DescriptorEvent::Failed(format!("{}: {}", hsdir, reason)))
+        }
+        _ => None,
+    }
+}
+
 /// Async handler injected into Torut to recieve Tor daemon async events
 /// Right now does nothing but is needed for AuthenticatedConnection from Torut to function correctly
 fn handler(_: AsyncEvent<'static>) -> Pin<Box<dyn Future<Output = Result<(), ConnError>>>> {
     Box::pin(async move { Ok(()) })
 }
 
+/// Connects to the control port and authenticates with the cookie Tor advertises
+async fn authenticated_conn<F>(
+    control_port: &str,
+    handle: Option<F>,
+) -> Result<AuthenticatedConn<TcpStream, F>, TorErrors> {
+    let s = TcpStream::connect(control_port.trim()).await?;
+    let mut utc = UnauthenticatedConn::new(s);
+    // returns node info + cookie location
+    let proto_info = utc
+        .load_protocol_info()
+        .await
+        .map_err(TorErrors::ControlConnectionError)?;
+    // loads cookie from loaded data and build auth info
+    let auth = proto_info
+        .make_auth_data()?
+        .ok_or(TorErrors::BootStrapError(String::from(
+            "Error making control auth data",
+        )))?;
+    utc.authenticate(&auth)
+        .await
+        .map_err(TorErrors::ControlConnectionError)?;
+    // upgrade connection to authenticated
+    let mut ac = utc.into_authenticated().await;
+    if handle.is_some() {
+        ac.set_async_event_handler(handle);
+    }
+    Ok(ac)
+}
+
 impl TorService {
     pub fn new(param: TorServiceParam) -> Result<Self, TorErrors> {
         param.try_into()
@@ -262,35 +1240,24 @@ impl TorService {
         &self,
         handle: Option<F>,
     ) -> Result<AuthenticatedConn<TcpStream, F>, TorErrors> {
-        let s = TcpStream::connect(self.control_port.trim()).await?;
-        let mut utc = UnauthenticatedConn::new(s);
-        // returns node info + cookie location
-        let proto_info = utc
-            .load_protocol_info()
-            .await
-            .map_err(TorErrors::ControlConnectionError)?;
-        // loads cookie from loaded data and build auth info
-        let auth = proto_info
-            .make_auth_data()?
-            .ok_or(TorErrors::BootStrapError(String::from(
-                "Error making control auth data",
-            )))?;
-        utc.authenticate(&auth)
-            .await
-            .map_err(TorErrors::ControlConnectionError)?;
-        // upgrade connection to authenticated
-        let mut ac = utc.into_authenticated().await;
-        if handle.is_some() {
-            ac.set_async_event_handler(handle);
-        }
-        Ok(ac)
+        authenticated_conn(&self.control_port, handle).await
     }
 
     /// Converts TorService to OwnedTorService, consuming the TorService
     /// and returning an OwnedTorService which is fully bootstrapped and under our control
     /// (If we drop this object the Tor daemon will shut down)
     pub fn into_owned_node(self) -> Result<OwnedTorService, TorErrors> {
-        ensure_runtime().lock().unwrap().block_on(
+        self.bootstrap_with_cancel(&CancelToken::new())
+    }
+
+    /// Same as into_owned_node but stops waiting as soon as `cancel` is cancelled
+    /// The daemon is then shut down and `TorErrors::BootstrapCancelled` returned once it exited
+    pub fn bootstrap_with_cancel(
+        mut self,
+        cancel: &CancelToken,
+    ) -> Result<OwnedTorService, TorErrors> {
+        let owning = Cell::new(false);
+        let result = ensure_runtime().lock().unwrap().block_on(
             async {
                 let mut ac = self
                     .get_control_auth_conn(Some(Box::new(handler) as F))
@@ -301,16 +1268,53 @@ impl TorService {
                     .compat()
                     .await
                     .map_err(TorErrors::ControlConnectionError)?;
-                ac.wait_bootstrap(Some(self.bootstrap_timeout_ms)).await?;
-                Ok(OwnedTorService {
+                owning.set(true);
+                if !self.conf.is_empty() {
+                    ControlConn::connect(&self.control_port)?.set_conf(&self.conf)?;
+                }
+                ac.wait_bootstrap_cancellable(Some(self.bootstrap_timeout_ms), cancel.clone())
+                    .await?;
+                Ok(ac)
+            }
+            .compat(),
+        );
+        match result {
+            Ok(ac) => {
+                killswitch::mark_ready(self.socks_port);
+                http_client::register_control_port(self.socks_port, &self.control_port);
+                let owned = OwnedTorService {
                     socks_port: self.socks_port,
                     control_port: self.control_port,
+                    dns_port: self.dns_port,
+                    conf: self.conf,
+                    warm_start: self.warm_start,
+                    ephemeral_dir: self.ephemeral_dir,
                     _handle: self._handle,
                     _ctl: RefCell::new(Some(ac)),
-                })
+                    raw_ctl: RefCell::new(None),
+                    onions: RefCell::new(Vec::new()),
+                    version: RefCell::new(None),
+                };
+                if self.prefer_fast_exits {
+                    // Only a performance tweak, the service works without it
+                    if let Err(e) = owned.prefer_fast_exits() {
+                        warn!("Could not restrict exits to fast relays: {:?}", e);
+                    }
+                }
+                Ok(owned)
             }
-            .compat(),
-        )
+            Err(e) => {
+                // With the owning control connection gone Tor is exiting, wait for it before
+                // deleting an ephemeral data dir it may still write to
+                if owning.get() {
+                    if let Some(handle) = self._handle.take() {
+                        let _ = handle.join();
+                    }
+                }
+                remove_ephemeral_dir(self.ephemeral_dir.take());
+                Err(e)
+            }
+        }
     }
 }
 
@@ -329,10 +1333,96 @@ impl OwnedTorService {
         let owned_result: Result<OwnedTorService, TorErrors> = param.try_into();
         owned_result
     }
+    /// Builds the service from a JSON `TorServiceParam`, see `TorServiceParam::from_json`
+    pub fn from_json(config: &str) -> Result<Self, TorErrors> {
+        OwnedTorService::new(TorServiceParam::from_json(config)?)
+    }
     pub fn create_hidden_service(
         &mut self,
         param: TorHiddenServiceParam,
     ) -> Result<TorHiddenService, TorErrors> {
+        self.require_tor_version("v3 onion services", ONION_V3_MIN_VERSION)?;
+        param.validate_streams()?;
+        let target = param.target()?;
+        if param.discard_key && param.secret_key.is_none() {
+            return self.create_discarded_key_hidden_service(&param, target);
+        }
+        let service_key = match param.secret_key {
+            Some(key) => key.into(),
+            _ => TorSecretKeyV3::generate(),
+        };
+        self.add_onion(
+            &service_key,
+            &[(param.hs_port, target)],
+            param.max_streams_close_circuit,
+            param.max_streams,
+        )?;
+
+        info!("Hidden service created!");
+        let onion_url = TorAddress::AddressPort(
+            service_key.public().get_onion_address().to_string(),
+            param.hs_port,
+        );
+        let secret_key = if param.discard_key {
+            None
+        } else {
+            Some(service_key.as_bytes())
+        };
+        Ok(TorHiddenService {
+            onion_url,
+            secret_key,
+        })
+    }
+
+    /// Publishes the onion service of an existing Tor instance under the same address, from
+    /// its `hs_ed25519_secret_key` file (see `read_hs_secret_key_file`)
+    /// `port_mappings` are (onion port, local port on 127.0.0.1) pairs, like the
+    /// `HiddenServicePort` lines of its torrc. The returned `onion_url` carries the first
+    /// onion port
+    pub fn create_hidden_service_from_key_file(
+        &mut self,
+        path: impl AsRef<Path>,
+        port_mappings: &[(u16, u16)],
+    ) -> Result<TorHiddenService, TorErrors> {
+        self.require_tor_version("v3 onion services", ONION_V3_MIN_VERSION)?;
+        let Some((first_port, _)) = port_mappings.first() else {
+            return Err(TorErrors::InvalidConfig(String::from(
+                "At least one port mapping is needed",
+            )));
+        };
+        let secret_key = read_hs_secret_key_file(path)?;
+        let service_key: TorSecretKeyV3 = secret_key.into();
+        let listeners: Vec<(u16, SocketAddr)> = port_mappings
+            .iter()
+            .map(|(hs_port, to_port)| {
+                (
+                    *hs_port,
+                    SocketAddr::new(IpAddr::from(Ipv4Addr::new(127, 0, 0, 1)), *to_port),
+                )
+            })
+            .collect();
+        self.add_onion(&service_key, &listeners, false, None)?;
+
+        info!("Hidden service created from key file!");
+        Ok(TorHiddenService {
+            onion_url: TorAddress::AddressPort(
+                service_key.public().get_onion_address().to_string(),
+                *first_port,
+            ),
+            secret_key: Some(secret_key),
+        })
+    }
+
+    /// `ADD_ONION` with `service_key` on the owned torut connection, so the service goes away
+    /// with it. Not retried after a reconnect: the command may have gone through before the
+    /// connection dropped, `reconnect_control` adds the services it knows about again instead
+    fn add_onion(
+        &self,
+        service_key: &TorSecretKeyV3,
+        listeners: &[(u16, SocketAddr)],
+        max_streams_close_circuit: bool,
+        max_streams: Option<u16>,
+    ) -> Result<(), TorErrors> {
         ensure_runtime().lock().unwrap().block_on(
             async {
                 let mut _ctl = self._ctl.borrow_mut();
@@ -340,39 +1430,48 @@ impl OwnedTorService {
                     .as_mut()
                     .ok_or(TorErrors::BootStrapError(String::from("Error mut lock")))?;
 
-                let service_key = match param.secret_key {
-                    Some(key) => key.into(),
-                    _ => TorSecretKeyV3::generate(),
-                };
-
                 ctl.add_onion_v3(
-                    &service_key,
-                    false,
+                    service_key,
                     false,
                     false,
-                    None,
-                    &mut [(
-                        param.hs_port,
-                        SocketAddr::new(IpAddr::from(Ipv4Addr::new(127, 0, 0, 1)), param.to_port),
-                    )]
-                    .iter(),
+                    max_streams_close_circuit,
+                    max_streams,
+                    &mut listeners.iter(),
                 )
                 .await
-                .map_err(TorErrors::ControlConnectionError)?;
-
-                info!("Hidden service created!");
-                let onion_url = TorAddress::AddressPort(
-                    service_key.public().get_onion_address().to_string(),
-                    param.hs_port,
-                );
-                let secret_key = service_key.as_bytes();
-                Ok(TorHiddenService {
-                    onion_url,
-                    secret_key,
-                })
+                .map_err(TorErrors::ControlConnectionError)
             }
             .compat(),
-        )
+        )?;
+        self.onions.borrow_mut().push(OwnedOnion {
+            secret_key: service_key.as_bytes(),
+            listeners: listeners.to_vec(),
+            max_streams_close_circuit,
+            max_streams,
+        });
+        Ok(())
+    }
+    /// `ADD_ONION NEW` with `Flags=DiscardPK`, Tor generates the key and never returns it
+    /// Detached as the raw control connection is closed right after
+    fn create_discarded_key_hidden_service(
+        &mut self,
+        param: &TorHiddenServiceParam,
+        target: SocketAddr,
+    ) -> Result<TorHiddenService, TorErrors> {
+        let reply = self.with_control(|ctl| ctl.command(&param.discarded_key_command(target)))?;
+        let service_id = reply
+            .lines
+            .iter()
+            .find_map(|l| l.strip_prefix("ServiceID="))
+            .ok_or(TorErrors::ControlCommandError {
+                code: reply.code,
+                message: String::from("No ServiceID in ADD_ONION reply"),
+            })?;
+        info!("Hidden service created without keeping its key!");
+        Ok(TorHiddenService {
+            onion_url: TorAddress::AddressPort(format!("{}.onion", service_id), param.hs_port),
+            secret_key: None,
+        })
     }
     pub fn delete_hidden_service(&mut self, onion: String) -> Result<(), TorErrors> {
         ensure_runtime().lock().unwrap().block_on(
@@ -387,45 +1486,646 @@ impl OwnedTorService {
                     .map_err(TorErrors::ControlConnectionError)?;
 
                 info!("Hidden serviec deleted !");
-                Ok(())
+                Ok::<_, TorErrors>(())
             }
             .compat(),
-        )
+        )?;
+        let address = format!("{}.onion", onion.trim_end_matches(".onion"));
+        self.onions.borrow_mut().retain(|owned| {
+            TorSecretKeyV3::from(owned.secret_key)
+                .public()
+                .get_onion_address()
+                .to_string()
+                != address
+        });
+        Ok(())
+    }
+
+    /// Onion addresses (with `.onion`) of the services created on this instance that are still
+    /// up, read back from Tor (`GETINFO onions/current` and `onions/detached`)
+    pub fn onion_addresses(&self) -> Result<Vec<String>, TorErrors> {
+        let current = match self.get_info("onions/current") {
+            Ok(list) => list,
+            // Tor answers 551 when there are none
+            Err(TorErrors::ControlConnectionError(ConnError::InvalidResponseCode(551))) => {
+                String::new()
+            }
+            Err(e) => return Err(e),
+        };
+        // Services created with discard_key are detached from the owning connection
+        let detached = match self.with_control(|ctl| ctl.get_info("onions/detached")) {
+            Ok(list) => list,
+            Err(TorErrors::ControlCommandError { code: 551, .. }) => String::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(parse_onion_list(&current)
+            .chain(parse_onion_list(&detached))
+            .collect())
+    }
+
+    /// Service with no Tor behind it, SOCKS traffic goes to `socks_port` and control calls
+    /// fail. For tests of crates built on this one
+    #[cfg(feature = "test-util")]
+    pub fn without_daemon(socks_port: u16) -> OwnedTorService {
+        OwnedTorService {
+            socks_port,
+            control_port: String::from("127.0.0.1:0"),
+            dns_port: None,
+            conf: Vec::new(),
+            warm_start: false,
+            ephemeral_dir: None,
+            _handle: None,
+            _ctl: RefCell::new(None),
+            raw_ctl: RefCell::new(None),
+            onions: RefCell::new(Vec::new()),
+            version: RefCell::new(None),
+        }
     }
 
     /// Get the status of the Tor daemon we own
     /// OwnedTorServiceBootstrapPhase will either be Done or Other(String) containing the stage of
     /// the boostrap the node is a
     pub fn get_status(&self) -> Result<OwnedTorServiceBootstrapPhase, TorErrors> {
-        ensure_runtime().lock().unwrap().block_on(
-            async {
-                let mut ctl = self._ctl.borrow_mut();
-                let r = ctl
-                    .as_mut()
-                    .ok_or(TorErrors::BootStrapError("Unable to get mut".into()))?
-                    .get_status()
-                    .await?;
-                Ok(r)
-            }
-            .compat(),
+        self.retry_on_reconnect(|service| {
+            ensure_runtime().lock().unwrap().block_on(
+                async {
+                    let mut ctl = service._ctl.borrow_mut();
+                    let r = ctl
+                        .as_mut()
+                        .ok_or(TorErrors::BootStrapError("Unable to get mut".into()))?
+                        .get_status()
+                        .await?;
+                    Ok(r)
+                }
+                .compat(),
+            )
+        })
+    }
+    /// Whether Tor started from a still valid cached consensus (`TorServiceParam::warm_start`)
+    pub fn is_warm_start(&self) -> bool {
+        self.warm_start
+    }
+    /// Resolves `hostname` with a UDP DNS query to Tor's DNSPort (`TorServiceParam::dns_port`)
+    /// This is not the SOCKS RESOLVE extension: no SOCKS connection is made, the query goes
+    /// to the DNSPort like to any DNS server and an exit resolves it. IPv4 addresses are
+    /// returned when there are any, IPv6 ones otherwise
+    pub fn resolve_via_dns_port(&self, hostname: &str) -> Result<Vec<IpAddr>, TorErrors> {
+        let port = self
+            .dns_port
+            .ok_or_else(|| TorErrors::InvalidConfig(String::from("dns_port is not enabled")))?;
+        // The DNSPort belongs to this service, it is usable once its SOCKS port is
+        killswitch::check(&format!("127.0.0.1:{}", self.socks_port))?;
+        dns::resolve(
+            (Ipv4Addr::LOCALHOST, port).into(),
+            hostname,
+            Duration::from_secs(30),
         )
     }
-    /// take control conn and drop it.
-    /// Closing the owned connection and causes tor daemon to shutdown
-    /// Then waits on the Tor daemon thread to exit
-    pub fn shutdown(&mut self) -> Result<(), TorErrors> {
-        {
-            let _ = self._ctl.borrow_mut().take();
+    /// Resolves `hostname` to one address with the SOCKS RESOLVE extension on our SOCKS port
+    pub fn resolve_via_socks(&self, hostname: &str) -> Result<IpAddr, TorErrors> {
+        dns::resolve_via_socks(
+            (Ipv4Addr::LOCALHOST, self.socks_port).into(),
+            hostname,
+            Duration::from_secs(30),
+        )
+    }
+    /// True once bootstrap is done, false while bootstrapping or when the status can't be read
+    pub fn is_bootstrapped(&self) -> bool {
+        matches!(self.get_status(), Ok(OwnedTorServiceBootstrapPhase::Done))
+    }
+    /// Runs `GETINFO <key>` on the owned control connection
+    fn get_info(&self, key: &str) -> Result<String, TorErrors> {
+        self.retry_on_reconnect(|service| {
+            ensure_runtime().lock().unwrap().block_on(
+                async {
+                    let mut ctl = service._ctl.borrow_mut();
+                    let info = ctl
+                        .as_mut()
+                        .ok_or(TorErrors::BootStrapError("Unable to get mut".into()))?
+                        .get_info(key)
+                        .await
+                        .map_err(TorErrors::ControlConnectionError)?;
+                    Ok(info)
+                }
+                .compat(),
+            )
+        })
+    }
+
+    /// Version of the Tor daemon we own, as reported by `GETINFO version`
+    /// Asked once, then cached until the control connection is replaced
+    pub fn version(&self) -> Result<String, TorErrors> {
+        if let Some(version) = self.version.borrow().as_ref() {
+            return Ok(version.clone());
+        }
+        let version = self.get_info("version")?.trim().to_string();
+        *self.version.borrow_mut() = Some(version.clone());
+        Ok(version)
+    }
+
+    /// Errors with `TorErrors::UnsupportedTorVersion` if the linked Tor is older than `required`
+    /// Call before issuing control commands that only exist in newer Tor releases
+    fn require_tor_version(&self, feature: &str, required: TorVersion) -> Result<(), TorErrors> {
+        check_tor_version(feature, required, self.version()?)
+    }
+
+    /// Runs `op` on the owned control connection, and once more after `reconnect_control` when
+    /// it failed because that connection dropped. Only for read-only queries, a command that
+    /// changes state may have been applied before the drop
+    fn retry_on_reconnect<T>(
+        &self,
+        op: impl Fn(&OwnedTorService) -> Result<T, TorErrors>,
+    ) -> Result<T, TorErrors> {
+        match op(self) {
+            Err(TorErrors::ControlConnectionError(ConnError::IOError(e))) => {
+                self.reconnect_control(&e)?;
+                op(self)
+            }
+            result => result,
+        }
+    }
+
+    /// Replaces the owned control connection: connects again, authenticates, takes ownership
+    /// back and adds the onion services of the old connection again with their original keys
+    /// Tor exits once the owning connection is closed, so this only recovers from drops the
+    /// daemon survived (it hadn't noticed the close yet, or was restarted on the same control
+    /// port). Fails with `TorErrors::ControlReconnectFailed` otherwise
+    fn reconnect_control(&self, cause: &io::Error) -> Result<(), TorErrors> {
+        warn!("Control connection lost ({}), reconnecting", cause);
+        let onions = self.onions.borrow();
+        let result = ensure_runtime().lock().unwrap().block_on(
+            async {
+                let mut ac =
+                    authenticated_conn(&self.control_port, Some(Box::new(handler) as F)).await?;
+                ac.take_ownership()
+                    .await
+                    .map_err(TorErrors::ControlConnectionError)?;
+                for onion in onions.iter() {
+                    ac.add_onion_v3(
+                        &TorSecretKeyV3::from(onion.secret_key),
+                        false,
+                        false,
+                        onion.max_streams_close_circuit,
+                        onion.max_streams,
+                        &mut onion.listeners.iter(),
+                    )
+                    .await
+                    .map_err(TorErrors::ControlConnectionError)?;
+                }
+                Ok::<_, TorErrors>(ac)
+            }
+            .compat(),
+        );
+        match result {
+            Ok(ac) => {
+                *self._ctl.borrow_mut() = Some(ac);
+                // Tor may have been restarted with another release
+                *self.version.borrow_mut() = None;
+                info!("Control connection re-established");
+                Ok(())
+            }
+            Err(e) => Err(TorErrors::ControlReconnectFailed(e.to_string())),
         }
-        let _ = self
+    }
+
+    /// Opens a separate control connection for commands the owned torut connection lacks
+    /// Only for commands that subscribe to events, the others go through `with_control`
+    fn raw_control(&self) -> Result<ControlConn, TorErrors> {
+        ControlConn::connect(&self.control_port)
+    }
+
+    /// Runs `f` on the control connection kept for commands the owned torut connection lacks,
+    /// connecting first when there is none. A connection that failed with an I/O error is
+    /// dropped, the next call opens a new one
+    fn with_control<T>(
+        &self,
+        f: impl FnOnce(&mut ControlConn) -> Result<T, TorErrors>,
+    ) -> Result<T, TorErrors> {
+        let mut cached = self.raw_ctl.borrow_mut();
+        let ctl = match cached.as_mut() {
+            Some(ctl) => ctl,
+            None => cached.insert(ControlConn::connect(&self.control_port)?),
+        };
+        let result = f(ctl);
+        if matches!(result, Err(TorErrors::IoError(_))) {
+            *cached = None;
+        }
+        result
+    }
+
+    /// Raw `GETINFO circuit-status` entries, one line per circuit starting with its id
+    /// e.g. "12 BUILT $AAAA~relay1,$BBBB~relay2 PURPOSE=GENERAL"
+    pub fn list_circuits(&self) -> Result<Vec<String>, TorErrors> {
+        let status = self.with_control(|ctl| ctl.get_info("circuit-status"))?;
+        Ok(status
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .map(String::from)
+            .collect())
+    }
+
+    /// Circuits Tor currently has open or is building, parsed from `list_circuits`
+    pub fn circuits(&self) -> Result<Vec<CircuitInfo>, TorErrors> {
+        Ok(self
+            .list_circuits()?
+            .iter()
+            .filter_map(|line| CircuitInfo::parse(line))
+            .collect())
+    }
+
+    /// Entry guards Tor picked (`GETINFO entry-guards`), in its order of preference
+    /// Every circuit starts at one of these, so they are the relays that see the client's IP
+    pub fn get_guards(&self) -> Result<Vec<GuardInfo>, TorErrors> {
+        Ok(self
+            .with_control(|ctl| ctl.get_info("entry-guards"))?
+            .lines()
+            .filter_map(GuardInfo::parse)
+            .collect())
+    }
+
+    /// Bootstrap status, version, circuits, traffic and onion services in one call, for
+    /// monitoring tools to scrape. Fails when any of them can't be read
+    pub fn service_health(&self) -> Result<ServiceHealth, TorErrors> {
+        Ok(ServiceHealth {
+            bootstrap: self.get_status()?,
+            version: self.version()?,
+            built_circuits: self
+                .circuits()?
+                .iter()
+                .filter(|circuit| circuit.status == "BUILT")
+                .count(),
+            bytes_read: self.traffic_counter("traffic/read")?,
+            bytes_written: self.traffic_counter("traffic/written")?,
+            hidden_services: self.onion_addresses()?.len(),
+            socks_port: self.socks_port,
+        })
+    }
+
+    /// `http_client::metrics_prometheus` plus this instance's circuits by status, traffic and
+    /// onion services, in the Prometheus text format for an endpoint to serve. Tor metrics
+    /// that can't be read are left out (with a warning) rather than failing the scrape
+    pub fn metrics_prometheus(&self) -> String {
+        use http_client::metrics_header as header;
+
+        let mut out = http_client::metrics_prometheus();
+        match self.circuits() {
+            Ok(circuits) => {
+                let mut by_status = std::collections::BTreeMap::new();
+                for circuit in circuits {
+                    *by_status.entry(circuit.status).or_insert(0) += 1;
+                }
+                header(
+                    &mut out,
+                    "tor_circuits",
+                    "gauge",
+                    "Circuits Tor has open or is building, by status",
+                );
+                for (status, count) in by_status {
+                    out.push_str(&format!(
+                        "tor_circuits{{status=\"{}\"}} {}\n",
+                        status, count
+                    ));
+                }
+            }
+            Err(e) => warn!("Circuits left out of the metrics: {:?}", e),
+        }
+        for (name, key, help) in [
+            (
+                "tor_traffic_read_bytes_total",
+                "traffic/read",
+                "Bytes Tor read from the network",
+            ),
+            (
+                "tor_traffic_written_bytes_total",
+                "traffic/written",
+                "Bytes Tor wrote to the network",
+            ),
+        ] {
+            match self.traffic_counter(key) {
+                Ok(bytes) => {
+                    header(&mut out, name, "counter", help);
+                    out.push_str(&format!("{} {}\n", name, bytes));
+                }
+                Err(e) => warn!("{} left out of the metrics: {:?}", key, e),
+            }
+        }
+        match self.onion_addresses() {
+            Ok(addresses) => {
+                header(
+                    &mut out,
+                    "tor_hidden_services",
+                    "gauge",
+                    "Onion services created on this instance that are still up",
+                );
+                out.push_str(&format!("tor_hidden_services {}\n", addresses.len()));
+            }
+            Err(e) => warn!("Onion services left out of the metrics: {:?}", e),
+        }
+        out
+    }
+
+    /// `GETINFO traffic/read` or `traffic/written` as a number
+    fn traffic_counter(&self, key: &str) -> Result<u64, TorErrors> {
+        let value = self.get_info(key)?;
+        value
+            .trim()
+            .parse()
+            .map_err(|_| TorErrors::ControlCommandError {
+                code: 250,
+                message: format!("Invalid {} value: {}", key, value),
+            })
+    }
+
+    /// Relays of the current consensus with their flags and bandwidth (`GETINFO ns/all`), e.g.
+    /// to pick exits or entry nodes by speed. Several MB of text to fetch and parse
+    pub fn relay_statuses(&self) -> Result<Vec<RelayStatus>, TorErrors> {
+        let document = self.with_control(|ctl| ctl.get_info("ns/all"))?;
+        Ok(RelayStatus::parse_all(&document))
+    }
+
+    /// Sets ExitNodes to the fastest quarter of the usable exits by consensus bandwidth (at
+    /// least `MIN_FAST_EXITS` of them), returns how many were kept. See
+    /// `TorServiceParam::prefer_fast_exits` for the anonymity cost. The list is computed
+    /// from the consensus of the moment and not refreshed when it changes
+    pub fn prefer_fast_exits(&self) -> Result<usize, TorErrors> {
+        let mut exits: Vec<RelayStatus> = self
+            .relay_statuses()?
+            .into_iter()
+            .filter(|relay| relay.is_exit() && relay.has_flag("Running") && relay.has_flag("Valid"))
+            .collect();
+        if exits.is_empty() {
+            return Err(TorErrors::InvalidConfig(String::from(
+                "prefer_fast_exits found no running exits in the consensus",
+            )));
+        }
+        exits.sort_by_key(|relay| std::cmp::Reverse(relay.bandwidth.unwrap_or(0)));
+        exits.truncate((exits.len() / 4).max(MIN_FAST_EXITS));
+        let exit_nodes = exits
+            .iter()
+            .map(|relay| format!("${}", relay.fingerprint))
+            .collect::<Vec<_>>()
+            .join(",");
+        self.with_control(|ctl| ctl.set_conf(&[(String::from("ExitNodes"), exit_nodes)]))?;
+        info!("Exits restricted to the {} fastest", exits.len());
+        Ok(exits.len())
+    }
+
+    /// Tears down a single circuit (`CLOSECIRCUIT`), streams on it are closed as well
+    /// Finer grained than a NEWNYM, find the id with `list_circuits`
+    pub fn close_circuit(&self, circuit_id: &str) -> Result<(), TorErrors> {
+        let circuit_id = check_circuit_id(circuit_id)?;
+        self.with_control(|ctl| ctl.command(&format!("CLOSECIRCUIT {}", circuit_id)))?;
+        info!("Circuit {} closed", circuit_id);
+        Ok(())
+    }
+
+    /// Asks Tor to build `count` general purpose circuits now, so the first requests don't wait
+    /// for one. Returns the ids of the circuits launched, they are still being built
+    /// Tor closes circuits left unused for a while (CircuitsAvailableTimeout, 30 minutes)
+    pub fn prebuild_circuits(&self, count: usize) -> Result<Vec<String>, TorErrors> {
+        let ids = self.with_control(|ctl| launch_circuits(ctl, count))?;
+        info!("Launched {} circuits", ids.len());
+        Ok(ids)
+    }
+
+    /// `prebuild_circuits` then waits up to `timeout_ms` for the circuits to finish building
+    /// Returns how many got built, the others failed or were still building at the timeout
+    /// E.g. run it behind a loading screen before the app's first requests
+    pub fn warm_up(&self, count: usize, timeout_ms: u64) -> Result<usize, TorErrors> {
+        let mut ctl = self.raw_control()?;
+        // Subscribed first so no status change of the new circuits is missed
+        ctl.command("SETEVENTS CIRC")?;
+        let mut pending = launch_circuits(&mut ctl, count)?;
+
+        let deadline = Instant::now() + Duration::from_millis(timeout_ms);
+        let mut built = 0;
+        while !pending.is_empty() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let Some(event) = ctl.next_event_within(remaining)? else {
+                break;
+            };
+            // "CIRC id status ...", same fields as a circuit-status line
+            let Some(circuit) = event
+                .lines
+                .first()
+                .and_then(|line| line.strip_prefix("CIRC "))
+                .and_then(CircuitInfo::parse)
+            else {
+                continue;
+            };
+            let Some(index) = pending.iter().position(|id| *id == circuit.id) else {
+                continue;
+            };
+            match circuit.status.as_str() {
+                "BUILT" => built += 1,
+                "FAILED" | "CLOSED" => {}
+                _ => continue,
+            }
+            pending.swap_remove(index);
+        }
+        info!("Warm up built {} of {} circuits", built, count);
+        Ok(built)
+    }
+
+    /// Makes Tor re-read its torrc (`SIGNAL RELOAD`, same as a SIGHUP) without restarting
+    /// Options this crate set from `TorServiceParam` are reset by the reload, so they are sent
+    /// again right after and keep precedence over the file
+    /// Errors with `TorErrors::ControlCommandError` carrying Tor's warnings when the torrc
+    /// fails to parse. Tor treats that as fatal and exits, so the service is gone afterwards
+    pub fn reload_config(&self) -> Result<(), TorErrors> {
+        let mut ctl = self.raw_control()?;
+        ctl.command("SETEVENTS WARN ERR")?;
+        ctl.command("SIGNAL RELOAD")?;
+
+        let deadline = Instant::now() + RELOAD_LOG_WINDOW;
+        let mut events = Vec::new();
+        let mut closed = None;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match ctl.next_event_within(remaining) {
+                Ok(Some(event)) => events.push(event),
+                Ok(None) => break,
+                // Tor closes the connection when it exits over a bad torrc
+                Err(e) => {
+                    closed = Some(e);
+                    break;
+                }
+            }
+        }
+        if let Some(message) = reload_failure(&events) {
+            return Err(TorErrors::ControlCommandError {
+                code: 0,
+                message: format!("Tor rejected the reloaded config: {}", message),
+            });
+        }
+        if let Some(e) = closed {
+            return Err(e);
+        }
+        ctl.command("SETEVENTS")?;
+        if !self.conf.is_empty() {
+            ctl.set_conf(&self.conf)?;
+        }
+        info!("Tor configuration reloaded");
+        Ok(())
+    }
+
+    /// Aliases `from` to `to` (`MAPADDRESS`), e.g. pins a hostname to an onion so requests to
+    /// `from` through the SOCKS port go to `to` instead
+    /// Returns the address that was mapped, when `from` is "." (or 0.0.0.0 / ::0) Tor picks
+    /// an unused virtual address and that one is returned
+    /// Errors with `TorErrors::InvalidConfig` when either side isn't a hostname or IP address
+    pub fn map_address(&self, from: &str, to: &str) -> Result<String, TorErrors> {
+        let command = format!(
+            "MAPADDRESS {}={}",
+            check_map_address(from)?,
+            check_map_address(to)?
+        );
+        let reply = self.with_control(|ctl| ctl.command(&command))?;
+        let mapped = parse_mapped_address(&reply.lines).ok_or(TorErrors::ControlCommandError {
+            code: reply.code,
+            message: format!("Unexpected MAPADDRESS reply: {}", reply.lines.join(" ")),
+        })?;
+        info!(
+            "{}",
+            safe_logging::scrub(&format!("Mapped {} to {}", mapped, to))
+        );
+        Ok(mapped)
+    }
+
+    /// Descriptor of `onion_address` from Tor's client cache (`GETINFO hs/client/desc/id`)
+    /// Only available after the service has been contacted, errors with a 552 reply otherwise
+    pub fn client_descriptor(&self, onion_address: &str) -> Result<HsDescriptor, TorErrors> {
+        let service_id = check_service_id(onion_address)?;
+        let raw =
+            self.with_control(|ctl| ctl.get_info(&format!("hs/client/desc/id/{}", service_id)))?;
+        HsDescriptor::parse(&raw).ok_or(TorErrors::ControlCommandError {
+            code: 250,
+            message: format!("Unparsable descriptor for {}", service_id),
+        })
+    }
+
+    /// Asks the HSDirs for the descriptor of `onion_address` (`HSFETCH`) and returns its text
+    /// Confirms a service is published, failures carry the HSDir reasons (e.g. NOT_FOUND)
+    /// Errors with `TorErrors::InvalidConfig` when `onion_address` isn't a v3 address
+    pub fn fetch_descriptor(&self, onion_address: &str) -> Result<String, TorErrors> {
+        let service_id = check_service_id(onion_address)?;
+        let mut ctl = self.raw_control()?;
+        ctl.command("SETEVENTS HS_DESC HS_DESC_CONTENT")?;
+        ctl.command(&format!("HSFETCH {}", service_id))?;
+
+        let deadline = Instant::now() + DESCRIPTOR_FETCH_TIMEOUT;
+        let mut failures = Vec::new();
+        while Instant::now() < deadline {
+            // Only wait what's left of the deadline, the connection is dropped afterwards
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let event = match ctl.next_event_within(remaining)? {
+                Some(event) => event,
+                None => break,
+            };
+            let first = event.lines.first().map(String::as_str).unwrap_or("");
+            match parse_descriptor_event(first, &service_id) {
+                Some(DescriptorEvent::Content(descriptor)) => return Ok(descriptor),
+                Some(DescriptorEvent::Failed(reason)) => failures.push(reason),
+                None => {}
+            }
+        }
+        Err(TorErrors::ControlCommandError {
+            code: 0,
+            message: format!(
+                "No descriptor for {} received ({})",
+                service_id,
+                if failures.is_empty() {
+                    String::from("timed out")
+                } else {
+                    failures.join(", ")
+                }
+            ),
+        })
+    }
+
+    /// Uploads a signed onion service descriptor to the responsible HSDirs (`HSPOST`), or to
+    /// `servers` (relay fingerprints) when given
+    /// Building blocks for OnionBalance style setups: a balancer fetches the instances'
+    /// descriptors with `client_descriptor`, builds and signs the combined frontend
+    /// descriptor itself and publishes it here
+    /// Errors with `TorErrors::InvalidConfig` when `onion_address` isn't a v3 address or a
+    /// server isn't a relay fingerprint
+    pub fn publish_descriptor(
+        &self,
+        descriptor: &str,
+        onion_address: &str,
+        servers: &[String],
+    ) -> Result<(), TorErrors> {
+        let mut command = String::from("HSPOST");
+        for server in servers {
+            command.push_str(&format!(" SERVER={}", check_fingerprint(server)?));
+        }
+        let service_id = check_service_id(onion_address)?;
+        command.push_str(&format!(" HSADDRESS={}", service_id));
+        self.with_control(|ctl| ctl.command_with_data(&command, descriptor))?;
+        info!(
+            "Descriptor posted for {}",
+            safe_logging::scrub(&format!("{}.onion", service_id))
+        );
+        Ok(())
+    }
+
+    /// Waits until `onion_address` accepts connections through this node, see
+    /// `wait_until_onion_reachable`. E.g. before handing a fresh onion to peers
+    pub fn wait_until_reachable(
+        &self,
+        onion_address: &str,
+        timeout_ms: u64,
+    ) -> Result<bool, TorErrors> {
+        wait_until_onion_reachable(self.socks_port, onion_address, timeout_ms)
+    }
+
+    /// Times a SOCKS connect to `host:port` through this node to gauge the current circuit
+    /// latency, see `measure_circuit_latency`
+    pub fn measure_latency(
+        &self,
+        host: &str,
+        port: u16,
+        timeout_ms: u64,
+    ) -> Result<Duration, TorErrors> {
+        measure_circuit_latency(self.socks_port, host, port, timeout_ms)
+    }
+    /// take control conn and drop it.
+    /// Closing the owned connection and causes tor daemon to shutdown
+    /// Then waits on the Tor daemon thread (or child process) to exit
+    pub fn shutdown(&mut self) -> Result<(), TorErrors> {
+        killswitch::mark_down(self.socks_port);
+        http_client::unregister_control_port(self.socks_port);
+        {
+            let _ = self.raw_ctl.borrow_mut().take();
+            let _ = self._ctl.borrow_mut().take();
+            self.onions.borrow_mut().clear();
+        }
+        let result = self
             ._handle
             .take()
             .ok_or(TorErrors::BootStrapError(String::from(
                 "Error shutdown take handle",
             )))?
-            .join()
-            .map_err(|_| TorErrors::BootStrapError(String::from("Error joining on shutdown")))?;
-        Ok(())
+            .join();
+        remove_ephemeral_dir(self.ephemeral_dir.take());
+        result
+    }
+}
+
+/// Stops the daemon when the service goes away without `shutdown` (early returns, panics)
+/// Dropping the owning control connection makes Tor exit, whether it runs on a thread of this
+/// process or as the `tor_binary` child
+impl Drop for OwnedTorService {
+    fn drop(&mut self) {
+        if self._handle.is_none() {
+            return;
+        }
+        debug!("OwnedTorService dropped without shutdown, stopping Tor");
+        if let Err(e) = self.shutdown() {
+            warn!("Failed to stop Tor on drop: {:?}", e);
+        }
     }
 }
 /// High level API for Torut used internally by TorService to expose
@@ -438,26 +2138,54 @@ where
     fn wait_bootstrap(
         &mut self,
         timeout_ms: Option<u64>,
+    ) -> Pin<Box<dyn Future<Output = Result<bool, TorErrors>> + '_>> {
+        self.wait_bootstrap_cancellable(timeout_ms, CancelToken::new())
+    }
+    fn wait_bootstrap_cancellable(
+        &mut self,
+        timeout_ms: Option<u64>,
+        cancel: CancelToken,
     ) -> Pin<Box<dyn Future<Output = Result<bool, TorErrors>> + '_>> {
         // Wait for boostrap to be done
         let future = async move {
-            timeout(
+            // Last skew Tor warned about, reported instead of a plain timeout
+            let skew = Cell::new(None);
+            let last_skew = &skew;
+            let waited = timeout(
                 Duration::from_millis(timeout_ms.unwrap_or(15000)),
                 async move {
                     let mut input = String::new();
                     while !input.trim().contains("PROGRESS=100 TAG=done") {
+                        if cancel.is_cancelled() {
+                            return Err(TorErrors::BootstrapCancelled);
+                        }
                         input = self
                             .get_info("status/bootstrap-phase")
                             .await
                             .map_err(TorErrors::ControlConnectionError)?;
+                        bootstrap::record(&input);
+                        // Tor keeps retrying but won't get anywhere until the clock is fixed
+                        if let Some(offset_seconds) = bootstrap::sustained_clock_skew(&input) {
+                            return Err(TorErrors::ClockSkew { offset_seconds });
+                        }
+                        let skewed = bootstrap::clock_skew(&input);
+                        if skewed.is_some() && last_skew.replace(skewed) != skewed {
+                            warn!("Tor reports a clock skew of {}s", skewed.unwrap_or(0));
+                        }
                         std::thread::sleep(std::time::Duration::from_millis(300));
                     }
                     Ok(true)
                 },
             )
             .compat()
-            .await
-            .map_err(|_| TorErrors::BootStrapError(String::from("Timeout waiting for boostrap")))?
+            .await;
+            match (waited, skew.get()) {
+                (Ok(result), _) => result,
+                (Err(_), Some(offset_seconds)) => Err(TorErrors::ClockSkew { offset_seconds }),
+                (Err(_), None) => Err(TorErrors::BootStrapError(String::from(
+                    "Timeout waiting for boostrap",
+                ))),
+            }
         }
         .compat();
         Box::pin(future)
@@ -473,6 +2201,7 @@ where
                     .compat()
                     .await
                     .map_err(TorErrors::ControlConnectionError)?;
+                bootstrap::record(&input);
                 if input.trim().contains("TAG=done") {
                     Ok(OwnedTorServiceBootstrapPhase::Done)
                 } else {
@@ -497,6 +2226,401 @@ mod tests {
     use std::io::Write;
     use std::net::TcpListener;
 
+    #[test]
+    fn parses_service_param_json() {
+        let param = TorServiceParam::from_json(
+            r#"{"data_dir": "/tmp/tor", "socks_port": 19050, "bootstrap_timeout_ms": 30000}"#,
+        )
+        .unwrap();
+        assert_eq!(param.data_dir, "/tmp/tor");
+        assert_eq!(param.socks_port, Some(19050));
+        assert_eq!(param.bootstrap_timeout_ms, Some(30000));
+
+        let param = TorServiceParam::from_json(r#"{"data_dir": "/tmp/tor"}"#).unwrap();
+        assert_eq!(param.socks_port, None);
+
+        match TorServiceParam::from_json(r#"{"data_dir": "/tmp/tor", "sock_port": 19050}"#) {
+            Err(TorErrors::InvalidConfig(message)) => {
+                assert!(message.contains("unknown field `sock_port`"), "{}", message)
+            }
+            other => panic!("Expected InvalidConfig, got {:?}", other.map(|_| ())),
+        }
+        assert!(matches!(
+            TorServiceParam::from_json(r#"{"socks_port": 19050}"#),
+            Err(TorErrors::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn creates_private_ephemeral_dirs() {
+        let first = TorServiceParam::ephemeral().unwrap();
+        let second = TorServiceParam::ephemeral().unwrap();
+        assert_ne!(first.data_dir, second.data_dir);
+        assert!(first.ephemeral.is_some());
+        assert_eq!(first.auto_socks_port, Some(true));
+        for mut param in [first, second] {
+            let metadata = fs::metadata(&param.data_dir).unwrap();
+            assert!(metadata.is_dir());
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                assert_eq!(metadata.permissions().mode() & 0o777, 0o700);
+            }
+            remove_ephemeral_dir(param.ephemeral.take());
+            assert!(!Path::new(&param.data_dir).exists());
+        }
+    }
+
+    #[test]
+    fn never_deletes_a_caller_data_dir() {
+        assert!(matches!(
+            TorServiceParam::from_json(r#"{"data_dir": "/tmp/tor", "ephemeral": true}"#),
+            Err(TorErrors::InvalidConfig(_))
+        ));
+
+        // Params that fail validation still drop their temp dir
+        let param = TorServiceParam {
+            num_entry_guards: Some(0),
+            ..TorServiceParam::ephemeral().unwrap()
+        };
+        let data_dir = param.data_dir.clone();
+        assert!(TorService::try_from(param).is_err());
+        assert!(!Path::new(&data_dir).exists());
+    }
+
+    #[test]
+    fn gives_up_on_unreachable_onion() {
+        let unused = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = unused.local_addr().unwrap().port();
+        drop(unused);
+        for invalid in ["example.com", ".onion", "abc.onion:http"] {
+            assert!(matches!(
+                wait_until_onion_reachable(port, invalid, 100),
+                Err(TorErrors::InvalidConfig(_))
+            ));
+        }
+        // Nothing listens on the SOCKS port, every probe fails until the deadline
+        let started = Instant::now();
+        assert!(!wait_until_onion_reachable(port, "abc.onion:8080", 300).unwrap());
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn detects_socks_port_in_use() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+        assert!(matches!(
+            resolve_socks_port(port, false),
+            Err(TorErrors::SocksPortInUse(p)) if p == port
+        ));
+        assert_eq!(TorErrors::SocksPortInUse(port).code(), 11);
+        assert_eq!(resolve_socks_port(port, true).unwrap(), None);
+        drop(taken);
+        assert_eq!(resolve_socks_port(port, false).unwrap(), Some(port));
+    }
+
+    #[test]
+    fn parses_socks_listeners() {
+        assert_eq!(parse_listener_port("\"127.0.0.1:45123\""), Some(45123));
+        assert_eq!(
+            parse_listener_port("\"[::1]:9150\" \"127.0.0.1:9150\""),
+            Some(9150)
+        );
+        assert_eq!(parse_listener_port(""), None);
+    }
+
+    #[test]
+    fn generates_onion_keypair_offline() {
+        let (onion_address, secret_key) = generate_onion_keypair();
+        assert!(onion_address.ends_with(".onion"));
+        assert_eq!(onion_address.len(), 62);
+        let restored: TorSecretKeyV3 = secret_key.into();
+        assert_eq!(
+            restored.public().get_onion_address().to_string(),
+            onion_address
+        );
+    }
+
+    #[test]
+    fn parses_tor_versions() {
+        assert_eq!(
+            TorVersion::parse("0.4.8.10 (git-6a9a5f4e5b2e1c9b)"),
+            Some(TorVersion::new(0, 4, 8, 10))
+        );
+        assert_eq!(
+            TorVersion::parse("0.4.9.1-alpha"),
+            Some(TorVersion::new(0, 4, 9, 1))
+        );
+        assert_eq!(
+            TorVersion::parse("0.3.5"),
+            Some(TorVersion::new(0, 3, 5, 0))
+        );
+        assert_eq!(TorVersion::parse("unknown"), None);
+        assert!(TorVersion::new(0, 4, 8, 10) > ONION_V3_MIN_VERSION);
+        assert!(TorVersion::new(0, 3, 2, 9) < ONION_V3_MIN_VERSION);
+    }
+
+    #[test]
+    fn only_refuses_known_old_versions() {
+        let check = |found: &str| check_tor_version("v3", ONION_V3_MIN_VERSION, found.into());
+        assert!(check("0.4.8.10").is_ok());
+        assert!(check("custom build").is_ok());
+        assert!(matches!(
+            check("0.3.2.9"),
+            Err(TorErrors::UnsupportedTorVersion { found, .. }) if found == "0.3.2.9"
+        ));
+    }
+
+    #[test]
+    fn parses_descriptor_events() {
+        let id = "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad";
+        assert_eq!(
+            parse_descriptor_event(
+                &format!(
+                    "HS_DESC_CONTENT {} descid $HSDIR~dir\nhs-descriptor 3\nsignature x",
+                    id
+                ),
+                id
+            ),
+            Some(DescriptorEvent::Content(String::from(
+                "hs-descriptor 3\nsignature x"
+            )))
+        );
+        assert_eq!(
+            parse_descriptor_event(
+                &format!(
+                    "HS_DESC FAILED {} NO_AUTH $HSDIR~dir descid REASON=NOT_FOUND",
+                    id
+                ),
+                id
+            ),
+            Some(DescriptorEvent::Failed(String::from(
+                "$HSDIR~dir: NOT_FOUND"
+            )))
+        );
+        assert_eq!(
+            parse_descriptor_event(&format!("HS_DESC_CONTENT {} descid $HSDIR~dir\n", id), id),
+            None
+        );
+        assert_eq!(
+            parse_descriptor_event("HS_DESC REQUESTED other NO_AUTH $HSDIR", id),
+            None
+        );
+    }
+
+    #[test]
+    fn resolves_hidden_service_targets() {
+        let param = |host: Option<&str>| TorHiddenServiceParam {
+            to_port: 8080,
+            hs_port: 80,
+            target_host: host.map(String::from),
+            ..Default::default()
+        };
+        assert_eq!(param(None).target().unwrap().to_string(), "127.0.0.1:8080");
+        assert_eq!(
+            param(Some("192.168.1.20")).target().unwrap().to_string(),
+            "192.168.1.20:8080"
+        );
+        for ipv6 in ["fd00::2", "[fd00::2]"] {
+            assert_eq!(
+                param(Some(ipv6)).target().unwrap().to_string(),
+                "[fd00::2]:8080"
+            );
+        }
+        assert_eq!(param(Some("localhost")).target().unwrap().port(), 8080);
+        for invalid in ["", "back end", "-app", "app:8080", "http://app"] {
+            assert!(
+                matches!(
+                    param(Some(invalid)).target(),
+                    Err(TorErrors::InvalidConfig(_))
+                ),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn maps_hidden_service_max_streams() {
+        let param = |max_streams, max_streams_close_circuit| TorHiddenServiceParam {
+            to_port: 8080,
+            hs_port: 80,
+            discard_key: true,
+            max_streams,
+            max_streams_close_circuit,
+            ..Default::default()
+        };
+        let target = SocketAddr::from(([127, 0, 0, 1], 8080));
+        assert_eq!(
+            param(None, false).discarded_key_command(target),
+            "ADD_ONION NEW:ED25519-V3 Flags=DiscardPK,Detach Port=80,127.0.0.1:8080"
+        );
+        let limited = param(Some(16), true);
+        assert!(limited.validate_streams().is_ok());
+        assert_eq!(
+            limited.discarded_key_command(target),
+            "ADD_ONION NEW:ED25519-V3 Flags=DiscardPK,Detach,MaxStreamsCloseCircuit \
+             MaxStreams=16 Port=80,127.0.0.1:8080"
+        );
+        assert!(param(Some(16), false).validate_streams().is_ok());
+        for invalid in [param(Some(0), false), param(None, true)] {
+            assert!(matches!(
+                invalid.validate_streams(),
+                Err(TorErrors::InvalidConfig(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn reports_reload_errors() {
+        let event = |line: &str| control::ControlReply {
+            code: control::EVENT_CODE,
+            lines: vec![String::from(line)],
+        };
+        assert_eq!(
+            reload_failure(&[event("WARN Skipping obsolete option")]),
+            None
+        );
+        assert_eq!(
+            reload_failure(&[
+                event("WARN Unknown option 'SocksPrt'.  Failing."),
+                event("ERR Reading config failed--see warnings above."),
+            ]),
+            Some(String::from(
+                "Unknown option 'SocksPrt'.  Failing.; Reading config failed--see warnings above."
+            ))
+        );
+    }
+
+    #[test]
+    fn checks_circuit_ids() {
+        assert_eq!(check_circuit_id(" 12 ").unwrap(), "12");
+        for id in ["", "12\r\nSIGNAL HALT", "12 13", "-1"] {
+            assert!(check_circuit_id(id).is_err(), "{:?} was accepted", id);
+        }
+    }
+
+    #[test]
+    fn checks_map_addresses() {
+        for address in [
+            ".",
+            "0.0.0.0",
+            "::0",
+            "[::1]",
+            "example.com",
+            "*.example.com",
+        ] {
+            assert!(
+                check_map_address(address).is_ok(),
+                "{:?} was refused",
+                address
+            );
+        }
+        for address in ["", "a b", "a.com\r\nSIGNAL HALT", "a=b", "*."] {
+            assert!(
+                check_map_address(address).is_err(),
+                "{:?} was accepted",
+                address
+            );
+        }
+    }
+
+    #[test]
+    fn checks_onion_ids_and_fingerprints() {
+        let id = "a".repeat(ONION_V3_ID_LEN);
+        assert_eq!(check_service_id(&format!(" {}.onion", id)).unwrap(), id);
+        assert_eq!(check_service_id(&id.to_uppercase()).unwrap(), id);
+        for address in [
+            String::from("short.onion"),
+            format!("{}1", &id[1..]),
+            format!("{}\r\nSIGNAL HALT", id),
+        ] {
+            assert!(
+                check_service_id(&address).is_err(),
+                "{:?} was accepted",
+                address
+            );
+        }
+
+        let fingerprint = "0123456789ABCDEFabcdef0123456789ABCDEF01";
+        assert!(check_fingerprint(fingerprint).is_ok());
+        assert!(check_fingerprint(&format!("${}", fingerprint)).is_ok());
+        for server in [
+            "",
+            &fingerprint[1..],
+            "0123456789ABCDEFabcdef0123456789ABCDEFXY",
+        ] {
+            assert!(
+                check_fingerprint(server).is_err(),
+                "{:?} was accepted",
+                server
+            );
+        }
+    }
+
+    #[test]
+    fn parses_mapped_address() {
+        let lines = vec![String::from("127.192.10.10=abc.onion")];
+        assert_eq!(
+            parse_mapped_address(&lines),
+            Some(String::from("127.192.10.10"))
+        );
+        assert_eq!(parse_mapped_address(&[String::from("OK")]), None);
+    }
+
+    #[test]
+    fn picks_worker_threads() {
+        assert_eq!(worker_threads(0, None), DEFAULT_WORKER_THREADS);
+        assert_eq!(worker_threads(0, Some(String::from(" 8 "))), 8);
+        assert_eq!(
+            worker_threads(0, Some(String::from("0"))),
+            DEFAULT_WORKER_THREADS
+        );
+        assert_eq!(
+            worker_threads(0, Some(String::from("many"))),
+            DEFAULT_WORKER_THREADS
+        );
+        assert_eq!(worker_threads(3, Some(String::from("8"))), 3);
+    }
+
+    #[test]
+    fn parses_hs_secret_key_files() {
+        let (onion_address, secret_key) = generate_onion_keypair();
+        let mut file = HS_SECRET_KEY_HEADER.to_vec();
+        file.resize(HS_SECRET_KEY_HEADER_LEN, 0);
+        file.extend_from_slice(&secret_key);
+        let parsed = parse_hs_secret_key(&file).unwrap();
+        let restored: TorSecretKeyV3 = parsed.into();
+        assert_eq!(
+            restored.public().get_onion_address().to_string(),
+            onion_address
+        );
+
+        assert!(parse_hs_secret_key(&file[..80]).is_err());
+        let mut public_key = file.clone();
+        public_key[..HS_SECRET_KEY_HEADER.len()].copy_from_slice(b"== ed25519v1-public: type0 ==");
+        assert!(parse_hs_secret_key(&public_key).is_err());
+        let mut unclamped = file.clone();
+        unclamped[HS_SECRET_KEY_HEADER_LEN] |= 1;
+        assert!(parse_hs_secret_key(&unclamped).is_err());
+    }
+
+    #[test]
+    fn parses_extended_circuit() {
+        assert_eq!(
+            parse_extended_circuit(&[String::from("EXTENDED 42")]),
+            Some(String::from("42"))
+        );
+        assert_eq!(parse_extended_circuit(&[String::from("OK")]), None);
+    }
+
+    #[test]
+    fn parses_onion_lists() {
+        let ids: Vec<String> = parse_onion_list("\nabc\ndef.onion\n").collect();
+        assert_eq!(ids, vec!["abc.onion", "def.onion"]);
+        assert_eq!(parse_onion_list("").count(), 0);
+    }
+
     #[test]
     #[serial(tor)]
     fn from_param_and_await_boostrap() {
@@ -506,6 +2630,7 @@ mod tests {
                     socks_port: Some(19051),
                     data_dir: String::from("/tmp/torlib2"),
                     bootstrap_timeout_ms: Some(45000),
+                    ..Default::default()
                 }
                 .try_into()
                 .unwrap();
@@ -540,6 +2665,7 @@ mod tests {
                     socks_port: Some(19051),
                     data_dir: String::from("/tmp/torlib2"),
                     bootstrap_timeout_ms: Some(1000),
+                    ..Default::default()
                 }
                 .try_into()
                 .unwrap();
@@ -554,6 +2680,46 @@ mod tests {
         );
     }
 
+    #[test]
+    #[serial(tor)]
+    fn drop_stops_tor() {
+        let owned_node: OwnedTorService = TorServiceParam {
+            socks_port: Some(19054),
+            data_dir: String::from("/tmp/sifir_rs_sdk"),
+            bootstrap_timeout_ms: Some(45000),
+            ..Default::default()
+        }
+        .try_into()
+        .unwrap();
+        let socks = format!("127.0.0.1:{}", owned_node.socks_port);
+        assert!(std::net::TcpStream::connect(&socks).is_ok());
+        drop(owned_node);
+        assert!(std::net::TcpStream::connect(&socks).is_err());
+    }
+
+    #[test]
+    #[serial(tor)]
+    fn bootstrap_cancelled() {
+        let service: TorService = TorServiceParam {
+            socks_port: Some(19051),
+            data_dir: String::from("/tmp/torlib2"),
+            bootstrap_timeout_ms: Some(45000),
+            ..Default::default()
+        }
+        .try_into()
+        .unwrap();
+        let cancel = CancelToken::new();
+        let canceller = cancel.clone();
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            canceller.cancel();
+        });
+        let started = Instant::now();
+        let result = service.bootstrap_with_cancel(&cancel);
+        assert!(matches!(result, Err(TorErrors::BootstrapCancelled)));
+        assert!(started.elapsed() < Duration::from_secs(30));
+    }
+
     #[test]
     #[serial(tor)]
     fn to_owned() {
@@ -561,6 +2727,7 @@ mod tests {
             socks_port: Some(19054),
             data_dir: String::from("/tmp/torlib2"),
             bootstrap_timeout_ms: Some(45000),
+            ..Default::default()
         }
         .try_into()
         .unwrap();
@@ -590,6 +2757,7 @@ mod tests {
             socks_port: Some(19054),
             data_dir: String::from("/tmp/sifir_rs_sdk/"),
             bootstrap_timeout_ms: Some(30000),
+            ..Default::default()
         }
         .try_into()
         .unwrap();
@@ -603,12 +2771,90 @@ mod tests {
             socks_port: Some(19054),
             data_dir: String::from("/tmp/sifir_rs_sdk"),
             bootstrap_timeout_ms: Some(45000),
+            ..Default::default()
         }
         .try_into()
         .unwrap();
         let mut owned_node = service.into_owned_node().unwrap();
         let status = owned_node.get_status().unwrap();
         assert!(matches!(status, OwnedTorServiceBootstrapPhase::Done));
+        assert!(owned_node.is_bootstrapped());
+        owned_node.shutdown().unwrap();
+    }
+    #[test]
+    #[serial(tor)]
+    fn service_health() {
+        let service: TorService = TorServiceParam {
+            socks_port: Some(19054),
+            data_dir: String::from("/tmp/sifir_rs_sdk"),
+            bootstrap_timeout_ms: Some(45000),
+            ..Default::default()
+        }
+        .try_into()
+        .unwrap();
+        let mut owned_node = service.into_owned_node().unwrap();
+        let health = owned_node.service_health().unwrap();
+        assert!(matches!(
+            health.bootstrap,
+            OwnedTorServiceBootstrapPhase::Done
+        ));
+        assert_eq!(health.version, owned_node.version().unwrap());
+        assert_eq!(health.socks_port, 19054);
+        assert_eq!(health.hidden_services, 0);
+        // Bootstrapping alone downloads the consensus
+        assert!(health.bytes_read > 0);
+        owned_node.shutdown().unwrap();
+    }
+    #[test]
+    #[serial(tor)]
+    fn version() {
+        let service: TorService = TorServiceParam {
+            socks_port: Some(19054),
+            data_dir: String::from("/tmp/sifir_rs_sdk"),
+            bootstrap_timeout_ms: Some(45000),
+            ..Default::default()
+        }
+        .try_into()
+        .unwrap();
+        let mut owned_node = service.into_owned_node().unwrap();
+        let version = owned_node.version().unwrap();
+        // e.g. "0.4.8.10 (git-6a9a5f4e5b2e1c9b)"
+        let numbers: Vec<&str> = version
+            .split_whitespace()
+            .next()
+            .unwrap()
+            .split('.')
+            .collect();
+        assert!(numbers.len() >= 3, "unexpected version {}", version);
+        assert!(
+            numbers[..3]
+                .iter()
+                .all(|n| !n.is_empty() && n.chars().all(|c| c.is_ascii_digit())),
+            "unexpected version {}",
+            version
+        );
+        owned_node.shutdown().unwrap();
+    }
+    #[test]
+    #[serial(tor)]
+    fn measure_latency() {
+        let service: TorService = TorServiceParam {
+            socks_port: Some(19054),
+            data_dir: String::from("/tmp/sifir_rs_sdk"),
+            bootstrap_timeout_ms: Some(45000),
+            ..Default::default()
+        }
+        .try_into()
+        .unwrap();
+        let mut owned_node = service.into_owned_node().unwrap();
+        let latency = owned_node
+            .measure_latency(
+                "keybase5wmilwokqirssclfnsqrjdsi7jdir5wy7y7iu3tanwmtp6oid.onion",
+                80,
+                60_000,
+            )
+            .unwrap();
+        assert!(latency > Duration::from_millis(0));
         owned_node.shutdown().unwrap();
     }
     #[test]
@@ -618,6 +2864,7 @@ mod tests {
             socks_port: Some(19054),
             data_dir: String::from("/tmp/sifir_rs_sdk"),
             bootstrap_timeout_ms: Some(45000),
+            ..Default::default()
         }
         .try_into()
         .unwrap();
@@ -627,7 +2874,7 @@ mod tests {
             .create_hidden_service(TorHiddenServiceParam {
                 to_port: 20000,
                 hs_port: 20011,
-                secret_key: None,
+                ..Default::default()
             })
             .unwrap();
         assert!(service_key.onion_url.to_string().contains(".onion"));
@@ -656,4 +2903,113 @@ mod tests {
         );
         owned_node.shutdown().unwrap();
     }
+
+    #[test]
+    #[serial(tor)]
+    fn concurrent_hidden_services_and_http_requests() {
+        use crate::http_client::{HttpRequestParams, make_http_request};
+        use std::io::Read;
+        use std::sync::mpsc;
+        use std::sync::{Arc, Mutex};
+
+        let socks_port = 19054;
+        let mut owned_node: OwnedTorService = TorServiceParam {
+            socks_port: Some(socks_port),
+            data_dir: String::from("/tmp/sifir_rs_sdk"),
+            bootstrap_timeout_ms: Some(45000),
+            ..Default::default()
+        }
+        .try_into()
+        .unwrap();
+        let service_key = owned_node
+            .create_hidden_service(TorHiddenServiceParam {
+                to_port: 20001,
+                hs_port: 20012,
+                ..Default::default()
+            })
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:20001").unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                std::thread::spawn(move || {
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match stream.read(&mut buf) {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let response =
+                        "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok";
+                    let _ = stream.write_all(response.as_bytes());
+                });
+            }
+        });
+
+        // Shared the way the FFI holds it, HTTP requests never take this lock
+        let owned_node = Arc::new(Mutex::new(owned_node));
+        let url = format!("http://{}/", service_key.onion_url);
+        let (done_tx, done_rx) = mpsc::channel();
+        let mut workers = Vec::new();
+        for _ in 0..4 {
+            let url = url.clone();
+            let done_tx = done_tx.clone();
+            workers.push(std::thread::spawn(move || {
+                for _ in 0..3 {
+                    let params = HttpRequestParams {
+                        url: url.clone(),
+                        timeout_ms: Some(60000),
+                        ..Default::default()
+                    };
+                    let response =
+                        make_http_request(params, format!("127.0.0.1:{}", socks_port)).unwrap();
+                    assert_eq!(response.error, None);
+                    assert_eq!(response.status_code, 200);
+                    assert_eq!(response.body, "ok");
+                }
+                done_tx.send(()).unwrap();
+            }));
+        }
+        {
+            let owned_node = owned_node.clone();
+            let done_tx = done_tx.clone();
+            workers.push(std::thread::spawn(move || {
+                for i in 0..3 {
+                    let created = owned_node
+                        .lock()
+                        .unwrap()
+                        .create_hidden_service(TorHiddenServiceParam {
+                            to_port: 20100 + i,
+                            hs_port: 80,
+                            ..Default::default()
+                        })
+                        .unwrap();
+                    let address = created.onion_url.to_string();
+                    let service_id = address.split(".onion").next().unwrap().to_string();
+                    owned_node
+                        .lock()
+                        .unwrap()
+                        .delete_hidden_service(service_id)
+                        .unwrap();
+                }
+                done_tx.send(()).unwrap();
+            }));
+        }
+
+        drop(done_tx);
+
+        // A deadlock shows up as a worker that never reports back
+        for _ in 0..workers.len() {
+            done_rx
+                .recv_timeout(Duration::from_secs(300))
+                .expect("Worker did not finish, possible deadlock");
+        }
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        owned_node.lock().unwrap().shutdown().unwrap();
+    }
 }
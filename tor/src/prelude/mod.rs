@@ -0,0 +1,42 @@
+//! Commonly used types, `use tor::prelude::*;` covers starting a service,
+//! publishing hidden services and making HTTP requests over Tor
+pub use crate::http_client::{
+    CircuitPinning, HttpMethod, HttpRequestParams, HttpResponse, make_http_request,
+};
+pub use crate::tcp_stream::TcpSocksStream;
+pub use crate::{
+    OwnedTorService, OwnedTorServiceBootstrapPhase, TorErrors, TorHiddenService,
+    TorHiddenServiceParam, TorService, TorServiceParam,
+};
+
+#[cfg(test)]
+mod tests {
+    use crate::prelude::*;
+
+    // Only needs to compile, a removed or renamed export breaks the build here
+    #[test]
+    fn prelude_exports_compile() {
+        fn _start(param: TorServiceParam) -> Result<OwnedTorService, TorErrors> {
+            OwnedTorService::new(param)
+        }
+        fn _publish(
+            service: &mut OwnedTorService,
+            param: TorHiddenServiceParam,
+        ) -> Result<TorHiddenService, TorErrors> {
+            service.create_hidden_service(param)
+        }
+        fn _phase(phase: OwnedTorServiceBootstrapPhase) -> bool {
+            matches!(phase, OwnedTorServiceBootstrapPhase::Done)
+        }
+        fn _stream(target: String, proxy: String) -> Result<TcpSocksStream, TorErrors> {
+            TcpSocksStream::new_timeout(target, proxy, 1000)
+        }
+        let params = HttpRequestParams {
+            method: HttpMethod::GET,
+            circuit_pinning: Some(CircuitPinning::PerHost),
+            ..Default::default()
+        };
+        let _: fn(HttpRequestParams, String) -> Result<HttpResponse, TorErrors> = make_http_request;
+        assert!(matches!(params.method, HttpMethod::GET));
+    }
+}
@@ -0,0 +1,64 @@
+//! Lowers the scheduling priority of the thread Tor runs on
+//! Threads Tor starts later (cpuworkers) inherit it on Linux and Android, where nice values
+//! are per thread. Other platforms only lower the main Tor thread
+
+/// Nice value used on Linux and Android, 0 is normal and 19 the lowest
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const LOW_PRIORITY_NICE: libc::c_int = 10;
+
+/// Lowers the priority of the calling thread only, returns false when the platform refused
+/// or isn't supported
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn lower_current_thread() -> bool {
+    // With PRIO_PROCESS and who = 0 Linux changes the calling thread, not the whole process
+    unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, LOW_PRIORITY_NICE) == 0 }
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+pub(crate) fn lower_current_thread() -> bool {
+    // setpriority would renice the whole app here, QoS classes are per thread
+    unsafe { libc::pthread_set_qos_class_self_np(libc::qos_class_t::QOS_CLASS_UTILITY, 0) == 0 }
+}
+
+#[cfg(windows)]
+pub(crate) fn lower_current_thread() -> bool {
+    use std::ffi::c_void;
+
+    const THREAD_PRIORITY_BELOW_NORMAL: i32 = -1;
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        fn GetCurrentThread() -> *mut c_void;
+        fn SetThreadPriority(thread: *mut c_void, priority: i32) -> i32;
+    }
+    unsafe { SetThreadPriority(GetCurrentThread(), THREAD_PRIORITY_BELOW_NORMAL) != 0 }
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    windows
+)))]
+pub(crate) fn lower_current_thread() -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn lowers_only_the_calling_thread() {
+        let lowered = std::thread::spawn(|| {
+            assert!(lower_current_thread());
+            unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) }
+        })
+        .join()
+        .unwrap();
+        assert!(lowered >= LOW_PRIORITY_NICE);
+        let current = unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) };
+        assert!(current < lowered);
+    }
+}
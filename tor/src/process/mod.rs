@@ -0,0 +1,202 @@
+//! The Tor daemon behind a service: the linked libtor on a thread of this process, or an
+//! external `tor` binary (`TorServiceParam::tor_binary`) run as a child process
+//! Both are configured the same way and driven over the control port, so everything past
+//! startup doesn't care which one it talks to
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::thread::JoinHandle;
+
+use crate::{TorErrors, TorServiceParam};
+
+pub(crate) enum TorProcess {
+    Embedded(JoinHandle<Result<u8, libtor::Error>>),
+    External(Child),
+}
+
+impl TorProcess {
+    /// Waits for the daemon to exit, which it does once the owning control connection closes
+    pub(crate) fn join(self) -> Result<(), TorErrors> {
+        match self {
+            TorProcess::Embedded(handle) => handle
+                .join()
+                .map(|_| ())
+                .map_err(|_| TorErrors::BootStrapError(String::from("Error joining on shutdown"))),
+            TorProcess::External(mut child) => child.wait().map(|_| ()).map_err(TorErrors::IoError),
+        }
+    }
+
+    /// Stops a daemon that never came under our control, only possible for a child process
+    /// An embedded Tor can't be stopped without its control port and is left to exit with us
+    pub(crate) fn abort(self) {
+        if let TorProcess::External(mut child) = self {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Checks the options only a `tor_binary` child supports: its environment and the sandbox
+pub(crate) fn validate_options(param: &TorServiceParam) -> Result<(), TorErrors> {
+    let Some(binary) = &param.tor_binary else {
+        if param.tor_env.is_some() || param.sandbox == Some(true) {
+            return Err(TorErrors::InvalidConfig(String::from(
+                "tor_env and sandbox need tor_binary, they can't apply to the embedded Tor",
+            )));
+        }
+        return Ok(());
+    };
+    validate_binary(binary)?;
+    if param.sandbox == Some(true) && !cfg!(target_os = "linux") {
+        return Err(TorErrors::InvalidConfig(String::from(
+            "sandbox is only supported on Linux",
+        )));
+    }
+    for (name, _) in param.tor_env.iter().flatten() {
+        if name.is_empty() || name.contains(['=', '\0']) {
+            return Err(TorErrors::InvalidConfig(format!(
+                "Invalid environment variable name: {:?}",
+                name
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Checks `binary` is an existing file we may execute
+pub(crate) fn validate_binary(binary: &Path) -> Result<(), TorErrors> {
+    let metadata = std::fs::metadata(binary)
+        .map_err(|e| TorErrors::InvalidConfig(format!("Tor binary {}: {}", binary.display(), e)))?;
+    if !metadata.is_file() {
+        return Err(TorErrors::InvalidConfig(format!(
+            "Tor binary {} is not a file",
+            binary.display()
+        )));
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if metadata.permissions().mode() & 0o111 == 0 {
+            return Err(TorErrors::InvalidConfig(format!(
+                "Tor binary {} is not executable",
+                binary.display()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Starts `binary` with the same torrc options the embedded Tor gets as flags
+/// `binary` is expected to have gone through `validate_options` already
+/// `__OwningControllerProcess` makes the child exit with us even before TAKEOWNERSHIP
+pub(crate) fn spawn_external(
+    binary: &Path,
+    data_dir: &str,
+    cache_dir: &str,
+    socks_port: Option<u16>,
+    ctl_file_path: &str,
+    torrc_file: Option<&str>,
+    param: &TorServiceParam,
+) -> Result<TorProcess, TorErrors> {
+    let mut args = external_args(data_dir, cache_dir, socks_port, ctl_file_path);
+    if let Some(torrc_file) = torrc_file {
+        args.extend([String::from("-f"), torrc_file.to_string()]);
+    }
+    if param.sandbox == Some(true) {
+        args.extend([String::from("--Sandbox"), String::from("1")]);
+    }
+    let child = Command::new(binary)
+        .args(args)
+        .envs(param.tor_env.iter().flatten().cloned())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    Ok(TorProcess::External(child))
+}
+
+/// `socks_port` is `None` for Tor to pick a free one (`SocksPort auto`)
+fn external_args(
+    data_dir: &str,
+    cache_dir: &str,
+    socks_port: Option<u16>,
+    ctl_file_path: &str,
+) -> Vec<String> {
+    [
+        ("--DataDirectory", data_dir.to_string()),
+        ("--CacheDirectory", cache_dir.to_string()),
+        (
+            "--SocksPort",
+            socks_port.map_or(String::from("auto"), |port| port.to_string()),
+        ),
+        ("--ControlPort", String::from("auto")),
+        ("--CookieAuthentication", String::from("1")),
+        ("--ControlPortWriteToFile", ctl_file_path.to_string()),
+        ("--ControlPortFileGroupReadable", String::from("1")),
+        (
+            "--__OwningControllerProcess",
+            std::process::id().to_string(),
+        ),
+    ]
+    .into_iter()
+    .flat_map(|(name, value)| [name.to_string(), value])
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_tor_binary() {
+        assert!(matches!(
+            validate_binary(Path::new("/nonexistent/tor")),
+            Err(TorErrors::InvalidConfig(_))
+        ));
+        assert!(matches!(
+            validate_binary(&std::env::temp_dir()),
+            Err(TorErrors::InvalidConfig(_))
+        ));
+        #[cfg(unix)]
+        {
+            let plain = std::env::temp_dir().join(format!("not-tor-{}", std::process::id()));
+            std::fs::write(&plain, b"").unwrap();
+            assert!(matches!(
+                validate_binary(&plain),
+                Err(TorErrors::InvalidConfig(_))
+            ));
+            std::fs::remove_file(&plain).unwrap();
+            assert!(validate_binary(Path::new("/bin/sh")).is_ok());
+        }
+    }
+
+    #[test]
+    fn validates_child_options() {
+        let mut param = TorServiceParam {
+            sandbox: Some(true),
+            ..Default::default()
+        };
+        assert!(matches!(
+            validate_options(&param),
+            Err(TorErrors::InvalidConfig(_))
+        ));
+        param.sandbox = Some(false);
+        assert!(validate_options(&param).is_ok());
+
+        param.tor_env = Some(vec![("LANG".into(), "C".into())]);
+        assert!(matches!(
+            validate_options(&param),
+            Err(TorErrors::InvalidConfig(_))
+        ));
+        #[cfg(target_os = "linux")]
+        {
+            param.tor_binary = Some("/bin/sh".into());
+            param.sandbox = Some(true);
+            assert!(validate_options(&param).is_ok());
+            param.tor_env = Some(vec![("A=B".into(), "C".into())]);
+            assert!(matches!(
+                validate_options(&param),
+                Err(TorErrors::InvalidConfig(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! Scrubbing of onion addresses and URLs in this crate's own log lines, the counterpart of
+//! Tor's `SafeLogging` (`TorServiceParam::safe_logging`) which only covers Tor's log
+//! Off by default. Once enabled, logged URLs keep only their scheme (`http://[scrubbed]`) and
+//! onion hostnames are replaced, so collected logs don't tell which services were used
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub const SCRUBBED: &str = "[scrubbed]";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_safe_logging(enabled: bool) {
+    ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+pub fn safe_logging_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// `text` as it may be logged: scrubbed when safe logging is on, untouched otherwise
+pub fn scrub(text: &str) -> Cow<'_, str> {
+    if safe_logging_enabled() {
+        Cow::Owned(scrub_text(text))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+/// Replaces everything after `://` up to the end of the URL, then any onion hostname left
+fn scrub_text(text: &str) -> String {
+    let mut scrubbed = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find("://") {
+        scrubbed.push_str(&rest[..i + 3]);
+        let url = &rest[i + 3..];
+        let end = url
+            .find(|c: char| c.is_whitespace() || matches!(c, '"' | '\'' | '<' | '>' | '`'))
+            .unwrap_or(url.len());
+        if end > 0 {
+            scrubbed.push_str(SCRUBBED);
+        }
+        rest = &url[end..];
+    }
+    scrubbed.push_str(rest);
+    scrub_onions(&scrubbed)
+}
+
+/// Replaces `[subdomain.]name.onion` hostnames, whatever their case
+fn scrub_onions(text: &str) -> String {
+    let lower = text.to_ascii_lowercase();
+    let mut scrubbed = String::with_capacity(text.len());
+    let mut pos = 0;
+    while let Some(found) = lower[pos..].find(".onion") {
+        let suffix = pos + found;
+        let end = suffix + ".onion".len();
+        let host_start = text[pos..suffix]
+            .char_indices()
+            .rev()
+            .find(|(_, c)| !(c.is_ascii_alphanumeric() || *c == '.' || *c == '-'))
+            .map_or(pos, |(i, c)| pos + i + c.len_utf8());
+        let is_host = host_start < suffix
+            && !text[end..]
+                .chars()
+                .next()
+                .is_some_and(|c| c.is_ascii_alphanumeric());
+        if is_host {
+            scrubbed.push_str(&text[pos..host_start]);
+            scrubbed.push_str(SCRUBBED);
+        } else {
+            scrubbed.push_str(&text[pos..end]);
+        }
+        pos = end;
+    }
+    scrubbed.push_str(&text[pos..]);
+    scrubbed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scrubs_urls_and_onion_addresses() {
+        let onion = "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+        assert_eq!(
+            scrub_text(&format!("Descriptor posted for {}", onion)),
+            "Descriptor posted for [scrubbed]"
+        );
+        assert_eq!(
+            scrub_text(&format!(
+                "params: \"http://{}/api?key=1\" and https://example.com/x",
+                onion
+            )),
+            "params: \"http://[scrubbed]\" and https://[scrubbed]"
+        );
+        assert_eq!(
+            scrub_text("Mapped www.Example.ONION:80 to 10.0.0.1"),
+            "Mapped [scrubbed]:80 to 10.0.0.1"
+        );
+        assert_eq!(
+            scrub_text("é.onionsite and .onion alone"),
+            "é.onionsite and .onion alone"
+        );
+        assert_eq!(scrub_text("éabc.onion"), "é[scrubbed]");
+        assert_eq!(scrub("plain text"), "plain text");
+    }
+}
@@ -1,23 +1,43 @@
-use crate::ensure_runtime;
+use crate::{ensure_runtime, runtime_handle};
 use crate::TorErrors;
-use socks::Socks5Stream;
+use crate::http_client::{TorTransport, validate_authority};
+use socket2::{SockRef, TcpKeepalive};
+use socks::{Socks5Stream, TargetAddr};
 use std::io::BufRead;
+use std::io::Read;
 use std::io::Write;
-use std::net::Shutdown;
+use std::net::{Shutdown, SocketAddr};
 use std::sync::Arc;
+use std::sync::mpsc::{self, RecvTimeoutError};
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::net::TcpStream;
 use tokio::sync::RwLock;
-use tokio::time::{timeout, Duration};
+use tokio::time::Duration;
 
 type TcpStreamDataHandler = Box<dyn DataObserver + Send + Sync + 'static>;
 
+/// Upper bound on the proxy reply to a CONNECT request
+const MAX_CONNECT_RESPONSE_BYTES: usize = 16 * 1024;
+
 pub struct TcpSocksStream {
     stream: Socks5Stream,
+    info: ConnectionInfo,
     data_handler: Arc<RwLock<Option<TcpStreamDataHandler>>>,
     read_buffer: Arc<RwLock<BufReader<TcpStream>>>,
 }
 
+/// Details of an established stream, for diagnostics
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    /// Target the stream was opened to, as passed to the proxy
+    pub target: String,
+    /// Local end of the connection to the SOCKS proxy
+    pub local_address: SocketAddr,
+    /// BND.ADDR:BND.PORT from the proxy's CONNECT reply. Tor doesn't know the exit's
+    /// outgoing address, so it typically reports 0.0.0.0:0
+    pub bound_address: String,
+}
+
 pub trait DataObserver {
     fn on_data(&self, data: String);
     fn on_error(&self, data: String);
@@ -26,32 +46,91 @@ pub trait DataObserver {
 impl TcpSocksStream {
     /// Blocks indefinitely until connection established
     fn new(target: String, socks_proxy: String) -> Result<Self, TorErrors> {
-        let socks_stream = Socks5Stream::connect(socks_proxy.as_str(), target.as_str())?;
+        crate::killswitch::check(&socks_proxy)?;
+        let socks_stream = Socks5Stream::connect(socks_proxy.as_str(), target.as_str())
+            .map_err(|e| TorErrors::from_socks_connect(e, &target))?;
         let tcpstream = socks_stream.get_ref().try_clone()?;
+        let info = ConnectionInfo {
+            target,
+            local_address: tcpstream.local_addr()?,
+            bound_address: match socks_stream.proxy_addr() {
+                TargetAddr::Ip(addr) => addr.to_string(),
+                TargetAddr::Domain(host, port) => format!("{}:{}", host, port),
+            },
+        };
         Ok(TcpSocksStream {
             stream: socks_stream,
+            info,
             read_buffer: Arc::new(RwLock::new(BufReader::new(TcpStream::from_std(tcpstream)?))),
             data_handler: Arc::new(RwLock::new(None)),
         })
     }
     /// New (connect) but with a timeout
     /// Blocks till connection established or timeout (in MS) expires
+    /// The SOCKS connect blocks, so it runs on a thread of its own rather than on the shared
+    /// runtime whose few workers it could starve. A connect still pending at the timeout is
+    /// left to finish on that thread
     pub fn new_timeout(
         target: String,
         socks_proxy: String,
         timeout_ms: u64,
     ) -> Result<Self, TorErrors> {
-        let socks_future = ensure_runtime()
-            .lock()
-            .unwrap()
-            .spawn(async move { TcpSocksStream::new(target, socks_proxy) });
-
-        ensure_runtime()
-            .lock()
-            .unwrap()
-            .block_on(async move { timeout(Duration::from_millis(timeout_ms), socks_future).await })
-            .map_err(|_| TorErrors::BootStrapError(String::from("Tcp connection timedout")))?
-            .map_err(TorErrors::ThreadingError)?
+        let (sender, receiver) = mpsc::channel();
+        let runtime = runtime_handle();
+        std::thread::spawn(move || {
+            // The stream's read half is registered with the runtime's reactor
+            let _runtime = runtime.enter();
+            let _ = sender.send(TcpSocksStream::new(target, socks_proxy));
+        });
+        match receiver.recv_timeout(Duration::from_millis(timeout_ms)) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => Err(TorErrors::BootStrapError(String::from(
+                "Tcp connection timedout",
+            ))),
+            Err(RecvTimeoutError::Disconnected) => Err(TorErrors::TcpStreamError(String::from(
+                "Tcp connection thread panicked",
+            ))),
+        }
+    }
+
+    /// Same as new_timeout but connects through `transport`, e.g. a SOCKS4a `SocksTransport`
+    /// or a `StubTransport`
+    pub fn new_timeout_via(
+        target: String,
+        transport: &dyn TorTransport,
+        timeout_ms: u64,
+    ) -> Result<Self, TorErrors> {
+        TcpSocksStream::new_timeout(target, transport.socks_proxy()?, timeout_ms)
+    }
+
+    /// Opens a tunnel to `tunnel_target` (host:port) through the HTTP proxy at `proxy_target`
+    /// with the CONNECT method, e.g. an onion hosted proxy in front of an upstream service
+    /// Blocks till the proxy accepted the tunnel or timeout (in MS) expires, after which the
+    /// stream carries raw tunnelled data
+    pub fn connect_tunnel(
+        proxy_target: String,
+        tunnel_target: String,
+        socks_proxy: String,
+        timeout_ms: u64,
+    ) -> Result<Self, TorErrors> {
+        // It is written into the request line as is
+        validate_authority(&tunnel_target)?;
+        let mut tunnel = TcpSocksStream::new_timeout(proxy_target, socks_proxy, timeout_ms)?;
+        let io_timeout = Some(Duration::from_millis(timeout_ms));
+        tunnel.send_data(
+            format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", tunnel_target),
+            io_timeout,
+        )?;
+        tunnel.stream.get_ref().set_read_timeout(io_timeout)?;
+        let status = read_connect_response(tunnel.stream.get_mut())?;
+        tunnel.stream.get_ref().set_read_timeout(None)?;
+        if !(200..300).contains(&status) {
+            return Err(TorErrors::TcpStreamError(format!(
+                "Proxy refused CONNECT to {} with status {}",
+                tunnel_target, status
+            )));
+        }
+        Ok(tunnel)
     }
 
     pub fn set_data_handler<F>(&self, callback: F) -> Result<(), TorErrors>
@@ -156,14 +235,63 @@ impl TcpSocksStream {
         tcp_stream.flush()?;
         Ok(())
     }
+    /// Sets TCP_NODELAY on the socket to the SOCKS proxy, disabling Nagle's algorithm
+    /// Useful for latency sensitive, chatty protocols
+    pub fn set_nodelay(&self, nodelay: bool) -> Result<(), TorErrors> {
+        self.stream.get_ref().set_nodelay(nodelay)?;
+        Ok(())
+    }
+    /// Enables TCP keepalive on the socket to the SOCKS proxy after `keepalive` of idle time,
+    /// None disables it
+    pub fn set_keepalive(&self, keepalive: Option<Duration>) -> Result<(), TorErrors> {
+        let socket = SockRef::from(self.stream.get_ref());
+        match keepalive {
+            Some(idle) => socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(idle))?,
+            None => socket.set_keepalive(false)?,
+        }
+        Ok(())
+    }
+    /// Target, local address and SOCKS bound address of this stream
+    pub fn connection_info(&self) -> &ConnectionInfo {
+        &self.info
+    }
     pub fn shutdown(&mut self) -> Result<(), TorErrors> {
         self.stream.get_ref().shutdown(Shutdown::Both)?;
         Ok(())
     }
 }
+/// Reads the proxy reply to CONNECT up to the end of its headers and returns the status code
+/// Reads byte by byte so none of the tunnelled data that may follow is consumed
+fn read_connect_response<R: Read>(reader: &mut R) -> Result<u16, TorErrors> {
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_CONNECT_RESPONSE_BYTES {
+            return Err(TorErrors::TcpStreamError(String::from(
+                "CONNECT response headers too large",
+            )));
+        }
+        if reader.read(&mut byte)? == 0 {
+            return Err(TorErrors::TcpStreamError(String::from(
+                "Proxy closed the connection during CONNECT",
+            )));
+        }
+        head.push(byte[0]);
+    }
+    let mut headers = [httparse::EMPTY_HEADER; 32];
+    let mut response = httparse::Response::new(&mut headers);
+    response
+        .parse(&head)
+        .map_err(|e| TorErrors::TcpStreamError(format!("Invalid CONNECT response: {}", e)))?;
+    response.code.ok_or(TorErrors::TcpStreamError(String::from(
+        "Incomplete CONNECT response",
+    )))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::http_client::socks5_accept;
     use crate::{TorService, TorServiceParam};
     use serial_test::serial;
 
@@ -171,6 +299,132 @@ mod tests {
 
     use std::sync::{Arc, Mutex};
 
+    #[test]
+    fn reads_connect_response_without_consuming_tunnel_data() {
+        let mut reply = std::io::Cursor::new(
+            b"HTTP/1.1 200 Connection established\r\nProxy-Agent: test\r\n\r\nSSH-2.0".to_vec(),
+        );
+        assert_eq!(read_connect_response(&mut reply).unwrap(), 200);
+        let mut rest = String::new();
+        reply.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "SSH-2.0");
+
+        let mut refused = std::io::Cursor::new(b"HTTP/1.1 403 Forbidden\r\n\r\n".to_vec());
+        assert_eq!(read_connect_response(&mut refused).unwrap(), 403);
+
+        let mut truncated = std::io::Cursor::new(b"HTTP/1.1 200 OK\r\n".to_vec());
+        assert!(read_connect_response(&mut truncated).is_err());
+    }
+
+    #[test]
+    fn rejects_injected_tunnel_targets() {
+        // Refused before connecting, nothing listens on the proxy port
+        let result = TcpSocksStream::connect_tunnel(
+            "proxy.onion:8080".into(),
+            "example.com:443 HTTP/1.1\r\nX-Admin: 1\r\n\r\nGET /".into(),
+            "127.0.0.1:9".into(),
+            1000,
+        );
+        assert!(matches!(result, Err(TorErrors::InvalidHeader(_))));
+    }
+
+    #[test]
+    fn reports_socks_bound_address() {
+        let proxy = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let socks_proxy = proxy.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || {
+            let (mut client, _) = proxy.accept().unwrap();
+            assert_eq!(socks5_accept(&mut client).unwrap(), "example.com:80");
+            client
+                .write_all(&[5, 0, 0, 1, 10, 1, 2, 3, 0x11, 0xd7])
+                .unwrap();
+            client
+        });
+
+        let stream =
+            TcpSocksStream::new_timeout("example.com:80".into(), socks_proxy, 5000).unwrap();
+        let _client = server.join().unwrap();
+        let info = stream.connection_info();
+        assert_eq!(info.target, "example.com:80");
+        assert_eq!(info.bound_address, "10.1.2.3:4567");
+        assert!(info.local_address.ip().is_loopback());
+    }
+
+    #[test]
+    fn connects_through_a_transport() {
+        use crate::http_client::StubTransport;
+
+        let transport = StubTransport::new();
+        transport.push_response("pong");
+        let mut stream =
+            TcpSocksStream::new_timeout_via("example.onion:80".into(), &transport, 5000).unwrap();
+        assert_eq!(stream.connection_info().target, "example.onion:80");
+        stream
+            .send_data("ping\r\n\r\n".into(), Some(Duration::from_secs(5)))
+            .unwrap();
+        // The stub records what reaches it through the bridge
+        let started = std::time::Instant::now();
+        let sent = |transport: &StubTransport| transport.requests().first().cloned();
+        while sent(&transport).as_deref() != Some(&b"ping\r\n\r\n"[..]) {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn pending_connects_leave_the_runtime_free() {
+        // A proxy that accepts and never answers keeps every connect pending until its timeout
+        let proxy = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let socks_proxy = proxy.local_addr().unwrap().to_string();
+        let connects: Vec<_> = (0..4)
+            .map(|_| {
+                let socks_proxy = socks_proxy.clone();
+                std::thread::spawn(move || {
+                    TcpSocksStream::new_timeout("example.onion:80".into(), socks_proxy, 2000)
+                })
+            })
+            .collect();
+        let _clients: Vec<_> = (0..4).map(|_| proxy.accept().unwrap()).collect();
+
+        // More pending connects than worker threads, spawned tasks still run
+        let started = std::time::Instant::now();
+        let task = ensure_runtime().lock().unwrap().spawn(async {});
+        runtime_handle().block_on(task).unwrap();
+        assert!(started.elapsed() < Duration::from_millis(500));
+
+        for connect in connects {
+            assert_eq!(connect.join().unwrap().err().unwrap().code(), 3);
+        }
+    }
+
+    #[test]
+    fn maps_socks_failure_replies() {
+        let proxy = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let socks_proxy = proxy.local_addr().unwrap().to_string();
+        let replies = [1u8, 4, 5, 6];
+        let server = std::thread::spawn(move || {
+            for reply in replies {
+                let (mut client, _) = proxy.accept().unwrap();
+                socks5_accept(&mut client).unwrap();
+                client
+                    .write_all(&[5, reply, 0, 1, 0, 0, 0, 0, 0, 0])
+                    .unwrap();
+            }
+        });
+
+        let codes: Vec<i32> = replies
+            .iter()
+            .map(|_| {
+                TcpSocksStream::new_timeout("example.onion:80".into(), socks_proxy.clone(), 5000)
+                    .err()
+                    .unwrap()
+                    .code()
+            })
+            .collect();
+        server.join().unwrap();
+        assert_eq!(codes, vec![17, 18, 19, 20]);
+    }
+
     #[test]
     #[serial(tor)]
     fn connects_with_timeout() {
@@ -178,6 +432,7 @@ mod tests {
             socks_port: Some(19054),
             data_dir: String::from("/tmp/sifir_rs_sdk/"),
             bootstrap_timeout_ms: Some(45000),
+            ..Default::default()
         }
         .try_into()
         .unwrap();
@@ -197,6 +452,7 @@ mod tests {
             socks_port: Some(19054),
             data_dir: String::from("/tmp/sifir_rs_sdk/"),
             bootstrap_timeout_ms: Some(45000),
+            ..Default::default()
         }
         .try_into()
         .unwrap();
@@ -250,6 +506,7 @@ mod tests {
             socks_port: Some(19054),
             data_dir: String::from("/tmp/sifir_rs_sdk/"),
             bootstrap_timeout_ms: Some(45000),
+            ..Default::default()
         }
         .try_into()
         .unwrap();
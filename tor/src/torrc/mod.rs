@@ -0,0 +1,431 @@
+//! Optional Tor configuration from `TorServiceParam`, applied with SETCONF once the control
+//! connection is up and before bootstrap is awaited
+//! Options Tor only reads at startup (the directory authorities of a private network) are
+//! written to a torrc file instead, see `startup_lines`
+//! Everything is validated before Tor is started so a typo fails the conversion
+//! instead of leaving a daemon running with half the config
+use std::net::{SocketAddrV4, SocketAddrV6};
+
+use crate::{TorErrors, TorServiceParam};
+
+/// More guards than this only spreads the client over more first hops that could be hostile
+const MAX_ENTRY_GUARDS: u8 = 10;
+
+/// Torrc options (name, value) requested by `param`
+pub(crate) fn options(param: &TorServiceParam) -> Result<Vec<(String, String)>, TorErrors> {
+    let mut options = Vec::new();
+    if let Some(use_entry_guards) = param.use_entry_guards {
+        options.push((
+            String::from("UseEntryGuards"),
+            String::from(if use_entry_guards { "1" } else { "0" }),
+        ));
+    }
+    if let Some(entry_nodes) = &param.entry_nodes {
+        if param.use_entry_guards == Some(false) {
+            return Err(TorErrors::InvalidConfig(String::from(
+                "entry_nodes only applies when use_entry_guards is enabled",
+            )));
+        }
+        options.push((String::from("EntryNodes"), node_list(entry_nodes)?));
+    }
+    if let Some(num_entry_guards) = param.num_entry_guards {
+        if param.use_entry_guards == Some(false) {
+            return Err(TorErrors::InvalidConfig(String::from(
+                "num_entry_guards only applies when use_entry_guards is enabled",
+            )));
+        }
+        if !(1..=MAX_ENTRY_GUARDS).contains(&num_entry_guards) {
+            return Err(TorErrors::InvalidConfig(format!(
+                "num_entry_guards must be between 1 and {}, got {}",
+                MAX_ENTRY_GUARDS, num_entry_guards
+            )));
+        }
+        options.push((String::from("NumEntryGuards"), num_entry_guards.to_string()));
+    }
+    if let Some(predicted_circuits) = param.predicted_circuits {
+        options.push((
+            String::from("__DisablePredictedCircuits"),
+            String::from(if predicted_circuits { "0" } else { "1" }),
+        ));
+    }
+    if let Some(safe_logging) = param.safe_logging {
+        options.push((
+            String::from("SafeLogging"),
+            String::from(if safe_logging { "1" } else { "0" }),
+        ));
+    }
+    if let Some(dns_port) = param.dns_port {
+        options.push((
+            String::from("DNSPort"),
+            listener("dns_port", dns_port, param)?,
+        ));
+    }
+    if let Some(trans_port) = param.trans_port {
+        if !cfg!(any(
+            target_os = "linux",
+            target_os = "freebsd",
+            target_os = "openbsd",
+            target_os = "macos"
+        )) {
+            return Err(TorErrors::InvalidConfig(String::from(
+                "trans_port is not supported on this platform",
+            )));
+        }
+        options.push((
+            String::from("TransPort"),
+            listener("trans_port", trans_port, param)?,
+        ));
+    }
+    Ok(options)
+}
+
+/// Torrc lines for the options that can't be changed once Tor runs, written to the torrc file
+/// Tor is started with. Empty unless a private network is configured
+/// TestingTorNetwork can't be set at runtime and bootstrapping against the public
+/// authorities would already have started by the time SETCONF runs
+pub(crate) fn startup_lines(param: &TorServiceParam) -> Result<Vec<String>, TorErrors> {
+    let mut lines = Vec::new();
+    for authority in param.dir_authorities.iter().flatten() {
+        if !is_valid_dir_authority(authority) {
+            return Err(TorErrors::InvalidConfig(format!(
+                "Invalid DirAuthority line: {:?}",
+                authority
+            )));
+        }
+        lines.push(format!("DirAuthority {}", authority.trim()));
+    }
+    for fallback in param.fallback_dirs.iter().flatten() {
+        if !is_valid_fallback_dir(fallback) {
+            return Err(TorErrors::InvalidConfig(format!(
+                "Invalid FallbackDir line: {:?}",
+                fallback
+            )));
+        }
+        lines.push(format!("FallbackDir {}", fallback.trim()));
+    }
+    if param.testing_tor_network == Some(true) {
+        if param.dir_authorities.as_ref().is_none_or(Vec::is_empty) {
+            return Err(TorErrors::InvalidConfig(String::from(
+                "testing_tor_network needs the network's dir_authorities",
+            )));
+        }
+        lines.push(String::from("TestingTorNetwork 1"));
+    }
+    Ok(lines)
+}
+
+/// Whether `line` is the value of a DirAuthority line:
+/// `[nickname] [flags] ipv4:dirport fingerprint`, flags being `orport=`, `v3ident=`, `ipv6=`,
+/// `weight=`, `bridge`, `hs`, `no-hs`, `no-v2` or `v1`, the fingerprint 40 hex digits that
+/// may be split in groups (as chutney writes them)
+pub fn is_valid_dir_authority(line: &str) -> bool {
+    if line.contains(['\n', '\r', '#']) {
+        return false;
+    }
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    let Some(address) = tokens
+        .iter()
+        .position(|t| t.parse::<SocketAddrV4>().is_ok_and(|a| a.port() != 0))
+    else {
+        return false;
+    };
+    if !is_fingerprint(&tokens[address + 1..].concat()) {
+        return false;
+    }
+    tokens[..address]
+        .iter()
+        .enumerate()
+        .all(|(i, token)| match token.split_once('=') {
+            Some(("orport", port)) => is_port(port),
+            Some(("v3ident", id)) => is_fingerprint(id),
+            Some(("ipv6", address)) => address.parse::<SocketAddrV6>().is_ok(),
+            Some(("weight", weight)) => weight.parse::<u32>().is_ok(),
+            Some(_) => false,
+            None => {
+                matches!(*token, "bridge" | "hs" | "no-hs" | "no-v2" | "v1")
+                    || (i == 0 && is_nickname(token))
+            }
+        })
+}
+
+/// Whether `line` is the value of a FallbackDir line:
+/// `ipv4:dirport orport=port id=fingerprint [weight=num] [ipv6=[address]:orport]`
+pub fn is_valid_fallback_dir(line: &str) -> bool {
+    if line.contains(['\n', '\r', '#']) {
+        return false;
+    }
+    let mut tokens = line.split_whitespace();
+    if !tokens
+        .next()
+        .is_some_and(|t| t.parse::<SocketAddrV4>().is_ok_and(|a| a.port() != 0))
+    {
+        return false;
+    }
+    let (mut orport, mut id) = (false, false);
+    let valid = tokens.all(|token| match token.split_once('=') {
+        Some(("orport", port)) => {
+            orport = true;
+            is_port(port)
+        }
+        Some(("id", fingerprint)) => {
+            id = true;
+            is_fingerprint(fingerprint)
+        }
+        Some(("weight", weight)) => weight.parse::<f64>().is_ok_and(|w| w >= 0.0),
+        Some(("ipv6", address)) => address.parse::<SocketAddrV6>().is_ok(),
+        _ => false,
+    });
+    valid && orport && id
+}
+
+/// 40 hex digits, a relay identity fingerprint
+fn is_fingerprint(fingerprint: &str) -> bool {
+    fingerprint.len() == 40 && fingerprint.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn is_port(port: &str) -> bool {
+    port.parse::<u16>().is_ok_and(|port| port != 0)
+}
+
+/// Local listener address for `port`, which must be set and not shared with another listener
+fn listener(name: &str, port: u16, param: &TorServiceParam) -> Result<String, TorErrors> {
+    let ports = [param.socks_port, param.dns_port, param.trans_port];
+    if port == 0 || ports.iter().filter(|p| **p == Some(port)).count() > 1 {
+        return Err(TorErrors::InvalidConfig(format!(
+            "Invalid {} {}, it must be non zero and differ from the other ports",
+            name, port
+        )));
+    }
+    Ok(format!("127.0.0.1:{}", port))
+}
+
+/// Joins relay specifiers into Tor's comma separated list, rejecting invalid ones
+fn node_list(nodes: &[String]) -> Result<String, TorErrors> {
+    if nodes.is_empty() {
+        return Err(TorErrors::InvalidConfig(String::from("Empty node list")));
+    }
+    if let Some(invalid) = nodes.iter().find(|n| !is_valid_node_spec(n)) {
+        return Err(TorErrors::InvalidConfig(format!(
+            "Invalid node specifier: {:?}",
+            invalid
+        )));
+    }
+    Ok(nodes.join(","))
+}
+
+/// Whether `spec` is a relay specifier Tor accepts in node lists (EntryNodes, ExitNodes, ...):
+/// a fingerprint (`$` + 40 hex, optionally followed by `~nickname` or `=nickname`),
+/// a nickname, a country code (`{de}`) or an IPv4 address with an optional `/bits` mask
+pub fn is_valid_node_spec(spec: &str) -> bool {
+    if let Some(country) = spec.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+        return country.len() == 2 && country.chars().all(|c| c.is_ascii_alphabetic());
+    }
+    let fingerprint = spec.strip_prefix('$').unwrap_or(spec);
+    let (fingerprint, nickname) = match fingerprint.find(['~', '=']) {
+        Some(i) => (&fingerprint[..i], Some(&fingerprint[i + 1..])),
+        None => (fingerprint, None),
+    };
+    if fingerprint.len() == 40 && fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+        return nickname.is_none_or(is_nickname);
+    }
+    if let Some((address, bits)) = spec.split_once('/') {
+        return address.parse::<std::net::Ipv4Addr>().is_ok()
+            && bits.parse::<u8>().is_ok_and(|bits| bits <= 32);
+    }
+    spec.parse::<std::net::Ipv4Addr>().is_ok() || (!spec.starts_with('$') && is_nickname(spec))
+}
+
+/// 1 to 19 ASCII letters or digits
+fn is_nickname(name: &str) -> bool {
+    (1..=19).contains(&name.len()) && name.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validates_node_specifiers() {
+        for valid in [
+            "$0123456789ABCDEF0123456789ABCDEF01234567",
+            "0123456789abcdef0123456789abcdef01234567",
+            "$0123456789ABCDEF0123456789ABCDEF01234567~relay1",
+            "relay1",
+            "{de}",
+            "192.0.2.1",
+            "192.0.2.0/24",
+        ] {
+            assert!(is_valid_node_spec(valid), "{}", valid);
+        }
+        for invalid in [
+            "",
+            "$0123",
+            "$0123456789ABCDEF0123456789ABCDEF0123456Z",
+            "{deu}",
+            "relay-with-dash",
+            "averyveryverylongnickname",
+            "192.0.2.0/40",
+            "relay1,relay2",
+        ] {
+            assert!(!is_valid_node_spec(invalid), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn maps_entry_guard_options() {
+        let mut param = TorServiceParam {
+            entry_nodes: Some(vec!["{de}".into(), "relay1".into()]),
+            ..Default::default()
+        };
+        assert_eq!(
+            options(&param).unwrap(),
+            vec![(String::from("EntryNodes"), String::from("{de},relay1"))]
+        );
+
+        param.use_entry_guards = Some(false);
+        assert!(matches!(options(&param), Err(TorErrors::InvalidConfig(_))));
+
+        param.entry_nodes = Some(vec!["not valid".into()]);
+        param.use_entry_guards = None;
+        assert!(matches!(options(&param), Err(TorErrors::InvalidConfig(_))));
+
+        param.entry_nodes = None;
+        param.use_entry_guards = Some(false);
+        assert_eq!(
+            options(&param).unwrap(),
+            vec![(String::from("UseEntryGuards"), String::from("0"))]
+        );
+    }
+
+    #[test]
+    fn maps_circuit_building_options() {
+        let mut param = TorServiceParam {
+            num_entry_guards: Some(2),
+            predicted_circuits: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(
+            options(&param).unwrap(),
+            vec![
+                (String::from("NumEntryGuards"), String::from("2")),
+                (
+                    String::from("__DisablePredictedCircuits"),
+                    String::from("1")
+                ),
+            ]
+        );
+        for invalid in [0, MAX_ENTRY_GUARDS + 1] {
+            param.num_entry_guards = Some(invalid);
+            assert!(matches!(options(&param), Err(TorErrors::InvalidConfig(_))));
+        }
+        param.num_entry_guards = Some(1);
+        param.use_entry_guards = Some(false);
+        assert!(matches!(options(&param), Err(TorErrors::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn writes_private_network_lines() {
+        let authority = "test000a orport=5000 no-v2 \
+            v3ident=0123456789ABCDEF0123456789ABCDEF01234567 127.0.0.1:7000 \
+            89AB CDEF 0123 4567 89AB CDEF 0123 4567 89AB CDEF";
+        let fallback =
+            "127.0.0.1:7001 orport=5001 id=0123456789ABCDEF0123456789ABCDEF01234567 weight=10";
+        let mut param = TorServiceParam {
+            dir_authorities: Some(vec![authority.into()]),
+            fallback_dirs: Some(vec![fallback.into()]),
+            testing_tor_network: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(
+            startup_lines(&param).unwrap(),
+            vec![
+                format!("DirAuthority {}", authority),
+                format!("FallbackDir {}", fallback),
+                String::from("TestingTorNetwork 1"),
+            ]
+        );
+        assert!(options(&param).unwrap().is_empty());
+        assert!(
+            startup_lines(&TorServiceParam::default())
+                .unwrap()
+                .is_empty()
+        );
+
+        param.dir_authorities = None;
+        assert!(matches!(
+            startup_lines(&param),
+            Err(TorErrors::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn validates_directory_lines() {
+        assert!(is_valid_dir_authority(
+            "moria1 orport=9101 v3ident=D586D18309DED4CD6D57C18FDB97EFA96D330566 \
+             128.31.0.39:9131 9695 DFC3 5FFE B861 329B 9F1A B04C 4639 7020 CE31"
+        ));
+        assert!(is_valid_dir_authority(
+            "127.0.0.1:7000 0123456789ABCDEF0123456789ABCDEF01234567"
+        ));
+        for invalid in [
+            "",
+            "test000a orport=5000 127.0.0.1:7000",
+            "test000a 127.0.0.1:7000 0123 4567",
+            "test000a orport=0 127.0.0.1:7000 0123456789ABCDEF0123456789ABCDEF01234567",
+            "test000a bogus=1 127.0.0.1:7000 0123456789ABCDEF0123456789ABCDEF01234567",
+            "a b 127.0.0.1:7000 0123456789ABCDEF0123456789ABCDEF01234567",
+            "test000a 127.0.0.1:7000 0123456789ABCDEF0123456789ABCDEF01234567\nControlPort 1",
+        ] {
+            assert!(!is_valid_dir_authority(invalid), "{}", invalid);
+        }
+
+        assert!(is_valid_fallback_dir(
+            "192.0.2.1:80 orport=443 id=0123456789ABCDEF0123456789ABCDEF01234567 \
+             ipv6=[2001:db8::1]:443"
+        ));
+        for invalid in [
+            "192.0.2.1:80 orport=443",
+            "192.0.2.1:80 id=0123456789ABCDEF0123456789ABCDEF01234567",
+            "example.com:80 orport=443 id=0123456789ABCDEF0123456789ABCDEF01234567",
+            "192.0.2.1:80 orport=443 id=0123456789ABCDEF0123456789ABCDEF01234567 weight=x",
+        ] {
+            assert!(!is_valid_fallback_dir(invalid), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn maps_dns_port() {
+        let mut param = TorServiceParam {
+            socks_port: Some(19050),
+            dns_port: Some(19053),
+            ..Default::default()
+        };
+        assert_eq!(
+            options(&param).unwrap(),
+            vec![(String::from("DNSPort"), String::from("127.0.0.1:19053"))]
+        );
+        for invalid in [0, 19050] {
+            param.dns_port = Some(invalid);
+            assert!(matches!(options(&param), Err(TorErrors::InvalidConfig(_))));
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn maps_trans_port() {
+        let mut param = TorServiceParam {
+            dns_port: Some(19053),
+            trans_port: Some(19040),
+            ..Default::default()
+        };
+        assert_eq!(
+            options(&param).unwrap(),
+            vec![
+                (String::from("DNSPort"), String::from("127.0.0.1:19053")),
+                (String::from("TransPort"), String::from("127.0.0.1:19040")),
+            ]
+        );
+        param.trans_port = Some(19053);
+        assert!(matches!(options(&param), Err(TorErrors::InvalidConfig(_))));
+    }
+}
@@ -0,0 +1,129 @@
+//! Vanity v3 onion addresses: keys are generated until the address starts with a chosen
+//! prefix. Each base32 character multiplies the work by 32, a 4 character prefix takes
+//! about a million attempts on average and 6 characters already about a billion
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use torut::onion::TorSecretKeyV3;
+
+use crate::TorErrors;
+
+/// Length of the base32 part of a v3 onion address
+const ADDRESS_LEN: usize = 56;
+/// Attempts a worker claims at once from the shared budget
+const BATCH: u64 = 64;
+/// How often the progress callback is called
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Generates keys until the onion address starts with `prefix` (base32: a-z and 2-7, case
+/// insensitive), giving up with `TorErrors::VanityNotFound` after `max_attempts` keys
+/// Returns the onion address and the secret key, like `generate_onion_keypair`
+pub fn generate_vanity_onion(
+    prefix: &str,
+    max_attempts: u64,
+) -> Result<(String, [u8; 64]), TorErrors> {
+    generate_vanity_onion_with_progress(prefix, max_attempts, |_| {})
+}
+
+/// Same as `generate_vanity_onion`, calling `progress` with the number of keys tried so far
+/// about every 250ms and once at the end. Keys are generated on one thread per CPU while
+/// `progress` runs on the calling thread
+pub fn generate_vanity_onion_with_progress<P>(
+    prefix: &str,
+    max_attempts: u64,
+    mut progress: P,
+) -> Result<(String, [u8; 64]), TorErrors>
+where
+    P: FnMut(u64),
+{
+    let prefix = prefix.trim().to_ascii_lowercase();
+    if prefix.len() > ADDRESS_LEN || !prefix.chars().all(|c| matches!(c, 'a'..='z' | '2'..='7')) {
+        return Err(TorErrors::InvalidConfig(format!(
+            "Invalid onion prefix {:?}, only a-z and 2-7 can appear in an address",
+            prefix
+        )));
+    }
+
+    let claimed = AtomicU64::new(0);
+    let tried = AtomicU64::new(0);
+    let done = AtomicBool::new(false);
+    let found = Mutex::new(None);
+    let workers = thread::available_parallelism().map_or(1, |n| n.get());
+    thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers)
+            .map(|_| {
+                scope.spawn(|| {
+                    while !done.load(Ordering::Relaxed) {
+                        let start = claimed.fetch_add(BATCH, Ordering::Relaxed);
+                        if start >= max_attempts {
+                            break;
+                        }
+                        for _ in start..max_attempts.min(start + BATCH) {
+                            let key = TorSecretKeyV3::generate();
+                            let address = key.public().get_onion_address().to_string();
+                            tried.fetch_add(1, Ordering::Relaxed);
+                            if address.starts_with(&prefix) {
+                                found
+                                    .lock()
+                                    .unwrap()
+                                    .get_or_insert((address, key.as_bytes()));
+                                done.store(true, Ordering::Relaxed);
+                                break;
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        while !handles.iter().all(|h| h.is_finished()) {
+            progress(tried.load(Ordering::Relaxed));
+            thread::sleep(PROGRESS_INTERVAL);
+        }
+    });
+
+    let attempts = tried.load(Ordering::Relaxed);
+    progress(attempts);
+    found
+        .into_inner()
+        .unwrap()
+        .ok_or(TorErrors::VanityNotFound { attempts })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_short_prefixes() {
+        let (address, key) = generate_vanity_onion("A", 100_000).unwrap();
+        assert!(address.starts_with('a') && address.ends_with(".onion"));
+        let restored: TorSecretKeyV3 = key.into();
+        assert_eq!(restored.public().get_onion_address().to_string(), address);
+
+        let mut reports = Vec::new();
+        let (address, _) =
+            generate_vanity_onion_with_progress("", 10, |n| reports.push(n)).unwrap();
+        assert!(address.ends_with(".onion"));
+        assert!(reports.last().is_some_and(|n| *n >= 1));
+    }
+
+    #[test]
+    fn rejects_bad_prefixes_and_gives_up() {
+        for invalid in ["1", "onion0", "ab-c", &"a".repeat(ADDRESS_LEN + 1)] {
+            assert!(
+                matches!(
+                    generate_vanity_onion(invalid, 10),
+                    Err(TorErrors::InvalidConfig(_))
+                ),
+                "{}",
+                invalid
+            );
+        }
+        assert!(matches!(
+            generate_vanity_onion(&"a".repeat(ADDRESS_LEN), 10),
+            Err(TorErrors::VanityNotFound { attempts: 10 })
+        ));
+    }
+}